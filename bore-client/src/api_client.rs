@@ -28,7 +28,7 @@ pub struct LoginResponse {
 }
 
 /// Tunnel instance information
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Instance {
     pub id: String,
     pub name: String,
@@ -50,6 +50,9 @@ pub struct ConnectionInfo {
     pub instance_id: String,
     pub tunnel_token: String,
     pub server_host: String,
+    /// Other servers the tunnel may fail over to.
+    #[serde(default)]
+    pub fallback_hosts: Vec<String>,
     pub local_port: u16,
     pub remote_port: u16,
     pub ttl: u64,
@@ -1,9 +1,11 @@
 //! Authentication and credential management for bore client.
 
 use anyhow::{Context, Result};
+use bore_shared::KeyPair;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 /// User credentials stored locally
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,3 +80,50 @@ impl Credentials {
             .unwrap_or(false)
     }
 }
+
+/// Default location of the private key used for public-key authentication
+pub fn default_key_path() -> Result<PathBuf> {
+    let home = dirs::home_dir().context("could not find home directory")?;
+    Ok(home.join(".bore").join("id_ed25519"))
+}
+
+/// Load a private key written by `bore keygen`
+pub fn load_key(path: &Path) -> Result<KeyPair> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read key file {}", path.display()))?;
+    KeyPair::from_hex(&content).with_context(|| format!("invalid key file {}", path.display()))
+}
+
+/// Save a private key to `path` and its public key to `path.pub`
+///
+/// Returns the path of the public key file.
+pub fn save_key(key: &KeyPair, path: &Path) -> Result<PathBuf> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Create the file as 600 (owner read/write only) on Unix, so the key is
+    // never readable by others.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).context("failed to write key file")?;
+    // An existing file keeps its mode; tighten it before writing the key.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(format!("{}\n", key.to_hex()).as_bytes())
+        .context("failed to write key file")?;
+
+    let mut public_path = path.as_os_str().to_owned();
+    public_path.push(".pub");
+    let public_path = PathBuf::from(public_path);
+    fs::write(&public_path, format!("{}\n", key.public_key()))
+        .context("failed to write public key file")?;
+    Ok(public_path)
+}
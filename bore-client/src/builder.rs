@@ -0,0 +1,543 @@
+//! Embedding tunnels in other applications.
+//!
+//! [`ClientBuilder`] opens a tunnel from a typed [`ClientConfig`] and runs it
+//! in the background. The [`ClientHandle`] it returns knows the public port,
+//! keeps connection statistics, changes settings of the running tunnel,
+//! broadcasts its [`ClientEvent`]s and stops it, so applications like the GUI
+//! don't have to wire up channels around [`Client::listen`] themselves.
+//!
+//! Given more than one server, the builder connects to the one answering
+//! fastest and moves the tunnel to another when it loses it.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bore_shared::{KeyPair, TunnelInfo, NETWORK_TIMEOUT};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tracing::warn;
+
+use crate::client::{Client, Command, Settings};
+use crate::event::ClientEvent;
+use crate::failover;
+use crate::health::HealthCheck;
+
+/// Number of events a slow subscriber may lag behind before missing some.
+const EVENT_CAPACITY: usize = 64;
+
+/// Callback run for every event of a tunnel.
+type Callback = Arc<dyn Fn(&ClientEvent) + Send + Sync>;
+
+/// Everything needed to open a tunnel.
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// Local host that is forwarded.
+    pub local_host: String,
+
+    /// Local port that is forwarded.
+    pub local_port: u16,
+
+    /// Address of the server.
+    pub server: String,
+
+    /// More servers to choose from and fail over to.
+    pub alternate_servers: Vec<String>,
+
+    /// Public port to ask for, or 0 for any free port.
+    pub remote_port: u16,
+
+    /// Secret or API key to authenticate with.
+    pub secret: Option<String>,
+
+    /// Key pair to authenticate with instead of a secret.
+    pub key: Option<KeyPair>,
+
+    /// Whether to share the public port with other clients.
+    pub share: bool,
+
+    /// Name of the shared tunnel to join, which implies `share`.
+    pub group: Option<String>,
+
+    /// Health check of the local service.
+    pub health_check: Option<HealthCheck>,
+
+    /// Time between health checks.
+    pub health_interval: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            local_host: "localhost".to_string(),
+            local_port: 0,
+            server: String::new(),
+            alternate_servers: Vec::new(),
+            remote_port: 0,
+            secret: None,
+            key: None,
+            share: false,
+            group: None,
+            health_check: None,
+            health_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl fmt::Debug for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Credentials are left out so configs can be logged.
+        f.debug_struct("ClientConfig")
+            .field("local_host", &self.local_host)
+            .field("local_port", &self.local_port)
+            .field("server", &self.server)
+            .field("alternate_servers", &self.alternate_servers)
+            .field("remote_port", &self.remote_port)
+            .field("secret", &self.secret.as_ref().map(|_| "..."))
+            .field("key", &self.key.as_ref().map(|_| "..."))
+            .field("share", &self.share)
+            .field("group", &self.group)
+            .field("health_check", &self.health_check)
+            .field("health_interval", &self.health_interval)
+            .finish()
+    }
+}
+
+/// Builds and starts a tunnel.
+#[derive(Clone)]
+pub struct ClientBuilder {
+    config: ClientConfig,
+    callbacks: Vec<Callback>,
+}
+
+impl ClientBuilder {
+    /// Forward `local_port` on localhost through the server at `server`.
+    pub fn new(server: &str, local_port: u16) -> Self {
+        Self::from_config(ClientConfig {
+            server: server.to_string(),
+            local_port,
+            ..ClientConfig::default()
+        })
+    }
+
+    /// Start from a complete configuration.
+    pub fn from_config(config: ClientConfig) -> Self {
+        ClientBuilder {
+            config,
+            callbacks: Vec::new(),
+        }
+    }
+
+    /// The configuration built so far.
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Also consider `server`, connecting to whichever server answers fastest
+    /// and failing over to the others.
+    pub fn alternate_server(mut self, server: &str) -> Self {
+        self.config.alternate_servers.push(server.to_string());
+        self
+    }
+
+    /// Forward a host other than localhost.
+    pub fn local_host(mut self, host: &str) -> Self {
+        self.config.local_host = host.to_string();
+        self
+    }
+
+    /// Ask for a specific public port.
+    pub fn remote_port(mut self, port: u16) -> Self {
+        self.config.remote_port = port;
+        self
+    }
+
+    /// Authenticate with a secret or API key.
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.config.secret = Some(secret.to_string());
+        self
+    }
+
+    /// Authenticate with a key pair.
+    pub fn key(mut self, key: KeyPair) -> Self {
+        self.config.key = Some(key);
+        self
+    }
+
+    /// Share the public port with other clients.
+    pub fn share(mut self) -> Self {
+        self.config.share = true;
+        self
+    }
+
+    /// Join the shared tunnel with this name.
+    pub fn group(mut self, name: &str) -> Self {
+        self.config.group = Some(name.to_string());
+        self
+    }
+
+    /// Check the local service every `interval`.
+    pub fn health_check(mut self, check: HealthCheck, interval: Duration) -> Self {
+        self.config.health_check = Some(check);
+        self.config.health_interval = interval;
+        self
+    }
+
+    /// Run `callback` for every event of the tunnel.
+    ///
+    /// Callbacks run on the tunnel's event task, so they should return quickly.
+    pub fn on_event(mut self, callback: impl Fn(&ClientEvent) + Send + Sync + 'static) -> Self {
+        self.callbacks.push(Arc::new(callback));
+        self
+    }
+
+    /// Open the tunnel without running it, on the server answering fastest.
+    pub async fn connect(&self) -> Result<Client> {
+        self.connect_avoiding(None).await
+    }
+
+    /// Open the tunnel on the fastest server that accepts it, trying `avoid` last.
+    async fn connect_avoiding(&self, avoid: Option<&str>) -> Result<Client> {
+        let mut servers = vec![self.config.server.clone()];
+        servers.extend(self.config.alternate_servers.iter().cloned());
+        let mut last_err = None;
+        for server in failover::rank(&servers, avoid).await {
+            match self.connect_to(&server).await {
+                Ok(client) => return Ok(client),
+                Err(err) if failover::worth_trying_another(&err) => {
+                    warn!(%server, "failed to connect: {err:#}");
+                    last_err = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_err.expect("at least one server"))
+    }
+
+    /// Open the tunnel on `server`.
+    async fn connect_to(&self, server: &str) -> Result<Client> {
+        let config = &self.config;
+        let secret = config.secret.as_deref();
+        let mut client = if config.share || config.group.is_some() {
+            Client::join(
+                &config.local_host,
+                config.local_port,
+                server,
+                config.remote_port,
+                config.group.as_deref(),
+                secret,
+                config.key.as_ref(),
+            )
+            .await?
+        } else if let Some(key) = &config.key {
+            Client::with_key(
+                &config.local_host,
+                config.local_port,
+                server,
+                config.remote_port,
+                key,
+            )
+            .await?
+        } else {
+            Client::new(
+                &config.local_host,
+                config.local_port,
+                server,
+                config.remote_port,
+                secret,
+            )
+            .await?
+        };
+        if let Some(check) = config.health_check.clone() {
+            client.set_health_check(check, config.health_interval);
+        }
+        Ok(client)
+    }
+
+    /// Run a client opened with [`ClientBuilder::connect`] until it stops for
+    /// good.
+    ///
+    /// When the client loses its server, because the server failed or closed
+    /// the tunnel, the tunnel moves to the fastest other server, reporting
+    /// [`ClientEvent::Reconnecting`] and then [`ClientEvent::Established`] with
+    /// the new server and port. With a single server, or once no server could
+    /// be reached for several rounds, this returns why the last one was lost.
+    pub async fn listen(&self, mut client: Client) -> Result<()> {
+        loop {
+            let session = client.session();
+            let server = client.server().to_string();
+            let result = client.listen().await;
+            if !failover::should_fail_over(&result) {
+                return result;
+            }
+            let mut attempt = 0;
+            client = loop {
+                attempt += 1;
+                if attempt > failover::MAX_ATTEMPTS {
+                    return result;
+                }
+                if attempt == 1 && !self.has_alternatives().await {
+                    return result;
+                }
+                let delay = failover::retry_delay(attempt);
+                session.emit(ClientEvent::Reconnecting {
+                    attempt,
+                    delay_ms: u64::try_from(delay.as_millis()).unwrap_or(u64::MAX),
+                });
+                sleep(delay).await;
+                match self.connect_avoiding(Some(&server)).await {
+                    Ok(client) => break client,
+                    Err(err) if failover::worth_trying_another(&err) => {
+                        warn!(attempt, "no server could be reached: {err:#}");
+                    }
+                    Err(err) => return Err(err),
+                }
+            };
+            client.resume(session);
+        }
+    }
+
+    /// Whether there is more than one server to connect to.
+    async fn has_alternatives(&self) -> bool {
+        let mut servers = vec![self.config.server.clone()];
+        servers.extend(self.config.alternate_servers.iter().cloned());
+        failover::rank(&servers, None).await.len() > 1
+    }
+
+    /// Open the tunnel and run it in the background.
+    pub async fn start(mut self) -> Result<ClientHandle> {
+        let mut client = self.connect().await?;
+        let endpoint = Arc::new(Mutex::new((
+            client.server().to_string(),
+            client.remote_port(),
+        )));
+        let settings = client.settings();
+        let commands = client.commands();
+
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        client.set_event_sink(events_tx);
+        let (broadcast, _) = broadcast::channel(EVENT_CAPACITY);
+        let stats = Arc::new(Mutex::new(ClientStats::default()));
+        tokio::spawn(forward_events(
+            events_rx,
+            std::mem::take(&mut self.callbacks),
+            broadcast.clone(),
+            Arc::clone(&stats),
+            Arc::clone(&endpoint),
+        ));
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                result = self.listen(client) => result,
+                _ = shutdown_rx => Ok(()),
+            }
+        });
+
+        Ok(ClientHandle {
+            endpoint,
+            settings,
+            commands,
+            events: broadcast,
+            stats,
+            shutdown: Some(shutdown_tx),
+            task: Some(task),
+        })
+    }
+}
+
+/// Counters of the connections forwarded by a tunnel.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ClientStats {
+    /// Connections forwarded so far.
+    pub connections: u64,
+
+    /// Connections currently open.
+    pub active_connections: u64,
+
+    /// Bytes received from visitors.
+    pub bytes_in: u64,
+
+    /// Bytes sent to visitors.
+    pub bytes_out: u64,
+}
+
+impl ClientStats {
+    fn record(&mut self, event: &ClientEvent) {
+        match event {
+            ClientEvent::ConnectionOpened { .. } => {
+                self.connections += 1;
+                self.active_connections += 1;
+            }
+            ClientEvent::ConnectionClosed {
+                bytes_in,
+                bytes_out,
+                ..
+            } => {
+                self.active_connections = self.active_connections.saturating_sub(1);
+                self.bytes_in += bytes_in;
+                self.bytes_out += bytes_out;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Control over a tunnel running in the background.
+///
+/// Dropping the handle stops the tunnel.
+pub struct ClientHandle {
+    endpoint: Arc<Mutex<(String, u16)>>,
+    settings: Arc<watch::Sender<Settings>>,
+    commands: mpsc::UnboundedSender<Command>,
+    events: broadcast::Sender<ClientEvent>,
+    stats: Arc<Mutex<ClientStats>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl ClientHandle {
+    /// Port that is publicly available on the server.
+    ///
+    /// It changes when the tunnel moves to another server.
+    pub fn remote_port(&self) -> u16 {
+        self.endpoint.lock().unwrap().1
+    }
+
+    /// Address of the server the tunnel is on.
+    pub fn server(&self) -> String {
+        self.endpoint.lock().unwrap().0.clone()
+    }
+
+    /// Receive the events of the tunnel from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    /// Counters of the connections forwarded so far.
+    pub fn stats(&self) -> ClientStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Forward new connections to another local address.
+    ///
+    /// Connections that are already open keep their address.
+    pub fn set_local_target(&self, host: &str, port: u16) {
+        self.settings.send_modify(|settings| {
+            settings.local_host = host.to_string();
+            settings.local_port = port;
+        });
+    }
+
+    /// Check the local service every `interval`, replacing any previous check.
+    pub fn set_health_check(&self, check: HealthCheck, interval: Duration) {
+        self.settings
+            .send_modify(|settings| settings.health_check = Some((check, interval)));
+    }
+
+    /// Stop checking the local service, which then counts as healthy.
+    pub fn clear_health_check(&self) {
+        self.settings
+            .send_modify(|settings| settings.health_check = None);
+    }
+
+    /// Open another tunnel on the same control connection, forwarding
+    /// `remote_port` (or any port for 0) to a local address.
+    ///
+    /// The new tunnel counts against the user's tunnel limit like any other,
+    /// but needs no new connection or authentication.
+    pub async fn open_tunnel(
+        &self,
+        local_host: &str,
+        local_port: u16,
+        remote_port: u16,
+    ) -> Result<TunnelInfo> {
+        self.request(|reply| Command::Open {
+            local_host: local_host.to_string(),
+            local_port,
+            remote_port,
+            reply,
+        })
+        .await
+    }
+
+    /// Close a tunnel of the control connection, the first one having ID 0.
+    pub async fn close_tunnel(&self, tunnel: u32) -> Result<()> {
+        self.request(|reply| Command::Close { tunnel, reply }).await
+    }
+
+    /// The tunnels open on the control connection, as the server sees them.
+    pub async fn tunnels(&self) -> Result<Vec<TunnelInfo>> {
+        self.request(|reply| Command::List { reply }).await
+    }
+
+    /// Send a command to the running client and wait for the server's answer.
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<T>>) -> Command,
+    ) -> Result<T> {
+        let (reply, answer) = oneshot::channel();
+        if self.commands.send(command(reply)).is_err() {
+            bail!("tunnel is not running");
+        }
+        match timeout(NETWORK_TIMEOUT, answer).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => bail!("tunnel stopped before the server answered"),
+            Err(_) => bail!("timed out waiting for the server"),
+        }
+    }
+
+    /// Whether the tunnel stopped.
+    pub fn is_finished(&self) -> bool {
+        self.task.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Stop the tunnel and wait for it to close.
+    pub async fn stop(mut self) -> Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        self.wait().await
+    }
+
+    /// Wait until the tunnel stops on its own, returning why it did.
+    ///
+    /// This is cancel safe, so it can race other futures in `select!`. Once
+    /// the result was returned, later calls return `Ok(())` right away.
+    pub async fn wait(&mut self) -> Result<()> {
+        let Some(task) = &mut self.task else {
+            return Ok(());
+        };
+        let result = task.await;
+        self.task = None;
+        result.context("tunnel task panicked")?
+    }
+}
+
+/// Pass the events of a client on to the stats, callbacks and subscribers.
+async fn forward_events(
+    mut events: mpsc::UnboundedReceiver<ClientEvent>,
+    callbacks: Vec<Callback>,
+    broadcast: broadcast::Sender<ClientEvent>,
+    stats: Arc<Mutex<ClientStats>>,
+    endpoint: Arc<Mutex<(String, u16)>>,
+) {
+    while let Some(event) = events.recv().await {
+        stats.lock().unwrap().record(&event);
+        if let ClientEvent::Established {
+            server,
+            remote_port,
+            ..
+        } = &event
+        {
+            *endpoint.lock().unwrap() = (server.clone(), *remote_port);
+        }
+        for callback in &callbacks {
+            callback(&event);
+        }
+        // Nobody may be subscribed, which is fine.
+        let _ = broadcast.send(event);
+    }
+}
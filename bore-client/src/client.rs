@@ -1,16 +1,24 @@
 //! Client implementation for the `bore` service.
 
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
-use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::time::{interval, timeout};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
 use tracing::{error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
 use bore_shared::{
-    Authenticator, ClientMessage, Delimited, ServerMessage, CONTROL_PORT, NETWORK_TIMEOUT,
+    Authenticator, ClientMessage, Delimited, KeyPair, ServerError, ServerMessage, TunnelInfo,
+    TunnelStatus, CONTROL_PORT, MAX_BINARY_FRAME_LENGTH, NETWORK_TIMEOUT,
 };
 
+use crate::event::ClientEvent;
+use crate::health::HealthCheck;
+
 /// State structure for the client.
 pub struct Client {
     /// Control connection to the server.
@@ -19,11 +27,8 @@ pub struct Client {
     /// Destination address of the server.
     to: String,
 
-    // Local host that is forwarded.
-    local_host: String,
-
-    /// Local port that is forwarded.
-    local_port: u16,
+    /// Settings that can change while the client runs.
+    settings: Arc<watch::Sender<Settings>>,
 
     /// Port that is publicly available on the remote.
     #[allow(dead_code)]
@@ -36,6 +41,69 @@ pub struct Client {
     /// Optional secret used to authenticate clients (legacy).
     #[allow(dead_code)]
     auth: Option<Authenticator>,
+
+    /// Where events of the running tunnel are sent, if anywhere.
+    events: Option<mpsc::UnboundedSender<ClientEvent>>,
+
+    /// Requests to change the tunnels of the running client.
+    commands: mpsc::UnboundedSender<Command>,
+
+    /// Receiving end of `commands`, held by [`Client::listen`] while it runs.
+    command_rx: Arc<Mutex<mpsc::UnboundedReceiver<Command>>>,
+}
+
+/// What a client hands on to the client replacing it on another server, so
+/// handles and event sinks keep working across a failover.
+#[derive(Clone)]
+pub(crate) struct Session {
+    settings: Arc<watch::Sender<Settings>>,
+    commands: mpsc::UnboundedSender<Command>,
+    command_rx: Arc<Mutex<mpsc::UnboundedReceiver<Command>>>,
+    events: Option<mpsc::UnboundedSender<ClientEvent>>,
+}
+
+impl Session {
+    /// Send an event to the sink of the client, if it has one.
+    pub(crate) fn emit(&self, event: ClientEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+}
+
+/// A request to change the tunnels of a running client, answered through `reply`.
+pub(crate) enum Command {
+    /// Open another tunnel forwarding to a local address.
+    Open {
+        local_host: String,
+        local_port: u16,
+        remote_port: u16,
+        reply: oneshot::Sender<Result<TunnelInfo>>,
+    },
+
+    /// Close a tunnel.
+    Close {
+        tunnel: u32,
+        reply: oneshot::Sender<Result<()>>,
+    },
+
+    /// List the open tunnels.
+    List {
+        reply: oneshot::Sender<Result<Vec<TunnelInfo>>>,
+    },
+}
+
+/// Settings a running client picks up without reconnecting.
+#[derive(Debug, Clone)]
+pub(crate) struct Settings {
+    /// Local host that is forwarded.
+    pub(crate) local_host: String,
+
+    /// Local port that is forwarded.
+    pub(crate) local_port: u16,
+
+    /// Optional health check of the local service, with its interval.
+    pub(crate) health_check: Option<(HealthCheck, Duration)>,
 }
 
 impl Client {
@@ -51,7 +119,68 @@ impl Client {
         port: u16,
         secret: Option<&str>,
     ) -> Result<Self> {
-        let mut stream = Delimited::new(connect_with_timeout(to, CONTROL_PORT).await?);
+        Self::connect(
+            local_host,
+            local_port,
+            to,
+            ClientMessage::Hello(port),
+            secret,
+            None,
+        )
+        .await
+    }
+
+    /// Create a new client that authenticates by signing the server's challenge
+    /// with an Ed25519 key listed in the server's authorized keys file.
+    pub async fn with_key(
+        local_host: &str,
+        local_port: u16,
+        to: &str,
+        port: u16,
+        key: &KeyPair,
+    ) -> Result<Self> {
+        Self::connect(
+            local_host,
+            local_port,
+            to,
+            ClientMessage::Hello(port),
+            None,
+            Some(key),
+        )
+        .await
+    }
+
+    /// Create a new client sharing its public port with the other clients of the
+    /// same user that join the same group. The server spreads incoming connections
+    /// across all of them.
+    ///
+    /// The group is found by `group` name if given, and by `port` otherwise. The
+    /// first client to join opens the tunnel; `port` may then be 0 for any port.
+    pub async fn join(
+        local_host: &str,
+        local_port: u16,
+        to: &str,
+        port: u16,
+        group: Option<&str>,
+        secret: Option<&str>,
+        key: Option<&KeyPair>,
+    ) -> Result<Self> {
+        let join = ClientMessage::Join {
+            port,
+            group: group.map(str::to_string),
+        };
+        Self::connect(local_host, local_port, to, join, secret, key).await
+    }
+
+    async fn connect(
+        local_host: &str,
+        local_port: u16,
+        to: &str,
+        hello: ClientMessage,
+        secret: Option<&str>,
+        key: Option<&KeyPair>,
+    ) -> Result<Self> {
+        let mut stream = negotiate(to).await?;
 
         // Determine authentication mode based on secret format:
         // - API keys start with "sk_" or "tk_" (tunnel token prefix)
@@ -86,8 +215,8 @@ impl Client {
             (None, auth)
         };
 
-        // Send Hello to request port
-        stream.send(ClientMessage::Hello(port)).await?;
+        // Send Hello (or Join) to request port
+        stream.send(hello).await?;
 
         // Receive response - may be Hello or Challenge
         let first_response = stream.recv_timeout().await?;
@@ -97,15 +226,25 @@ impl Client {
                 // Server sent a challenge - we need to authenticate
                 // We already consumed the Challenge, so manually perform HMAC response
                 // instead of calling client_handshake (which would wait for another Challenge)
-                if let Some(ref authenticator) = auth {
+                let reply = if let Some(key) = key {
+                    info!("Received challenge, signing with public key");
+                    Some(key.answer(&challenge))
+                } else if let Some(ref authenticator) = auth {
                     info!("Received challenge, performing HMAC response");
-                    let tag = authenticator.answer(&challenge);
-                    stream.send(ClientMessage::Authenticate(tag)).await?;
+                    Some(ClientMessage::Authenticate(
+                        authenticator.answer(&challenge),
+                    ))
+                } else {
+                    None
+                };
+                if let Some(reply) = reply {
+                    stream.send(reply).await?;
 
                     // Now wait for the Hello message after successful auth
                     match stream.recv_timeout().await? {
                         Some(ServerMessage::Hello(remote_port)) => remote_port,
                         Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+                        Some(ServerMessage::Failure(error)) => bail!(error),
                         Some(_) => bail!("unexpected message after authentication"),
                         None => bail!("unexpected EOF after authentication"),
                     }
@@ -115,12 +254,13 @@ impl Client {
             }
             Some(ServerMessage::Hello(remote_port)) => {
                 // Security check: if client has legacy auth configured, server MUST challenge
-                if auth.is_some() {
+                if auth.is_some() || key.is_some() {
                     bail!("server accepted connection without authentication challenge");
                 }
                 remote_port
             }
             Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+            Some(ServerMessage::Failure(error)) => bail!(error),
             Some(_) => bail!("unexpected initial non-hello message"),
             None => bail!("unexpected EOF"),
         };
@@ -128,60 +268,202 @@ impl Client {
         info!(remote_port, "connected to server");
         info!("listening at {to}:{remote_port}");
 
-        // Only show public URL when not using modern authentication (standalone/legacy mode)
-        // In managed mode (API keys/tunnel tokens), the start command handles the output
-        if api_key.is_none() {
-            println!("\n✓ Tunnel established!");
-            println!("  Public URL: {to}:{remote_port}");
-            println!("  Forwarding to: {local_host}:{local_port}\n");
-        }
-
+        let settings = Settings {
+            local_host: local_host.to_string(),
+            local_port,
+            health_check: None,
+        };
+        let (commands, command_rx) = mpsc::unbounded_channel();
         Ok(Client {
             conn: Some(stream),
             to: to.to_string(),
-            local_host: local_host.to_string(),
-            local_port,
+            settings: Arc::new(watch::Sender::new(settings)),
             remote_port,
             api_key,
             auth,
+            events: None,
+            commands,
+            command_rx: Arc::new(Mutex::new(command_rx)),
         })
     }
 
+    /// Address of the server the client is connected to.
+    pub fn server(&self) -> &str {
+        &self.to
+    }
+
     /// Returns the port publicly available on the remote.
     #[allow(dead_code)]
     pub fn remote_port(&self) -> u16 {
         self.remote_port
     }
 
+    /// Check the local service every `interval`, reporting changes to the server.
+    ///
+    /// While the service is unhealthy, the server sends visitors elsewhere: to
+    /// other clients sharing the tunnel, or else to an error page for HTTP
+    /// services, or it disconnects them.
+    pub fn set_health_check(&mut self, check: HealthCheck, interval: Duration) {
+        self.settings
+            .send_modify(|settings| settings.health_check = Some((check, interval)));
+    }
+
+    /// The settings of this client, shared with handles that change them while it runs.
+    pub(crate) fn settings(&self) -> Arc<watch::Sender<Settings>> {
+        Arc::clone(&self.settings)
+    }
+
+    /// Requests to the running client, for handles that open and close tunnels.
+    pub(crate) fn commands(&self) -> mpsc::UnboundedSender<Command> {
+        self.commands.clone()
+    }
+
+    /// Settings, requests and event sink of this client, to be taken over by
+    /// the client replacing it.
+    pub(crate) fn session(&self) -> Session {
+        Session {
+            settings: Arc::clone(&self.settings),
+            commands: self.commands.clone(),
+            command_rx: Arc::clone(&self.command_rx),
+            events: self.events.clone(),
+        }
+    }
+
+    /// Take over the settings, requests and event sink of a previous client,
+    /// including changes made while it ran.
+    ///
+    /// Tunnels it opened besides its first one are not reopened.
+    pub(crate) fn resume(&mut self, session: Session) {
+        self.settings = session.settings;
+        self.commands = session.commands;
+        self.command_rx = session.command_rx;
+        self.events = session.events;
+    }
+
+    /// Send the events of the running tunnel to `events`, starting with
+    /// [`ClientEvent::Established`] when [`Client::listen`] is called.
+    pub fn set_event_sink(&mut self, events: mpsc::UnboundedSender<ClientEvent>) {
+        self.events = Some(events);
+    }
+
     /// Start the client, listening for new connections.
     pub async fn listen(mut self) -> Result<()> {
         let mut conn = self.conn.take().unwrap();
+        let (status_tx, mut statuses) = mpsc::channel(1);
+        tokio::spawn(watch_health(self.settings.subscribe(), status_tx));
+        let settings = self.settings.borrow().clone();
+        self.emit(ClientEvent::Established {
+            server: self.to.clone(),
+            remote_port: self.remote_port,
+            local_host: settings.local_host,
+            local_port: settings.local_port,
+        });
+        let mut commands = Arc::clone(&self.command_rx).lock_owned().await;
+        let mut tunnels = Tunnels::default();
         let this = Arc::new(self);
         loop {
-            match conn.recv().await? {
+            let message = tokio::select! {
+                message = conn.recv() => message?,
+                Some(status) = statuses.recv() => {
+                    this.emit(ClientEvent::HealthChanged {
+                        healthy: status.healthy,
+                        detail: status.detail.clone(),
+                    });
+                    conn.send(ClientMessage::Status(status)).await?;
+                    continue;
+                }
+                Some(command) = commands.recv() => {
+                    tunnels.request(command, &mut conn).await?;
+                    continue;
+                }
+            };
+            match message {
                 Some(ServerMessage::Hello(_)) => warn!("unexpected hello"),
                 Some(ServerMessage::Challenge(_)) => warn!("unexpected challenge"),
                 Some(ServerMessage::Heartbeat) => (),
-                Some(ServerMessage::Connection(id)) => {
-                    let this = Arc::clone(&this);
-                    tokio::spawn(
-                        async move {
-                            info!("new connection");
-                            match this.handle_connection(id).await {
-                                Ok(_) => info!("connection exited"),
-                                Err(err) => warn!(%err, "connection exited with error"),
-                            }
-                        }
-                        .instrument(info_span!("proxy", %id)),
-                    );
+                Some(ServerMessage::Connection(id)) => this.spawn_connection(id, None),
+                Some(ServerMessage::TunnelConnection { tunnel, id }) => {
+                    match tunnels.targets.get(&tunnel) {
+                        Some(target) => this.spawn_connection(id, Some(target.clone())),
+                        None => warn!(tunnel, "connection for unknown tunnel"),
+                    }
+                }
+                Some(ServerMessage::Opened { tunnel, port }) => {
+                    if let Some(event) = tunnels.opened(tunnel, port) {
+                        info!(tunnel, port, "opened tunnel");
+                        this.emit(event);
+                    }
+                }
+                Some(ServerMessage::Refused { tunnel, error }) => {
+                    warn!(tunnel, %error, "server refused tunnel");
+                    tunnels.refused(tunnel, error);
+                }
+                Some(ServerMessage::Closed(tunnel)) => {
+                    info!(tunnel, "closed tunnel");
+                    tunnels.closed(tunnel);
+                    this.emit(ClientEvent::TunnelClosed { tunnel });
+                }
+                Some(ServerMessage::Tunnels(list)) => tunnels.listed(list),
+                Some(ServerMessage::Error(err)) => {
+                    error!(%err, "server error");
+                    this.emit(ClientEvent::Error {
+                        message: err,
+                        code: None,
+                    });
+                }
+                // The server only reports failures on a running session when it
+                // closes the session, e.g. because the key was revoked.
+                Some(ServerMessage::Failure(err)) => bail!(err),
+                None => {
+                    this.emit(ClientEvent::Disconnected);
+                    return Ok(());
                 }
-                Some(ServerMessage::Error(err)) => error!(%err, "server error"),
-                None => return Ok(()),
             }
         }
     }
 
-    async fn handle_connection(&self, id: Uuid) -> Result<()> {
+    /// Forward a visitor's connection in the background, to `target` or else
+    /// the local address of the first tunnel.
+    fn spawn_connection(self: &Arc<Self>, id: Uuid, target: Option<(String, u16)>) {
+        let this = Arc::clone(self);
+        tokio::spawn(
+            async move {
+                info!("new connection");
+                this.emit(ClientEvent::ConnectionOpened { id });
+                let (bytes_in, bytes_out, error) = match this.handle_connection(id, target).await {
+                    Ok((bytes_in, bytes_out)) => {
+                        info!("connection exited");
+                        (bytes_in, bytes_out, None)
+                    }
+                    Err(err) => {
+                        warn!(%err, "connection exited with error");
+                        (0, 0, Some(err.to_string()))
+                    }
+                };
+                this.emit(ClientEvent::ConnectionClosed {
+                    id,
+                    bytes_in,
+                    bytes_out,
+                    error,
+                });
+            }
+            .instrument(info_span!("proxy", %id)),
+        );
+    }
+
+    fn emit(&self, event: ClientEvent) {
+        if let Some(events) = &self.events {
+            // Nobody listening any more is not an error of the tunnel.
+            let _ = events.send(event);
+        }
+    }
+
+    /// Forward a visitor's connection, returning the bytes received from and sent to them.
+    async fn handle_connection(
+        &self,
+        id: Uuid,
+        target: Option<(String, u16)>,
+    ) -> Result<(u64, u64)> {
         let mut remote_conn =
             Delimited::new(connect_with_timeout(&self.to[..], CONTROL_PORT).await?);
 
@@ -192,13 +474,204 @@ impl Client {
         // send a Challenge for Accept messages.
 
         remote_conn.send(ClientMessage::Accept(id)).await?;
-        let mut local_conn = connect_with_timeout(&self.local_host, self.local_port).await?;
+        let (local_host, local_port) = target.unwrap_or_else(|| {
+            let settings = self.settings.borrow();
+            (settings.local_host.clone(), settings.local_port)
+        });
+        let mut local_conn = connect_with_timeout(&local_host, local_port).await?;
         let mut parts = remote_conn.into_parts();
         debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
         local_conn.write_all(&parts.read_buf).await?; // mostly of the cases, this will be empty
-        tokio::io::copy_bidirectional(&mut local_conn, &mut parts.io).await?;
+        let buffered = parts.read_buf.len() as u64;
+        let (to_visitor, from_visitor) =
+            tokio::io::copy_bidirectional(&mut local_conn, &mut parts.io).await?;
+        Ok((from_visitor + buffered, to_visitor))
+    }
+}
+
+/// Tunnels a running client opened besides its first one, and the requests
+/// about them the server has yet to answer.
+#[derive(Default)]
+struct Tunnels {
+    /// Local address each tunnel forwards to.
+    targets: HashMap<u32, (String, u16)>,
+
+    /// ID of the last tunnel opened.
+    last_id: u32,
+
+    /// Tunnels being opened, with their local address.
+    opening: HashMap<u32, Opening>,
+
+    /// Tunnels being closed.
+    closing: HashMap<u32, Vec<oneshot::Sender<Result<()>>>>,
+
+    /// Requests for the list of tunnels, answered in order.
+    listing: VecDeque<oneshot::Sender<Result<Vec<TunnelInfo>>>>,
+}
+
+/// A tunnel the client asked the server to open.
+struct Opening {
+    local_host: String,
+    local_port: u16,
+    reply: oneshot::Sender<Result<TunnelInfo>>,
+}
+
+impl Tunnels {
+    /// Pass a command on to the server.
+    async fn request(&mut self, command: Command, conn: &mut Delimited<TcpStream>) -> Result<()> {
+        match command {
+            Command::Open {
+                local_host,
+                local_port,
+                remote_port,
+                reply,
+            } => {
+                self.last_id += 1;
+                let tunnel = self.last_id;
+                conn.send(ClientMessage::Open {
+                    tunnel,
+                    port: remote_port,
+                })
+                .await?;
+                let opening = Opening {
+                    local_host,
+                    local_port,
+                    reply,
+                };
+                self.opening.insert(tunnel, opening);
+            }
+            Command::Close { tunnel, reply } => {
+                conn.send(ClientMessage::Close(tunnel)).await?;
+                self.closing.entry(tunnel).or_default().push(reply);
+            }
+            Command::List { reply } => {
+                conn.send(ClientMessage::List).await?;
+                self.listing.push_back(reply);
+            }
+        }
         Ok(())
     }
+
+    /// The server opened a tunnel, which starts forwarding to its local address.
+    fn opened(&mut self, tunnel: u32, port: u16) -> Option<ClientEvent> {
+        let Opening {
+            local_host,
+            local_port,
+            reply,
+        } = self.opening.remove(&tunnel)?;
+        self.targets
+            .insert(tunnel, (local_host.clone(), local_port));
+        let _ = reply.send(Ok(TunnelInfo { tunnel, port }));
+        Some(ClientEvent::TunnelOpened {
+            tunnel,
+            remote_port: port,
+            local_host,
+            local_port,
+        })
+    }
+
+    /// The server refused to open a tunnel.
+    fn refused(&mut self, tunnel: u32, error: ServerError) {
+        if let Some(opening) = self.opening.remove(&tunnel) {
+            let _ = opening.reply.send(Err(error.into()));
+        }
+    }
+
+    /// The server closed a tunnel, because the client asked or on its own.
+    fn closed(&mut self, tunnel: u32) {
+        self.targets.remove(&tunnel);
+        for reply in self.closing.remove(&tunnel).unwrap_or_default() {
+            let _ = reply.send(Ok(()));
+        }
+    }
+
+    /// The server listed the open tunnels.
+    fn listed(&mut self, list: Vec<TunnelInfo>) {
+        if let Some(reply) = self.listing.pop_front() {
+            let _ = reply.send(Ok(list));
+        }
+    }
+}
+
+/// Check the local service periodically, sending its status whenever it changes.
+///
+/// The check restarts whenever the settings change. Once a health check is
+/// removed, an unhealthy service is reported healthy again, since nothing
+/// checks it any more.
+async fn watch_health(
+    mut settings: watch::Receiver<Settings>,
+    statuses: mpsc::Sender<TunnelStatus>,
+) {
+    let mut last: Option<TunnelStatus> = None;
+    loop {
+        let Settings {
+            local_host,
+            local_port,
+            health_check,
+        } = settings.borrow_and_update().clone();
+
+        match health_check {
+            Some((check, every)) => {
+                let mut ticks = interval(every);
+                loop {
+                    tokio::select! {
+                        _ = ticks.tick() => {}
+                        changed = settings.changed() => match changed {
+                            Ok(()) => break,
+                            Err(_) => return,
+                        },
+                        () = statuses.closed() => return,
+                    }
+                    let status = check.status(&local_host, local_port).await;
+                    if last.as_ref() == Some(&status) {
+                        continue;
+                    }
+                    match &status.detail {
+                        Some(detail) => warn!(%detail, "local service is unhealthy"),
+                        None => info!("local service is healthy"),
+                    }
+                    last = Some(status.clone());
+                    if statuses.send(status).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            None => {
+                if let Some(previous) = last.take().filter(|status| !status.healthy) {
+                    let status = TunnelStatus {
+                        healthy: true,
+                        http: previous.http,
+                        detail: None,
+                    };
+                    if statuses.send(status).await.is_err() {
+                        return;
+                    }
+                }
+                tokio::select! {
+                    changed = settings.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                    }
+                    () = statuses.closed() => return,
+                }
+            }
+        }
+    }
+}
+
+/// Open a control connection with binary frames, or JSON frames if the
+/// server predates them.
+async fn negotiate(to: &str) -> Result<Delimited<TcpStream>> {
+    let stream = connect_with_timeout(to, CONTROL_PORT).await?;
+    match Delimited::negotiate(stream, MAX_BINARY_FRAME_LENGTH).await {
+        Ok(stream) => Ok(stream),
+        Err(err) => {
+            warn!(%err, "server does not support binary frames, falling back to JSON");
+            let stream = connect_with_timeout(to, CONTROL_PORT).await?;
+            Ok(Delimited::new(stream))
+        }
+    }
 }
 
 async fn connect_with_timeout(to: &str, port: u16) -> Result<TcpStream> {
@@ -0,0 +1,89 @@
+//! Events emitted by a running client.
+//!
+//! A [`Client`](crate::Client) reports what happens to its tunnel as typed
+//! events instead of printing, so the command line can render them as text or
+//! as a stream of JSON lines for scripts and other frontends.
+
+use bore_shared::ErrorCode;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Something that happened to a client's tunnel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ClientEvent {
+    /// The tunnel is up and forwarding.
+    Established {
+        /// Address of the server.
+        server: String,
+        /// Port that is publicly available on the server.
+        remote_port: u16,
+        /// Local host that is forwarded.
+        local_host: String,
+        /// Local port that is forwarded.
+        local_port: u16,
+    },
+
+    /// Another tunnel was opened on the same control connection.
+    TunnelOpened {
+        /// ID of the tunnel on the control connection.
+        tunnel: u32,
+        /// Port that is publicly available on the server.
+        remote_port: u16,
+        /// Local host that is forwarded.
+        local_host: String,
+        /// Local port that is forwarded.
+        local_port: u16,
+    },
+
+    /// A tunnel of the control connection was closed.
+    TunnelClosed {
+        /// ID of the tunnel on the control connection.
+        tunnel: u32,
+    },
+
+    /// A visitor connected to the public port.
+    ConnectionOpened {
+        /// ID of the connection, assigned by the server.
+        id: Uuid,
+    },
+
+    /// A visitor's connection ended.
+    ConnectionClosed {
+        /// ID of the connection, assigned by the server.
+        id: Uuid,
+        /// Bytes received from the visitor.
+        bytes_in: u64,
+        /// Bytes sent to the visitor.
+        bytes_out: u64,
+        /// Why the connection failed, if it did.
+        error: Option<String>,
+    },
+
+    /// The health of the local service changed.
+    HealthChanged {
+        /// Whether the local service passed its health check.
+        healthy: bool,
+        /// Why the health check failed, if it did.
+        detail: Option<String>,
+    },
+
+    /// The connection to the server is lost and about to be retried.
+    Reconnecting {
+        /// Number of the next attempt, starting at 1.
+        attempt: u32,
+        /// Delay before the attempt, in milliseconds.
+        delay_ms: u64,
+    },
+
+    /// The server closed the tunnel.
+    Disconnected,
+
+    /// An error reported by the server or met by the client.
+    Error {
+        /// Description of the error.
+        message: String,
+        /// What went wrong, if the server said.
+        code: Option<ErrorCode>,
+    },
+}
@@ -0,0 +1,36 @@
+//! Exit codes of the command line client.
+//!
+//! Errors the server reports with an [`ErrorCode`] get an exit code of their
+//! own, so scripts can tell whether to log in again, pick another port, or
+//! retry later. Every other failure exits with 1.
+
+use bore_shared::{ErrorCode, ServerError};
+
+/// Exit code for failures without a more specific one.
+pub const FAILURE: u8 = 1;
+
+/// Exit code of the client when the server fails with `code`.
+#[must_use]
+pub fn for_code(code: ErrorCode) -> u8 {
+    match code {
+        ErrorCode::AuthRequired => 10,
+        ErrorCode::InvalidCredentials => 11,
+        ErrorCode::UsageNotAllowed => 12,
+        ErrorCode::TunnelLimit => 13,
+        ErrorCode::PortInUse => 14,
+        ErrorCode::PortNotAllowed => 15,
+        ErrorCode::NoPortsAvailable => 16,
+        ErrorCode::BackendUnavailable => 17,
+        ErrorCode::InvalidRequest => 18,
+        ErrorCode::Internal => 19,
+        ErrorCode::Revoked => 20,
+        ErrorCode::Unknown => FAILURE,
+    }
+}
+
+/// Exit code of the client when it fails with `err`.
+#[must_use]
+pub fn for_error(err: &anyhow::Error) -> u8 {
+    err.downcast_ref::<ServerError>()
+        .map_or(FAILURE, |error| for_code(error.code))
+}
@@ -0,0 +1,120 @@
+//! Choosing among several servers, and moving to another when one is lost.
+//!
+//! A client may be given several servers, or a DNS name with several A/AAAA
+//! records. Each address is probed with a TCP handshake to the control port,
+//! and the client connects to the one answering fastest. When it loses that
+//! server, because it failed or closed the tunnel while draining, the client
+//! moves to the next one.
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bore_shared::{ServerError, CONTROL_PORT};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::{debug, info};
+
+/// How long a probe waits for a server's handshake.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Delay before the first attempt to reach another server, doubled after
+/// every round in which no server could be reached.
+const INITIAL_RETRY: Duration = Duration::from_millis(500);
+
+/// Longest delay between attempts.
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// Rounds over all servers after which the client gives up.
+pub(crate) const MAX_ATTEMPTS: u32 = 5;
+
+/// Resolve `servers` into the addresses to connect to, the fastest first.
+///
+/// A name with a single address is kept as it is. A name with several is
+/// replaced by each of its addresses, so the client reaches the same server
+/// for every connection of its tunnel. Addresses that do not answer are
+/// tried last, and `avoid` after all others. Only if there is more than one
+/// address are they probed.
+pub(crate) async fn rank(servers: &[String], avoid: Option<&str>) -> Vec<String> {
+    let mut candidates: Vec<String> = Vec::new();
+    for server in servers {
+        for candidate in resolve(server).await {
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+    }
+    if candidates.len() > 1 {
+        let mut probes = JoinSet::new();
+        for (index, candidate) in candidates.iter().enumerate() {
+            let candidate = candidate.clone();
+            probes.spawn(async move { (index, probe(&candidate).await) });
+        }
+        let mut latencies = vec![None; candidates.len()];
+        while let Some(Ok((index, latency))) = probes.join_next().await {
+            debug!(server = %candidates[index], ?latency, "probed server");
+            latencies[index] = latency;
+        }
+        let mut ranked: Vec<(Option<Duration>, String)> =
+            latencies.into_iter().zip(candidates).collect();
+        ranked.sort_by_key(|(latency, _)| (latency.is_none(), *latency));
+        candidates = ranked.into_iter().map(|(_, candidate)| candidate).collect();
+        info!(servers = ?candidates, "ranked servers by latency");
+    }
+    if let Some(avoid) = avoid {
+        candidates.sort_by_key(|candidate| candidate == avoid);
+    }
+    candidates
+}
+
+/// The addresses to try for one server.
+async fn resolve(server: &str) -> Vec<String> {
+    if server.parse::<IpAddr>().is_ok() {
+        return vec![server.to_string()];
+    }
+    let mut addrs: Vec<IpAddr> = match lookup_host((server, CONTROL_PORT)).await {
+        Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+        // Connecting will report the error.
+        Err(_) => return vec![server.to_string()],
+    };
+    addrs.sort_unstable();
+    addrs.dedup();
+    if addrs.len() > 1 {
+        addrs.iter().map(IpAddr::to_string).collect()
+    } else {
+        vec![server.to_string()]
+    }
+}
+
+/// Time a TCP handshake with the server's control port.
+async fn probe(server: &str) -> Option<Duration> {
+    let start = Instant::now();
+    match timeout(PROBE_TIMEOUT, TcpStream::connect((server, CONTROL_PORT))).await {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        _ => None,
+    }
+}
+
+/// Whether another server may accept the client after this one failed with
+/// `err`. Errors about the credential or request would fail anywhere.
+pub(crate) fn worth_trying_another(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ServerError>()
+        .is_none_or(|error| error.code.retry_after().is_some())
+}
+
+/// Whether a client whose tunnel stopped with `result` should move to another server.
+pub(crate) fn should_fail_over(result: &Result<()>) -> bool {
+    match result {
+        // The server closed the tunnel, e.g. while draining.
+        Ok(()) => true,
+        Err(err) => worth_trying_another(err),
+    }
+}
+
+/// Delay before attempt number `attempt`, starting at 1.
+pub(crate) fn retry_delay(attempt: u32) -> Duration {
+    INITIAL_RETRY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_RETRY)
+}
@@ -0,0 +1,108 @@
+//! Health checks against the local service behind a tunnel.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bore_shared::TunnelStatus;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// How long a single health check may take before the service counts as down.
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How the client checks that its local service is up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthCheck {
+    /// The local port accepts TCP connections.
+    Tcp,
+
+    /// A `GET` of this path answers with a 2xx or 3xx status.
+    Http {
+        /// Path requested, starting with `/`.
+        path: String,
+    },
+}
+
+impl HealthCheck {
+    /// Whether the local service speaks HTTP.
+    #[must_use]
+    pub fn is_http(&self) -> bool {
+        matches!(self, HealthCheck::Http { .. })
+    }
+
+    /// Check the service at `host:port` once.
+    pub async fn probe(&self, host: &str, port: u16) -> Result<()> {
+        timeout(CHECK_TIMEOUT, self.probe_inner(host, port))
+            .await
+            .context("health check timed out")?
+    }
+
+    /// Check the service and describe the result as a tunnel status.
+    pub async fn status(&self, host: &str, port: u16) -> TunnelStatus {
+        let result = self.probe(host, port).await;
+        TunnelStatus {
+            healthy: result.is_ok(),
+            http: self.is_http(),
+            detail: result.err().map(|err| format!("{err:#}")),
+        }
+    }
+
+    async fn probe_inner(&self, host: &str, port: u16) -> Result<()> {
+        let mut stream = TcpStream::connect((host, port))
+            .await
+            .with_context(|| format!("could not connect to {host}:{port}"))?;
+        let HealthCheck::Http { path } = self else {
+            return Ok(());
+        };
+
+        let request =
+            format!("GET {path} HTTP/1.1\r\nHost: {host}:{port}\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Only the status line matters; it fits in the first read.
+        let mut buf = [0; 512];
+        let n = stream.read(&mut buf).await?;
+        let head = String::from_utf8_lossy(&buf[..n]);
+        let status = head
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .context("invalid HTTP response")?;
+        if !(200..400).contains(&status) {
+            bail!("{path} returned HTTP {status}");
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for HealthCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthCheck::Tcp => f.write_str("tcp"),
+            HealthCheck::Http { path } if path == "/" => f.write_str("http"),
+            HealthCheck::Http { path } => write!(f, "http:{path}"),
+        }
+    }
+}
+
+impl FromStr for HealthCheck {
+    type Err = anyhow::Error;
+
+    /// Parse `tcp`, `http` or `http:/some/path`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "tcp" => Ok(HealthCheck::Tcp),
+            None if s == "http" => Ok(HealthCheck::Http {
+                path: "/".to_string(),
+            }),
+            Some(("http", path)) if path.starts_with('/') => Ok(HealthCheck::Http {
+                path: path.to_string(),
+            }),
+            _ => bail!("unknown health check {s:?}, expected tcp, http or http:/path"),
+        }
+    }
+}
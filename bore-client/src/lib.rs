@@ -1,6 +1,14 @@
 pub mod api_client;
 pub mod auth;
+pub mod builder;
 pub mod client;
+pub mod event;
+pub mod exit;
+mod failover;
+pub mod health;
+pub mod output;
 
 // Re-export commonly used items for testing
+pub use builder::{ClientBuilder, ClientConfig, ClientHandle, ClientStats};
 pub use client::Client;
+pub use event::ClientEvent;
@@ -1,8 +1,26 @@
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use tokio::{signal, sync::oneshot};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
 
-use bore_client::{api_client::ApiClient, auth::Credentials, client::Client};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use tokio::{
+    signal,
+    sync::{mpsc, oneshot},
+};
+
+use bore_client::{
+    api_client::ApiClient,
+    auth,
+    auth::Credentials,
+    exit,
+    health::HealthCheck,
+    output::{print_json, OutputFormat},
+    ClientBuilder, ClientConfig, ClientEvent,
+};
+use bore_shared::logging::{LogConfig, LogFormat, LogRotation};
+use bore_shared::{KeyPair, ServerError};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about = "bore client - local proxy for TCP tunnels")]
@@ -10,6 +28,45 @@ struct Args {
     #[clap(subcommand)]
     command: Option<Command>,
 
+    /// Output format: `text`, or `json` for one JSON document per result and an
+    /// event stream with one JSON object per line while a tunnel runs.
+    #[clap(
+        long,
+        global = true,
+        env = "BORE_OUTPUT",
+        default_value = "text",
+        value_name = "FORMAT"
+    )]
+    output: OutputFormat,
+
+    /// Log format: `text`, or `json` for one JSON object per line.
+    #[clap(
+        long,
+        global = true,
+        env = "BORE_LOG_FORMAT",
+        default_value = "text",
+        value_name = "FORMAT"
+    )]
+    log_format: LogFormat,
+
+    /// Log level filter, e.g. `debug` or `warn,bore_client=info`. Defaults to
+    /// RUST_LOG, then `info`.
+    #[clap(long, global = true, env = "BORE_LOG_LEVEL", value_name = "FILTER")]
+    log_level: Option<String>,
+
+    /// File to write logs to instead of the terminal.
+    #[clap(long, global = true, env = "BORE_LOG_FILE", value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// How often to rotate the log file: `never`, `hourly` or `daily`.
+    #[clap(
+        long,
+        global = true,
+        env = "BORE_LOG_ROTATION",
+        default_value = "never"
+    )]
+    log_rotation: LogRotation,
+
     /// The local port to expose.
     #[clap(env = "BORE_LOCAL_PORT")]
     local_port: Option<u16>,
@@ -18,7 +75,8 @@ struct Args {
     #[clap(short = 'l', long, value_name = "HOST", default_value = "localhost")]
     local_host: String,
 
-    /// Address of the remote server to expose local ports to.
+    /// Address of the remote server to expose local ports to, or several
+    /// separated by commas to use the fastest and fail over to the others.
     #[clap(short, long, env = "BORE_SERVER")]
     to: Option<String>,
 
@@ -26,9 +84,33 @@ struct Args {
     #[clap(short, long, default_value_t = 0)]
     port: u16,
 
+    /// Share the remote port with other clients joining the same group, named
+    /// NAME or found by --port. The server balances connections across them.
+    #[clap(long, value_name = "NAME")]
+    share: Option<Option<String>>,
+
+    /// Check the local service with `tcp`, `http` or `http:/PATH`, and report its
+    /// health to the server, which turns visitors away while it is down.
+    #[clap(long, value_name = "CHECK")]
+    health_check: Option<HealthCheck>,
+
+    /// Seconds between health checks.
+    #[clap(long, value_name = "SECONDS", default_value_t = 10)]
+    health_interval: u64,
+
     /// Optional secret for authentication.
     #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
     secret: Option<String>,
+
+    /// Private key file for public-key authentication (see `bore keygen`).
+    #[clap(
+        short,
+        long,
+        env = "BORE_KEY",
+        value_name = "FILE",
+        conflicts_with = "secret"
+    )]
+    key: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -43,7 +125,8 @@ enum Command {
         #[clap(short = 'l', long, value_name = "HOST", default_value = "localhost")]
         local_host: String,
 
-        /// Address of the remote server to expose local ports to.
+        /// Address of the remote server to expose local ports to, or several
+        /// separated by commas to use the fastest and fail over to the others.
         #[clap(short, long, env = "BORE_SERVER")]
         to: String,
 
@@ -51,9 +134,44 @@ enum Command {
         #[clap(short, long, default_value_t = 0)]
         port: u16,
 
+        /// Share the remote port with other clients joining the same group, named
+        /// NAME or found by --port. The server balances connections across them.
+        #[clap(long, value_name = "NAME")]
+        share: Option<Option<String>>,
+
+        /// Check the local service with `tcp`, `http` or `http:/PATH`, and report its
+        /// health to the server, which turns visitors away while it is down.
+        #[clap(long, value_name = "CHECK")]
+        health_check: Option<HealthCheck>,
+
+        /// Seconds between health checks.
+        #[clap(long, value_name = "SECONDS", default_value_t = 10)]
+        health_interval: u64,
+
         /// Optional secret for authentication.
         #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
         secret: Option<String>,
+
+        /// Private key file for public-key authentication (see `bore keygen`).
+        #[clap(
+            short,
+            long,
+            env = "BORE_KEY",
+            value_name = "FILE",
+            conflicts_with = "secret"
+        )]
+        key: Option<PathBuf>,
+    },
+
+    /// Generate a key pair for public-key authentication
+    Keygen {
+        /// Where to write the private key (default: ~/.bore/id_ed25519)
+        #[clap(short = 'o', long = "file", value_name = "FILE")]
+        file: Option<PathBuf>,
+
+        /// Overwrite an existing key file
+        #[clap(long)]
+        force: bool,
     },
 
     /// Login to your bore account
@@ -85,22 +203,31 @@ enum Command {
 
 #[tokio::main]
 async fn run(args: Args) -> Result<()> {
+    let output = args.output;
     match args.command {
-        Some(Command::Login { api_endpoint }) => handle_login(api_endpoint).await,
-        Some(Command::Logout) => handle_logout(),
-        Some(Command::List) => handle_list().await,
-        Some(Command::Start { instance }) => handle_start(instance).await,
-        Some(Command::Stop) => handle_stop(),
+        Some(Command::Login { api_endpoint }) => handle_login(api_endpoint, output).await,
+        Some(Command::Logout) => handle_logout(output),
+        Some(Command::List) => handle_list(output).await,
+        Some(Command::Start { instance }) => handle_start(instance, output).await,
+        Some(Command::Stop) => handle_stop(output),
+        Some(Command::Keygen { file, force }) => handle_keygen(file, force, output),
         Some(Command::Local {
             local_host,
             local_port,
             to,
             port,
+            share,
+            health_check,
+            health_interval,
             secret,
+            key,
         }) => {
             // Legacy mode: direct tunnel connection
-            let client = Client::new(&local_host, local_port, &to, port, secret.as_deref()).await?;
-            run_client_with_shutdown(client).await
+            let mut builder = tunnel(&local_host, local_port, &to, port, share, secret, key)?;
+            if let Some(check) = health_check {
+                builder = builder.health_check(check, Duration::from_secs(health_interval));
+            }
+            run_client_with_shutdown(&builder, output, |_| {}).await
         }
         None => {
             // Direct arguments mode (backwards compatibility)
@@ -110,30 +237,96 @@ async fn run(args: Args) -> Result<()> {
             let to = args
                 .to
                 .ok_or_else(|| anyhow::anyhow!("--to <SERVER> is required"))?;
-            let client = Client::new(
+            let mut builder = tunnel(
                 &args.local_host,
                 local_port,
                 &to,
                 args.port,
-                args.secret.as_deref(),
-            )
-            .await?;
-            run_client_with_shutdown(client).await
+                args.share,
+                args.secret,
+                args.key,
+            )?;
+            if let Some(check) = args.health_check {
+                builder = builder.health_check(check, Duration::from_secs(args.health_interval));
+            }
+            run_client_with_shutdown(&builder, output, |_| {}).await
         }
     }
 }
 
-/// Run the client with graceful shutdown handling
-async fn run_client_with_shutdown(client: Client) -> Result<()> {
-    tokio::select! {
-        result = client.listen() => {
-            result
-        }
-        _ = shutdown_signal() => {
-            println!("\n✓ Shutting down gracefully...");
-            Ok(())
+/// Configure a direct tunnel to the servers in `to`, separated by commas, joining a
+/// shared one if asked and authenticating with a key file if one was given
+fn tunnel(
+    local_host: &str,
+    local_port: u16,
+    to: &str,
+    port: u16,
+    share: Option<Option<String>>,
+    secret: Option<String>,
+    key: Option<PathBuf>,
+) -> Result<ClientBuilder> {
+    let key = key.map(|path| auth::load_key(&path)).transpose()?;
+    let (share, group) = match share {
+        Some(group) => (true, group),
+        None => (false, None),
+    };
+    let mut servers = to
+        .split(',')
+        .map(str::trim)
+        .filter(|server| !server.is_empty())
+        .map(str::to_string);
+    let server = servers.next().context("--to <SERVER> is required")?;
+    let config = ClientConfig {
+        local_host: local_host.to_string(),
+        local_port,
+        server,
+        alternate_servers: servers.collect(),
+        remote_port: port,
+        secret,
+        key,
+        share,
+        group,
+        ..ClientConfig::default()
+    };
+    Ok(ClientBuilder::from_config(config))
+}
+
+/// Connect and run the tunnel with graceful shutdown handling, failing over to
+/// other servers, printing its events and passing them to `on_event`
+async fn run_client_with_shutdown(
+    builder: &ClientBuilder,
+    output: OutputFormat,
+    mut on_event: impl FnMut(&ClientEvent),
+) -> Result<()> {
+    let mut client = builder.connect().await?;
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    client.set_event_sink(events_tx);
+
+    let listen = builder.listen(client);
+    let shutdown = shutdown_signal();
+    tokio::pin!(listen, shutdown);
+    let result = loop {
+        tokio::select! {
+            result = &mut listen => break result,
+            Some(event) = events.recv() => {
+                on_event(&event);
+                output.print_event(&event)?;
+            }
+            () = &mut shutdown => {
+                if !output.is_json() {
+                    println!("\n✓ Shutting down gracefully...");
+                }
+                break Ok(());
+            }
         }
+    };
+
+    // Events sent right before the tunnel stopped
+    while let Ok(event) = events.try_recv() {
+        on_event(&event);
+        output.print_event(&event)?;
     }
+    result
 }
 
 /// Wait for shutdown signal (Ctrl+C or SIGTERM)
@@ -198,14 +391,20 @@ async fn shutdown_signal() {
 }
 
 /// Handle login command
-async fn handle_login(api_endpoint: String) -> Result<()> {
+async fn handle_login(api_endpoint: String, output: OutputFormat) -> Result<()> {
     use std::io::{self, Write};
 
-    println!("Login to your bore account\n");
+    // Prompts go to stderr with JSON output, so stdout only carries the result
+    let mut prompt: Box<dyn Write> = if output.is_json() {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+    writeln!(prompt, "Login to your bore account\n")?;
 
     // Prompt for email
-    print!("Email: ");
-    io::stdout().flush()?;
+    write!(prompt, "Email: ")?;
+    prompt.flush()?;
     let mut email = String::new();
     io::stdin().read_line(&mut email)?;
     let email = email.trim().to_string();
@@ -213,7 +412,7 @@ async fn handle_login(api_endpoint: String) -> Result<()> {
     // Prompt for password (using rpassword for hidden input)
     let password = rpassword::prompt_password("Password: ").context("failed to read password")?;
 
-    println!("\nAuthenticating...");
+    writeln!(prompt, "\nAuthenticating...")?;
 
     // Login via API
     let mut api_client = ApiClient::new(api_endpoint.clone());
@@ -223,6 +422,12 @@ async fn handle_login(api_endpoint: String) -> Result<()> {
     let credentials = Credentials::new(api_endpoint, login_response.token, login_response.user_id);
     credentials.save()?;
 
+    if output.is_json() {
+        return print_json(&json!({
+            "user_id": credentials.user_id,
+            "api_endpoint": credentials.api_endpoint,
+        }));
+    }
     println!("✓ Successfully logged in!");
     println!("  User ID: {}", credentials.user_id);
 
@@ -230,22 +435,34 @@ async fn handle_login(api_endpoint: String) -> Result<()> {
 }
 
 /// Handle logout command
-fn handle_logout() -> Result<()> {
-    if !Credentials::exists() {
-        println!("You are not logged in.");
-        return Ok(());
+fn handle_logout(output: OutputFormat) -> Result<()> {
+    let logged_in = Credentials::exists();
+    if logged_in {
+        Credentials::delete()?;
     }
 
-    Credentials::delete()?;
-    println!("✓ Successfully logged out");
-    Ok(())
+    if output.is_json() {
+        print_json(&json!({ "logged_out": logged_in }))
+    } else {
+        if logged_in {
+            println!("✓ Successfully logged out");
+        } else {
+            println!("You are not logged in.");
+        }
+        Ok(())
+    }
 }
 
 /// Handle list command
-async fn handle_list() -> Result<()> {
+async fn handle_list(output: OutputFormat) -> Result<()> {
     let credentials = Credentials::load()?;
     let api_client = ApiClient::from_credentials(&credentials);
 
+    if output.is_json() {
+        let instances = api_client.list_instances().await?;
+        return print_json(&json!({ "instances": instances }));
+    }
+
     println!("Fetching your tunnel instances...\n");
     let instances = api_client.list_instances().await?;
 
@@ -283,20 +500,28 @@ async fn handle_list() -> Result<()> {
 }
 
 /// Handle start command
-async fn handle_start(instance_name_or_id: String) -> Result<()> {
+async fn handle_start(instance_name_or_id: String, output: OutputFormat) -> Result<()> {
     let credentials = Credentials::load()?;
     let api_client = ApiClient::from_credentials(&credentials);
 
-    println!("Finding instance '{}'...", instance_name_or_id);
+    // With JSON output, the tunnel's event stream is all that goes to stdout
+    let text = !output.is_json();
+    if text {
+        println!("Finding instance '{}'...", instance_name_or_id);
+    }
     let instance = api_client.find_instance(&instance_name_or_id).await?;
 
-    println!("Connecting to '{}'...", instance.name);
+    if text {
+        println!("Connecting to '{}'...", instance.name);
+    }
     let connection_info = api_client.connect_instance(&instance.id).await?;
 
-    println!("\n✓ Connected to \"{}\"", instance.name);
-    println!("✓ Forwarding localhost:{}\n", connection_info.local_port);
-    println!("  Instance ID: {}", connection_info.instance_id);
-    println!("  Token TTL: {}s\n", connection_info.ttl);
+    if text {
+        println!("\n✓ Connected to \"{}\"", instance.name);
+        println!("✓ Forwarding localhost:{}\n", connection_info.local_port);
+        println!("  Instance ID: {}", connection_info.instance_id);
+        println!("  Token TTL: {}s\n", connection_info.ttl);
+    }
 
     // Start heartbeat task to report online status
     let instance_id = instance.id.clone();
@@ -331,35 +556,54 @@ async fn handle_start(instance_name_or_id: String) -> Result<()> {
         }
     });
 
+    // Report the public URL whenever the tunnel is established, which it is
+    // again on another server after a failover
+    let (established_tx, mut established) = mpsc::unbounded_channel::<(String, u16)>();
+    let update_client = ApiClient::from_credentials(&credentials);
+    let instance_id_for_update = instance_id.clone();
+    let update_handle = tokio::spawn(async move {
+        while let Some((server, remote_port)) = established.recv().await {
+            let public_url = format!("{server}:{remote_port}");
+            if let Err(err) = update_client
+                .update_instance_connection(
+                    &instance_id_for_update,
+                    Some("active"),
+                    Some(remote_port),
+                    Some(&public_url),
+                )
+                .await
+            {
+                tracing::warn!(
+                    "Failed to update backend connection state for {}: {}",
+                    instance_id_for_update,
+                    err
+                );
+            }
+        }
+    });
+
     // Start the tunnel using the temporary token
-    let client = Client::new(
-        "localhost",
-        connection_info.local_port,
-        &connection_info.server_host,
-        connection_info.remote_port,
-        Some(&connection_info.tunnel_token),
-    )
-    .await?;
-
-    let assigned_remote_port = client.remote_port();
-    let public_url = format!("{}:{}", connection_info.server_host, assigned_remote_port);
-    if let Err(err) = api_client
-        .update_instance_connection(
-            &instance_id,
-            Some("active"),
-            Some(assigned_remote_port),
-            Some(&public_url),
-        )
-        .await
-    {
-        tracing::warn!(
-            "Failed to update backend connection state for {}: {}",
-            instance_id,
-            err
-        );
+    let mut builder = ClientBuilder::new(&connection_info.server_host, connection_info.local_port)
+        .remote_port(connection_info.remote_port)
+        .secret(&connection_info.tunnel_token);
+    for server in &connection_info.fallback_hosts {
+        builder = builder.alternate_server(server);
+    }
+    let client_result = run_client_with_shutdown(&builder, output, |event| {
+        if let ClientEvent::Established {
+            server,
+            remote_port,
+            ..
+        } = event
+        {
+            let _ = established_tx.send((server.clone(), *remote_port));
+        }
+    })
+    .await;
+    drop(established_tx);
+    if let Err(join_err) = update_handle.await {
+        tracing::warn!("Connection update task join error: {}", join_err);
     }
-
-    let client_result = run_client_with_shutdown(client).await;
 
     if heartbeat_shutdown_tx.send(()).is_err() {
         tracing::debug!(
@@ -377,24 +621,99 @@ async fn handle_start(instance_name_or_id: String) -> Result<()> {
     }
 
     match api_client.disconnect_instance(&instance_id).await {
-        Ok(()) => println!("✓ Instance '{}' disconnected.", instance.name),
+        Ok(()) if text => println!("✓ Instance '{}' disconnected.", instance.name),
+        Ok(()) => {}
         Err(err) => tracing::warn!("Failed to disconnect instance {}: {}", instance_id, err),
     }
 
     client_result
 }
 
+/// Handle keygen command
+fn handle_keygen(file: Option<PathBuf>, force: bool, output: OutputFormat) -> Result<()> {
+    let path = match file {
+        Some(path) => path,
+        None => auth::default_key_path()?,
+    };
+    if path.exists() && !force {
+        bail!(
+            "{} already exists. Use --force to overwrite it",
+            path.display()
+        );
+    }
+
+    let key = KeyPair::generate();
+    let public_path = auth::save_key(&key, &path)?;
+
+    if output.is_json() {
+        return print_json(&json!({
+            "private_key_file": path,
+            "public_key_file": public_path,
+            "public_key": key.public_key().to_string(),
+        }));
+    }
+    println!("✓ Generated key pair");
+    println!("  Private key: {}", path.display());
+    println!("  Public key:  {}", public_path.display());
+    println!("\nAdd this line to the server's authorized keys file:\n");
+    println!("  {} <USER> max_tunnels=5", key.public_key());
+    println!(
+        "\nThen connect with: bore <LOCAL_PORT> --to <SERVER> --key {}",
+        path.display()
+    );
+    Ok(())
+}
+
 /// Handle stop command
-fn handle_stop() -> Result<()> {
+fn handle_stop(output: OutputFormat) -> Result<()> {
     // This is a placeholder - in reality you'd need to track running tunnels
     // and send them a shutdown signal, possibly using a local daemon or PID file
+    if output.is_json() {
+        return print_json(&json!({
+            "stopped": false,
+            "message": "stop is not yet implemented, use Ctrl+C to stop the tunnel",
+        }));
+    }
     println!("Stop command not yet implemented.");
     println!("For now, use Ctrl+C to stop the tunnel.");
     Ok(())
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    run(Args::parse())
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let output = args.output;
+    let logs = LogConfig {
+        format: args.log_format,
+        level: args.log_level.clone(),
+        file: args.log_file.clone(),
+        rotation: args.log_rotation,
+        // Keep stdout for JSON documents
+        stderr: output.is_json(),
+        ..LogConfig::default()
+    };
+    let _logs = match logs.init() {
+        Ok(guard) => guard,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let Err(err) = run(args) else {
+        return ExitCode::SUCCESS;
+    };
+    if output.is_json() {
+        let code = err.downcast_ref::<ServerError>().map(|error| error.code);
+        let event = ClientEvent::Error {
+            message: format!("{err:#}"),
+            code,
+        };
+        if let Err(err) = print_json(&event) {
+            eprintln!("Error: {err:?}");
+        }
+    } else {
+        eprintln!("Error: {err:?}");
+    }
+    ExitCode::from(exit::for_error(&err))
 }
 // Trigger Rust CI workflow
@@ -0,0 +1,105 @@
+//! Rendering of command output as human-readable text or JSON.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::event::ClientEvent;
+
+/// How commands write their results to stdout.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Text for people.
+    #[default]
+    Text,
+
+    /// One JSON document per result, or per event while a tunnel runs.
+    Json,
+}
+
+impl OutputFormat {
+    /// Whether output is JSON.
+    #[must_use]
+    pub fn is_json(self) -> bool {
+        self == OutputFormat::Json
+    }
+
+    /// Print an event of a running tunnel.
+    ///
+    /// JSON output gets every event as one line. Text output only shows the
+    /// events people care about, since connections are already logged.
+    pub fn print_event(self, event: &ClientEvent) -> Result<()> {
+        if self.is_json() {
+            return print_json(event);
+        }
+        match event {
+            ClientEvent::Established {
+                server,
+                remote_port,
+                local_host,
+                local_port,
+            } => {
+                println!("\n✓ Tunnel established!");
+                println!("  Public URL: {server}:{remote_port}");
+                println!("  Forwarding to: {local_host}:{local_port}\n");
+            }
+            ClientEvent::TunnelOpened {
+                tunnel,
+                remote_port,
+                local_host,
+                local_port,
+            } => {
+                println!(
+                    "✓ Tunnel {tunnel} forwarding port {remote_port} to {local_host}:{local_port}"
+                );
+            }
+            ClientEvent::TunnelClosed { tunnel } => println!("Tunnel {tunnel} closed"),
+            ClientEvent::HealthChanged {
+                healthy: false,
+                detail,
+            } => {
+                let detail = detail.as_deref().unwrap_or("health check failed");
+                println!("✗ Local service is down: {detail}");
+            }
+            ClientEvent::HealthChanged { healthy: true, .. } => {
+                println!("✓ Local service is up");
+            }
+            ClientEvent::Reconnecting { attempt, delay_ms } => {
+                println!("Reconnecting in {delay_ms}ms (attempt {attempt})...");
+            }
+            ClientEvent::Disconnected => println!("✗ Server closed the tunnel"),
+            ClientEvent::Error { message, .. } => println!("✗ {message}"),
+            ClientEvent::ConnectionOpened { .. } | ClientEvent::ConnectionClosed { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+        })
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("unknown output format {s:?}, expected text or json"),
+        }
+    }
+}
+
+/// Print a value as a single line of JSON.
+pub fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use bore_client::client::Client;
+use bore_client::ClientBuilder;
 use tokio::sync::oneshot;
 use tracing::info;
 
@@ -20,34 +20,35 @@ pub async fn start_tunnel_connection(config: TunnelConfig) -> Result<()> {
         config.instance_id, config.server_host
     );
 
-    let client = Client::new(
-        &config.local_host,
-        config.local_port,
-        &config.server_host,
-        config.remote_port,
-        config.secret.as_deref(),
-    )
-    .await?;
+    let mut builder = ClientBuilder::new(&config.server_host, config.local_port)
+        .local_host(&config.local_host)
+        .remote_port(config.remote_port);
+    if let Some(secret) = &config.secret {
+        builder = builder.secret(secret);
+    }
+    let mut handle = builder.start().await?;
 
     if let Some(tx) = config.ready_tx {
-        let _ = tx.send(client.remote_port());
+        let _ = tx.send(handle.remote_port());
     }
 
     // Run client with graceful shutdown support
-    if let Some(mut shutdown_rx) = config.shutdown_rx {
-        tokio::select! {
-            result = client.listen() => {
+    if let Some(shutdown_rx) = config.shutdown_rx {
+        let shutdown = tokio::select! {
+            result = handle.wait() => {
                 result?;
+                false
             }
-            _ = &mut shutdown_rx => {
-                info!("Tunnel shutdown signal received for instance {}", config.instance_id);
-                // Graceful shutdown - just exit the select and drop the client
-            }
+            _ = shutdown_rx => true,
+        };
+        if shutdown {
+            info!("Tunnel shutdown signal received for instance {}", config.instance_id);
+            handle.stop().await?;
         }
     } else {
-        client.listen().await?;
+        handle.wait().await?;
     }
-    
+
     info!("Tunnel connection closed for instance {}", config.instance_id);
     Ok(())
 }
@@ -0,0 +1,173 @@
+//! Access log of visitor connections.
+//!
+//! Every connection to a tunnel's public port produces one record on
+//! [`ACCESS_LOG_TARGET`] once it closes, with the tunnel, its user, the
+//! visitor's address, how long the connection lasted, the bytes forwarded each
+//! way and why it closed. The server binary can write these records to a
+//! dedicated file.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bore_shared::logging::ACCESS_LOG_TARGET;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::info;
+
+use crate::groups::TunnelGroup;
+
+/// Why a visitor connection closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CloseReason {
+    /// Either side closed the connection after forwarding.
+    Closed,
+
+    /// Forwarding failed, e.g. because a side reset the connection.
+    Error,
+
+    /// No client accepted the connection in time.
+    NotAccepted,
+
+    /// No client of the tunnel had a healthy local service.
+    Unavailable,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::Closed => "closed",
+            CloseReason::Error => "error",
+            CloseReason::NotAccepted => "not_accepted",
+            CloseReason::Unavailable => "unavailable",
+        })
+    }
+}
+
+/// A visitor connection to a tunnel, logged when finished.
+pub(crate) struct Visit {
+    port: u16,
+    group: Option<String>,
+    user: String,
+    visitor: IpAddr,
+    started: Instant,
+}
+
+impl Visit {
+    /// A connection from `visitor` to the public port of `group`, starting now.
+    pub(crate) fn new(group: &TunnelGroup, visitor: SocketAddr) -> Self {
+        Self {
+            port: group.port(),
+            group: group.name().map(str::to_string),
+            user: group.owner().to_string(),
+            visitor: visitor.ip(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Write the access record of the connection, which closed for `reason`
+    /// after forwarding `bytes_in` from the visitor and `bytes_out` to them.
+    pub(crate) fn finish(
+        self,
+        bytes_in: u64,
+        bytes_out: u64,
+        reason: CloseReason,
+        error: Option<&str>,
+    ) {
+        let duration_ms = u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX);
+        info!(
+            target: ACCESS_LOG_TARGET,
+            tunnel = self.port,
+            group = self.group.as_deref(),
+            user = %self.user,
+            visitor = %self.visitor,
+            duration_ms,
+            bytes_in,
+            bytes_out,
+            reason = %reason,
+            error,
+            "visitor connection closed"
+        );
+    }
+}
+
+/// A stream counting the bytes read from and written to it.
+pub(crate) struct Counted<T> {
+    inner: T,
+    read: u64,
+    written: u64,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Self {
+            inner,
+            read: 0,
+            written: 0,
+        }
+    }
+
+    /// Bytes read so far.
+    pub(crate) fn read(&self) -> u64 {
+        self.read
+    }
+
+    /// Bytes written so far.
+    pub(crate) fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.read += (buf.filled().len() - before) as u64;
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.written += n as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn counts_bytes_both_ways() {
+        let (near, mut far) = tokio::io::duplex(64);
+        let mut counted = Counted::new(near);
+        counted.write_all(b"hello").await.unwrap();
+        far.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        counted.read_exact(&mut buf).await.unwrap();
+        assert_eq!((counted.read(), counted.written()), (2, 5));
+    }
+}
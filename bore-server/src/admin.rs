@@ -0,0 +1,471 @@
+//! Admin HTTP API for operating a running server.
+//!
+//! This is a deliberately small HTTP/1.1 listener, meant to be bound to a private
+//! address. Each request is answered and the connection closed.
+//!
+//! Routes:
+//! - `GET /healthz`, answering 200 while the process serves requests.
+//! - `GET /readyz`, answering 200 when the server should receive clients and 503
+//!   otherwise: before its control listener is bound, while it drains, or while
+//!   the backend circuit is open, if clients are authenticated by the backend.
+//! - `GET /metrics` with server metrics in the Prometheus text format.
+//! - `POST /cache/invalidate` with a JSON body holding one of `api_key`,
+//!   `key_hash`, `user_id` or `"all": true`, answered with the number of
+//!   cache entries removed.
+//!
+//! The health routes do not require the bearer token, so orchestrator probes
+//! can reach them. Servers without the admin API can serve only these routes.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use bore_shared::NETWORK_TIMEOUT;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::backend::resilience::CircuitState;
+use crate::backend::UsageBackend;
+use crate::cache::ValidationCache;
+use crate::metrics::{self, Exposition};
+use crate::outbox::Outbox;
+
+/// Maximum size of the request line and headers.
+const MAX_HEAD_LENGTH: usize = 16 * 1024;
+
+/// Maximum size of a request body.
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+
+/// Server state the admin API operates on.
+pub(crate) struct AdminState {
+    /// Bearer token required on every request, if set.
+    pub token: Option<String>,
+
+    /// Validation cache, if enabled.
+    pub cache: Option<Arc<ValidationCache>>,
+
+    /// Backend client, for its circuit breaker state and call counters.
+    pub backend: Arc<dyn UsageBackend>,
+
+    /// Events waiting for delivery to the backend.
+    pub outbox: Arc<Outbox>,
+
+    /// Whether the control listener is bound and accepting.
+    pub listening: watch::Receiver<bool>,
+
+    /// Whether the server is shutting down.
+    pub draining: watch::Receiver<bool>,
+
+    /// Whether authenticating clients needs the backend, so that an open
+    /// circuit makes the server unready.
+    pub backend_required: bool,
+
+    /// Serve only the health routes.
+    pub health_only: bool,
+}
+
+/// A parsed admin request.
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+/// A response to an admin request.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: &Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::json(status, &json!({ "error": message.into() }))
+    }
+}
+
+/// Body of `POST /cache/invalidate`.
+#[derive(Debug, Default, Deserialize)]
+struct InvalidateRequest {
+    api_key: Option<String>,
+    key_hash: Option<String>,
+    user_id: Option<String>,
+    #[serde(default)]
+    all: bool,
+}
+
+/// Accept admin requests until the listener fails.
+pub(crate) async fn serve(listener: TcpListener, state: Arc<AdminState>) -> Result<()> {
+    info!(addr = ?listener.local_addr()?, "admin API listening");
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(
+            async move {
+                if let Err(err) = handle(stream, &state).await {
+                    warn!(%err, "admin request failed");
+                }
+            }
+            .instrument(info_span!("admin", ?addr)),
+        );
+    }
+}
+
+async fn handle(mut stream: TcpStream, state: &AdminState) -> Result<()> {
+    let request = match timeout(NETWORK_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(err)) => {
+            return write_response(&mut stream, Response::error(400, format!("{err:#}"))).await;
+        }
+        Err(_) => bail!("timed out reading admin request"),
+    };
+
+    let response = route(state, &request);
+    info!(method = %request.method, path = %request.path, status = response.status, "admin request");
+    write_response(&mut stream, response).await
+}
+
+fn route(state: &AdminState, request: &Request) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/healthz") => return Response::json(200, &json!({ "status": "ok" })),
+        ("GET", "/readyz") => return readiness(state),
+        (_, "/healthz" | "/readyz") => return Response::error(405, "method not allowed"),
+        _ if state.health_only => return Response::error(404, "not found"),
+        _ => {}
+    }
+
+    if let Some(token) = &state.token {
+        let expected = format!("Bearer {token}");
+        let provided = request.authorization.as_deref().unwrap_or_default();
+        if !constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+            return Response::error(401, "unauthorized");
+        }
+    }
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => render_metrics(state),
+        ("POST", "/cache/invalidate") => invalidate_cache(state, &request.body),
+        (_, "/metrics" | "/cache/invalidate") => Response::error(405, "method not allowed"),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn readiness(state: &AdminState) -> Response {
+    let listening = *state.listening.borrow();
+    let draining = *state.draining.borrow();
+    let circuit = state
+        .backend
+        .resilience()
+        .map(|resilience| resilience.breaker().state());
+    let backend_down = state.backend_required && circuit == Some(CircuitState::Open);
+    let ready = listening && !draining && !backend_down;
+    let body = json!({
+        "ready": ready,
+        "listening": listening,
+        "draining": draining,
+        "backend_circuit": circuit.map(|state| state.to_string()),
+    });
+    Response::json(if ready { 200 } else { 503 }, &body)
+}
+
+fn render_metrics(state: &AdminState) -> Response {
+    let mut exp = Exposition::default();
+    if let Some(resilience) = state.backend.resilience() {
+        metrics::write_backend(&mut exp, resilience);
+    }
+    metrics::write_outbox(&mut exp, &state.outbox);
+    if let Some(cache) = &state.cache {
+        metrics::write_cache(&mut exp, cache);
+    }
+    Response {
+        status: 200,
+        content_type: "text/plain; version=0.0.4",
+        body: exp.finish(),
+    }
+}
+
+fn invalidate_cache(state: &AdminState, body: &[u8]) -> Response {
+    let Some(cache) = &state.cache else {
+        return Response::error(404, "validation cache is disabled");
+    };
+    let request: InvalidateRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(err) => return Response::error(400, format!("invalid body: {err}")),
+    };
+
+    let removed = if request.all {
+        cache.clear()
+    } else if let Some(api_key) = &request.api_key {
+        usize::from(cache.invalidate(api_key))
+    } else if let Some(hash) = &request.key_hash {
+        usize::from(cache.invalidate_hash(hash))
+    } else if let Some(user_id) = &request.user_id {
+        cache.invalidate_user(user_id)
+    } else {
+        return Response::error(400, "expected one of api_key, key_hash, user_id or all");
+    };
+    info!(removed, "validation cache invalidated");
+    Response::json(200, &json!({ "removed": removed }))
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if buf.len() > MAX_HEAD_LENGTH {
+            bail!("request head too large");
+        }
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed before end of request head");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = std::str::from_utf8(&buf[..head_end]).context("request head is not UTF-8")?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().context("missing request path")?;
+    let path = path.split('?').next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().context("invalid content-length")?;
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_string());
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        bail!("request body too large");
+    }
+
+    let mut body = buf.split_off(head_end + 4);
+    if body.len() < content_length {
+        let start = body.len();
+        body.resize(content_length, 0);
+        stream.read_exact(&mut body[start..]).await?;
+    }
+    body.truncate(content_length);
+
+    Ok(Request {
+        method,
+        path,
+        authorization,
+        body,
+    })
+}
+
+async fn write_response(stream: &mut TcpStream, response: Response) -> Result<()> {
+    let Response {
+        status,
+        content_type,
+        body,
+    } = response;
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Error",
+    };
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Compare two byte strings without leaking where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BackendClient;
+    use crate::cache::CacheConfig;
+
+    async fn spawn(token: Option<&str>) -> (String, Arc<ValidationCache>) {
+        let (url, cache, _) = spawn_with(
+            token,
+            Arc::new(BackendClient::new("http://127.0.0.1:9", None)),
+        )
+        .await;
+        (url, cache)
+    }
+
+    /// Serve the admin API, returning its URL, cache, and the listening and
+    /// draining flags it reports.
+    async fn spawn_with(
+        token: Option<&str>,
+        backend: Arc<dyn UsageBackend>,
+    ) -> (String, Arc<ValidationCache>, [watch::Sender<bool>; 2]) {
+        spawn_state(token, backend, true, false).await
+    }
+
+    async fn spawn_state(
+        token: Option<&str>,
+        backend: Arc<dyn UsageBackend>,
+        backend_required: bool,
+        health_only: bool,
+    ) -> (String, Arc<ValidationCache>, [watch::Sender<bool>; 2]) {
+        let cache = Arc::new(ValidationCache::new(CacheConfig::default()));
+        let listening = watch::Sender::new(true);
+        let draining = watch::Sender::new(false);
+        let state = Arc::new(AdminState {
+            token: token.map(str::to_string),
+            cache: Some(Arc::clone(&cache)),
+            backend,
+            outbox: Arc::new(Outbox::in_memory()),
+            listening: listening.subscribe(),
+            draining: draining.subscribe(),
+            backend_required,
+            health_only,
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, state));
+        (url, cache, [listening, draining])
+    }
+
+    #[tokio::test]
+    async fn invalidate_requires_token() {
+        let (url, _cache) = spawn(Some("s3cret")).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(format!("{url}/cache/invalidate"))
+            .json(&json!({ "all": true }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = client
+            .post(format!("{url}/cache/invalidate"))
+            .bearer_auth("s3cret")
+            .json(&json!({ "all": true }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["removed"], 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_routes_and_bodies() {
+        let (url, _cache) = spawn(None).await;
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("{url}/nope")).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        let response = client.get(format!("{url}/metrics")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("bore_backend_circuit_state 0"), "{body}");
+
+        let response = client
+            .post(format!("{url}/cache/invalidate"))
+            .json(&json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn health_routes_skip_token() {
+        let backend = Arc::new(BackendClient::new("http://127.0.0.1:9", None));
+        let (url, _cache, [listening, draining]) = spawn_with(
+            Some("s3cret"),
+            Arc::clone(&backend) as Arc<dyn UsageBackend>,
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let status = |path: &'static str| {
+            let request = client.get(format!("{url}{path}"));
+            async move { request.send().await.unwrap().status() }
+        };
+
+        assert_eq!(status("/healthz").await, 200);
+        assert_eq!(status("/readyz").await, 200);
+        assert_eq!(status("/metrics").await, 401);
+
+        listening.send_replace(false);
+        assert_eq!(status("/readyz").await, 503);
+        listening.send_replace(true);
+        draining.send_replace(true);
+        assert_eq!(status("/readyz").await, 503);
+        draining.send_replace(false);
+
+        // Open the backend circuit.
+        let breaker = backend.resilience().unwrap().breaker();
+        while breaker.state() != CircuitState::Open {
+            breaker.record_failure();
+        }
+        let response = client.get(format!("{url}/readyz")).send().await.unwrap();
+        assert_eq!(response.status(), 503);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["backend_circuit"], "open");
+        assert_eq!(status("/healthz").await, 200);
+    }
+
+    #[tokio::test]
+    async fn open_circuit_is_ready_without_backend_auth() {
+        let backend = Arc::new(BackendClient::new("http://127.0.0.1:9", None));
+        let breaker = backend.resilience().unwrap().breaker();
+        while breaker.state() != CircuitState::Open {
+            breaker.record_failure();
+        }
+        let (url, _cache, _flags) = spawn_state(
+            None,
+            Arc::clone(&backend) as Arc<dyn UsageBackend>,
+            false,
+            true,
+        )
+        .await;
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("{url}/readyz")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["backend_circuit"], "open");
+
+        // Only the health routes are served.
+        let response = client.get(format!("{url}/metrics")).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+    }
+}
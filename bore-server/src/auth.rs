@@ -0,0 +1,651 @@
+//! Pluggable client authentication.
+//!
+//! Every way a client can prove who it is goes through an [`AuthProvider`]: the
+//! server turns the client's handshake into a [`Credential`], and the provider
+//! decides which [`Identity`] it belongs to, if any. Providers are chained with
+//! [`AuthChain`], so a server can accept signed tokens, backend API keys and
+//! shared secrets at the same time, and library users can add their own.
+
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use bore_shared::{Authenticator, ErrorCode, PublicKey, ServerError};
+
+use crate::backend::{UsageBackend, ValidateKeyResponse};
+use crate::cache::ValidationCache;
+use crate::commands::Revocations;
+use crate::jwt::{self, TokenVerifier};
+use crate::keys::AuthorizedKeys;
+use crate::secrets::SharedSecrets;
+
+/// Tunnel limit of identities that do not carry one, such as legacy clients.
+pub const UNLIMITED_TUNNELS: u32 = 999;
+
+/// Tunnel limit of backend users whose plan does not specify one.
+pub const DEFAULT_MAX_TUNNELS: u32 = 5;
+
+/// What a client presented to prove who it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// A bare `Hello`, without any credential.
+    Anonymous,
+
+    /// An API key or tunnel token, sent with `Authenticate` before `Hello`.
+    Token(String),
+
+    /// An HMAC of the server's challenge, computed with a shared secret.
+    Secret {
+        /// Challenge sent by the server.
+        challenge: Uuid,
+        /// Hex-encoded HMAC sent by the client.
+        tag: String,
+    },
+
+    /// A signature of the server's challenge with an Ed25519 key.
+    Signature {
+        /// Challenge sent by the server.
+        challenge: Uuid,
+        /// Hex-encoded public key of the client.
+        key: String,
+        /// Hex-encoded signature over the challenge.
+        signature: String,
+    },
+}
+
+impl Credential {
+    /// Message sent to a client whose credential no provider accepted.
+    #[must_use]
+    pub fn unsupported_message(&self) -> &'static str {
+        match self {
+            Credential::Anonymous => "Authentication required. Please provide a valid API key.",
+            Credential::Token(_) => "Authentication method not supported. Use shared secret mode.",
+            Credential::Secret { .. } => "server requires a public key, but a secret was provided",
+            Credential::Signature { .. } => "server requires secret, but a public key was provided",
+        }
+    }
+}
+
+/// Information about the connecting client, beyond its credential.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Address of the client's control connection.
+    pub addr: SocketAddr,
+}
+
+/// Who a client is, and what it may do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// User that tunnels are counted against.
+    pub user_id: String,
+
+    /// Maximum number of concurrent tunnels for this user.
+    pub max_tunnels: u32,
+
+    /// Ports the client may listen on, within the server's range. Empty means any.
+    pub allowed_ports: Vec<RangeInclusive<u16>>,
+
+    /// Ports reserved for this user, in addition to the server's reservations file.
+    pub reserved_ports: Vec<u16>,
+
+    /// Dashboard instance the tunnel belongs to, reported to the backend.
+    pub instance_id: Option<String>,
+
+    /// Subscription plan of the user, for logging.
+    pub plan: Option<String>,
+}
+
+impl Identity {
+    /// An identity with the given user ID and tunnel limit, and no other restrictions.
+    #[must_use]
+    pub fn new(user_id: impl Into<String>, max_tunnels: u32) -> Self {
+        Self {
+            user_id: user_id.into(),
+            max_tunnels,
+            allowed_ports: Vec::new(),
+            reserved_ports: Vec::new(),
+            instance_id: None,
+            plan: None,
+        }
+    }
+
+    /// Turn an accepted backend validation into an identity.
+    fn from_validation(validation: ValidateKeyResponse) -> Result<Self> {
+        if !validation.valid {
+            let message = validation
+                .message
+                .unwrap_or_else(|| "Invalid API key".to_string());
+            bail!(ServerError::new(ErrorCode::InvalidCredentials, message));
+        }
+        if !validation.usage_allowed {
+            let message = validation.message.unwrap_or_else(|| {
+                "Subscription expired or usage limit exceeded. Please visit the dashboard."
+                    .to_string()
+            });
+            bail!(ServerError::new(ErrorCode::UsageNotAllowed, message));
+        }
+
+        // CRITICAL: Don't panic on missing user_id - handle gracefully to prevent DoS
+        // Backend bugs (data migration, partial rollouts, etc.) should not crash the server
+        let Some(user_id) = validation.user_id else {
+            error!(
+                "Backend returned valid=true but missing user_id. This is a backend bug. \
+                Rejecting connection to prevent undefined behavior."
+            );
+            bail!(ServerError::new(
+                ErrorCode::Internal,
+                "Authentication service returned invalid data. Please contact support.",
+            ));
+        };
+
+        Ok(Self {
+            user_id,
+            max_tunnels: validation
+                .max_concurrent_tunnels
+                .unwrap_or(DEFAULT_MAX_TUNNELS),
+            allowed_ports: Vec::new(),
+            reserved_ports: validation.reserved_ports,
+            instance_id: validation.instance_id,
+            plan: validation.plan_type,
+        })
+    }
+}
+
+/// A way of authenticating clients.
+///
+/// Returning `Ok(None)` means the provider does not handle this kind of
+/// credential, and the next provider in the chain is asked. Returning an error
+/// rejects the client; the error message is sent to it.
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Whether clients sending a bare `Hello` should be challenged for a secret or key.
+    fn wants_challenge(&self) -> bool {
+        false
+    }
+
+    /// Whether clients can only be authenticated while the backend is reachable.
+    fn uses_backend(&self) -> bool {
+        false
+    }
+
+    /// Check a credential, returning the identity it proves.
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        peer: &PeerInfo,
+    ) -> Result<Option<Identity>>;
+}
+
+#[async_trait]
+impl<P: AuthProvider + ?Sized> AuthProvider for Arc<P> {
+    fn wants_challenge(&self) -> bool {
+        (**self).wants_challenge()
+    }
+
+    fn uses_backend(&self) -> bool {
+        (**self).uses_backend()
+    }
+
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        peer: &PeerInfo,
+    ) -> Result<Option<Identity>> {
+        (**self).authenticate(credential, peer).await
+    }
+}
+
+/// Providers asked in order until one of them handles the credential.
+#[derive(Default)]
+pub struct AuthChain {
+    providers: Vec<Arc<dyn AuthProvider>>,
+}
+
+impl AuthChain {
+    /// An empty chain, which accepts nobody.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a provider to the chain.
+    #[must_use]
+    pub fn with(mut self, provider: impl AuthProvider + 'static) -> Self {
+        self.push(provider);
+        self
+    }
+
+    /// Append a provider to the chain.
+    pub fn push(&mut self, provider: impl AuthProvider + 'static) {
+        self.providers.push(Arc::new(provider));
+    }
+
+    /// Number of providers in the chain.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.providers.len()
+    }
+
+    /// Whether the chain has no providers.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+}
+
+#[async_trait]
+impl AuthProvider for AuthChain {
+    fn wants_challenge(&self) -> bool {
+        self.providers.iter().any(|p| p.wants_challenge())
+    }
+
+    fn uses_backend(&self) -> bool {
+        self.providers.iter().any(|p| p.uses_backend())
+    }
+
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        peer: &PeerInfo,
+    ) -> Result<Option<Identity>> {
+        for provider in &self.providers {
+            if let Some(identity) = provider.authenticate(credential, peer).await? {
+                return Ok(Some(identity));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Accepts every client, for servers running without authentication.
+///
+/// Clients sending a bare `Hello` share the `legacy-user` identity; clients
+/// sending a token are `local-user`. Neither has a meaningful tunnel limit.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoAuth;
+
+#[async_trait]
+impl AuthProvider for NoAuth {
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        _peer: &PeerInfo,
+    ) -> Result<Option<Identity>> {
+        Ok(match credential {
+            Credential::Anonymous => {
+                info!("Using legacy authentication mode");
+                Some(Identity::new("legacy-user", UNLIMITED_TUNNELS))
+            }
+            Credential::Token(_) => Some(Identity::new("local-user", UNLIMITED_TUNNELS)),
+            Credential::Secret { .. } | Credential::Signature { .. } => None,
+        })
+    }
+}
+
+/// Checks challenge replies against shared secrets.
+///
+/// Named secrets from a secrets file are identities of their own, the users
+/// `secret:<name>` with their own tunnel limits; the unnamed legacy secret is
+/// the `legacy-user`.
+pub struct SharedSecretProvider {
+    auth: Authenticator,
+    secrets: SharedSecrets,
+}
+
+impl SharedSecretProvider {
+    /// Accept replies computed with any secret of `auth`, naming identities after `secrets`.
+    #[must_use]
+    pub fn new(auth: Authenticator, secrets: SharedSecrets) -> Self {
+        Self { auth, secrets }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for SharedSecretProvider {
+    fn wants_challenge(&self) -> bool {
+        true
+    }
+
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        _peer: &PeerInfo,
+    ) -> Result<Option<Identity>> {
+        let Credential::Secret { challenge, tag } = credential else {
+            return Ok(None);
+        };
+        let name = self.auth.check(challenge, tag)?;
+        if self.secrets.contains(name) {
+            info!(secret = %name, "Authenticated with named secret");
+            let max_tunnels = self.secrets.max_tunnels(name).unwrap_or(UNLIMITED_TUNNELS);
+            Ok(Some(Identity::new(format!("secret:{name}"), max_tunnels)))
+        } else {
+            info!("Using legacy authentication mode");
+            Ok(Some(Identity::new("legacy-user", UNLIMITED_TUNNELS)))
+        }
+    }
+}
+
+/// Checks challenge signatures against a file of authorized Ed25519 keys.
+pub struct KeyFileProvider {
+    keys: AuthorizedKeys,
+}
+
+impl KeyFileProvider {
+    /// Accept signatures by any of these keys.
+    #[must_use]
+    pub fn new(keys: AuthorizedKeys) -> Self {
+        Self { keys }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for KeyFileProvider {
+    fn wants_challenge(&self) -> bool {
+        true
+    }
+
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        _peer: &PeerInfo,
+    ) -> Result<Option<Identity>> {
+        let Credential::Signature {
+            challenge,
+            key,
+            signature,
+        } = credential
+        else {
+            return Ok(None);
+        };
+        let entry = PublicKey::from_hex(key).ok().and_then(|key| {
+            self.keys
+                .get(&key)
+                .filter(|_| key.verify(challenge, signature))
+        });
+        let Some(entry) = entry else {
+            bail!("invalid or unauthorized public key");
+        };
+        info!(user_id = %entry.user_id, "Authenticated with public key");
+        Ok(Some(Identity {
+            user_id: entry.user_id.clone(),
+            max_tunnels: entry.max_tunnels.unwrap_or(UNLIMITED_TUNNELS),
+            allowed_ports: entry.ports.clone(),
+            reserved_ports: Vec::new(),
+            instance_id: None,
+            plan: None,
+        }))
+    }
+}
+
+/// Verifies signed `tk_` tunnel tokens locally.
+///
+/// Other tokens are left to the next provider, usually the backend.
+pub struct JwtProvider {
+    verifier: TokenVerifier,
+    revocation: Option<Arc<dyn UsageBackend>>,
+    revocations: Option<Arc<Revocations>>,
+}
+
+impl JwtProvider {
+    /// Accept tokens signed by one of the verifier's keys.
+    #[must_use]
+    pub fn new(verifier: TokenVerifier) -> Self {
+        Self {
+            verifier,
+            revocation: None,
+            revocations: None,
+        }
+    }
+
+    /// Ask the backend whether tokens carrying a `jti` have been revoked.
+    ///
+    /// If that check fails the token is accepted, since it is already signed and
+    /// unexpired.
+    #[must_use]
+    pub fn with_revocation_check(mut self, backend: Arc<dyn UsageBackend>) -> Self {
+        self.revocation = Some(backend);
+        self
+    }
+
+    /// Refuse tokens of users and instances the backend has revoked, until the
+    /// tokens expire.
+    #[must_use]
+    pub fn with_revocations(mut self, revocations: Arc<Revocations>) -> Self {
+        self.revocations = Some(revocations);
+        self
+    }
+}
+
+#[async_trait]
+impl AuthProvider for JwtProvider {
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        _peer: &PeerInfo,
+    ) -> Result<Option<Identity>> {
+        let Credential::Token(token) = credential else {
+            return Ok(None);
+        };
+        if !jwt::is_signed_token(token) {
+            return Ok(None);
+        }
+        let claims = self.verifier.verify(token)?;
+        info!(user_id = %claims.user_id, "Verified tunnel token locally");
+
+        let revoked = self.revocations.as_ref().and_then(|revocations| {
+            revocations.check_token(&claims.user_id, claims.instance_id.as_deref(), claims.exp)
+        });
+        if let Some(error) = revoked {
+            bail!(error);
+        }
+
+        if let (Some(backend), Some(jti)) = (&self.revocation, &claims.jti) {
+            match backend.is_token_revoked(jti).await {
+                Ok(true) => bail!("tunnel token has been revoked"),
+                Ok(false) => {}
+                Err(err) => warn!(%err, "Revocation check failed, accepting signed token"),
+            }
+        }
+        Identity::from_validation(claims.into()).map(Some)
+    }
+}
+
+/// Validates opaque API keys with the backend, through a cache if one is set.
+pub struct BackendProvider {
+    backend: Arc<dyn UsageBackend>,
+    cache: Option<Arc<ValidationCache>>,
+    revocations: Option<Arc<Revocations>>,
+}
+
+impl BackendProvider {
+    /// Validate API keys with this backend.
+    #[must_use]
+    pub fn new(backend: Arc<dyn UsageBackend>) -> Self {
+        Self {
+            backend,
+            cache: None,
+            revocations: None,
+        }
+    }
+
+    /// Serve repeated validations of the same key from this cache.
+    #[must_use]
+    pub fn with_cache(mut self, cache: Arc<ValidationCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Refuse users and instances the backend has revoked, even if their key
+    /// still validates.
+    #[must_use]
+    pub fn with_revocations(mut self, revocations: Arc<Revocations>) -> Self {
+        self.revocations = Some(revocations);
+        self
+    }
+}
+
+#[async_trait]
+impl AuthProvider for BackendProvider {
+    fn uses_backend(&self) -> bool {
+        true
+    }
+
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        _peer: &PeerInfo,
+    ) -> Result<Option<Identity>> {
+        let Credential::Token(api_key) = credential else {
+            return Ok(None);
+        };
+        info!("Authenticating with backend API");
+        let validation = match &self.cache {
+            Some(cache) => cache.validate(self.backend.as_ref(), api_key).await,
+            None => self.backend.validate_api_key(api_key).await,
+        };
+        match validation {
+            Ok(validation) => {
+                let identity = Identity::from_validation(validation)?;
+                let revoked = self.revocations.as_ref().and_then(|revocations| {
+                    revocations.check(&identity.user_id, identity.instance_id.as_deref())
+                });
+                if let Some(error) = revoked {
+                    bail!(error);
+                }
+                Ok(Some(identity))
+            }
+            Err(err) => {
+                warn!(%err, "Failed to connect to backend API");
+                bail!(ServerError::new(
+                    ErrorCode::BackendUnavailable,
+                    "Authentication service unavailable",
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> PeerInfo {
+        PeerInfo {
+            addr: "127.0.0.1:4000".parse().unwrap(),
+        }
+    }
+
+    /// Accepts a single token, as a library user might.
+    struct StaticToken(&'static str);
+
+    #[async_trait]
+    impl AuthProvider for StaticToken {
+        async fn authenticate(
+            &self,
+            credential: &Credential,
+            _peer: &PeerInfo,
+        ) -> Result<Option<Identity>> {
+            match credential {
+                Credential::Token(token) if token == self.0 => {
+                    Ok(Some(Identity::new(format!("static-{token}"), 1)))
+                }
+                Credential::Token(_) => bail!("unknown token"),
+                _ => Ok(None),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_stops_at_first_answer() -> Result<()> {
+        let chain = AuthChain::new().with(StaticToken("a")).with(NoAuth);
+        let peer = peer();
+
+        let identity = chain
+            .authenticate(&Credential::Token("a".into()), &peer)
+            .await?;
+        assert_eq!(identity.unwrap().user_id, "static-a");
+
+        // A rejection stops the chain, even though NoAuth would accept.
+        let err = chain
+            .authenticate(&Credential::Token("b".into()), &peer)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unknown token");
+
+        // Credentials the first provider ignores fall through.
+        let identity = chain.authenticate(&Credential::Anonymous, &peer).await?;
+        assert_eq!(identity.unwrap().user_id, "legacy-user");
+        assert!(!chain.wants_challenge());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_secret_names_identities() -> Result<()> {
+        let secrets = SharedSecrets::parse("ci s3cret max_tunnels=2\nlegacy-user hunter2\n")?;
+        let provider =
+            SharedSecretProvider::new(secrets.extend_authenticator(None), secrets.clone());
+        let challenge = Uuid::new_v4();
+        let client = Authenticator::new("s3cret");
+        let credential = Credential::Secret {
+            challenge,
+            tag: client.answer(&challenge),
+        };
+
+        let identity = provider.authenticate(&credential, &peer()).await?.unwrap();
+        assert_eq!(identity, Identity::new("secret:ci", 2));
+        assert!(provider.wants_challenge());
+
+        // A secret's name cannot pass for a user authenticated another way.
+        let impostor = Credential::Secret {
+            challenge,
+            tag: Authenticator::new("hunter2").answer(&challenge),
+        };
+        let identity = provider.authenticate(&impostor, &peer()).await?.unwrap();
+        assert_eq!(identity.user_id, "secret:legacy-user");
+
+        let wrong = Credential::Secret {
+            challenge,
+            tag: Authenticator::new("other").answer(&challenge),
+        };
+        assert!(provider.authenticate(&wrong, &peer()).await.is_err());
+        assert!(provider
+            .authenticate(&Credential::Token("x".into()), &peer())
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn validation_limits_and_rejections() {
+        let mut validation = ValidateKeyResponse {
+            valid: true,
+            user_id: Some("user_1".into()),
+            email: None,
+            plan_type: Some("pro".into()),
+            max_concurrent_tunnels: None,
+            max_bandwidth_gb: None,
+            usage_allowed: true,
+            message: None,
+            instance_id: None,
+            reserved_ports: Vec::new(),
+        };
+        let identity = Identity::from_validation(validation.clone()).unwrap();
+        assert_eq!(identity.max_tunnels, DEFAULT_MAX_TUNNELS);
+        assert_eq!(identity.plan.as_deref(), Some("pro"));
+
+        validation.usage_allowed = false;
+        let err = Identity::from_validation(validation.clone()).unwrap_err();
+        assert!(err.to_string().contains("Subscription expired"));
+
+        validation.valid = false;
+        validation.message = Some("Key revoked".into());
+        let err = Identity::from_validation(validation).unwrap_err();
+        assert_eq!(err.to_string(), "Key revoked");
+    }
+}
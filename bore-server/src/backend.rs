@@ -1,16 +1,117 @@
 //! Backend API client for user authentication and usage tracking.
+//!
+//! The server talks to the backend through the [`UsageBackend`] trait. The HTTP
+//! [`BackendClient`] is the production implementation; [`NoopBackend`] runs a
+//! server without a backend, and [`RecordingBackend`] keeps everything in
+//! memory for embedders and tests.
 
-use anyhow::{anyhow, Context, Error, Result};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
 use bore_shared::timeouts::BACKEND_HTTP_TIMEOUT;
-use reqwest::{Client, Method, RequestBuilder};
+use bore_shared::TunnelStatus;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
-use std::time::Duration;
+use std::time::Instant;
 use tokio::time::sleep;
 use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::outbox::OutboxEvent;
+use crate::registry::{ServerInfo, ServerLoad};
+
+mod noop;
+mod recording;
+pub mod resilience;
+
+pub use noop::NoopBackend;
+pub use recording::RecordingBackend;
+use resilience::{BreakerConfig, Endpoint, Resilience};
+
+/// Header carrying an event's ID, so the backend can drop duplicate deliveries.
+pub const EVENT_ID_HEADER: &str = "Idempotency-Key";
+
+/// What the server needs from a backend: validating credentials, and receiving
+/// tunnel lifecycle, instance status and usage events.
+#[async_trait]
+pub trait UsageBackend: Send + Sync {
+    /// Whether clients are authenticated against this backend and events are sent to it.
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    /// Validate an API key.
+    ///
+    /// Rejected keys are `Ok` with `valid: false`; `Err` means the backend could
+    /// not tell, e.g. because it is unreachable.
+    async fn validate_api_key(&self, api_key: &str) -> Result<ValidateKeyResponse>;
+
+    /// Check whether the signed tunnel token with this ID has been revoked.
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool>;
+
+    /// Deliver a tunnel start or end, instance status or usage event.
+    ///
+    /// Events are redelivered until this succeeds, so implementations should
+    /// drop events whose `event_id` they have already applied.
+    async fn deliver_event(&self, event_id: &str, event: &OutboxEvent) -> Result<()>;
 
-const RETRY_ATTEMPTS: usize = 3;
-const RETRY_DELAY_MS: u64 = 300;
+    /// Add the server to the backend's registry, or update its entry.
+    async fn register_server(&self, _info: &ServerInfo) -> Result<()> {
+        Ok(())
+    }
+
+    /// Report the current load of a registered server.
+    async fn report_load(&self, _server_id: &str, _load: &ServerLoad) -> Result<()> {
+        Ok(())
+    }
+
+    /// Remove the server from the backend's registry.
+    async fn deregister_server(&self, _server_id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Retry policies, circuit breaker and call counters, exported as metrics.
+    fn resilience(&self) -> Option<&Resilience> {
+        None
+    }
+}
+
+#[async_trait]
+impl<B: UsageBackend + ?Sized> UsageBackend for Arc<B> {
+    fn is_enabled(&self) -> bool {
+        (**self).is_enabled()
+    }
+
+    async fn validate_api_key(&self, api_key: &str) -> Result<ValidateKeyResponse> {
+        (**self).validate_api_key(api_key).await
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool> {
+        (**self).is_token_revoked(jti).await
+    }
+
+    async fn deliver_event(&self, event_id: &str, event: &OutboxEvent) -> Result<()> {
+        (**self).deliver_event(event_id, event).await
+    }
+
+    async fn register_server(&self, info: &ServerInfo) -> Result<()> {
+        (**self).register_server(info).await
+    }
+
+    async fn report_load(&self, server_id: &str, load: &ServerLoad) -> Result<()> {
+        (**self).report_load(server_id, load).await
+    }
+
+    async fn deregister_server(&self, server_id: &str) -> Result<()> {
+        (**self).deregister_server(server_id).await
+    }
+
+    fn resilience(&self) -> Option<&Resilience> {
+        (**self).resilience()
+    }
+}
 
 /// Request to validate an API key with the backend.
 #[derive(Debug, Serialize)]
@@ -19,7 +120,7 @@ struct ValidateKeyRequest {
 }
 
 /// Response from the backend after validating an API key.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ValidateKeyResponse {
     pub valid: bool,
     pub user_id: Option<String>,
@@ -32,11 +133,63 @@ pub struct ValidateKeyResponse {
     pub usage_allowed: bool,
     pub message: Option<String>,
     pub instance_id: Option<String>,
+    /// Ports reserved for this user, which only the user may bind.
+    #[serde(default)]
+    pub reserved_ports: Vec<u16>,
+}
+
+impl ValidateKeyResponse {
+    /// A valid key of this user, with this tunnel limit.
+    #[must_use]
+    pub fn allowed(user_id: impl Into<String>, max_concurrent_tunnels: u32) -> Self {
+        Self {
+            valid: true,
+            user_id: Some(user_id.into()),
+            email: None,
+            plan_type: None,
+            max_concurrent_tunnels: Some(max_concurrent_tunnels),
+            max_bandwidth_gb: None,
+            usage_allowed: true,
+            message: None,
+            instance_id: None,
+            reserved_ports: Vec::new(),
+        }
+    }
+
+    /// A rejected key, with the message shown to the client.
+    #[must_use]
+    pub fn rejected(message: impl Into<String>) -> Self {
+        Self {
+            valid: false,
+            user_id: None,
+            email: None,
+            plan_type: None,
+            max_concurrent_tunnels: None,
+            max_bandwidth_gb: None,
+            usage_allowed: false,
+            message: Some(message.into()),
+            instance_id: None,
+            reserved_ports: Vec::new(),
+        }
+    }
+}
+
+/// Request to check whether a signed tunnel token has been revoked.
+#[derive(Debug, Serialize)]
+struct RevocationCheckRequest {
+    jti: String,
+}
+
+/// Response to a revocation check.
+#[derive(Debug, Deserialize)]
+struct RevocationCheckResponse {
+    revoked: bool,
 }
 
 /// Request to start a tunnel session.
 #[derive(Debug, Serialize)]
 struct TunnelStartRequest {
+    session_id: String,
     user_id: String,
     public_port: u16,
     local_port: u16,
@@ -52,7 +205,6 @@ struct TunnelEndRequest {
 
 /// Request to log bandwidth usage.
 #[derive(Debug, Serialize)]
-#[allow(dead_code)]
 struct UsageLogRequest {
     user_id: String,
     session_id: String,
@@ -60,18 +212,12 @@ struct UsageLogRequest {
     bytes_out: u64,
 }
 
-/// Response from starting a tunnel session.
-#[derive(Debug, Deserialize)]
-struct SessionResponse {
-    session_id: String,
-}
-
-/// Backend API client for authentication and usage tracking.
+/// HTTP client for the backend API.
 pub struct BackendClient {
     http_client: Client,
     base_url: String,
-    pub enabled: bool,
     api_key: Option<String>,
+    resilience: Resilience,
 }
 
 impl BackendClient {
@@ -89,15 +235,12 @@ impl BackendClient {
         self.apply_internal_auth(builder)
     }
 
-    /// Create a new backend client.
+    /// Create a client for the backend at `base_url`, authenticating internal
+    /// calls with `api_key` if set.
     ///
-    /// If `backend_url` is None, the client will be disabled and all operations
-    /// will succeed without making actual API calls (fallback mode).
-    pub fn new(backend_url: Option<String>, api_key: Option<String>) -> Self {
-        let (base_url, enabled) = match backend_url {
-            Some(url) => (url, true),
-            None => (String::new(), false),
-        };
+    /// Servers without a backend use [`NoopBackend`] instead.
+    pub fn new(base_url: impl Into<String>, api_key: Option<String>) -> Self {
+        let base_url = base_url.into();
 
         // Backend HTTP client timeout
         //
@@ -113,7 +256,6 @@ impl BackendClient {
             .expect("Failed to create HTTP client");
 
         info!(
-            enabled = enabled,
             base_url = %base_url,
             api_key_configured = api_key.is_some(),
             "Backend API client initialized"
@@ -122,39 +264,130 @@ impl BackendClient {
         Self {
             http_client,
             base_url,
-            enabled,
             api_key,
+            resilience: Resilience::default(),
         }
     }
 
-    /// Validate an API key with the backend.
+    /// Replace the circuit breaker settings.
+    pub fn set_breaker_config(&mut self, config: BreakerConfig) {
+        self.resilience = Resilience::new(config);
+    }
+
+    /// Mutable access to the resilience layer, to override retry policies.
+    pub fn resilience_mut(&mut self) -> &mut Resilience {
+        &mut self.resilience
+    }
+
+    /// Send a request built by `build`, retrying according to the endpoint's policy.
     ///
-    /// Returns validation result with user information and permissions.
-    pub async fn validate_api_key(&self, api_key: &str) -> Result<ValidateKeyResponse> {
-        // If backend is disabled, allow all connections (fallback mode)
-        if !self.enabled {
-            debug!("Backend disabled, allowing connection without validation");
-            return Ok(ValidateKeyResponse {
-                valid: true,
-                user_id: Some("local-user".to_string()),
-                email: None,
-                plan_type: Some("unlimited".to_string()),
-                max_concurrent_tunnels: Some(999),
-                max_bandwidth_gb: Some(999999),
-                usage_allowed: true,
-                message: Some("Backend validation disabled".to_string()),
-                instance_id: None,
-            });
+    /// Network errors and 5xx or 429 responses count as failures: they are retried
+    /// while the policy and retry budget allow, and feed the circuit breaker. Any
+    /// other response is returned to the caller. While the circuit is open, this
+    /// fails immediately with a [`resilience::CircuitOpenError`].
+    async fn execute(
+        &self,
+        endpoint: Endpoint,
+        build: impl Fn() -> RequestBuilder,
+    ) -> Result<Response> {
+        // Dropped if this future is cancelled, letting another call probe a
+        // half-open circuit.
+        let mut permit = self.resilience.begin(endpoint)?;
+        let policy = self.resilience.policy(endpoint);
+        let started = Instant::now();
+
+        let mut attempt = 0;
+        loop {
+            let mut request = build();
+            if let Some(deadline) = policy.deadline {
+                let remaining = deadline.saturating_sub(started.elapsed());
+                request = request.timeout(remaining.min(BACKEND_HTTP_TIMEOUT));
+            }
+
+            let error = match request.send().await {
+                Ok(response) if !is_failure(response.status()) => {
+                    self.resilience.finish(endpoint, permit, true);
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let text = response.text().await.unwrap_or_default();
+                    anyhow!("backend responded with status {status}: {text}")
+                }
+                Err(err) => err.into(),
+            };
+
+            permit.record_failure();
+            warn!(
+                attempt = attempt + 1,
+                %endpoint,
+                error = %error,
+                circuit = %self.resilience.breaker().state(),
+                "Backend call failed"
+            );
+            match self.resilience.retry_delay(endpoint, attempt, started) {
+                Some(delay) => {
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                None => {
+                    self.resilience.finish(endpoint, permit, false);
+                    return Err(error.context(format!("backend {endpoint} call failed")));
+                }
+            }
         }
+    }
+
+    /// Notify the backend right away that an instance's tunnel connected, along
+    /// with the health of its local service if the client checks it.
+    ///
+    /// The server queues this through its [`Outbox`](crate::outbox::Outbox) instead.
+    pub async fn notify_tunnel_connected(
+        &self,
+        instance_id: &str,
+        remote_port: Option<u16>,
+        public_url: Option<&str>,
+        status: Option<&TunnelStatus>,
+    ) -> Result<()> {
+        let event = OutboxEvent::TunnelConnected {
+            instance_id: instance_id.to_string(),
+            remote_port,
+            public_url: public_url.map(str::to_string),
+            status: status.cloned(),
+        };
+        self.deliver_event(&Uuid::new_v4().to_string(), &event)
+            .await
+    }
+
+    /// Notify the backend right away that an instance's tunnel disconnected.
+    ///
+    /// The server queues this through its [`Outbox`](crate::outbox::Outbox) instead.
+    pub async fn notify_tunnel_disconnected(&self, instance_id: &str) -> Result<()> {
+        let event = OutboxEvent::TunnelDisconnected {
+            instance_id: instance_id.to_string(),
+        };
+        self.deliver_event(&Uuid::new_v4().to_string(), &event)
+            .await
+    }
+}
 
+#[async_trait]
+impl UsageBackend for BackendClient {
+    /// Validate an API key with the backend.
+    ///
+    /// Returns validation result with user information and permissions. Server
+    /// errors (5xx) are returned as `Err`, since they say nothing about the key.
+    async fn validate_api_key(&self, api_key: &str) -> Result<ValidateKeyResponse> {
         debug!("Validating API key with backend");
 
+        let request = ValidateKeyRequest {
+            api_key: api_key.to_string(),
+        };
         let response = self
-            .request(Method::POST, "api/internal/validate-key")
-            .json(&ValidateKeyRequest {
-                api_key: api_key.to_string(),
+            .execute(Endpoint::ValidateKey, || {
+                self.request(Method::POST, "api/internal/validate-key")
+                    .json(&request)
             })
-            .send()
             .await
             .context("Failed to connect to backend API")?;
 
@@ -162,17 +395,9 @@ impl BackendClient {
             let status = response.status();
             let body = response.text().await.unwrap_or_default();
             error!(status = %status, body = %body, "Backend API returned error");
-            return Ok(ValidateKeyResponse {
-                valid: false,
-                user_id: None,
-                email: None,
-                plan_type: None,
-                max_concurrent_tunnels: None,
-                max_bandwidth_gb: None,
-                usage_allowed: false,
-                message: Some(format!("Backend error: {status}")),
-                instance_id: None,
-            });
+            return Ok(ValidateKeyResponse::rejected(format!(
+                "Backend error: {status}"
+            )));
         }
 
         let validation = response
@@ -190,183 +415,173 @@ impl BackendClient {
         Ok(validation)
     }
 
-    /// Log the start of a tunnel session.
-    pub async fn log_tunnel_start(
-        &self,
-        user_id: &str,
-        public_port: u16,
-        local_port: u16,
-        server_id: &str,
-    ) -> Result<String> {
-        if !self.enabled {
-            return Ok(format!("session-{}", uuid::Uuid::new_v4()));
-        }
-
-        debug!(
-            user_id = %user_id,
-            public_port = public_port,
-            local_port = local_port,
-            "Logging tunnel start"
-        );
+    /// Check whether the tunnel token with this ID has been revoked.
+    ///
+    /// Signed tunnel tokens are verified locally, so this is the only backend call
+    /// needed to admit them.
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool> {
+        debug!(jti = %jti, "Checking tunnel token revocation");
 
+        let request = RevocationCheckRequest {
+            jti: jti.to_string(),
+        };
         let response = self
-            .request(Method::POST, "api/internal/tunnel/start")
-            .json(&TunnelStartRequest {
-                user_id: user_id.to_string(),
-                public_port,
-                local_port,
-                server_id: server_id.to_string(),
+            .execute(Endpoint::TokenRevocation, || {
+                self.request(Method::POST, "api/internal/tokens/revocation")
+                    .json(&request)
             })
-            .send()
             .await?
-            .error_for_status()?; // Propagate HTTP errors (4xx/5xx)
+            .error_for_status()?; // Propagate remaining HTTP errors (4xx)
 
-        let session = response.json::<SessionResponse>().await?;
-        Ok(session.session_id)
+        Ok(response.json::<RevocationCheckResponse>().await?.revoked)
     }
 
-    /// Log the end of a tunnel session.
-    pub async fn log_tunnel_end(&self, session_id: &str, bytes_transferred: u64) -> Result<()> {
-        if !self.enabled {
-            return Ok(());
-        }
-
-        debug!(
-            session_id = %session_id,
-            bytes_transferred = bytes_transferred,
-            "Logging tunnel end"
-        );
-
-        self.request(Method::POST, "api/internal/tunnel/end")
-            .json(&TunnelEndRequest {
-                session_id: session_id.to_string(),
+    /// Deliver an outbox event.
+    ///
+    /// The event ID is sent in the `Idempotency-Key` header, so the backend can
+    /// recognize events redelivered after a lost response.
+    async fn deliver_event(&self, event_id: &str, event: &OutboxEvent) -> Result<()> {
+        debug!(event_id = %event_id, event = ?event, "Delivering event to backend");
+
+        let (endpoint, path, body) = match event {
+            OutboxEvent::TunnelStart {
+                session_id,
+                user_id,
+                public_port,
+                local_port,
+                server_id,
+            } => (
+                Endpoint::TunnelStart,
+                "api/internal/tunnel/start".to_string(),
+                Some(json!(TunnelStartRequest {
+                    session_id: session_id.clone(),
+                    user_id: user_id.clone(),
+                    public_port: *public_port,
+                    local_port: *local_port,
+                    server_id: server_id.clone(),
+                })),
+            ),
+            OutboxEvent::TunnelEnd {
+                session_id,
                 bytes_transferred,
-            })
-            .send()
-            .await?
-            .error_for_status()?; // Propagate HTTP errors (4xx/5xx)
-
-        Ok(())
-    }
-
-    async fn post_with_retry(&self, path: &str, body: Option<&Value>) -> Result<()> {
-        if !self.enabled {
-            return Ok(());
-        }
-
-        let mut last_error: Option<Error> = None;
-
-        // Retry loop with exponential backoff to handle transient network failures
-        // and temporary backend unavailability
-        for attempt in 0..RETRY_ATTEMPTS {
-            // Build the request for this attempt
-            let mut request = self.request(Method::POST, path);
-            if let Some(payload) = body {
-                request = request.json(payload);
-            }
-
-            match request.send().await {
-                Ok(response) if response.status().is_success() => return Ok(()),
-                Ok(response) => {
-                    // Backend returned an HTTP error status
-                    let status = response.status();
-                    let text = response.text().await.unwrap_or_default();
-                    warn!(
-                        attempt = attempt + 1,
-                        path = %path,
-                        status = %status,
-                        "Backend returned error for internal POST"
-                    );
-                    last_error = Some(anyhow!("backend responded with status {status}: {text}"));
+            } => (
+                Endpoint::TunnelEnd,
+                "api/internal/tunnel/end".to_string(),
+                Some(json!(TunnelEndRequest {
+                    session_id: session_id.clone(),
+                    bytes_transferred: *bytes_transferred,
+                })),
+            ),
+            OutboxEvent::TunnelConnected {
+                instance_id,
+                remote_port,
+                public_url,
+                status,
+            } => {
+                let mut payload = Map::new();
+                if let Some(port) = remote_port {
+                    payload.insert("remotePort".into(), json!(port));
                 }
-                Err(err) => {
-                    // Network error or connection failure
-                    warn!(
-                        attempt = attempt + 1,
-                        path = %path,
-                        error = %err,
-                        "Failed to call backend internal POST"
-                    );
-                    last_error = Some(err.into());
+                if let Some(url) = public_url {
+                    payload.insert("publicUrl".into(), json!(url));
                 }
+                if let Some(status) = status {
+                    let health = if status.healthy {
+                        "healthy"
+                    } else {
+                        "unhealthy"
+                    };
+                    payload.insert("status".into(), json!(health));
+                    if let Some(detail) = &status.detail {
+                        payload.insert("statusDetail".into(), json!(detail));
+                    }
+                }
+                (
+                    Endpoint::InstanceStatus,
+                    format!("api/internal/instances/{instance_id}/tunnel-connected"),
+                    (!payload.is_empty()).then_some(Value::Object(payload)),
+                )
             }
+            OutboxEvent::TunnelDisconnected { instance_id } => (
+                Endpoint::InstanceStatus,
+                format!("api/internal/instances/{instance_id}/tunnel-disconnected"),
+                None,
+            ),
+            OutboxEvent::Usage {
+                user_id,
+                session_id,
+                bytes_in,
+                bytes_out,
+            } => (
+                Endpoint::Usage,
+                "api/internal/tunnel/usage".to_string(),
+                Some(json!(UsageLogRequest {
+                    user_id: user_id.clone(),
+                    session_id: session_id.clone(),
+                    bytes_in: *bytes_in,
+                    bytes_out: *bytes_out,
+                })),
+            ),
+        };
 
-            // Exponential backoff: delay increases with each retry attempt
-            // attempt 0: 300ms, attempt 1: 600ms, attempt 2: 900ms, etc.
-            if attempt + 1 < RETRY_ATTEMPTS {
-                let delay = Duration::from_millis(RETRY_DELAY_MS * (attempt as u64 + 1));
-                sleep(delay).await;
+        self.execute(endpoint, || {
+            let request = self
+                .request(Method::POST, &path)
+                .header(EVENT_ID_HEADER, event_id);
+            match &body {
+                Some(payload) => request.json(payload),
+                None => request,
             }
-        }
+        })
+        .await?
+        .error_for_status()?; // Propagate remaining HTTP errors (4xx)
 
-        // All retry attempts exhausted
-        Err(last_error.unwrap_or_else(|| {
-            anyhow!("backend POST {path} failed after {RETRY_ATTEMPTS} attempts")
-        }))
+        Ok(())
     }
 
-    pub async fn notify_tunnel_connected(
-        &self,
-        instance_id: &str,
-        remote_port: Option<u16>,
-        public_url: Option<&str>,
-    ) -> Result<()> {
-        if !self.enabled {
-            return Ok(());
-        }
-
-        let mut payload = Map::new();
-        if let Some(port) = remote_port {
-            payload.insert("remotePort".into(), json!(port));
-        }
-        if let Some(url) = public_url {
-            payload.insert("publicUrl".into(), json!(url));
-        }
-
-        let body = if payload.is_empty() {
-            None
-        } else {
-            Some(Value::Object(payload))
-        };
-
-        let path = format!("api/internal/instances/{instance_id}/tunnel-connected");
-        self.post_with_retry(&path, body.as_ref()).await
+    /// Register the server, updating its entry if the backend already knows it.
+    async fn register_server(&self, info: &ServerInfo) -> Result<()> {
+        debug!(server_id = %info.id, "Registering with backend");
+        self.execute(Endpoint::Registry, || {
+            self.request(Method::POST, "api/internal/servers/register")
+                .json(info)
+        })
+        .await?
+        .error_for_status()?;
+        Ok(())
     }
 
-    pub async fn notify_tunnel_disconnected(&self, instance_id: &str) -> Result<()> {
-        if !self.enabled {
-            return Ok(());
-        }
-
-        let path = format!("api/internal/instances/{instance_id}/tunnel-disconnected");
-        self.post_with_retry(&path, None).await
+    /// Report the server's load, failing if the backend no longer knows it.
+    async fn report_load(&self, server_id: &str, load: &ServerLoad) -> Result<()> {
+        self.execute(Endpoint::Registry, || {
+            self.request(
+                Method::POST,
+                &format!("api/internal/servers/{server_id}/heartbeat"),
+            )
+            .json(load)
+        })
+        .await?
+        .error_for_status()?;
+        Ok(())
     }
 
-    /// Log bandwidth usage for a session.
-    #[allow(dead_code)]
-    pub async fn log_usage(
-        &self,
-        user_id: &str,
-        session_id: &str,
-        bytes_in: u64,
-        bytes_out: u64,
-    ) -> Result<()> {
-        if !self.enabled {
-            return Ok(());
-        }
-
-        self.request(Method::POST, "api/internal/tunnel/usage")
-            .json(&UsageLogRequest {
-                user_id: user_id.to_string(),
-                session_id: session_id.to_string(),
-                bytes_in,
-                bytes_out,
-            })
-            .send()
-            .await?;
-
+    async fn deregister_server(&self, server_id: &str) -> Result<()> {
+        self.execute(Endpoint::Registry, || {
+            self.request(Method::DELETE, &format!("api/internal/servers/{server_id}"))
+        })
+        .await?
+        .error_for_status()?;
         Ok(())
     }
+
+    fn resilience(&self) -> Option<&Resilience> {
+        Some(&self.resilience)
+    }
+}
+
+/// Whether a response means the backend itself is failing, rather than rejecting the request.
+fn is_failure(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
 }
 
 #[cfg(test)]
@@ -445,10 +660,10 @@ mod tests {
 
         let handle = tokio::spawn(capture_single_request(listener));
 
-        let client = BackendClient::new(Some(backend_url), Some("internal-secret".to_string()));
+        let client = BackendClient::new(backend_url, Some("internal-secret".to_string()));
 
         client
-            .notify_tunnel_connected("inst_123", Some(5555), None)
+            .notify_tunnel_connected("inst_123", Some(5555), None, None)
             .await
             .expect("connected notification to succeed");
 
@@ -473,7 +688,7 @@ mod tests {
 
         let handle = tokio::spawn(capture_single_request(listener));
 
-        let client = BackendClient::new(Some(backend_url), Some("internal-secret".to_string()));
+        let client = BackendClient::new(backend_url, Some("internal-secret".to_string()));
 
         client
             .notify_tunnel_disconnected("inst_123")
@@ -490,4 +705,56 @@ mod tests {
             Some(&"internal-secret".to_string())
         );
     }
+
+    #[tokio::test]
+    async fn circuit_opens_on_failing_backend() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0u8; 4096];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket
+                    .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+
+        let mut client = BackendClient::new(backend_url, None);
+        client.set_breaker_config(BreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(60),
+        });
+
+        let end_event = OutboxEvent::TunnelEnd {
+            session_id: "session-1".to_string(),
+            bytes_transferred: 0,
+        };
+
+        // The second failed attempt opens the circuit, which stops further retries.
+        assert!(client.deliver_event("event-1", &end_event).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            client.resilience.breaker().state(),
+            resilience::CircuitState::Open
+        );
+
+        // While open, calls fail without reaching the backend.
+        let err = client
+            .deliver_event("event-2", &end_event)
+            .await
+            .unwrap_err();
+        assert!(err.is::<resilience::CircuitOpenError>(), "{err:#}");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        let stats = client.resilience.stats(Endpoint::TunnelEnd);
+        assert_eq!(stats.rejections.load(Ordering::Relaxed), 1);
+    }
 }
@@ -0,0 +1,31 @@
+//! Backend for servers running without one.
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+use super::{UsageBackend, ValidateKeyResponse};
+use crate::outbox::OutboxEvent;
+
+/// No backend at all: clients authenticate with the server's own methods, and
+/// events are dropped.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopBackend;
+
+#[async_trait]
+impl UsageBackend for NoopBackend {
+    fn is_enabled(&self) -> bool {
+        false
+    }
+
+    async fn validate_api_key(&self, _api_key: &str) -> Result<ValidateKeyResponse> {
+        bail!("no backend configured")
+    }
+
+    async fn is_token_revoked(&self, _jti: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn deliver_event(&self, _event_id: &str, _event: &OutboxEvent) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! In-memory backend that records what the server sends it.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+use super::{UsageBackend, ValidateKeyResponse};
+use crate::outbox::{OutboxEvent, OutboxRecord};
+use crate::registry::{ServerInfo, ServerLoad};
+
+/// Backend kept in memory, for embedding the server and for tests.
+///
+/// API keys and revoked tokens are registered up front; delivered events are
+/// recorded once per event ID, like a backend honoring idempotency keys.
+#[derive(Default)]
+pub struct RecordingBackend {
+    keys: Mutex<HashMap<String, ValidateKeyResponse>>,
+    revoked: Mutex<HashSet<String>>,
+    events: Mutex<Vec<OutboxRecord>>,
+    validations: AtomicUsize,
+    servers: Mutex<HashMap<String, ServerInfo>>,
+    loads: Mutex<Vec<ServerLoad>>,
+}
+
+impl RecordingBackend {
+    /// An empty backend, which knows no API keys.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer validations of `api_key` with this response.
+    pub fn add_key(&self, api_key: impl Into<String>, response: ValidateKeyResponse) {
+        self.keys.lock().unwrap().insert(api_key.into(), response);
+    }
+
+    /// Mark the tunnel token with this ID as revoked.
+    pub fn revoke_token(&self, jti: impl Into<String>) {
+        self.revoked.lock().unwrap().insert(jti.into());
+    }
+
+    /// Events delivered so far, in order.
+    #[must_use]
+    pub fn events(&self) -> Vec<OutboxEvent> {
+        let events = self.events.lock().unwrap();
+        events.iter().map(|record| record.event.clone()).collect()
+    }
+
+    /// Events delivered so far, with their IDs.
+    #[must_use]
+    pub fn records(&self) -> Vec<OutboxRecord> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Number of API key validations requested.
+    #[must_use]
+    pub fn validations(&self) -> usize {
+        self.validations.load(Ordering::Relaxed)
+    }
+
+    /// Servers currently registered.
+    #[must_use]
+    pub fn servers(&self) -> Vec<ServerInfo> {
+        self.servers.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Loads reported so far, in order.
+    #[must_use]
+    pub fn loads(&self) -> Vec<ServerLoad> {
+        self.loads.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl UsageBackend for RecordingBackend {
+    async fn validate_api_key(&self, api_key: &str) -> Result<ValidateKeyResponse> {
+        self.validations.fetch_add(1, Ordering::Relaxed);
+        let keys = self.keys.lock().unwrap();
+        Ok(keys
+            .get(api_key)
+            .cloned()
+            .unwrap_or_else(|| ValidateKeyResponse::rejected("Invalid API key")))
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool> {
+        Ok(self.revoked.lock().unwrap().contains(jti))
+    }
+
+    async fn deliver_event(&self, event_id: &str, event: &OutboxEvent) -> Result<()> {
+        let mut events = self.events.lock().unwrap();
+        if !events.iter().any(|record| record.id == event_id) {
+            events.push(OutboxRecord {
+                id: event_id.to_string(),
+                event: event.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn register_server(&self, info: &ServerInfo) -> Result<()> {
+        let mut servers = self.servers.lock().unwrap();
+        servers.insert(info.id.clone(), info.clone());
+        Ok(())
+    }
+
+    async fn report_load(&self, server_id: &str, load: &ServerLoad) -> Result<()> {
+        if !self.servers.lock().unwrap().contains_key(server_id) {
+            bail!("server {server_id} is not registered");
+        }
+        self.loads.lock().unwrap().push(*load);
+        Ok(())
+    }
+
+    async fn deregister_server(&self, server_id: &str) -> Result<()> {
+        self.servers.lock().unwrap().remove(server_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn records_each_event_once() -> Result<()> {
+        let backend = RecordingBackend::new();
+        backend.add_key("sk_a", ValidateKeyResponse::allowed("user_a", 2));
+
+        assert!(backend.validate_api_key("sk_a").await?.valid);
+        assert!(!backend.validate_api_key("sk_b").await?.valid);
+        assert_eq!(backend.validations(), 2);
+
+        let event = OutboxEvent::TunnelDisconnected {
+            instance_id: "inst_1".into(),
+        };
+        backend.deliver_event("e1", &event).await?;
+        backend.deliver_event("e1", &event).await?;
+        assert_eq!(backend.events(), [event]);
+        Ok(())
+    }
+}
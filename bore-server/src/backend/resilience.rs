@@ -0,0 +1,642 @@
+//! Retry and circuit-breaking policy for backend calls.
+//!
+//! Every call to the backend goes through one [`Resilience`] layer shared by the
+//! whole [`BackendClient`](super::BackendClient). Each endpoint has its own
+//! [`RetryPolicy`], retries are capped by a [`RetryBudget`] so an outage does not
+//! multiply load on the backend, and a [`CircuitBreaker`] fails calls fast once
+//! the backend looks down, letting a single probe through after a cooldown.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+/// Backend endpoints, each with its own retry policy and counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// API key validation, on the client's authentication path.
+    ValidateKey,
+    /// Revocation check for locally verified tunnel tokens.
+    TokenRevocation,
+    /// Start of a tunnel session.
+    TunnelStart,
+    /// End of a tunnel session.
+    TunnelEnd,
+    /// Instance status notifications (connected and disconnected).
+    InstanceStatus,
+    /// Bandwidth usage reports.
+    Usage,
+    /// Server registration, load reports and deregistration.
+    Registry,
+}
+
+impl Endpoint {
+    /// All endpoints, in a stable order for metrics output.
+    pub const ALL: [Endpoint; 7] = [
+        Endpoint::ValidateKey,
+        Endpoint::TokenRevocation,
+        Endpoint::TunnelStart,
+        Endpoint::TunnelEnd,
+        Endpoint::InstanceStatus,
+        Endpoint::Usage,
+        Endpoint::Registry,
+    ];
+
+    /// Name used in logs and metric labels.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Endpoint::ValidateKey => "validate_key",
+            Endpoint::TokenRevocation => "token_revocation",
+            Endpoint::TunnelStart => "tunnel_start",
+            Endpoint::TunnelEnd => "tunnel_end",
+            Endpoint::InstanceStatus => "instance_status",
+            Endpoint::Usage => "usage",
+            Endpoint::Registry => "registry",
+        }
+    }
+
+    /// Default retry policy for this endpoint.
+    ///
+    /// Calls made while a client waits for its handshake are bounded well below
+    /// the client's own timeout; bookkeeping calls can afford to retry longer.
+    #[must_use]
+    pub fn default_policy(self) -> RetryPolicy {
+        match self {
+            Endpoint::ValidateKey | Endpoint::TokenRevocation => RetryPolicy {
+                max_attempts: 2,
+                base_delay: Duration::from_millis(100),
+                max_delay: Duration::from_secs(1),
+                deadline: Some(bore_shared::BACKEND_HTTP_TIMEOUT),
+            },
+            Endpoint::TunnelStart | Endpoint::TunnelEnd | Endpoint::InstanceStatus => RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(300),
+                max_delay: Duration::from_secs(3),
+                deadline: None,
+            },
+            // Heartbeats come again soon, and registration is retried with them.
+            Endpoint::Usage | Endpoint::Registry => RetryPolicy::none(),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How often, and how far apart, a failed call is retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first.
+    pub max_attempts: u32,
+
+    /// Delay before the first retry, doubled for each one after.
+    pub base_delay: Duration,
+
+    /// Upper bound on a single delay.
+    pub max_delay: Duration,
+
+    /// Total time after which no further attempt is started.
+    pub deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// A single attempt, never retried.
+    #[must_use]
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            deadline: None,
+        }
+    }
+
+    /// Delay before retry number `retry` (starting at 0), with equal jitter: half
+    /// of the exponential delay is fixed, the other half random.
+    #[must_use]
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_delay);
+        let half = exp / 2;
+        half + half.mul_f64(fastrand::f64())
+    }
+}
+
+/// Caps retries to a fraction of first attempts, so retries cannot multiply the
+/// load on a struggling backend.
+#[derive(Debug)]
+pub struct RetryBudget {
+    /// Tokens earned by each first attempt.
+    ratio: f64,
+    /// Maximum number of banked tokens.
+    capacity: f64,
+    tokens: Mutex<f64>,
+}
+
+impl RetryBudget {
+    /// Allow roughly `ratio` retries per first attempt, banking at most `capacity`.
+    #[must_use]
+    pub fn new(ratio: f64, capacity: f64) -> Self {
+        Self {
+            ratio,
+            capacity,
+            tokens: Mutex::new(capacity),
+        }
+    }
+
+    fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.ratio).min(self.capacity);
+    }
+
+    fn try_withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self::new(0.2, 10.0)
+    }
+}
+
+/// State of a [`CircuitBreaker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through normally.
+    Closed,
+    /// Calls fail immediately until the cooldown has passed.
+    Open,
+    /// A single probe call is let through to test the backend.
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Numeric value for the circuit state gauge.
+    #[must_use]
+    pub fn as_gauge(self) -> u8 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        }
+    }
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        })
+    }
+}
+
+/// When a [`CircuitBreaker`] opens, and for how long.
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Consecutive failures after which the circuit opens.
+    pub failure_threshold: u32,
+
+    /// How long the circuit stays open before probing the backend again.
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Returned instead of calling the backend while the circuit is open.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitOpenError {
+    /// Endpoint whose call was rejected.
+    pub endpoint: Endpoint,
+}
+
+impl fmt::Display for CircuitOpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "backend circuit open, {} call rejected", self.endpoint)
+    }
+}
+
+impl std::error::Error for CircuitOpenError {}
+
+struct BreakerInner {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probe_in_flight: bool,
+}
+
+/// Circuit breaker shared by all calls to one backend.
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    /// Create a closed circuit breaker.
+    #[must_use]
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(BreakerInner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probe_in_flight: false,
+            }),
+        }
+    }
+
+    /// Current state, moving from open to half-open once the cooldown has passed.
+    pub fn state(&self) -> CircuitState {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Open if self.cooldown_elapsed(&inner, Instant::now()) => {
+                CircuitState::HalfOpen
+            }
+            state => state,
+        }
+    }
+
+    /// Admit a call to the backend now, unless the circuit is open.
+    ///
+    /// In the half-open state only one probe is admitted at a time, until its
+    /// outcome is recorded on the permit or the permit is dropped.
+    pub fn try_acquire(&self) -> Option<CallPermit<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        let probe = match inner.state {
+            CircuitState::Closed => false,
+            CircuitState::Open => {
+                if !self.cooldown_elapsed(&inner, Instant::now()) {
+                    return None;
+                }
+                info!("Backend circuit half-open, probing backend");
+                inner.state = CircuitState::HalfOpen;
+                inner.probe_in_flight = true;
+                true
+            }
+            CircuitState::HalfOpen if inner.probe_in_flight => return None,
+            CircuitState::HalfOpen => {
+                inner.probe_in_flight = true;
+                true
+            }
+        };
+        Some(CallPermit {
+            breaker: self,
+            probe,
+        })
+    }
+
+    /// Record a call that reached a healthy backend.
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != CircuitState::Closed {
+            info!("Backend circuit closed, backend recovered");
+        }
+        inner.state = CircuitState::Closed;
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.probe_in_flight = false;
+    }
+
+    /// Record a call that failed because of the backend.
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.probe_in_flight = false;
+        let trip = match inner.state {
+            CircuitState::Closed => inner.consecutive_failures >= self.config.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if trip {
+            warn!(
+                failures = inner.consecutive_failures,
+                cooldown = ?self.config.cooldown,
+                "Backend circuit open, failing backend calls fast"
+            );
+            inner.state = CircuitState::Open;
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    fn cooldown_elapsed(&self, inner: &BreakerInner, now: Instant) -> bool {
+        inner
+            .opened_at
+            .is_none_or(|opened| now.duration_since(opened) >= self.config.cooldown)
+    }
+
+    /// Let another call probe, after one was abandoned without an outcome.
+    fn abandon_probe(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == CircuitState::HalfOpen {
+            inner.probe_in_flight = false;
+        }
+    }
+}
+
+/// A call admitted by a [`CircuitBreaker`].
+///
+/// Dropping the permit of a half-open probe before recording its outcome, e.g.
+/// because the caller was cancelled, admits the next call as the probe.
+#[must_use = "dropping the permit abandons the call"]
+pub struct CallPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+}
+
+impl CallPermit<'_> {
+    /// Whether this call probes a half-open circuit.
+    #[must_use]
+    pub fn is_probe(&self) -> bool {
+        self.probe
+    }
+
+    /// Record that the call reached a healthy backend.
+    pub fn record_success(mut self) {
+        self.probe = false;
+        self.breaker.record_success();
+    }
+
+    /// Record an attempt of the call that failed because of the backend.
+    pub fn record_failure(&mut self) {
+        self.probe = false;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for CallPermit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.abandon_probe();
+        }
+    }
+}
+
+/// Per-endpoint call counters.
+#[derive(Debug, Default)]
+pub struct EndpointStats {
+    /// Calls that got an answer from the backend.
+    pub successes: AtomicU64,
+    /// Calls that failed after all attempts.
+    pub failures: AtomicU64,
+    /// Calls rejected because the circuit was open.
+    pub rejections: AtomicU64,
+    /// Retries made.
+    pub retries: AtomicU64,
+    /// Retries skipped because the retry budget was exhausted.
+    pub budget_exhausted: AtomicU64,
+}
+
+/// Retry policies, retry budget and circuit breaker for one backend.
+pub struct Resilience {
+    policies: HashMap<Endpoint, RetryPolicy>,
+    budget: RetryBudget,
+    breaker: CircuitBreaker,
+    stats: HashMap<Endpoint, EndpointStats>,
+}
+
+impl Resilience {
+    /// Create the layer with each endpoint's default policy.
+    #[must_use]
+    pub fn new(breaker: BreakerConfig) -> Self {
+        Self {
+            policies: Endpoint::ALL
+                .into_iter()
+                .map(|e| (e, e.default_policy()))
+                .collect(),
+            budget: RetryBudget::default(),
+            breaker: CircuitBreaker::new(breaker),
+            stats: Endpoint::ALL
+                .into_iter()
+                .map(|e| (e, EndpointStats::default()))
+                .collect(),
+        }
+    }
+
+    /// Override the retry policy of one endpoint.
+    pub fn set_policy(&mut self, endpoint: Endpoint, policy: RetryPolicy) {
+        self.policies.insert(endpoint, policy);
+    }
+
+    /// Replace the retry budget.
+    pub fn set_budget(&mut self, budget: RetryBudget) {
+        self.budget = budget;
+    }
+
+    /// Retry policy of an endpoint.
+    #[must_use]
+    pub fn policy(&self, endpoint: Endpoint) -> RetryPolicy {
+        self.policies[&endpoint]
+    }
+
+    /// The circuit breaker.
+    #[must_use]
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    /// Counters of an endpoint.
+    #[must_use]
+    pub fn stats(&self, endpoint: Endpoint) -> &EndpointStats {
+        &self.stats[&endpoint]
+    }
+
+    /// Called before the first attempt of a call; fails if the circuit is open.
+    pub(crate) fn begin(&self, endpoint: Endpoint) -> Result<CallPermit<'_>, CircuitOpenError> {
+        let Some(permit) = self.breaker.try_acquire() else {
+            self.stats(endpoint)
+                .rejections
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(CircuitOpenError { endpoint });
+        };
+        self.budget.deposit();
+        Ok(permit)
+    }
+
+    /// Whether a failed call may be retried, and after how long.
+    pub(crate) fn retry_delay(
+        &self,
+        endpoint: Endpoint,
+        attempt: u32,
+        started: Instant,
+    ) -> Option<Duration> {
+        let policy = self.policy(endpoint);
+        if attempt + 1 >= policy.max_attempts {
+            return None;
+        }
+        let delay = policy.delay(attempt);
+        if policy
+            .deadline
+            .is_some_and(|deadline| started.elapsed() + delay >= deadline)
+        {
+            return None;
+        }
+        // The breaker may have opened because of other calls in the meantime.
+        if self.breaker.state() != CircuitState::Closed {
+            return None;
+        }
+        let stats = self.stats(endpoint);
+        if !self.budget.try_withdraw() {
+            stats.budget_exhausted.fetch_add(1, Ordering::Relaxed);
+            warn!(%endpoint, "Backend retry budget exhausted, not retrying");
+            return None;
+        }
+        stats.retries.fetch_add(1, Ordering::Relaxed);
+        Some(delay)
+    }
+
+    /// Record the final outcome of a call.
+    pub(crate) fn finish(&self, endpoint: Endpoint, permit: CallPermit<'_>, success: bool) {
+        let stats = self.stats(endpoint);
+        if success {
+            stats.successes.fetch_add(1, Ordering::Relaxed);
+            permit.record_success();
+        } else {
+            stats.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for Resilience {
+    fn default() -> Self {
+        Self::new(BreakerConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(threshold: u32, cooldown_ms: u64) -> CircuitBreaker {
+        CircuitBreaker::new(BreakerConfig {
+            failure_threshold: threshold,
+            cooldown: Duration::from_millis(cooldown_ms),
+        })
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold() {
+        let breaker = breaker(3, 10_000);
+        for _ in 0..2 {
+            breaker.try_acquire().unwrap().record_failure();
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.try_acquire().is_none());
+    }
+
+    #[test]
+    fn success_resets_failure_count() {
+        let breaker = breaker(2, 10_000);
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn half_open_admits_single_probe() {
+        let breaker = breaker(1, 0);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        let mut probe = breaker.try_acquire().unwrap();
+        assert!(probe.is_probe());
+        assert!(breaker.try_acquire().is_none(), "only one probe at a time");
+
+        // A failed probe opens the circuit again.
+        probe.record_failure();
+        drop(probe);
+        breaker.try_acquire().unwrap().record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        let first = breaker.try_acquire().unwrap();
+        let second = breaker.try_acquire().unwrap();
+        assert!(!first.is_probe() && !second.is_probe());
+    }
+
+    #[test]
+    fn dropped_probe_admits_next_call() {
+        let breaker = breaker(1, 0);
+        breaker.record_failure();
+
+        // The probe's caller is cancelled before the backend answers.
+        let probe = breaker.try_acquire().unwrap();
+        assert!(breaker.try_acquire().is_none());
+        drop(probe);
+
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        let probe = breaker.try_acquire().unwrap();
+        assert!(probe.is_probe());
+        probe.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn backoff_grows_with_jitter() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            deadline: None,
+        };
+        for _ in 0..20 {
+            let first = policy.delay(0);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = policy.delay(2);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+            assert!(policy.delay(10) <= Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    fn budget_limits_retries() {
+        let mut resilience = Resilience::default();
+        resilience.set_budget(RetryBudget::new(0.5, 1.0));
+        let started = Instant::now();
+
+        let _call = resilience.begin(Endpoint::TunnelEnd).unwrap();
+        assert!(resilience
+            .retry_delay(Endpoint::TunnelEnd, 0, started)
+            .is_some());
+        assert!(resilience
+            .retry_delay(Endpoint::TunnelEnd, 1, started)
+            .is_none());
+
+        // Two more first attempts earn another retry.
+        let _calls = [
+            resilience.begin(Endpoint::TunnelEnd).unwrap(),
+            resilience.begin(Endpoint::TunnelEnd).unwrap(),
+        ];
+        assert!(resilience
+            .retry_delay(Endpoint::TunnelEnd, 0, started)
+            .is_some());
+        let stats = resilience.stats(Endpoint::TunnelEnd);
+        assert_eq!(stats.retries.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.budget_exhausted.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,383 @@
+//! In-memory cache of backend API key validations.
+//!
+//! Clients that reconnect often would otherwise hit the backend on every
+//! connection. Entries are keyed by a SHA-256 hash of the key, so the cache
+//! holds no raw keys; the sessions revalidating their key keep it until they
+//! close. Entries that can no longer be served are swept out periodically.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{debug, warn};
+
+use crate::backend::{UsageBackend, ValidateKeyResponse};
+use crate::commands::Revocations;
+
+/// How often entries that can no longer be served are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long validation results are kept.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    /// Lifetime of a successful validation.
+    pub positive_ttl: Duration,
+
+    /// Lifetime of a rejected key, so bad keys do not hammer the backend.
+    pub negative_ttl: Duration,
+
+    /// How long past `positive_ttl` a successful validation may still be used
+    /// while the backend is unreachable. Zero disables serving stale entries.
+    pub stale_if_error: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            positive_ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(10),
+            stale_if_error: Duration::ZERO,
+        }
+    }
+}
+
+struct Entry {
+    response: ValidateKeyResponse,
+    stored_at: Instant,
+}
+
+impl Entry {
+    fn is_positive(&self) -> bool {
+        self.response.valid && self.response.usage_allowed
+    }
+}
+
+/// Cache of validation results in front of [`UsageBackend::validate_api_key`].
+pub struct ValidationCache {
+    config: CacheConfig,
+    entries: DashMap<String, Entry>,
+    revocations: Option<Arc<Revocations>>,
+}
+
+impl ValidationCache {
+    /// Create an empty cache.
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            entries: DashMap::new(),
+            revocations: None,
+        }
+    }
+
+    /// Never serve validations of users and instances revoked by the backend,
+    /// even if they were fetched before the revocation.
+    #[must_use]
+    pub fn with_revocations(mut self, revocations: Arc<Revocations>) -> Self {
+        self.revocations = Some(revocations);
+        self
+    }
+
+    /// Hash under which an API key is cached, as accepted by [`Self::invalidate_hash`].
+    #[must_use]
+    pub fn key_hash(api_key: &str) -> String {
+        hex::encode(Sha256::digest(api_key.as_bytes()))
+    }
+
+    /// Validate a key, answering from the cache when possible.
+    ///
+    /// If the backend cannot be reached and a successful validation expired less
+    /// than `stale_if_error` ago, the stale result is returned instead of the error.
+    pub async fn validate(
+        &self,
+        backend: &dyn UsageBackend,
+        api_key: &str,
+    ) -> Result<ValidateKeyResponse> {
+        let hash = Self::key_hash(api_key);
+        if let Some(response) = self.lookup(&hash, Instant::now()) {
+            debug!("API key validation served from cache");
+            return Ok(response);
+        }
+
+        match backend.validate_api_key(api_key).await {
+            Ok(response) => {
+                self.store(hash, response.clone(), Instant::now());
+                Ok(response)
+            }
+            Err(err) => match self.lookup_stale(&hash, Instant::now()) {
+                Some(response) => {
+                    warn!(%err, "Backend unreachable, serving stale API key validation");
+                    Ok(response)
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Remove the entry for an API key. Returns whether one was cached.
+    pub fn invalidate(&self, api_key: &str) -> bool {
+        self.invalidate_hash(&Self::key_hash(api_key))
+    }
+
+    /// Remove the entry for a key hash. Returns whether one was cached.
+    pub fn invalidate_hash(&self, hash: &str) -> bool {
+        self.entries.remove(&hash.to_ascii_lowercase()).is_some()
+    }
+
+    /// Remove all entries belonging to a user. Returns how many were removed.
+    pub fn invalidate_user(&self, user_id: &str) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, entry| entry.response.user_id.as_deref() != Some(user_id));
+        before - self.entries.len()
+    }
+
+    /// Remove all entries belonging to a dashboard instance. Returns how many were removed.
+    pub fn invalidate_instance(&self, instance_id: &str) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, entry| entry.response.instance_id.as_deref() != Some(instance_id));
+        before - self.entries.len()
+    }
+
+    /// Remove all entries. Returns how many were removed.
+    pub fn clear(&self) -> usize {
+        let count = self.entries.len();
+        self.entries.clear();
+        count
+    }
+
+    /// Number of cached entries, including expired ones not yet evicted.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the cache holds no entries.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn store(&self, hash: String, response: ValidateKeyResponse, now: Instant) {
+        let entry = Entry {
+            response,
+            stored_at: now,
+        };
+        let ttl = if entry.is_positive() {
+            self.config.positive_ttl
+        } else {
+            self.config.negative_ttl
+        };
+        if ttl.is_zero() && self.config.stale_if_error.is_zero() {
+            return;
+        }
+        self.entries.insert(hash, entry);
+    }
+
+    fn lookup(&self, hash: &str, now: Instant) -> Option<ValidateKeyResponse> {
+        let entry = self
+            .entries
+            .get(hash)
+            .filter(|entry| !self.is_revoked(entry))?;
+        let ttl = if entry.is_positive() {
+            self.config.positive_ttl
+        } else {
+            self.config.negative_ttl
+        };
+        (now.duration_since(entry.stored_at) < ttl).then(|| entry.response.clone())
+    }
+
+    fn lookup_stale(&self, hash: &str, now: Instant) -> Option<ValidateKeyResponse> {
+        let entry = self
+            .entries
+            .get(hash)
+            .filter(|entry| !self.is_revoked(entry))?;
+        let max_age = self.config.positive_ttl + self.config.stale_if_error;
+        (entry.is_positive() && now.duration_since(entry.stored_at) < max_age)
+            .then(|| entry.response.clone())
+    }
+
+    /// Whether the entry accepts a user or instance the backend has since revoked.
+    fn is_revoked(&self, entry: &Entry) -> bool {
+        let (Some(revocations), Some(user_id)) = (&self.revocations, &entry.response.user_id)
+        else {
+            return false;
+        };
+        entry.is_positive()
+            && revocations
+                .check(user_id, entry.response.instance_id.as_deref())
+                .is_some()
+    }
+
+    /// Drop entries that can no longer be served, even as stale fallbacks.
+    fn evict_expired(&self, now: Instant) {
+        let positive_max = self.config.positive_ttl + self.config.stale_if_error;
+        let negative_max = self.config.negative_ttl;
+        self.entries.retain(|_, entry| {
+            let max_age = if entry.is_positive() {
+                positive_max
+            } else {
+                negative_max
+            };
+            now.duration_since(entry.stored_at) < max_age
+        });
+    }
+}
+
+/// Drop expired entries of `cache` every [`SWEEP_INTERVAL`], until the task is aborted.
+pub(crate) async fn sweep(cache: Arc<ValidationCache>) {
+    let mut ticks = interval(SWEEP_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        cache.evict_expired(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::BackendCommand;
+
+    fn response(valid: bool, user_id: &str) -> ValidateKeyResponse {
+        ValidateKeyResponse {
+            valid,
+            user_id: Some(user_id.to_string()),
+            email: None,
+            plan_type: None,
+            max_concurrent_tunnels: Some(2),
+            max_bandwidth_gb: None,
+            usage_allowed: valid,
+            message: None,
+            instance_id: None,
+            reserved_ports: Vec::new(),
+        }
+    }
+
+    fn cache(stale_if_error: u64) -> ValidationCache {
+        ValidationCache::new(CacheConfig {
+            positive_ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+            stale_if_error: Duration::from_secs(stale_if_error),
+        })
+    }
+
+    #[test]
+    fn positive_and_negative_ttls() {
+        let cache = cache(0);
+        let start = Instant::now();
+        cache.store("good".into(), response(true, "u1"), start);
+        cache.store("bad".into(), response(false, "u2"), start);
+
+        let later = start + Duration::from_secs(10);
+        assert!(cache.lookup("good", later).is_some());
+        assert!(cache.lookup("bad", later).is_none());
+
+        let much_later = start + Duration::from_secs(61);
+        assert!(cache.lookup("good", much_later).is_none());
+    }
+
+    #[test]
+    fn stale_entries_are_bounded() {
+        let cache = cache(30);
+        let start = Instant::now();
+        cache.store("good".into(), response(true, "u1"), start);
+        cache.store("bad".into(), response(false, "u2"), start);
+
+        let expired = start + Duration::from_secs(75);
+        assert!(cache.lookup("good", expired).is_none());
+        assert!(cache.lookup_stale("good", expired).is_some());
+        assert!(cache.lookup_stale("bad", start).is_none());
+
+        let too_old = start + Duration::from_secs(91);
+        assert!(cache.lookup_stale("good", too_old).is_none());
+    }
+
+    #[test]
+    fn stale_serving_disabled_by_default() {
+        let cache = cache(0);
+        let start = Instant::now();
+        cache.store("good".into(), response(true, "u1"), start);
+        assert!(cache
+            .lookup_stale("good", start + Duration::from_secs(61))
+            .is_none());
+    }
+
+    #[test]
+    fn sweep_drops_unservable_entries() {
+        let cache = cache(30);
+        let start = Instant::now();
+        cache.store("good".into(), response(true, "u1"), start);
+        cache.store("bad".into(), response(false, "u2"), start);
+
+        // Storing does not walk the cache; expired entries wait for the sweep.
+        let later = start + Duration::from_secs(10);
+        cache.store("other".into(), response(true, "u3"), later);
+        assert_eq!(cache.len(), 3);
+
+        cache.evict_expired(later);
+        assert_eq!(cache.len(), 2, "the rejected key expired");
+        cache.evict_expired(start + Duration::from_secs(91));
+        assert_eq!(cache.len(), 1, "past its stale window");
+    }
+
+    #[test]
+    fn invalidation() {
+        let cache = cache(0);
+        let now = Instant::now();
+        cache.store(ValidationCache::key_hash("sk_a"), response(true, "u1"), now);
+        cache.store(ValidationCache::key_hash("sk_b"), response(true, "u1"), now);
+        cache.store(ValidationCache::key_hash("sk_c"), response(true, "u2"), now);
+
+        assert!(cache.invalidate("sk_a"));
+        assert!(!cache.invalidate("sk_a"));
+        assert_eq!(cache.invalidate_user("u1"), 1);
+        assert_eq!(cache.clear(), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn revoked_users_are_not_served() {
+        let revocations = Arc::new(Revocations::new());
+        let cache = cache(30).with_revocations(Arc::clone(&revocations));
+        let start = Instant::now();
+        let mut instance = response(true, "u2");
+        instance.instance_id = Some("inst_1".into());
+        cache.store("u1".into(), response(true, "u1"), start);
+        cache.store("u2".into(), instance, start);
+
+        revocations.record(&BackendCommand::RevokeUser {
+            user_id: "u1".into(),
+            reason: None,
+        });
+        revocations.record(&BackendCommand::RevokeInstance {
+            instance_id: "inst_1".into(),
+            reason: None,
+        });
+        assert!(cache.lookup("u1", start).is_none());
+        assert!(cache.lookup("u2", start).is_none());
+        let expired = start + Duration::from_secs(75);
+        assert!(cache.lookup_stale("u1", expired).is_none());
+    }
+
+    #[tokio::test]
+    async fn backend_outage_uses_stale_entry() {
+        // Nothing listens on port 9 of localhost, so every request fails fast.
+        let backend = crate::backend::BackendClient::new("http://127.0.0.1:9", None);
+        let cache = cache(30);
+        let hash = ValidationCache::key_hash("sk_a");
+        cache.store(
+            hash,
+            response(true, "u1"),
+            Instant::now() - Duration::from_secs(70),
+        );
+
+        let response = cache.validate(&backend, "sk_a").await.unwrap();
+        assert_eq!(response.user_id.as_deref(), Some("u1"));
+        assert!(cache.validate(&backend, "sk_unknown").await.is_err());
+    }
+}
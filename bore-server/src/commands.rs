@@ -0,0 +1,443 @@
+//! Commands pushed by the backend, such as revocations.
+//!
+//! Revalidation only notices a revoked key at its next interval. With a
+//! [`CommandStream`], the server subscribes to a server-sent event stream from
+//! the backend instead, and applies each [`BackendCommand`] to the matching
+//! sessions as soon as it arrives. Every event carries one command as JSON in
+//! its data, e.g.
+//!
+//! ```text
+//! id: 42
+//! data: {"type": "revoke_user", "user_id": "user_1", "reason": "Account suspended"}
+//! ```
+//!
+//! The stream is reopened after errors, sending the ID of the last event seen
+//! as `Last-Event-ID` so the backend can replay what was missed.
+//!
+//! Revoked users and instances are also kept in [`Revocations`], so clients
+//! still holding a signed token or a cached validation cannot reconnect.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use bore_shared::{ErrorCode, ServerError};
+use dashmap::DashMap;
+use reqwest::{header, Client};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, warn};
+
+use crate::cache::ValidationCache;
+
+/// Commands kept for sessions that have yet to see them.
+const COMMAND_CAPACITY: usize = 256;
+
+/// How long a revoked user or instance is refused, unless a token presented
+/// since expires later.
+const REVOCATION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Delay before reopening a failed stream, doubled after each failure.
+const INITIAL_RETRY: Duration = Duration::from_secs(1);
+
+/// Longest delay between attempts to reopen the stream.
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// How long the stream may stay silent, keep-alive comments included, before
+/// it is considered dead and reopened.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// An instruction from the backend about running sessions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BackendCommand {
+    /// Close every session of a user.
+    RevokeUser {
+        /// User whose sessions to close.
+        user_id: String,
+        /// Why, shown to the clients.
+        #[serde(default)]
+        reason: Option<String>,
+    },
+
+    /// Close every session of a dashboard instance.
+    RevokeInstance {
+        /// Instance whose sessions to close.
+        instance_id: String,
+        /// Why, shown to the clients.
+        #[serde(default)]
+        reason: Option<String>,
+    },
+
+    /// Close one tunnel, as identified in its tunnel start event.
+    CloseSession {
+        /// Session ID from the tunnel start event.
+        session_id: String,
+        /// Why, shown to the client.
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+impl BackendCommand {
+    /// Error sent to clients whose tunnels the command closes.
+    #[must_use]
+    pub fn error(&self) -> ServerError {
+        let (reason, default) = match self {
+            BackendCommand::RevokeUser { reason, .. }
+            | BackendCommand::RevokeInstance { reason, .. } => {
+                (reason, "Access revoked. Please visit the dashboard.")
+            }
+            BackendCommand::CloseSession { reason, .. } => {
+                (reason, "Session closed by the backend")
+            }
+        };
+        let message = reason.clone().unwrap_or_else(|| default.to_string());
+        ServerError::new(ErrorCode::Revoked, message)
+    }
+}
+
+/// A revoked user or instance.
+struct Revocation {
+    /// Error sent to clients that try to reconnect.
+    error: ServerError,
+    /// When the revocation is dropped.
+    until: Instant,
+}
+
+/// Users and instances revoked by the backend, refused until the credentials
+/// issued before the revocation expire.
+#[derive(Default)]
+pub struct Revocations {
+    users: DashMap<String, Revocation>,
+    instances: DashMap<String, Revocation>,
+}
+
+impl Revocations {
+    /// Create an empty list.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember the user or instance a command revokes. Returns whether it revokes one.
+    pub fn record(&self, command: &BackendCommand) -> bool {
+        let now = Instant::now();
+        let (revoked, id) = match command {
+            BackendCommand::RevokeUser { user_id, .. } => (&self.users, user_id),
+            BackendCommand::RevokeInstance { instance_id, .. } => (&self.instances, instance_id),
+            BackendCommand::CloseSession { .. } => return false,
+        };
+        for list in [&self.users, &self.instances] {
+            list.retain(|_, revocation| revocation.until > now);
+        }
+        let revocation = Revocation {
+            error: command.error(),
+            until: now + REVOCATION_TTL,
+        };
+        revoked.insert(id.clone(), revocation);
+        true
+    }
+
+    /// The error to refuse a user with, if it or its instance is revoked.
+    #[must_use]
+    pub fn check(&self, user_id: &str, instance_id: Option<&str>) -> Option<ServerError> {
+        self.refuse(user_id, instance_id, None)
+    }
+
+    /// The error to refuse a signed token with, if its user or instance is
+    /// revoked. The revocation is then kept at least until the token expires.
+    #[must_use]
+    pub fn check_token(
+        &self,
+        user_id: &str,
+        instance_id: Option<&str>,
+        expires: u64,
+    ) -> Option<ServerError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let until = Instant::now() + Duration::from_secs(expires.saturating_sub(now));
+        self.refuse(user_id, instance_id, Some(until))
+    }
+
+    /// Find an unexpired revocation of the user or its instance, keeping it at
+    /// least until `keep_until`.
+    fn refuse(
+        &self,
+        user_id: &str,
+        instance_id: Option<&str>,
+        keep_until: Option<Instant>,
+    ) -> Option<ServerError> {
+        let now = Instant::now();
+        let subjects = [(&self.users, Some(user_id)), (&self.instances, instance_id)];
+        subjects.into_iter().find_map(|(revoked, id)| {
+            let mut revocation = revoked.get_mut(id?)?;
+            if revocation.until <= now {
+                return None;
+            }
+            if let Some(until) = keep_until {
+                revocation.until = revocation.until.max(until);
+            }
+            Some(revocation.error.clone())
+        })
+    }
+}
+
+/// Commands for running sessions, each of which checks whether it applies.
+#[derive(Clone)]
+pub(crate) struct Commands {
+    sender: broadcast::Sender<BackendCommand>,
+    revocations: Arc<Revocations>,
+    cache: Option<Arc<ValidationCache>>,
+}
+
+impl Commands {
+    /// Pass commands on to sessions, remembering revocations in `revocations`.
+    pub(crate) fn new(revocations: Arc<Revocations>) -> Self {
+        Self {
+            sender: broadcast::channel(COMMAND_CAPACITY).0,
+            revocations,
+            cache: None,
+        }
+    }
+
+    /// Drop the cached validations of users and instances as they are revoked.
+    pub(crate) fn set_cache(&mut self, cache: Arc<ValidationCache>) {
+        self.cache = Some(cache);
+    }
+
+    /// Receive the commands sent from now on.
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<BackendCommand> {
+        self.sender.subscribe()
+    }
+
+    /// Record what a command revokes, then pass it on to running sessions.
+    pub(crate) fn send(&self, command: BackendCommand) {
+        if self.revocations.record(&command) {
+            if let Some(cache) = &self.cache {
+                let removed = match &command {
+                    BackendCommand::RevokeUser { user_id, .. } => cache.invalidate_user(user_id),
+                    BackendCommand::RevokeInstance { instance_id, .. } => {
+                        cache.invalidate_instance(instance_id)
+                    }
+                    BackendCommand::CloseSession { .. } => 0,
+                };
+                debug!(removed, "Dropped cached validations of revoked credentials");
+            }
+        }
+        // Without sessions, nobody needs to hear about it.
+        let _ = self.sender.send(command);
+    }
+}
+
+/// Subscription to the backend's command stream.
+pub struct CommandStream {
+    http_client: Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+impl CommandStream {
+    /// Subscribe to the stream at `url`, authenticating with the backend's
+    /// internal `api_key` if set.
+    #[must_use]
+    pub fn new(url: impl Into<String>, api_key: Option<String>) -> Self {
+        // No overall timeout, since the response never ends; silence is caught
+        // by the idle timeout instead.
+        let http_client = Client::builder()
+            .connect_timeout(bore_shared::timeouts::BACKEND_HTTP_TIMEOUT)
+            .build()
+            .expect("Failed to create HTTP client");
+        Self {
+            http_client,
+            url: url.into(),
+            api_key,
+        }
+    }
+
+    /// Receive commands until the server stops, passing them on to `commands`.
+    pub(crate) async fn run(self, commands: Commands) {
+        let mut last_event_id = None;
+        let mut retry = INITIAL_RETRY;
+        loop {
+            match self.follow(&commands, &mut last_event_id).await {
+                Ok(()) => {
+                    info!("Command stream ended, reopening it");
+                    sleep(INITIAL_RETRY).await;
+                    retry = INITIAL_RETRY;
+                }
+                Err(err) => {
+                    warn!(%err, ?retry, "Command stream failed, reopening it");
+                    sleep(retry).await;
+                    retry = (retry * 2).min(MAX_RETRY);
+                }
+            }
+        }
+    }
+
+    /// Open the stream once and pass on its commands until it ends.
+    async fn follow(&self, commands: &Commands, last_event_id: &mut Option<String>) -> Result<()> {
+        let mut request = self
+            .http_client
+            .get(&self.url)
+            .header(header::ACCEPT, "text/event-stream");
+        if let Some(key) = &self.api_key {
+            request = request.header("x-internal-api-key", key);
+        }
+        if let Some(id) = last_event_id.as_deref() {
+            request = request.header("Last-Event-ID", id);
+        }
+        let mut response = request
+            .send()
+            .await
+            .context("Failed to connect to command stream")?;
+        if !response.status().is_success() {
+            bail!("command stream returned {}", response.status());
+        }
+        info!(url = %self.url, "Subscribed to backend commands");
+
+        let mut parser = EventParser::default();
+        while let Some(chunk) = timeout(IDLE_TIMEOUT, response.chunk())
+            .await
+            .context("command stream went silent")??
+        {
+            for event in parser.feed(&chunk) {
+                if event.id.is_some() {
+                    last_event_id.clone_from(&event.id);
+                }
+                if !matches!(event.event.as_deref(), None | Some("message" | "command")) {
+                    debug!(event = ?event.event, "Ignoring backend event");
+                    continue;
+                }
+                match serde_json::from_str::<BackendCommand>(&event.data) {
+                    Ok(command) => {
+                        info!(?command, "Received backend command");
+                        commands.send(command);
+                    }
+                    Err(err) => warn!(%err, data = %event.data, "Ignoring invalid backend command"),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A server-sent event.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Event {
+    /// Event type, if not the default `message`.
+    event: Option<String>,
+    /// Data lines, joined by newlines.
+    data: String,
+    /// ID of the event, if it has one.
+    id: Option<String>,
+}
+
+/// Incremental parser of a `text/event-stream` body.
+#[derive(Default)]
+struct EventParser {
+    /// Bytes of an incomplete line.
+    line: Vec<u8>,
+    /// Event being assembled.
+    pending: Event,
+    /// Whether the pending event has any data.
+    has_data: bool,
+}
+
+impl EventParser {
+    /// Parse another part of the body, returning the events it completes.
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Event> {
+        let mut events = Vec::new();
+        for &byte in bytes {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let mut line = std::mem::take(&mut self.line);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if let Some(event) = self.line_done(&String::from_utf8_lossy(&line)) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Handle a complete line, returning the event a blank line completes.
+    fn line_done(&mut self, line: &str) -> Option<Event> {
+        if line.is_empty() {
+            let event = std::mem::take(&mut self.pending);
+            return std::mem::take(&mut self.has_data).then_some(event);
+        }
+        if line.starts_with(':') {
+            // A comment, usually a keep-alive.
+            return None;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "data" => {
+                if self.has_data {
+                    self.pending.data.push('\n');
+                }
+                self.pending.data.push_str(value);
+                self.has_data = true;
+            }
+            "event" => self.pending.event = Some(value.to_string()),
+            "id" => self.pending.id = Some(value.to_string()),
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_events_split_across_chunks() {
+        let mut parser = EventParser::default();
+        assert!(parser.feed(b": keep-alive\n\nid: 7\r\nda").is_empty());
+        let events = parser.feed(b"ta: {\"a\":\r\ndata: 1}\r\n\r\nevent: ping\ndata\n\n");
+        assert_eq!(
+            events,
+            vec![
+                Event {
+                    event: None,
+                    data: "{\"a\":\n1}".to_string(),
+                    id: Some("7".to_string()),
+                },
+                Event {
+                    event: Some("ping".to_string()),
+                    data: String::new(),
+                    id: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_from_json() {
+        let command: BackendCommand =
+            serde_json::from_str(r#"{"type": "revoke_user", "user_id": "user_1"}"#).unwrap();
+        assert_eq!(
+            command,
+            BackendCommand::RevokeUser {
+                user_id: "user_1".to_string(),
+                reason: None,
+            }
+        );
+        assert_eq!(command.error().code, ErrorCode::Revoked);
+
+        let command: BackendCommand = serde_json::from_str(
+            r#"{"type": "close_session", "session_id": "s1", "reason": "Closed from the dashboard"}"#,
+        )
+        .unwrap();
+        assert_eq!(command.error().message, "Closed from the dashboard");
+    }
+}
@@ -0,0 +1,491 @@
+//! Tunnel groups: several clients serving one public port.
+//!
+//! Every tunnel is a group listening on its public port. A plain tunnel is a
+//! group with a single member, while clients that join a shared tunnel become
+//! further members of the same group. Incoming connections are handed to one
+//! live member, chosen by the group's [`BalanceStrategy`]. Members whose client
+//! reports its local service as unhealthy are skipped; when no member is healthy,
+//! visitors get a 502 page if the service speaks HTTP, and are disconnected
+//! otherwise. A member leaves when its control connection ends, and the port is
+//! released once the last one has left.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_shared::TunnelStatus;
+use dashmap::DashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{sleep, timeout};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::access::{CloseReason, Visit};
+use crate::listener::MultiListener;
+use crate::ports::PortLease;
+
+/// How long an incoming connection waits for its client to accept it.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a visitor's request before answering with the error page.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Page shown to HTTP visitors while no member's local service is healthy.
+const BAD_GATEWAY_PAGE: &str = "<!DOCTYPE html>
+<html><head><title>502 Bad Gateway</title></head>
+<body><h1>Service unavailable</h1><p>The tunneled service is not responding.</p></body></html>
+";
+
+/// How a group picks the member serving an incoming connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// Each member in turn.
+    #[default]
+    RoundRobin,
+
+    /// The member forwarding the fewest connections, taking turns on ties.
+    LeastConnections,
+
+    /// Any member, chosen uniformly at random.
+    Random,
+}
+
+impl fmt::Display for BalanceStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BalanceStrategy::RoundRobin => "round-robin",
+            BalanceStrategy::LeastConnections => "least-connections",
+            BalanceStrategy::Random => "random",
+        })
+    }
+}
+
+impl FromStr for BalanceStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round-robin" => Ok(BalanceStrategy::RoundRobin),
+            "least-connections" => Ok(BalanceStrategy::LeastConnections),
+            "random" => Ok(BalanceStrategy::Random),
+            _ => bail!(
+                "unknown balance strategy {s:?}, expected round-robin, least-connections or random"
+            ),
+        }
+    }
+}
+
+/// An incoming connection waiting for a client to accept it.
+pub(crate) struct PendingConnection {
+    /// The connection from the public port.
+    pub(crate) stream: TcpStream,
+
+    /// Counts the connection and its bytes against its member.
+    pub(crate) load: Load,
+
+    /// Access log record of the connection.
+    pub(crate) visit: Visit,
+}
+
+/// Counts one connection of a member, until dropped.
+pub(crate) struct Load {
+    active: Arc<AtomicUsize>,
+    usage: Arc<Usage>,
+}
+
+impl Load {
+    /// Add the bytes forwarded over the connection to its member's usage.
+    pub(crate) fn add_usage(&self, bytes_in: u64, bytes_out: u64) {
+        self.usage.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.usage.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Load {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Bytes forwarded for the visitors of a member.
+#[derive(Default)]
+struct Usage {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+struct Member {
+    id: u64,
+    connections: mpsc::UnboundedSender<Uuid>,
+    active: Arc<AtomicUsize>,
+    usage: Arc<Usage>,
+
+    /// Last status reported by the client; healthy until it reports otherwise.
+    status: Option<TunnelStatus>,
+}
+
+impl Member {
+    fn is_healthy(&self) -> bool {
+        self.status.as_ref().is_none_or(|status| status.healthy)
+    }
+}
+
+/// Clients sharing one public port.
+pub(crate) struct TunnelGroup {
+    name: Option<String>,
+    owner: String,
+    port: u16,
+    strategy: BalanceStrategy,
+
+    /// Current members, or `None` once the last one left and the group closed.
+    members: Mutex<Option<Vec<Member>>>,
+    next_id: AtomicU64,
+    cursor: AtomicUsize,
+    closed: Notify,
+}
+
+impl TunnelGroup {
+    /// An empty group of `owner` on `port`, named if clients join it by name.
+    pub(crate) fn new(
+        name: Option<String>,
+        owner: &str,
+        port: u16,
+        strategy: BalanceStrategy,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            owner: owner.to_string(),
+            port,
+            strategy,
+            members: Mutex::new(Some(Vec::new())),
+            next_id: AtomicU64::new(0),
+            cursor: AtomicUsize::new(0),
+            closed: Notify::new(),
+        })
+    }
+
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub(crate) fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Whether the last member left.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.members.lock().unwrap().is_none()
+    }
+
+    /// Add a member, unless the group already closed.
+    pub(crate) fn join(self: &Arc<Self>) -> Option<Membership> {
+        let mut members = self.members.lock().unwrap();
+        let members = members.as_mut()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let usage = Arc::new(Usage::default());
+        members.push(Member {
+            id,
+            connections: tx,
+            active: Arc::new(AtomicUsize::new(0)),
+            usage: Arc::clone(&usage),
+            status: None,
+        });
+        Some(Membership {
+            group: Arc::clone(self),
+            id,
+            connections: rx,
+            usage,
+        })
+    }
+
+    /// Accept connections on the group's port until it closes.
+    ///
+    /// Each connection is stored in `conns` and its ID sent to the chosen member,
+    /// whose client then accepts it. The port is released when the group closes.
+    pub(crate) fn spawn(
+        self: &Arc<Self>,
+        listener: MultiListener,
+        lease: PortLease,
+        conns: Arc<DashMap<Uuid, PendingConnection>>,
+    ) {
+        let group = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    result = listener.accept() => match result {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            warn!(%err, port = group.port, "tunnel listener failed");
+                            break;
+                        }
+                    },
+                    () = group.closed.notified() => break,
+                };
+                info!(?addr, port = group.port, "new connection");
+                let visit = Visit::new(&group, addr);
+
+                let Some((member, load)) = group.pick() else {
+                    if group.serves_http() {
+                        tokio::spawn(bad_gateway(stream, visit));
+                    } else {
+                        visit.finish(0, 0, CloseReason::Unavailable, None);
+                    }
+                    continue;
+                };
+
+                // Generate unique ID for this connection to match client's Accept message
+                let id = Uuid::new_v4();
+                conns.insert(
+                    id,
+                    PendingConnection {
+                        stream,
+                        load,
+                        visit,
+                    },
+                );
+
+                // Spawn a cleanup task to prevent memory leaks from unaccepted connections
+                // If the bore client doesn't send Accept(id) within 10 seconds, we remove
+                // the stored connection. This handles cases where:
+                // - The bore client crashes or disconnects
+                // - Network issues prevent the Connection message from arriving
+                // - The client rejects the connection for any reason
+                let conns = Arc::clone(&conns);
+                tokio::spawn(async move {
+                    sleep(PENDING_TIMEOUT).await;
+                    if let Some((_, pending)) = conns.remove(&id) {
+                        warn!(%id, "removed stale connection");
+                        pending.visit.finish(0, 0, CloseReason::NotAccepted, None);
+                    }
+                });
+
+                // Notify the member's session, which tells its bore client
+                let _ = member.send(id);
+            }
+
+            // Ending the members' sessions if the listener failed.
+            group.members.lock().unwrap().take();
+            drop(listener);
+            drop(lease);
+        });
+    }
+
+    /// Choose the healthy member serving the next connection, counting it against them.
+    fn pick(&self) -> Option<(mpsc::UnboundedSender<Uuid>, Load)> {
+        let members = self.members.lock().unwrap();
+        let members: Vec<&Member> = members
+            .iter()
+            .flatten()
+            .filter(|member| member.is_healthy())
+            .collect();
+        if members.is_empty() {
+            return None;
+        }
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        let member = match self.strategy {
+            BalanceStrategy::RoundRobin => members[start % members.len()],
+            BalanceStrategy::LeastConnections => (0..members.len())
+                .map(|i| members[(start + i) % members.len()])
+                .min_by_key(|member| member.active.load(Ordering::Relaxed))
+                .expect("group has members"),
+            BalanceStrategy::Random => members[fastrand::usize(..members.len())],
+        };
+        member.active.fetch_add(1, Ordering::Relaxed);
+        let load = Load {
+            active: Arc::clone(&member.active),
+            usage: Arc::clone(&member.usage),
+        };
+        Some((member.connections.clone(), load))
+    }
+
+    /// Whether any member reported an HTTP service.
+    fn serves_http(&self) -> bool {
+        let members = self.members.lock().unwrap();
+        members
+            .iter()
+            .flatten()
+            .any(|member| member.status.as_ref().is_some_and(|status| status.http))
+    }
+
+    fn set_status(&self, id: u64, status: TunnelStatus) {
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.iter_mut().flatten().find(|member| member.id == id) {
+            member.status = Some(status);
+        }
+    }
+
+    fn leave(&self, id: u64) {
+        let mut guard = self.members.lock().unwrap();
+        if let Some(members) = guard.as_mut() {
+            members.retain(|member| member.id != id);
+            if members.is_empty() {
+                *guard = None;
+                self.closed.notify_one();
+            }
+        }
+    }
+}
+
+/// A client's place in a group, left when dropped.
+pub(crate) struct Membership {
+    group: Arc<TunnelGroup>,
+    id: u64,
+    connections: mpsc::UnboundedReceiver<Uuid>,
+    usage: Arc<Usage>,
+}
+
+impl Membership {
+    pub(crate) fn group(&self) -> &Arc<TunnelGroup> {
+        &self.group
+    }
+
+    /// Record the health of this member's local service, as reported by its client.
+    pub(crate) fn set_status(&self, status: TunnelStatus) {
+        self.group.set_status(self.id, status);
+    }
+
+    /// Bytes received from and sent to this member's visitors so far, counting
+    /// each connection once it closed.
+    pub(crate) fn usage(&self) -> (u64, u64) {
+        (
+            self.usage.bytes_in.load(Ordering::Relaxed),
+            self.usage.bytes_out.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The next connection handed to this member, or `None` if the group closed.
+    pub(crate) async fn next_connection(&mut self) -> Option<Uuid> {
+        self.connections.recv().await
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.group.leave(self.id);
+    }
+}
+
+/// Answer an HTTP visitor with the error page, after reading their request.
+async fn bad_gateway(mut stream: TcpStream, visit: Visit) {
+    let mut request = [0; 1024];
+    let read = match timeout(REQUEST_TIMEOUT, stream.read(&mut request)).await {
+        Ok(Ok(read)) => read,
+        _ => 0,
+    };
+    let response = format!(
+        "HTTP/1.1 502 Bad Gateway\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{BAD_GATEWAY_PAGE}",
+        BAD_GATEWAY_PAGE.len()
+    );
+    let written = match stream.write_all(response.as_bytes()).await {
+        Ok(()) => response.len(),
+        Err(_) => 0,
+    };
+    let _ = stream.shutdown().await;
+    visit.finish(read as u64, written as u64, CloseReason::Unavailable, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(strategy: BalanceStrategy) -> (Arc<TunnelGroup>, Vec<Membership>) {
+        let group = TunnelGroup::new(None, "alice", 9000, strategy);
+        let members = (0..3).map(|_| group.join().unwrap()).collect();
+        (group, members)
+    }
+
+    /// Pick a member, returning its index among `members` and the load guard.
+    fn pick(group: &TunnelGroup, members: &mut [Membership]) -> (usize, Load) {
+        let (sender, load) = group.pick().unwrap();
+        let id = Uuid::new_v4();
+        sender.send(id).unwrap();
+        let index = members
+            .iter_mut()
+            .position(|member| member.connections.try_recv() == Ok(id))
+            .unwrap();
+        (index, load)
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let (group, mut members) = group(BalanceStrategy::RoundRobin);
+        let picks: Vec<usize> = (0..6).map(|_| pick(&group, &mut members).0).collect();
+        assert_eq!(picks, [0, 1, 2, 0, 1, 2]);
+
+        // A member that left is skipped.
+        members.remove(1);
+        let picks: Vec<usize> = (0..4).map(|_| pick(&group, &mut members).0).collect();
+        assert_eq!(picks, [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn least_connections_avoids_busy_members() {
+        let (group, mut members) = group(BalanceStrategy::LeastConnections);
+        let (first, first_load) = pick(&group, &mut members);
+        let (second, _second_load) = pick(&group, &mut members);
+        let (third, _third_load) = pick(&group, &mut members);
+        let mut picked = [first, second, third];
+        picked.sort_unstable();
+        assert_eq!(picked, [0, 1, 2], "every member gets one connection");
+
+        // Once its connection ends, the first member is the least loaded.
+        drop(first_load);
+        assert_eq!(pick(&group, &mut members).0, first);
+    }
+
+    #[test]
+    fn skips_unhealthy_members() {
+        let (group, mut members) = group(BalanceStrategy::RoundRobin);
+        let down = |http| TunnelStatus {
+            healthy: false,
+            http,
+            detail: Some("connection refused".to_string()),
+        };
+        members[0].set_status(down(false));
+        let picks: Vec<usize> = (0..4).map(|_| pick(&group, &mut members).0).collect();
+        assert_eq!(picks, [1, 2, 1, 2]);
+
+        members[1].set_status(down(true));
+        members[2].set_status(down(false));
+        assert!(group.pick().is_none());
+        assert!(group.serves_http());
+    }
+
+    #[test]
+    fn closes_when_last_member_leaves() {
+        let (group, members) = group(BalanceStrategy::Random);
+        assert!(group.pick().is_some());
+        drop(members);
+        assert!(group.is_closed());
+        assert!(group.pick().is_none());
+        assert!(group.join().is_none(), "a closed group cannot be joined");
+    }
+
+    #[test]
+    fn parses_strategies() {
+        for strategy in [
+            BalanceStrategy::RoundRobin,
+            BalanceStrategy::LeastConnections,
+            BalanceStrategy::Random,
+        ] {
+            assert_eq!(
+                strategy.to_string().parse::<BalanceStrategy>().unwrap(),
+                strategy
+            );
+        }
+        assert!("weighted".parse::<BalanceStrategy>().is_err());
+    }
+}
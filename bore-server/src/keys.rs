@@ -0,0 +1,177 @@
+//! Authorized keys file for Ed25519 public-key client authentication.
+//!
+//! Each non-empty line maps one public key to a user and that user's limits:
+//!
+//! ```text
+//! # key                  user   options
+//! ed25519:3b6a27bc...    alice  max_tunnels=2 ports=9000-9010,9500
+//! ed25519:8f1c05d2...    ci
+//! ```
+//!
+//! Supported options are `max_tunnels=N` and `ports=RANGES`, where `RANGES` is
+//! a comma-separated list of single ports or inclusive `LOW-HIGH` ranges. Keys
+//! without `ports` may bind any port in the server's range.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use bore_shared::PublicKey;
+
+/// A public key allowed to open tunnels, with its owner and limits.
+#[derive(Debug, Clone)]
+pub struct AuthorizedKey {
+    /// User ID that tunnels opened with this key are counted against.
+    pub user_id: String,
+
+    /// Maximum number of concurrent tunnels, if limited.
+    pub max_tunnels: Option<u32>,
+
+    /// Port ranges this key may bind, or empty for the whole server range.
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+/// Set of public keys loaded from an authorized keys file.
+#[derive(Debug, Default)]
+pub struct AuthorizedKeys {
+    keys: HashMap<PublicKey, AuthorizedKey>,
+}
+
+impl AuthorizedKeys {
+    /// Load authorized keys from a file on disk.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read authorized keys file {}", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("invalid authorized keys file {}", path.display()))
+    }
+
+    /// Parse the contents of an authorized keys file.
+    ///
+    /// ```
+    /// use bore_server::keys::AuthorizedKeys;
+    /// use bore_shared::KeyPair;
+    ///
+    /// let key = KeyPair::generate().public_key();
+    /// let keys = AuthorizedKeys::parse(&format!("{key} alice max_tunnels=2 ports=9000-9010")).unwrap();
+    ///
+    /// let entry = keys.get(&key).unwrap();
+    /// assert_eq!(entry.user_id, "alice");
+    /// assert_eq!(entry.max_tunnels, Some(2));
+    /// assert_eq!(entry.ports, vec![9000..=9010]);
+    /// ```
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, entry) = parse_line(line).with_context(|| format!("line {}", index + 1))?;
+            ensure!(
+                keys.insert(key, entry).is_none(),
+                "line {}: duplicate key {key}",
+                index + 1
+            );
+        }
+        Ok(Self { keys })
+    }
+
+    /// Look up the entry for a public key.
+    #[must_use]
+    pub fn get(&self, key: &PublicKey) -> Option<&AuthorizedKey> {
+        self.keys.get(key)
+    }
+
+    /// Number of keys in the set.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Whether the set contains no keys.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+fn parse_line(line: &str) -> Result<(PublicKey, AuthorizedKey)> {
+    let mut fields = line.split_whitespace();
+    let key: PublicKey = fields.next().context("missing public key")?.parse()?;
+    let user_id = fields.next().context("missing user ID")?.to_string();
+
+    let mut entry = AuthorizedKey {
+        user_id,
+        max_tunnels: None,
+        ports: Vec::new(),
+    };
+    for option in fields {
+        match option.split_once('=') {
+            Some(("max_tunnels", value)) => {
+                entry.max_tunnels = Some(value.parse().context("invalid max_tunnels")?);
+            }
+            Some(("ports", value)) => entry.ports = parse_port_ranges(value)?,
+            _ => bail!("unknown option {option:?}"),
+        }
+    }
+    Ok((key, entry))
+}
+
+/// Parse a comma-separated list of ports and inclusive `LOW-HIGH` ranges.
+pub(crate) fn parse_port_ranges(value: &str) -> Result<Vec<RangeInclusive<u16>>> {
+    value
+        .split(',')
+        .map(|part| {
+            let range = match part.split_once('-') {
+                Some((low, high)) => low.parse()?..=high.parse()?,
+                None => {
+                    let port = part.parse()?;
+                    port..=port
+                }
+            };
+            ensure!(!range.is_empty(), "empty port range {part:?}");
+            Ok(range)
+        })
+        .collect::<Result<_>>()
+        .with_context(|| format!("invalid port list {value:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bore_shared::KeyPair;
+
+    #[test]
+    fn parses_comments_and_defaults() {
+        let key = KeyPair::generate().public_key();
+        let content = format!("# authorized keys\n\n{key} ci\n");
+        let keys = AuthorizedKeys::parse(&content).unwrap();
+
+        assert_eq!(keys.len(), 1);
+        let entry = keys.get(&key).unwrap();
+        assert_eq!(entry.user_id, "ci");
+        assert_eq!(entry.max_tunnels, None);
+        assert!(entry.ports.is_empty());
+    }
+
+    #[test]
+    fn parses_port_lists() {
+        assert_eq!(
+            parse_port_ranges("9000-9010,9500").unwrap(),
+            vec![9000..=9010, 9500..=9500]
+        );
+        assert!(parse_port_ranges("9010-9000").is_err());
+        assert!(parse_port_ranges("abc").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        let key = KeyPair::generate().public_key();
+        assert!(AuthorizedKeys::parse("ed25519:nothex alice").is_err());
+        assert!(AuthorizedKeys::parse(&format!("{key}")).is_err());
+        assert!(AuthorizedKeys::parse(&format!("{key} alice color=blue")).is_err());
+        assert!(AuthorizedKeys::parse(&format!("{key} alice\n{key} bob")).is_err());
+    }
+}
@@ -1,5 +1,21 @@
+mod access;
+mod admin;
+pub mod auth;
 pub mod backend;
+pub mod cache;
+pub mod commands;
+pub mod groups;
+pub mod jwt;
+pub mod keys;
+mod listener;
+mod metrics;
+pub mod outbox;
+pub mod ports;
+pub mod registry;
+pub mod reservations;
+pub mod secrets;
 pub mod server;
+pub mod state;
 
 // Re-export commonly used items for testing
 pub use server::Server;
@@ -0,0 +1,101 @@
+//! Listeners bound on several addresses at once.
+//!
+//! A server may listen on both an IPv4 and an IPv6 address, for the control
+//! port and for every tunnel. Each address gets its own socket, and
+//! connections from all of them are accepted together.
+
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::task::Poll;
+
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Backlog of each listening socket, as used by the standard library.
+const BACKLOG: i32 = 128;
+
+/// TCP listeners for one port on several addresses.
+#[derive(Debug)]
+pub struct MultiListener {
+    listeners: Vec<TcpListener>,
+}
+
+impl MultiListener {
+    /// Bind `port` on every address, failing if any of them cannot be bound.
+    ///
+    /// IPv6 sockets are made IPv6-only when an IPv4 address is bound too, so
+    /// `0.0.0.0` and `::` can share a port. A lone IPv6 address keeps the system
+    /// default, which usually accepts IPv4 clients as well.
+    pub fn bind(addrs: &[IpAddr], port: u16) -> io::Result<Self> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to bind to",
+            ));
+        }
+        let has_v4 = addrs.iter().any(IpAddr::is_ipv4);
+        let listeners = addrs
+            .iter()
+            .map(|&ip| bind_one(SocketAddr::new(ip, port), ip.is_ipv6() && has_v4))
+            .collect::<io::Result<_>>()?;
+        Ok(Self { listeners })
+    }
+
+    /// Accept a connection on any of the addresses.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        poll_fn(|cx| {
+            for listener in &self.listeners {
+                if let Poll::Ready(result) = listener.poll_accept(cx) {
+                    return Poll::Ready(result);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Addresses the listeners are bound to.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+}
+
+fn bind_one(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    // Like `TcpListener::bind`, so restarted servers can rebind right away.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[tokio::test]
+    async fn accepts_on_both_stacks() -> io::Result<()> {
+        let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let port = std::net::TcpListener::bind((v4, 0))?.local_addr()?.port();
+
+        let listener = MultiListener::bind(&[v4, v6], port)?;
+        assert_eq!(listener.local_addrs()?.len(), 2);
+        for ip in [v4, v6] {
+            let _client = TcpStream::connect((ip, port)).await?;
+            let (_, peer) = listener.accept().await?;
+            assert_eq!(peer.ip(), ip);
+        }
+
+        // Every address must be free for the port to be bound.
+        assert!(MultiListener::bind(&[v6], port).is_err());
+        Ok(())
+    }
+}
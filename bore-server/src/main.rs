@@ -1,12 +1,28 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::Result;
 use clap::{error::ErrorKind, CommandFactory, Parser};
 
-mod backend;
-mod server;
-
-use server::Server;
+use bore_server::{
+    backend::resilience::BreakerConfig,
+    backend::{BackendClient, NoopBackend},
+    cache::CacheConfig,
+    commands::CommandStream,
+    groups::BalanceStrategy,
+    jwt::TokenVerifier,
+    keys::AuthorizedKeys,
+    outbox::Outbox,
+    ports::AllocationStrategy,
+    registry::RegistryConfig,
+    reservations::PortReservations,
+    secrets::SharedSecrets,
+    state::RedisState,
+    Server,
+};
+use bore_shared::logging::{LogConfig, LogFormat, LogRotation};
+use bore_shared::MAX_BINARY_FRAME_LENGTH;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about = "bore server - TCP tunnel server")]
@@ -23,6 +39,10 @@ struct Args {
     #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
     secret: Option<String>,
 
+    /// File of named secrets, each with an optional expiry, accepted alongside --secret.
+    #[clap(long, env = "BORE_SECRETS_FILE", value_name = "FILE")]
+    secrets_file: Option<PathBuf>,
+
     /// Backend API URL for user authentication and usage tracking.
     #[clap(long, env = "BORE_BACKEND_URL")]
     backend_url: Option<String>,
@@ -35,13 +55,203 @@ struct Args {
     #[clap(long, env = "BORE_SERVER_ID", default_value = "default")]
     server_id: String,
 
-    /// IP address to bind to, clients must reach this.
+    /// Authorized keys file for Ed25519 public-key client authentication.
+    #[clap(long, env = "BORE_AUTHORIZED_KEYS", value_name = "FILE")]
+    authorized_keys: Option<PathBuf>,
+
+    /// Order in which ports are handed out to clients asking for any port:
+    /// random, sequential or lru.
+    #[clap(
+        long,
+        env = "BORE_PORT_STRATEGY",
+        default_value = "random",
+        value_name = "STRATEGY"
+    )]
+    port_strategy: AllocationStrategy,
+
+    /// How connections to a shared tunnel are spread across its clients:
+    /// round-robin, least-connections or random.
+    #[clap(
+        long,
+        env = "BORE_BALANCE_STRATEGY",
+        default_value = "round-robin",
+        value_name = "STRATEGY"
+    )]
+    balance_strategy: BalanceStrategy,
+
+    /// Largest binary frame, in bytes, accepted from clients that negotiate them.
+    #[clap(
+        long,
+        env = "BORE_MAX_FRAME_LENGTH",
+        default_value_t = MAX_BINARY_FRAME_LENGTH,
+        value_name = "BYTES"
+    )]
+    max_frame_length: usize,
+
+    /// File of ports reserved for particular users, one `PORT USER` per line.
+    #[clap(long, env = "BORE_RESERVATIONS", value_name = "FILE")]
+    reservations: Option<PathBuf>,
+
+    /// PEM public key for verifying signed `tk_` tunnel tokens locally.
+    #[clap(
+        long,
+        env = "BORE_TOKEN_PUBLIC_KEY",
+        value_name = "FILE",
+        conflicts_with = "token_jwks"
+    )]
+    token_public_key: Option<PathBuf>,
+
+    /// JWKS file for verifying signed `tk_` tunnel tokens locally.
+    #[clap(long, env = "BORE_TOKEN_JWKS", value_name = "FILE")]
+    token_jwks: Option<PathBuf>,
+
+    /// Required `iss` claim of signed tunnel tokens.
+    #[clap(long, env = "BORE_TOKEN_ISSUER")]
+    token_issuer: Option<String>,
+
+    /// Ask the backend whether locally verified tunnel tokens have been revoked.
+    #[clap(long, env = "BORE_TOKEN_CHECK_REVOCATION")]
+    token_check_revocation: bool,
+
+    /// How long successful API key validations are cached, 0 to disable caching.
+    #[clap(long, env = "BORE_CACHE_TTL", default_value = "60s", value_parser = humantime::parse_duration)]
+    cache_ttl: Duration,
+
+    /// How long rejected API keys are cached.
+    #[clap(long, env = "BORE_CACHE_NEGATIVE_TTL", default_value = "10s", value_parser = humantime::parse_duration)]
+    cache_negative_ttl: Duration,
+
+    /// How long past its TTL a cached validation may be used while the backend is unreachable.
+    #[clap(long, env = "BORE_CACHE_STALE_IF_ERROR", default_value = "0s", value_parser = humantime::parse_duration)]
+    cache_stale_if_error: Duration,
+
+    /// How often running sessions are checked against the backend again, closing
+    /// those whose key was revoked or whose usage is no longer allowed. 0 to disable.
+    #[clap(long, env = "BORE_REVALIDATE_INTERVAL", default_value = "5m", value_parser = humantime::parse_duration)]
+    revalidate_interval: Duration,
+
+    /// URL of the backend's server-sent event stream of revocations and other
+    /// commands, applied to running sessions as they arrive.
+    #[clap(long, env = "BORE_COMMAND_STREAM", value_name = "URL")]
+    command_stream: Option<String>,
+
+    /// Host name or address clients reach this server at. If set, the server
+    /// registers with the backend's registry and reports its load.
+    #[clap(long, env = "BORE_PUBLIC_HOST")]
+    public_host: Option<String>,
+
+    /// Region or location announced to the backend's registry.
+    #[clap(long, env = "BORE_REGION", default_value = "default")]
+    region: String,
+
+    /// Concurrent tunnels announced to the registry, defaults to the size of the port range.
+    #[clap(long, env = "BORE_CAPACITY")]
+    capacity: Option<u32>,
+
+    /// Bandwidth announced to the registry, in megabits per second.
+    #[clap(long, env = "BORE_MAX_BANDWIDTH_MBPS")]
+    max_bandwidth_mbps: Option<u32>,
+
+    /// How often load is reported to the backend's registry.
+    #[clap(long, env = "BORE_HEARTBEAT_INTERVAL", default_value = "15s", value_parser = humantime::parse_duration)]
+    heartbeat_interval: Duration,
+
+    /// Redis URL, e.g. `redis://:password@host:6379/0`, of state shared by the
+    /// servers of a cluster, so tunnel limits and port reservations hold across
+    /// all of them. Each server enforces them on its own if not set.
+    #[clap(
+        long,
+        env = "BORE_SHARED_STATE",
+        value_name = "URL",
+        hide_env_values = true
+    )]
+    shared_state: Option<String>,
+
+    /// How long a server's tunnels keep counting in the shared state after it
+    /// stops renewing them, e.g. because it crashed.
+    #[clap(long, env = "BORE_LEASE_TTL", default_value = "30s", value_parser = humantime::parse_duration)]
+    lease_ttl: Duration,
+
+    /// File where events for the backend are queued until delivered, so they survive restarts.
+    #[clap(long, env = "BORE_OUTBOX", value_name = "FILE")]
+    outbox: Option<PathBuf>,
+
+    /// Consecutive backend failures after which backend calls fail fast.
+    #[clap(long, env = "BORE_BACKEND_BREAKER_THRESHOLD", default_value_t = 5)]
+    backend_breaker_threshold: u32,
+
+    /// How long backend calls fail fast before the backend is probed again.
+    #[clap(long, env = "BORE_BACKEND_BREAKER_COOLDOWN", default_value = "10s", value_parser = humantime::parse_duration)]
+    backend_breaker_cooldown: Duration,
+
+    /// Address for the admin API, e.g. 127.0.0.1:7836. Disabled if not set.
+    /// Also serves the /healthz and /readyz probes.
+    #[clap(long, env = "BORE_ADMIN_ADDR")]
+    admin_addr: Option<SocketAddr>,
+
+    /// Address serving only the /healthz and /readyz probes, for servers
+    /// without the admin API.
+    #[clap(long, env = "BORE_HEALTH_ADDR", conflicts_with = "admin_addr")]
+    health_addr: Option<SocketAddr>,
+
+    /// Bearer token required by the admin API.
+    #[clap(long, env = "BORE_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// IP address to bind to, clients must reach this. Repeat for dual-stack,
+    /// e.g. `--bind-addr 0.0.0.0 --bind-addr ::`.
     #[clap(long, default_value = "0.0.0.0")]
-    bind_addr: IpAddr,
+    bind_addr: Vec<IpAddr>,
 
-    /// IP address where tunnels will listen on, defaults to --bind-addr.
+    /// IP address where tunnels will listen on, defaults to --bind-addr. Repeat
+    /// to accept tunnel connections on several addresses.
     #[clap(long)]
-    bind_tunnels: Option<IpAddr>,
+    bind_tunnels: Vec<IpAddr>,
+
+    /// Log format: `text`, or `json` for one JSON object per line.
+    #[clap(
+        long,
+        env = "BORE_LOG_FORMAT",
+        default_value = "text",
+        value_name = "FORMAT"
+    )]
+    log_format: LogFormat,
+
+    /// Log level filter, e.g. `debug` or `warn,bore_server=info`. Defaults to
+    /// RUST_LOG, then `info`.
+    #[clap(long, env = "BORE_LOG_LEVEL", value_name = "FILTER")]
+    log_level: Option<String>,
+
+    /// File to write logs to instead of stdout.
+    #[clap(long, env = "BORE_LOG_FILE", value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// How often to rotate the log file and access log: `never`, `hourly` or `daily`.
+    #[clap(long, env = "BORE_LOG_ROTATION", default_value = "never")]
+    log_rotation: LogRotation,
+
+    /// Rotated files to keep of each log, all of them if not set.
+    #[clap(long, env = "BORE_LOG_MAX_FILES", value_name = "N")]
+    log_max_files: Option<usize>,
+
+    /// File for the access log, one JSON line per visitor connection. Access
+    /// records stay in the regular log if not set.
+    #[clap(long, env = "BORE_ACCESS_LOG", value_name = "FILE")]
+    access_log: Option<PathBuf>,
+}
+
+impl Args {
+    fn log_config(&self) -> LogConfig {
+        LogConfig {
+            format: self.log_format,
+            level: self.log_level.clone(),
+            file: self.log_file.clone(),
+            rotation: self.log_rotation,
+            max_files: self.log_max_files,
+            stderr: false,
+            access_log: self.access_log.clone(),
+        }
+    }
 }
 
 #[tokio::main]
@@ -52,21 +262,131 @@ async fn run(args: Args) -> Result<()> {
             .error(ErrorKind::InvalidValue, "port range is empty")
             .exit();
     }
-    let mut server = Server::new(
-        port_range,
-        args.secret.as_deref(),
-        args.backend_url,
-        args.backend_api_key,
-        args.server_id,
-    );
-    server.set_bind_addr(args.bind_addr);
-    server.set_bind_tunnels(args.bind_tunnels.unwrap_or(args.bind_addr));
-    server.listen().await?;
+    if args.lease_ttl.is_zero() {
+        Args::command()
+            .error(ErrorKind::InvalidValue, "lease TTL must not be zero")
+            .exit();
+    }
+    if args.heartbeat_interval.is_zero() {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "heartbeat interval must not be zero",
+            )
+            .exit();
+    }
+    let backend_enabled = args.backend_url.is_some();
+    let command_stream = args
+        .command_stream
+        .map(|url| CommandStream::new(url, args.backend_api_key.clone()));
+    let mut server = match args.backend_url {
+        Some(url) => {
+            let mut backend = BackendClient::new(url, args.backend_api_key);
+            backend.set_breaker_config(BreakerConfig {
+                failure_threshold: args.backend_breaker_threshold,
+                cooldown: args.backend_breaker_cooldown,
+            });
+            Server::new(port_range, args.secret.as_deref(), backend, args.server_id)
+        }
+        None => Server::new(
+            port_range,
+            args.secret.as_deref(),
+            NoopBackend,
+            args.server_id,
+        ),
+    };
+    if let Some(path) = &args.outbox {
+        server.set_outbox(Outbox::open(path)?);
+    }
+    if let Some(path) = &args.secrets_file {
+        server.set_secrets(SharedSecrets::load(path)?);
+    }
+    if let Some(path) = &args.authorized_keys {
+        server.set_authorized_keys(AuthorizedKeys::load(path)?);
+    }
+    server.set_port_strategy(args.port_strategy);
+    server.set_balance_strategy(args.balance_strategy);
+    server.set_max_frame_length(args.max_frame_length);
+    if let Some(path) = &args.reservations {
+        server.set_port_reservations(PortReservations::load(path)?);
+    }
+    let verifier = match (&args.token_public_key, &args.token_jwks) {
+        (Some(path), _) => Some(TokenVerifier::from_pem_file(path)?),
+        (None, Some(path)) => Some(TokenVerifier::from_jwks_file(path)?),
+        (None, None) => None,
+    };
+    if let Some(verifier) = verifier {
+        server.set_token_verifier(
+            verifier.with_issuer(args.token_issuer),
+            args.token_check_revocation,
+        );
+    }
+    if backend_enabled && !args.cache_ttl.is_zero() {
+        server.set_validation_cache(CacheConfig {
+            positive_ttl: args.cache_ttl,
+            negative_ttl: args.cache_negative_ttl,
+            stale_if_error: args.cache_stale_if_error,
+        });
+    }
+    if let Some(stream) = command_stream {
+        server.set_command_stream(stream);
+    }
+    if !args.revalidate_interval.is_zero() {
+        server.set_revalidate_interval(args.revalidate_interval);
+    }
+    if let Some(url) = &args.shared_state {
+        server.set_shared_state(RedisState::new(url)?);
+    }
+    server.set_lease_ttl(args.lease_ttl);
+    if let Some(public_host) = args.public_host {
+        let mut config = RegistryConfig::new(public_host, args.region);
+        config.capacity = args.capacity;
+        config.max_bandwidth_mbps = args.max_bandwidth_mbps;
+        config.heartbeat_interval = args.heartbeat_interval;
+        server.set_registry(config);
+    }
+    if let Some(addr) = args.admin_addr {
+        server.set_admin(addr, args.admin_token);
+    }
+    if let Some(addr) = args.health_addr {
+        server.set_health_addr(addr);
+    }
+    let bind_tunnels = if args.bind_tunnels.is_empty() {
+        args.bind_addr.clone()
+    } else {
+        args.bind_tunnels
+    };
+    server.set_bind_addrs(args.bind_addr);
+    server.set_bind_tunnels_all(bind_tunnels);
+    server.listen_until(shutdown_signal()).await?;
 
     Ok(())
 }
 
+/// Wait for Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(err) => tracing::warn!(%err, "Failed to listen for SIGTERM"),
+        }
+    }
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        tracing::warn!(%err, "Failed to listen for Ctrl-C");
+        std::future::pending::<()>().await;
+    }
+}
+
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    run(Args::parse())
+    let args = Args::parse();
+    let _logs = args.log_config().init()?;
+    run(args)
 }
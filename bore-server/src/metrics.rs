@@ -0,0 +1,154 @@
+//! Prometheus text exposition of server metrics, served by the admin API.
+
+use std::fmt::{Display, Write};
+use std::sync::atomic::Ordering;
+
+use crate::backend::resilience::{Endpoint, Resilience};
+use crate::cache::ValidationCache;
+use crate::outbox::Outbox;
+
+/// Builder for a Prometheus text-format response.
+#[derive(Default)]
+pub(crate) struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    /// Start a metric family with its help text and type.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {kind}");
+    }
+
+    /// Add a sample to the current family.
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+                .collect();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {value}");
+    }
+
+    /// The finished exposition.
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Circuit breaker state and per-endpoint call counters of the backend client.
+pub(crate) fn write_backend(exp: &mut Exposition, resilience: &Resilience) {
+    let state = resilience.breaker().state();
+    exp.family(
+        "bore_backend_circuit_state",
+        "gauge",
+        "Backend circuit breaker state (0 closed, 1 half-open, 2 open).",
+    );
+    exp.sample("bore_backend_circuit_state", &[], state.as_gauge());
+
+    exp.family(
+        "bore_backend_requests_total",
+        "counter",
+        "Backend calls by endpoint and outcome.",
+    );
+    for endpoint in Endpoint::ALL {
+        let stats = resilience.stats(endpoint);
+        for (outcome, counter) in [
+            ("success", &stats.successes),
+            ("failure", &stats.failures),
+            ("rejected", &stats.rejections),
+        ] {
+            exp.sample(
+                "bore_backend_requests_total",
+                &[("endpoint", endpoint.name()), ("outcome", outcome)],
+                counter.load(Ordering::Relaxed),
+            );
+        }
+    }
+
+    exp.family(
+        "bore_backend_retries_total",
+        "counter",
+        "Backend call retries by endpoint.",
+    );
+    for endpoint in Endpoint::ALL {
+        exp.sample(
+            "bore_backend_retries_total",
+            &[("endpoint", endpoint.name())],
+            resilience.stats(endpoint).retries.load(Ordering::Relaxed),
+        );
+    }
+
+    exp.family(
+        "bore_backend_retry_budget_exhausted_total",
+        "counter",
+        "Retries skipped because the retry budget was exhausted.",
+    );
+    for endpoint in Endpoint::ALL {
+        exp.sample(
+            "bore_backend_retry_budget_exhausted_total",
+            &[("endpoint", endpoint.name())],
+            resilience
+                .stats(endpoint)
+                .budget_exhausted
+                .load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Size of the API key validation cache.
+pub(crate) fn write_cache(exp: &mut Exposition, cache: &ValidationCache) {
+    exp.family(
+        "bore_validation_cache_entries",
+        "gauge",
+        "Entries in the API key validation cache.",
+    );
+    exp.sample("bore_validation_cache_entries", &[], cache.len());
+}
+
+/// Events waiting for delivery to the backend.
+pub(crate) fn write_outbox(exp: &mut Exposition, outbox: &Outbox) {
+    exp.family(
+        "bore_outbox_pending_events",
+        "gauge",
+        "Events waiting in the outbox for delivery to the backend.",
+    );
+    exp.sample("bore_outbox_pending_events", &[], outbox.len());
+
+    exp.family(
+        "bore_outbox_dropped_events_total",
+        "counter",
+        "Events dropped because the outbox was full.",
+    );
+    exp.sample("bore_outbox_dropped_events_total", &[], outbox.dropped());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_families_and_labels() {
+        let mut exp = Exposition::default();
+        exp.family("bore_test_total", "counter", "A test counter.");
+        exp.sample("bore_test_total", &[("name", "a\"b")], 3);
+        exp.sample("bore_test_total", &[], 4);
+        assert_eq!(
+            exp.finish(),
+            "# HELP bore_test_total A test counter.\n\
+             # TYPE bore_test_total counter\n\
+             bore_test_total{name=\"a\\\"b\"} 3\n\
+             bore_test_total 4\n"
+        );
+    }
+}
@@ -0,0 +1,519 @@
+//! Durable outbox for tunnel lifecycle and usage events.
+//!
+//! Events bound for the backend are first appended to a local log, then
+//! delivered in order by a background task. Each event carries a unique ID that
+//! is sent as an idempotency key, so the backend can drop the duplicates that
+//! at-least-once delivery produces, giving exactly-once processing overall.
+//!
+//! The log is a JSON Lines file with two kinds of records: `event` records,
+//! appended when an event is queued, and `ack` records, appended once the
+//! backend has accepted it. On startup, unacknowledged events are replayed in
+//! their original order.
+//!
+//! Records are written and synced by a dedicated thread, so a slow disk delays
+//! only the log, never the connections queueing events. At most
+//! [`MAX_PENDING`] events wait for delivery; further events are dropped and
+//! counted until the backend catches up.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bore_shared::TunnelStatus;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::backend::UsageBackend;
+
+/// Number of acknowledged records after which an empty log is truncated.
+const COMPACT_THRESHOLD: usize = 1000;
+
+/// Events waiting for delivery beyond which new events are dropped.
+pub const MAX_PENDING: usize = 100_000;
+
+/// An event reported to the backend.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboxEvent {
+    /// A tunnel session started.
+    TunnelStart {
+        /// Session ID, generated by the server.
+        session_id: String,
+        /// User owning the tunnel.
+        user_id: String,
+        /// Public port of the tunnel.
+        public_port: u16,
+        /// Port the client asked for, 0 if any.
+        local_port: u16,
+        /// Server the tunnel runs on.
+        server_id: String,
+    },
+
+    /// A tunnel session ended.
+    TunnelEnd {
+        /// Session ID from the matching `TunnelStart`.
+        session_id: String,
+        /// Bytes forwarded during the session.
+        bytes_transferred: u64,
+    },
+
+    /// A dashboard instance's tunnel connected.
+    TunnelConnected {
+        /// Instance ID.
+        instance_id: String,
+        /// Public port of the tunnel.
+        remote_port: Option<u16>,
+        /// Public URL of the tunnel.
+        public_url: Option<String>,
+        /// Health of the local service, once the client reported it.
+        #[serde(default)]
+        status: Option<TunnelStatus>,
+    },
+
+    /// A dashboard instance's tunnel disconnected.
+    TunnelDisconnected {
+        /// Instance ID.
+        instance_id: String,
+    },
+
+    /// Bandwidth used by a session.
+    Usage {
+        /// User owning the session.
+        user_id: String,
+        /// Session ID.
+        session_id: String,
+        /// Bytes received from the public side.
+        bytes_in: u64,
+        /// Bytes sent to the public side.
+        bytes_out: u64,
+    },
+}
+
+/// A queued event with its ID.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutboxRecord {
+    /// Unique event ID, sent to the backend as an idempotency key.
+    pub id: String,
+
+    /// The event itself.
+    pub event: OutboxEvent,
+}
+
+/// A line of the outbox log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LogLine {
+    Event(OutboxRecord),
+    Ack { id: String },
+}
+
+/// A change to the log, applied by its writer thread.
+enum LogOp {
+    Append(LogLine),
+    Truncate,
+}
+
+/// The thread writing the log, which finishes pending writes when dropped.
+struct LogWriter {
+    ops: Option<mpsc::Sender<LogOp>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LogWriter {
+    fn spawn(path: PathBuf, mut file: File) -> Result<Self> {
+        let (ops, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("bore-outbox".to_string())
+            .spawn(move || {
+                // Every batch of queued changes is synced to disk at once.
+                while let Ok(op) = rx.recv() {
+                    let batch = std::iter::once(op).chain(rx.try_iter());
+                    if let Err(err) = apply(&mut file, &path, batch) {
+                        error!(%err, path = %path.display(), "Failed to write outbox log");
+                    }
+                }
+            })?;
+        Ok(Self {
+            ops: Some(ops),
+            thread: Some(thread),
+        })
+    }
+
+    fn send(&self, op: LogOp) {
+        if let Some(ops) = &self.ops {
+            // The thread only stops once this writer is dropped.
+            let _ = ops.send(op);
+        }
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        self.ops.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn apply(file: &mut File, path: &Path, batch: impl Iterator<Item = LogOp>) -> Result<()> {
+    for op in batch {
+        match op {
+            LogOp::Append(line) => write_line(file, &line)?,
+            LogOp::Truncate => {
+                debug!(path = %path.display(), "Compacting outbox log");
+                file.set_len(0)?;
+            }
+        }
+    }
+    file.sync_data()?;
+    Ok(())
+}
+
+struct Log {
+    writer: LogWriter,
+    /// Acknowledged records written since the log was last compacted.
+    acked: usize,
+}
+
+struct Inner {
+    pending: VecDeque<OutboxRecord>,
+    log: Option<Log>,
+}
+
+/// Ordered queue of events for the backend, optionally persisted to disk.
+pub struct Outbox {
+    inner: Mutex<Inner>,
+    notify: Notify,
+    initial_retry_delay: Duration,
+    max_retry_delay: Duration,
+    max_pending: usize,
+    dropped: AtomicU64,
+}
+
+impl Outbox {
+    /// An outbox kept only in memory: events are retried, but lost on restart.
+    #[must_use]
+    pub fn in_memory() -> Self {
+        Self::with_inner(Inner {
+            pending: VecDeque::new(),
+            log: None,
+        })
+    }
+
+    /// Open or create an outbox log, recovering events not yet acknowledged.
+    pub fn open(path: &Path) -> Result<Self> {
+        let pending = match File::open(path) {
+            Ok(file) => read_log(BufReader::new(file))
+                .with_context(|| format!("failed to read outbox {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to open outbox {}", path.display()))
+            }
+        };
+        if !pending.is_empty() {
+            info!(pending = pending.len(), path = %path.display(), "Recovered undelivered outbox events");
+        }
+
+        // Rewrite the log with only the pending events, dropping acknowledged history.
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for record in &pending {
+                write_line(&mut file, &LogLine::Event(record.clone()))?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self::with_inner(Inner {
+            pending,
+            log: Some(Log {
+                writer: LogWriter::spawn(path.to_path_buf(), file)?,
+                acked: 0,
+            }),
+        }))
+    }
+
+    fn with_inner(inner: Inner) -> Self {
+        Self {
+            inner: Mutex::new(inner),
+            notify: Notify::new(),
+            initial_retry_delay: Duration::from_millis(500),
+            max_retry_delay: Duration::from_secs(30),
+            max_pending: MAX_PENDING,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Set the delays between delivery attempts of the event at the head of the queue.
+    #[must_use]
+    pub fn with_retry_delays(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_retry_delay = initial;
+        self.max_retry_delay = max;
+        self
+    }
+
+    /// Drop new events while this many wait for delivery, instead of [`MAX_PENDING`].
+    #[must_use]
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Queue an event, returning its ID.
+    ///
+    /// The event is written to the log in the background. Fails, dropping the
+    /// event, if too many events are waiting for delivery.
+    pub fn push(&self, event: OutboxEvent) -> Result<String> {
+        let record = OutboxRecord {
+            id: Uuid::new_v4().to_string(),
+            event,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        if inner.pending.len() >= self.max_pending {
+            drop(inner);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            bail!("outbox full with {} pending events", self.max_pending);
+        }
+        // Queued under the lock, so the log keeps the order of the queue.
+        if let Some(log) = &inner.log {
+            log.writer
+                .send(LogOp::Append(LogLine::Event(record.clone())));
+        }
+        let id = record.id.clone();
+        inner.pending.push_back(record);
+        drop(inner);
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    /// Number of events waiting for delivery.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+
+    /// Number of events dropped because the outbox was full.
+    #[must_use]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether all events have been delivered.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The next event to deliver.
+    fn peek(&self) -> Option<OutboxRecord> {
+        self.inner.lock().unwrap().pending.front().cloned()
+    }
+
+    /// Mark the event at the head of the queue as delivered.
+    fn ack(&self, id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.pending.front().is_some_and(|r| r.id == id) {
+            inner.pending.pop_front();
+        }
+        let empty = inner.pending.is_empty();
+        if let Some(log) = &mut inner.log {
+            log.writer
+                .send(LogOp::Append(LogLine::Ack { id: id.to_string() }));
+            log.acked += 1;
+            if empty && log.acked >= COMPACT_THRESHOLD {
+                log.writer.send(LogOp::Truncate);
+                log.acked = 0;
+            }
+        }
+    }
+
+    /// Deliver queued events to the backend, in order, forever.
+    ///
+    /// The event at the head of the queue is retried with exponential backoff
+    /// until the backend accepts it. Events the backend rejects as invalid (4xx)
+    /// are dropped, since retrying them would block the queue forever.
+    pub async fn run(self: Arc<Self>, backend: Arc<dyn UsageBackend>) {
+        let mut delay = self.initial_retry_delay;
+        loop {
+            let Some(record) = self.peek() else {
+                self.notify.notified().await;
+                continue;
+            };
+
+            let result = backend.deliver_event(&record.id, &record.event).await;
+            let delivered = match &result {
+                Ok(()) => true,
+                Err(err) if is_permanent(err) => {
+                    error!(
+                        id = %record.id,
+                        event = ?record.event,
+                        err = %format!("{err:#}"),
+                        "Backend rejected outbox event, dropping it"
+                    );
+                    true
+                }
+                Err(err) => {
+                    warn!(
+                        id = %record.id,
+                        pending = self.len(),
+                        retry_in = ?delay,
+                        err = %format!("{err:#}"),
+                        "Failed to deliver outbox event"
+                    );
+                    false
+                }
+            };
+
+            if delivered {
+                self.ack(&record.id);
+                delay = self.initial_retry_delay;
+            } else {
+                sleep(delay).await;
+                delay = (delay * 2).min(self.max_retry_delay);
+            }
+        }
+    }
+}
+
+/// Whether a delivery error means the backend will never accept the event.
+fn is_permanent(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .filter_map(reqwest::Error::status)
+        .any(|status| status.is_client_error())
+}
+
+fn write_line(file: &mut File, line: &LogLine) -> Result<()> {
+    let mut json = serde_json::to_vec(line)?;
+    json.push(b'\n');
+    file.write_all(&json)?;
+    Ok(())
+}
+
+/// Replay a log, returning unacknowledged events in order.
+///
+/// A torn final line, left by a crash in the middle of a write, is ignored.
+fn read_log(reader: impl BufRead) -> Result<VecDeque<OutboxRecord>> {
+    let lines: Vec<String> = reader.lines().collect::<Result<_, _>>()?;
+    let mut pending = VecDeque::new();
+    let mut seen = HashSet::new();
+    let mut acked = HashSet::new();
+    for (i, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(LogLine::Event(record)) => {
+                if seen.insert(record.id.clone()) {
+                    pending.push_back(record);
+                }
+            }
+            Ok(LogLine::Ack { id }) => {
+                acked.insert(id);
+            }
+            Err(err) if i + 1 == lines.len() => {
+                warn!(%err, "Ignoring truncated last outbox record");
+            }
+            Err(err) => {
+                return Err(err).context(format!("invalid outbox record on line {}", i + 1))
+            }
+        }
+    }
+    pending.retain(|record| !acked.contains(&record.id));
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("bore-outbox-{}.jsonl", Uuid::new_v4()))
+    }
+
+    fn end(session_id: &str) -> OutboxEvent {
+        OutboxEvent::TunnelEnd {
+            session_id: session_id.to_string(),
+            bytes_transferred: 0,
+        }
+    }
+
+    #[test]
+    fn survives_restart() -> Result<()> {
+        let path = temp_path();
+        let outbox = Outbox::open(&path)?;
+        let first = outbox.push(end("s1"))?;
+        outbox.push(end("s2"))?;
+        outbox.push(end("s3"))?;
+        outbox.ack(&first);
+        drop(outbox);
+
+        let outbox = Outbox::open(&path)?;
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.peek().unwrap().event, end("s2"));
+
+        // Reopening compacts the log down to the pending events.
+        let content = fs::read_to_string(&path)?;
+        assert_eq!(content.lines().count(), 2);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_torn_last_line() -> Result<()> {
+        let record = OutboxRecord {
+            id: "e1".into(),
+            event: end("s1"),
+        };
+        let log = format!(
+            "{}\n{{\"type\":\"ack\",\"id\":\"e",
+            serde_json::to_string(&LogLine::Event(record.clone()))?
+        );
+        let pending = read_log(log.as_bytes())?;
+        assert_eq!(pending, [record]);
+
+        let corrupt = format!("garbage\n{log}");
+        assert!(read_log(corrupt.as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn deduplicates_replayed_events() -> Result<()> {
+        let record = LogLine::Event(OutboxRecord {
+            id: "e1".into(),
+            event: end("s1"),
+        });
+        let line = serde_json::to_string(&record)?;
+        let pending = read_log(format!("{line}\n{line}\n").as_bytes())?;
+        assert_eq!(pending.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn drops_events_when_full() -> Result<()> {
+        let outbox = Outbox::in_memory().with_max_pending(2);
+        let first = outbox.push(end("s1"))?;
+        outbox.push(end("s2"))?;
+        assert!(outbox.push(end("s3")).is_err());
+        assert_eq!((outbox.len(), outbox.dropped()), (2, 1));
+
+        outbox.ack(&first);
+        outbox.push(end("s4"))?;
+        assert_eq!(outbox.dropped(), 1);
+        Ok(())
+    }
+}
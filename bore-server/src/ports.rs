@@ -0,0 +1,414 @@
+//! Allocation of public tunnel ports.
+//!
+//! The allocator tracks which ports of the server's range are held by tunnels,
+//! so a client asking for any port is offered only free ones, in the order of
+//! the configured [`AllocationStrategy`]. Ports can still turn out to be taken
+//! by other processes when bound; the caller then moves on to the next one,
+//! up to a bounded number of attempts. Candidates are found lazily, so a few
+//! attempts do not cost a walk over the whole range.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+
+/// Order in which free ports are handed out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationStrategy {
+    /// Any free port, chosen uniformly at random.
+    #[default]
+    Random,
+
+    /// The next free port after the last one allocated, wrapping around.
+    Sequential,
+
+    /// The free port released longest ago, so a port is reused as late as possible.
+    LeastRecentlyUsed,
+}
+
+impl fmt::Display for AllocationStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AllocationStrategy::Random => "random",
+            AllocationStrategy::Sequential => "sequential",
+            AllocationStrategy::LeastRecentlyUsed => "lru",
+        })
+    }
+}
+
+impl FromStr for AllocationStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "random" => Ok(AllocationStrategy::Random),
+            "sequential" => Ok(AllocationStrategy::Sequential),
+            "lru" => Ok(AllocationStrategy::LeastRecentlyUsed),
+            _ => {
+                bail!("unknown port allocation strategy {s:?}, expected random, sequential or lru")
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Ports currently held by a lease.
+    in_use: HashSet<u16>,
+
+    /// Where the sequential strategy continues.
+    cursor: u16,
+
+    /// When each port was last released, as a counter value.
+    released: HashMap<u16, u64>,
+
+    /// Counter incremented on every release.
+    clock: u64,
+}
+
+/// Tracks the ports held by tunnels and picks free ones.
+pub struct PortAllocator {
+    range: RangeInclusive<u16>,
+    strategy: AllocationStrategy,
+    state: Mutex<State>,
+}
+
+impl PortAllocator {
+    /// An allocator for this range, with no port in use.
+    #[must_use]
+    pub fn new(range: RangeInclusive<u16>, strategy: AllocationStrategy) -> Self {
+        let state = State {
+            cursor: *range.start(),
+            ..State::default()
+        };
+        Self {
+            range,
+            strategy,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// The range ports are allocated from.
+    #[must_use]
+    pub fn range(&self) -> &RangeInclusive<u16> {
+        &self.range
+    }
+
+    /// The strategy ordering free ports.
+    #[must_use]
+    pub fn strategy(&self) -> AllocationStrategy {
+        self.strategy
+    }
+
+    /// Free ports for which `accept` holds, in the order they should be tried.
+    ///
+    /// Each port is checked when the iterator reaches it, so ports claimed in
+    /// the meantime are skipped.
+    pub fn candidates<F: Fn(u16) -> bool>(&self, accept: F) -> Candidates<'_, F> {
+        let len = u32::from(self.range.end() - self.range.start()) + 1;
+        let state = self.state.lock().unwrap();
+        let order = match self.strategy {
+            AllocationStrategy::Random => Order::Walk(Walk::random(len)),
+            AllocationStrategy::Sequential => Order::Walk(Walk::new(
+                len,
+                u32::from(state.cursor - self.range.start()),
+                1,
+            )),
+            AllocationStrategy::LeastRecentlyUsed => {
+                let mut released: Vec<(u64, u16)> = state
+                    .released
+                    .iter()
+                    .map(|(&port, &at)| (at, port))
+                    .collect();
+                released.sort_unstable();
+                Order::LeastRecentlyUsed {
+                    fresh: Walk::new(len, 0, 1),
+                    released: released
+                        .into_iter()
+                        .map(|(_, port)| port)
+                        .collect::<Vec<_>>()
+                        .into_iter(),
+                }
+            }
+        };
+        Candidates {
+            allocator: self,
+            order,
+            accept,
+        }
+    }
+
+    /// Mark a port as in use, unless it already is or lies outside the range.
+    ///
+    /// The port is released when the returned lease is dropped.
+    pub fn claim(self: &Arc<Self>, port: u16) -> Option<PortLease> {
+        if !self.range.contains(&port) {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        if !state.in_use.insert(port) {
+            return None;
+        }
+        if self.strategy == AllocationStrategy::Sequential {
+            state.cursor = if port == *self.range.end() {
+                *self.range.start()
+            } else {
+                port + 1
+            };
+        }
+        Some(PortLease {
+            allocator: Arc::clone(self),
+            port,
+        })
+    }
+
+    /// Whether a tunnel holds this port.
+    #[must_use]
+    pub fn is_in_use(&self, port: u16) -> bool {
+        self.state.lock().unwrap().in_use.contains(&port)
+    }
+
+    /// Number of ports held by tunnels.
+    #[must_use]
+    pub fn in_use(&self) -> usize {
+        self.state.lock().unwrap().in_use.len()
+    }
+
+    fn release(&self, port: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.in_use.remove(&port);
+        state.clock += 1;
+        let now = state.clock;
+        state.released.insert(port, now);
+    }
+}
+
+/// Free ports of a [`PortAllocator`], in the order of its strategy.
+pub struct Candidates<'a, F> {
+    allocator: &'a PortAllocator,
+    order: Order,
+    accept: F,
+}
+
+enum Order {
+    Walk(Walk),
+
+    /// Never-used ports in ascending order, then released ones, oldest first.
+    LeastRecentlyUsed {
+        fresh: Walk,
+        released: std::vec::IntoIter<u16>,
+    },
+}
+
+impl<F: Fn(u16) -> bool> Iterator for Candidates<'_, F> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        let start = *self.allocator.range.start();
+        loop {
+            let port = match &mut self.order {
+                Order::Walk(walk) => start + walk.next()?,
+                Order::LeastRecentlyUsed { fresh, released } => match fresh.next() {
+                    Some(offset) => {
+                        let port = start + offset;
+                        if self
+                            .allocator
+                            .state
+                            .lock()
+                            .unwrap()
+                            .released
+                            .contains_key(&port)
+                        {
+                            continue;
+                        }
+                        port
+                    }
+                    None => released.next()?,
+                },
+            };
+            if !self.allocator.is_in_use(port) && (self.accept)(port) {
+                return Some(port);
+            }
+        }
+    }
+}
+
+/// Every offset into a range of `len` ports exactly once, starting at `first`
+/// and advancing by `step`, which shares no factor with `len`.
+struct Walk {
+    len: u32,
+    first: u32,
+    step: u32,
+    taken: u32,
+}
+
+impl Walk {
+    fn new(len: u32, first: u32, step: u32) -> Self {
+        Self {
+            len,
+            first,
+            step,
+            taken: 0,
+        }
+    }
+
+    /// A walk in a random order, without listing the range.
+    fn random(len: u32) -> Self {
+        let step = loop {
+            let step = fastrand::u32(1..=len);
+            if gcd(step, len) == 1 {
+                break step;
+            }
+        };
+        Self::new(len, fastrand::u32(0..len), step)
+    }
+}
+
+impl Iterator for Walk {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        if self.taken == self.len {
+            return None;
+        }
+        let offset = (u64::from(self.first) + u64::from(self.taken) * u64::from(self.step))
+            % u64::from(self.len);
+        self.taken += 1;
+        Some(offset as u16)
+    }
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// A port held by a tunnel, released when dropped.
+pub struct PortLease {
+    allocator: Arc<PortAllocator>,
+    port: u16,
+}
+
+impl PortLease {
+    /// The leased port.
+    #[must_use]
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl fmt::Debug for PortLease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PortLease")
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        self.allocator.release(self.port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocator(strategy: AllocationStrategy) -> Arc<PortAllocator> {
+        Arc::new(PortAllocator::new(9000..=9002, strategy))
+    }
+
+    fn candidates(allocator: &PortAllocator, accept: impl Fn(u16) -> bool) -> Vec<u16> {
+        allocator.candidates(accept).collect()
+    }
+
+    /// Claim the first candidate, as the server does when binding succeeds.
+    fn allocate(allocator: &Arc<PortAllocator>) -> Option<PortLease> {
+        allocator
+            .candidates(|_| true)
+            .find_map(|port| allocator.claim(port))
+    }
+
+    #[test]
+    fn exhausts_small_range() {
+        let allocator = allocator(AllocationStrategy::Random);
+        let leases: Vec<PortLease> = (0..3).map(|_| allocate(&allocator).unwrap()).collect();
+        let mut ports: Vec<u16> = leases.iter().map(PortLease::port).collect();
+        ports.sort_unstable();
+        assert_eq!(ports, [9000, 9001, 9002]);
+
+        // The range is full: no candidates at all, rather than failed guesses.
+        assert_eq!(allocator.candidates(|_| true).next(), None);
+        assert!(allocator.claim(9001).is_none());
+
+        drop(leases);
+        assert_eq!(allocator.in_use(), 0);
+        assert!(allocator.claim(9003).is_none(), "outside the range");
+    }
+
+    #[test]
+    fn sequential_wraps_around() {
+        let allocator = allocator(AllocationStrategy::Sequential);
+        let first = allocate(&allocator).unwrap();
+        let second = allocate(&allocator).unwrap();
+        assert_eq!((first.port(), second.port()), (9000, 9001));
+        drop(first);
+
+        assert_eq!(candidates(&allocator, |_| true), [9002, 9000]);
+        let third = allocate(&allocator).unwrap();
+        assert_eq!(third.port(), 9002);
+        assert_eq!(allocate(&allocator).unwrap().port(), 9000);
+    }
+
+    #[test]
+    fn lru_reuses_oldest_release() {
+        let allocator = allocator(AllocationStrategy::LeastRecentlyUsed);
+        let leases: Vec<PortLease> = (0..3).map(|_| allocate(&allocator).unwrap()).collect();
+        let [a, b, c] = <[PortLease; 3]>::try_from(leases).unwrap();
+        drop(b);
+        drop(c);
+        drop(a);
+        assert_eq!(candidates(&allocator, |_| true), [9001, 9002, 9000]);
+        assert_eq!(candidates(&allocator, |port| port != 9001), [9002, 9000]);
+
+        // Ports never used come before any released one.
+        let allocator = Arc::new(PortAllocator::new(
+            9000..=9003,
+            AllocationStrategy::LeastRecentlyUsed,
+        ));
+        drop(allocator.claim(9001).unwrap());
+        assert_eq!(candidates(&allocator, |_| true), [9000, 9002, 9003, 9001]);
+    }
+
+    #[test]
+    fn random_visits_every_free_port_once() {
+        let allocator = Arc::new(PortAllocator::new(1024..=65535, AllocationStrategy::Random));
+        let _lease = allocator.claim(2000).unwrap();
+        let mut ports = candidates(&allocator, |_| true);
+        assert!(!ports.contains(&2000));
+        ports.sort_unstable();
+        ports.dedup();
+        assert_eq!(ports.len(), 65535 - 1024);
+    }
+
+    #[test]
+    fn parses_strategies() {
+        for strategy in [
+            AllocationStrategy::Random,
+            AllocationStrategy::Sequential,
+            AllocationStrategy::LeastRecentlyUsed,
+        ] {
+            assert_eq!(
+                strategy.to_string().parse::<AllocationStrategy>().unwrap(),
+                strategy
+            );
+        }
+        assert!("first-fit".parse::<AllocationStrategy>().is_err());
+    }
+}
@@ -0,0 +1,259 @@
+//! Registration of the server with the backend's server registry.
+//!
+//! A server configured with a [`RegistryConfig`] announces itself on startup
+//! with its public host, region, port range and capacity, then reports its
+//! load in periodic heartbeats, so the backend can send new clients to the
+//! least loaded server. On shutdown it removes itself from the registry.
+//!
+//! The backend forgets servers that stop sending heartbeats, so a failed
+//! heartbeat is followed by a fresh registration.
+
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout, Instant, MissedTickBehavior};
+use tracing::{info, warn};
+
+use bore_shared::timeouts::BACKEND_HTTP_TIMEOUT;
+
+use crate::backend::UsageBackend;
+
+/// Default interval of load reports, well within the backend's 60s TTL.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How the server describes itself to the registry.
+#[derive(Debug, Clone)]
+pub struct RegistryConfig {
+    /// Host name or address clients connect to.
+    pub public_host: String,
+
+    /// Region or location of the server, e.g. `eu-north`.
+    pub region: String,
+
+    /// Concurrent tunnels the server is meant to carry, by default one per
+    /// port of its range.
+    pub capacity: Option<u32>,
+
+    /// Bandwidth the server is meant to carry, in megabits per second.
+    pub max_bandwidth_mbps: Option<u32>,
+
+    /// How often load is reported.
+    pub heartbeat_interval: Duration,
+}
+
+impl RegistryConfig {
+    /// Register under this public host and region, with the default capacity
+    /// and heartbeat interval.
+    #[must_use]
+    pub fn new(public_host: impl Into<String>, region: impl Into<String>) -> Self {
+        Self {
+            public_host: public_host.into(),
+            region: region.into(),
+            capacity: None,
+            max_bandwidth_mbps: None,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+        }
+    }
+}
+
+/// A server's entry in the registry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Server ID, as sent with tunnel events.
+    pub id: String,
+    /// Host name or address clients connect to.
+    pub host: String,
+    /// Control port clients connect to.
+    pub port: u16,
+    /// Region or location of the server.
+    pub location: String,
+    /// Lowest port tunnels can get.
+    pub min_port: u16,
+    /// Highest port tunnels can get.
+    pub max_port: u16,
+    /// Concurrent tunnels the server is meant to carry.
+    pub max_concurrent_tunnels: u32,
+    /// Bandwidth the server is meant to carry, in megabits per second.
+    pub max_bandwidth_mbps: Option<u32>,
+    /// Version of the server.
+    pub version: String,
+}
+
+impl ServerInfo {
+    /// Describe a server with this ID and port range.
+    pub(crate) fn new(
+        id: &str,
+        port: u16,
+        ports: &RangeInclusive<u16>,
+        config: &RegistryConfig,
+    ) -> Self {
+        let range = u32::from(*ports.end() - *ports.start()) + 1;
+        Self {
+            id: id.to_string(),
+            host: config.public_host.clone(),
+            port,
+            location: config.region.clone(),
+            min_port: *ports.start(),
+            max_port: *ports.end(),
+            max_concurrent_tunnels: config.capacity.unwrap_or(range),
+            max_bandwidth_mbps: config.max_bandwidth_mbps,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// Load of a server, reported in heartbeats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerLoad {
+    /// Open tunnels.
+    pub current_load: u32,
+    /// Visitor connections being forwarded.
+    pub active_connections: u32,
+    /// Bytes forwarded since the last heartbeat, by connections that ended.
+    pub bytes_transferred: u64,
+    /// Average bandwidth since the last heartbeat, in megabits per second.
+    pub current_bandwidth_mbps: f64,
+}
+
+/// Counters of the server, sampled for every heartbeat.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct LoadSample {
+    /// Open tunnels.
+    pub tunnels: u32,
+    /// Visitor connections being forwarded.
+    pub connections: u32,
+    /// Bytes forwarded since the server started.
+    pub bytes: u64,
+}
+
+impl ServerLoad {
+    /// Load between two samples taken `elapsed` apart.
+    fn between(previous: LoadSample, current: LoadSample, elapsed: Duration) -> Self {
+        let bytes = current.bytes.saturating_sub(previous.bytes);
+        let seconds = elapsed.as_secs_f64();
+        #[allow(clippy::cast_precision_loss)]
+        let mbps = if seconds > 0.0 {
+            bytes as f64 * 8.0 / seconds / 1_000_000.0
+        } else {
+            0.0
+        };
+        Self {
+            current_load: current.tunnels,
+            active_connections: current.connections,
+            bytes_transferred: bytes,
+            current_bandwidth_mbps: mbps,
+        }
+    }
+}
+
+/// The running registration of a server, until it is stopped.
+pub(crate) struct Registration {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Registration {
+    /// Register `info` with the backend and keep reporting the load `sample` returns.
+    pub fn start(
+        backend: Arc<dyn UsageBackend>,
+        info: ServerInfo,
+        every: Duration,
+        sample: impl Fn() -> LoadSample + Send + 'static,
+    ) -> Self {
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(run(backend, info, every, sample, stopped));
+        Self { stop, task }
+    }
+
+    /// Stop reporting, and remove the server from the registry.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+async fn run(
+    backend: Arc<dyn UsageBackend>,
+    info: ServerInfo,
+    every: Duration,
+    sample: impl Fn() -> LoadSample,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let mut ticks = interval(every);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut registered = false;
+    let mut previous = (sample(), Instant::now());
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = &mut stopped => break,
+        }
+        if !registered {
+            match backend.register_server(&info).await {
+                Ok(()) => {
+                    info!(server_id = %info.id, host = %info.host, location = %info.location, "Registered with backend");
+                    registered = true;
+                }
+                Err(err) => warn!(%err, "Failed to register with backend, retrying"),
+            }
+            continue;
+        }
+
+        let current = (sample(), Instant::now());
+        let load = ServerLoad::between(previous.0, current.0, current.1 - previous.1);
+        previous = current;
+        if let Err(err) = backend.report_load(&info.id, &load).await {
+            // The backend may have forgotten the server; announce it again.
+            warn!(%err, "Failed to report load to backend");
+            registered = false;
+        }
+    }
+
+    if registered {
+        match timeout(BACKEND_HTTP_TIMEOUT, backend.deregister_server(&info.id)).await {
+            Ok(Ok(())) => info!(server_id = %info.id, "Deregistered from backend"),
+            Ok(Err(err)) => warn!(%err, "Failed to deregister from backend"),
+            Err(_) => warn!("Timed out deregistering from backend"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_between_samples() {
+        let previous = LoadSample {
+            tunnels: 1,
+            connections: 0,
+            bytes: 1_000,
+        };
+        let current = LoadSample {
+            tunnels: 3,
+            connections: 2,
+            bytes: 2_251_000,
+        };
+        let load = ServerLoad::between(previous, current, Duration::from_secs(2));
+        assert_eq!(load.current_load, 3);
+        assert_eq!(load.active_connections, 2);
+        assert_eq!(load.bytes_transferred, 2_250_000);
+        assert!((load.current_bandwidth_mbps - 9.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn capacity_defaults_to_port_range() {
+        let mut config = RegistryConfig::new("bore.example.com", "eu-north");
+        let info = ServerInfo::new("s1", 7835, &(9000..=9099), &config);
+        assert_eq!(info.max_concurrent_tunnels, 100);
+        assert_eq!((info.min_port, info.max_port), (9000, 9099));
+
+        config.capacity = Some(20);
+        let info = ServerInfo::new("s1", 7835, &(1024..=65535), &config);
+        assert_eq!(info.max_concurrent_tunnels, 20);
+    }
+}
@@ -0,0 +1,153 @@
+//! Ports reserved for particular users.
+//!
+//! A reservations file maps each reserved port to the user owning it:
+//!
+//! ```text
+//! # port  user
+//! 9000    alice
+//! 9001    ci
+//! ```
+//!
+//! Backends can reserve more ports through `reserved_ports` in their validation
+//! responses. Only the owner may bind a reserved port, and reserved ports are
+//! never handed out at random, so a reconnecting client gets the same public
+//! address every time.
+
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+
+/// Reserved ports and their owners.
+#[derive(Debug, Default)]
+pub struct PortReservations {
+    owners: DashMap<u16, String>,
+}
+
+impl PortReservations {
+    /// Load reservations from a file on disk.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read reservations file {}", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("invalid reservations file {}", path.display()))
+    }
+
+    /// Parse the contents of a reservations file.
+    ///
+    /// ```
+    /// use bore_server::reservations::PortReservations;
+    ///
+    /// let reservations = PortReservations::parse("9000 alice\n9001 ci").unwrap();
+    /// assert_eq!(reservations.owner(9000).as_deref(), Some("alice"));
+    /// assert_eq!(reservations.ports_of("ci"), [9001]);
+    /// ```
+    pub fn parse(content: &str) -> Result<Self> {
+        let reservations = Self::default();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (port, user_id) =
+                parse_line(line).with_context(|| format!("line {}", index + 1))?;
+            ensure!(
+                reservations.owners.insert(port, user_id).is_none(),
+                "line {}: duplicate reservation of port {port}",
+                index + 1
+            );
+        }
+        Ok(reservations)
+    }
+
+    /// Reserve a port for a user, unless another user already holds it.
+    ///
+    /// Returns whether the port is now reserved for `user_id`.
+    pub fn reserve(&self, port: u16, user_id: &str) -> bool {
+        match self.owners.entry(port) {
+            Entry::Occupied(entry) => entry.get() == user_id,
+            Entry::Vacant(entry) => {
+                entry.insert(user_id.to_string());
+                true
+            }
+        }
+    }
+
+    /// The user a port is reserved for, if any.
+    #[must_use]
+    pub fn owner(&self, port: u16) -> Option<String> {
+        self.owners.get(&port).map(|owner| owner.clone())
+    }
+
+    /// Whether a port is reserved for anyone.
+    #[must_use]
+    pub fn is_reserved(&self, port: u16) -> bool {
+        self.owners.contains_key(&port)
+    }
+
+    /// Ports reserved for a user, in ascending order.
+    #[must_use]
+    pub fn ports_of(&self, user_id: &str) -> Vec<u16> {
+        let mut ports: Vec<u16> = self
+            .owners
+            .iter()
+            .filter(|entry| entry.value() == user_id)
+            .map(|entry| *entry.key())
+            .collect();
+        ports.sort_unstable();
+        ports
+    }
+
+    /// Number of reserved ports.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.owners.len()
+    }
+
+    /// Whether no ports are reserved.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty()
+    }
+}
+
+fn parse_line(line: &str) -> Result<(u16, String)> {
+    let mut fields = line.split_whitespace();
+    let port = fields.next().context("missing port")?;
+    let port: u16 = port
+        .parse()
+        .with_context(|| format!("invalid port {port}"))?;
+    ensure!(port != 0, "port 0 cannot be reserved");
+    let user_id = fields.next().context("missing user")?;
+    ensure!(
+        fields.next().is_none(),
+        "unexpected text after user {user_id}"
+    );
+    Ok((port, user_id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(PortReservations::parse("9000").is_err());
+        assert!(PortReservations::parse("0 alice").is_err());
+        assert!(PortReservations::parse("70000 alice").is_err());
+        assert!(PortReservations::parse("9000 alice extra").is_err());
+        assert!(PortReservations::parse("9000 alice\n9000 bob").is_err());
+    }
+
+    #[test]
+    fn first_owner_keeps_port() {
+        let reservations = PortReservations::parse("# comment\n9000 alice\n").unwrap();
+        assert!(reservations.reserve(9000, "alice"));
+        assert!(!reservations.reserve(9000, "bob"));
+        assert!(reservations.reserve(9002, "bob"));
+        assert!(reservations.reserve(9001, "bob"));
+        assert_eq!(reservations.ports_of("bob"), [9001, 9002]);
+        assert_eq!(reservations.len(), 3);
+    }
+}
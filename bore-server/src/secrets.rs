@@ -0,0 +1,177 @@
+//! Secrets file for the shared-secret authentication mode.
+//!
+//! Each non-empty line names one accepted secret:
+//!
+//! ```text
+//! # name     secret                              options
+//! ci-2025    6f1d0c9a42e1b7f3d3c5a8e0b1f27d44    expires=2025-12-31T00:00:00Z max_tunnels=3
+//! laptop     0a9b8c7d6e5f40312233445566778899
+//! ```
+//!
+//! Supported options are `expires=TIME`, an RFC 3339 timestamp after which the
+//! secret is rejected, and `max_tunnels=N`. Each secret acts as its own
+//! identity, the user `secret:<name>`, so its tunnels never count against a
+//! user authenticated another way. The name `default` is reserved for the
+//! server's `--secret`.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use bore_shared::auth::DEFAULT_SECRET_NAME;
+use bore_shared::{Authenticator, NamedSecret};
+
+/// Named secrets loaded from a secrets file, with their tunnel limits.
+#[derive(Clone, Default)]
+pub struct SharedSecrets {
+    secrets: Vec<NamedSecret>,
+    max_tunnels: HashMap<String, Option<u32>>,
+}
+
+impl SharedSecrets {
+    /// Load secrets from a file on disk.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read secrets file {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("invalid secrets file {}", path.display()))
+    }
+
+    /// Parse the contents of a secrets file.
+    ///
+    /// ```
+    /// use bore_server::secrets::SharedSecrets;
+    ///
+    /// let secrets = SharedSecrets::parse("ci hunter2 max_tunnels=3\nlaptop swordfish").unwrap();
+    /// assert_eq!(secrets.len(), 2);
+    /// assert_eq!(secrets.max_tunnels("ci"), Some(3));
+    /// assert_eq!(secrets.max_tunnels("laptop"), None);
+    /// ```
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut this = Self::default();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (secret, max_tunnels) =
+                parse_line(line).with_context(|| format!("line {}", index + 1))?;
+            ensure!(
+                this.max_tunnels
+                    .insert(secret.name().to_string(), max_tunnels)
+                    .is_none(),
+                "line {}: duplicate secret name {:?}",
+                index + 1,
+                secret.name()
+            );
+            this.secrets.push(secret);
+        }
+        Ok(this)
+    }
+
+    /// Whether a secret with this name was loaded from the file.
+    #[must_use]
+    pub fn contains(&self, name: &str) -> bool {
+        self.max_tunnels.contains_key(name)
+    }
+
+    /// Concurrent tunnel limit for the named secret, if limited.
+    #[must_use]
+    pub fn max_tunnels(&self, name: &str) -> Option<u32> {
+        self.max_tunnels.get(name).copied().flatten()
+    }
+
+    /// Number of secrets in the file.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.secrets.len()
+    }
+
+    /// Whether the file contained no secrets.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+
+    /// Add these secrets to an authenticator, creating one if needed.
+    #[must_use]
+    pub fn extend_authenticator(&self, auth: Option<Authenticator>) -> Authenticator {
+        let mut auth = auth.unwrap_or_else(|| Authenticator::from_secrets([]));
+        for secret in &self.secrets {
+            auth.add(secret.clone());
+        }
+        auth
+    }
+}
+
+fn parse_line(line: &str) -> Result<(NamedSecret, Option<u32>)> {
+    let mut fields = line.split_whitespace();
+    let name = fields.next().context("missing secret name")?;
+    ensure!(
+        name != DEFAULT_SECRET_NAME,
+        "secret name {name:?} is reserved for --secret"
+    );
+    let value = fields.next().context("missing secret value")?;
+
+    let mut secret = NamedSecret::new(name, value);
+    let mut max_tunnels = None;
+    for option in fields {
+        match option.split_once('=') {
+            Some(("expires", value)) => {
+                let expires_at = humantime::parse_rfc3339_weak(value)
+                    .with_context(|| format!("invalid expiry time {value:?}"))?;
+                secret = secret.expires_at(expires_at);
+            }
+            Some(("max_tunnels", value)) => {
+                max_tunnels = Some(value.parse().context("invalid max_tunnels")?);
+            }
+            _ => bail!("unknown option {option:?}"),
+        }
+    }
+    Ok((secret, max_tunnels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+    use uuid::Uuid;
+
+    #[test]
+    fn expired_secrets_are_rejected() {
+        let secrets = SharedSecrets::parse(
+            "old s1 expires=2000-01-01T00:00:00Z\nnew s2 expires=2999-01-01T00:00:00Z",
+        )
+        .unwrap();
+        let auth = secrets.extend_authenticator(None);
+        let challenge = Uuid::new_v4();
+
+        let reply = Authenticator::new("s1").answer(&challenge);
+        let err = auth.check(&challenge, &reply).unwrap_err();
+        assert!(err.to_string().contains("expired"), "{err}");
+
+        let reply = Authenticator::new("s2").answer(&challenge);
+        assert_eq!(auth.check(&challenge, &reply).unwrap(), "new");
+        assert!(secrets.secrets[1].is_active(SystemTime::now() + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn extends_existing_authenticator() {
+        let secrets = SharedSecrets::parse("ci s2").unwrap();
+        let auth = secrets.extend_authenticator(Some(Authenticator::new("s1")));
+        let challenge = Uuid::new_v4();
+
+        let reply = Authenticator::new("s1").answer(&challenge);
+        assert_eq!(auth.check(&challenge, &reply).unwrap(), "default");
+        let reply = Authenticator::new("s2").answer(&challenge);
+        assert_eq!(auth.check(&challenge, &reply).unwrap(), "ci");
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert!(SharedSecrets::parse("lonely").is_err());
+        assert!(SharedSecrets::parse("ci s1 expires=tomorrow").is_err());
+        assert!(SharedSecrets::parse("ci s1 color=blue").is_err());
+        assert!(SharedSecrets::parse("ci s1\nci s2").is_err());
+        assert!(SharedSecrets::parse("default s1").is_err());
+    }
+}
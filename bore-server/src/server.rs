@@ -1,67 +1,168 @@
 //! Server implementation for the `bore` service.
 
-use std::net::{IpAddr, Ipv4Addr};
+use std::collections::{hash_map::Entry, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::{io, ops::RangeInclusive, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use dashmap::DashMap;
+use futures_util::future::select_all;
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{sleep, timeout};
-use tracing::{error, info, info_span, warn, Instrument};
+use tokio::sync::{broadcast, watch, Mutex};
+use tokio::time::{interval, interval_at, Instant, Interval};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
-use bore_shared::{Authenticator, ClientMessage, Delimited, ServerMessage, CONTROL_PORT};
-
-use crate::backend::BackendClient;
-
-/// Timeout for polling new connections while allowing heartbeat checks.
-const HEARTBEAT_POLL_TIMEOUT: Duration = Duration::from_millis(500);
+use bore_shared::{
+    Authenticator, ClientMessage, Delimited, ErrorCode, ServerError, ServerMessage, TunnelInfo,
+    CONTROL_PORT, MAX_BINARY_FRAME_LENGTH,
+};
+
+use crate::access::{CloseReason, Counted};
+use crate::admin::{self, AdminState};
+use crate::auth::{
+    AuthChain, AuthProvider, BackendProvider, Credential, Identity, JwtProvider, KeyFileProvider,
+    NoAuth, PeerInfo, SharedSecretProvider,
+};
+use crate::backend::UsageBackend;
+use crate::cache::{self, CacheConfig, ValidationCache};
+use crate::commands::{BackendCommand, CommandStream, Commands, Revocations};
+use crate::groups::{BalanceStrategy, Membership, PendingConnection, TunnelGroup};
+use crate::jwt::TokenVerifier;
+use crate::keys::AuthorizedKeys;
+use crate::listener::MultiListener;
+use crate::outbox::{Outbox, OutboxEvent};
+use crate::ports::{AllocationStrategy, PortAllocator, PortLease};
+use crate::registry::{LoadSample, Registration, RegistryConfig, ServerInfo};
+use crate::reservations::PortReservations;
+use crate::secrets::SharedSecrets;
+use crate::state::{keep_leases, Leases, MemoryState, SharedState, DEFAULT_LEASE_TTL};
+
+/// Interval of heartbeats checking that the client is still reachable.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Ports tried before a request for any port fails.
+const MAX_PORT_ATTEMPTS: usize = 150;
 
 /// State structure for the server.
 pub struct Server {
-    /// Range of TCP ports that can be forwarded.
-    port_range: RangeInclusive<u16>,
+    /// Allocator of the TCP ports that can be forwarded.
+    ports: Arc<PortAllocator>,
+
+    /// Ports reserved for particular users.
+    reservations: PortReservations,
 
     /// Optional secret used to authenticate clients (deprecated).
     auth: Option<Authenticator>,
 
+    /// Named secrets loaded from a secrets file, each acting as its own identity.
+    secrets: SharedSecrets,
+
+    /// Optional set of public keys that may authenticate with a signature.
+    authorized_keys: Option<AuthorizedKeys>,
+
     /// Backend API client for user authentication and usage tracking.
-    backend: Arc<BackendClient>,
+    backend: Arc<dyn UsageBackend>,
+
+    /// Queue of lifecycle and usage events waiting for delivery to the backend.
+    outbox: Arc<Outbox>,
+
+    /// Optional verifier for signed tunnel tokens, checked without calling the backend.
+    token_verifier: Option<TokenVerifier>,
+
+    /// Whether to ask the backend if a locally verified token has been revoked.
+    check_token_revocation: bool,
+
+    /// Provider authenticating clients, replacing the one built from the settings above.
+    auth_provider: Option<Arc<dyn AuthProvider>>,
+
+    /// Optional cache of backend API key validations.
+    validation_cache: Option<Arc<ValidationCache>>,
+
+    /// How often the credentials of running sessions are checked again, if at all.
+    revalidate_interval: Option<Duration>,
+
+    /// Stream of commands from the backend, subscribed to by [`Server::listen`].
+    command_stream: Option<CommandStream>,
+
+    /// Commands for running sessions, each of which checks whether it applies.
+    commands: Commands,
+
+    /// Users and instances revoked by the backend, refused when they reconnect.
+    revocations: Arc<Revocations>,
+
+    /// Address of the admin API, if enabled.
+    admin_addr: Option<SocketAddr>,
+
+    /// Bearer token required by the admin API.
+    admin_token: Option<String>,
+
+    /// Address serving only the health routes of the admin API, if enabled.
+    health_addr: Option<SocketAddr>,
 
     /// Server ID for multi-server deployments.
     server_id: String,
 
+    /// How the server announces itself to the backend's registry, if it does.
+    registry: Option<RegistryConfig>,
+
+    /// Forwarded connections and bytes, reported to the registry.
+    traffic: Traffic,
+
+    /// Whether the control listener is bound and accepting, for readiness checks.
+    listening: watch::Sender<bool>,
+
+    /// Set once the server shuts down, closing every session.
+    draining: watch::Sender<bool>,
+
     /// Concurrent map of IDs to incoming connections.
-    conns: Arc<DashMap<Uuid, TcpStream>>,
+    conns: Arc<DashMap<Uuid, PendingConnection>>,
+
+    /// Shared tunnels that further clients can join.
+    groups: Mutex<Vec<Arc<TunnelGroup>>>,
 
-    /// Concurrent map of user IDs to their active tunnel count.
+    /// How shared tunnels spread connections across their clients.
+    balance: BalanceStrategy,
+
+    /// Largest binary frame accepted from clients that negotiate them.
+    max_frame_length: usize,
+
+    /// Concurrent map of user IDs to their active tunnel count on this server.
     user_tunnels: Arc<DashMap<String, u32>>,
 
-    /// IP address where the control server will bind to.
-    bind_addr: IpAddr,
+    /// Tunnel counts and port reservations shared with the rest of the cluster.
+    state: Arc<dyn SharedState>,
+
+    /// How long leases in the shared state last without being renewed.
+    lease_ttl: Duration,
 
-    /// IP address where tunnels will listen on.
-    bind_tunnels: IpAddr,
+    /// Leases this server holds in the shared state.
+    leases: Arc<Leases>,
+
+    /// IP addresses where the control server will bind to.
+    bind_addrs: Vec<IpAddr>,
+
+    /// IP addresses where tunnels will listen on.
+    bind_tunnels: Vec<IpAddr>,
 }
 
 impl Server {
-    /// Create a new server with backend API integration.
+    /// Create a new server reporting to `backend`.
     ///
-    /// If `backend_url` is None, the server will fall back to using the
-    /// optional `secret` for authentication (deprecated mode).
+    /// With a [`NoopBackend`](crate::backend::NoopBackend), the server falls back
+    /// to using the optional `secret` for authentication (deprecated mode).
     pub fn new(
         port_range: RangeInclusive<u16>,
         secret: Option<&str>,
-        backend_url: Option<String>,
-        backend_api_key: Option<String>,
+        backend: impl UsageBackend + 'static,
         server_id: String,
     ) -> Self {
         assert!(!port_range.is_empty(), "must provide at least one port");
 
-        let backend = BackendClient::new(backend_url.clone(), backend_api_key.clone());
-
-        if backend_url.is_some() {
+        if backend.is_enabled() {
             info!("Backend API enabled - using individual user authentication");
         } else if secret.is_some() {
             warn!("Running in legacy mode with shared secret (not recommended for production)");
@@ -69,41 +170,323 @@ impl Server {
             warn!("Running without authentication - all connections allowed!");
         }
 
+        let revocations = Arc::new(Revocations::new());
         Server {
-            port_range,
+            ports: Arc::new(PortAllocator::new(
+                port_range,
+                AllocationStrategy::default(),
+            )),
+            reservations: PortReservations::default(),
             conns: Arc::new(DashMap::new()),
+            groups: Mutex::new(Vec::new()),
+            balance: BalanceStrategy::default(),
+            max_frame_length: MAX_BINARY_FRAME_LENGTH,
             user_tunnels: Arc::new(DashMap::new()),
+            state: Arc::new(MemoryState::new()),
+            lease_ttl: DEFAULT_LEASE_TTL,
+            leases: Arc::new(Leases::default()),
             auth: secret.map(Authenticator::new),
+            secrets: SharedSecrets::default(),
+            authorized_keys: None,
             backend: Arc::new(backend),
+            outbox: Arc::new(Outbox::in_memory()),
+            token_verifier: None,
+            check_token_revocation: false,
+            auth_provider: None,
+            validation_cache: None,
+            revalidate_interval: None,
+            command_stream: None,
+            commands: Commands::new(Arc::clone(&revocations)),
+            revocations,
+            admin_addr: None,
+            admin_token: None,
+            health_addr: None,
             server_id,
-            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-            bind_tunnels: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            registry: None,
+            traffic: Traffic::default(),
+            listening: watch::Sender::new(false),
+            draining: watch::Sender::new(false),
+            bind_addrs: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            bind_tunnels: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
         }
     }
 
-    /// Set the IP address where tunnels will listen on.
-    pub fn set_bind_addr(&mut self, bind_addr: IpAddr) {
-        self.bind_addr = bind_addr;
+    /// Accept any of these named secrets, in addition to the `secret` passed to [`Server::new`].
+    ///
+    /// Tunnels are counted against the name of the secret a client used, so secrets
+    /// can be rotated one client at a time and each carries its own limit.
+    pub fn set_secrets(&mut self, secrets: SharedSecrets) {
+        info!(secrets = secrets.len(), "Named shared secrets enabled");
+        self.auth = Some(secrets.extend_authenticator(self.auth.take()));
+        self.secrets = secrets;
+    }
+
+    /// Allow clients holding one of these Ed25519 keys to authenticate.
+    ///
+    /// Each key carries its own user ID and limits, so small self-hosted setups
+    /// can have per-user accounts without running the backend.
+    pub fn set_authorized_keys(&mut self, keys: AuthorizedKeys) {
+        info!(keys = keys.len(), "Public-key authentication enabled");
+        self.authorized_keys = Some(keys);
+    }
+
+    /// Hand out ports to clients asking for any port in this order.
+    pub fn set_port_strategy(&mut self, strategy: AllocationStrategy) {
+        let range = self.ports.range().clone();
+        self.ports = Arc::new(PortAllocator::new(range, strategy));
+    }
+
+    /// Reserve these ports for their owners.
+    ///
+    /// Reserved ports are only bound for their owner, who gets them back first
+    /// when asking for any port. Backends can reserve further ports per user.
+    pub fn set_port_reservations(&mut self, reservations: PortReservations) {
+        info!(ports = reservations.len(), "Port reservations enabled");
+        self.reservations = reservations;
+    }
+
+    /// Spread connections to shared tunnels across their clients this way.
+    pub fn set_balance_strategy(&mut self, strategy: BalanceStrategy) {
+        self.balance = strategy;
+    }
+
+    /// Accept binary frames up to `max_frame_length` bytes from clients that
+    /// negotiate them, or less if the client offers less.
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.max_frame_length = max_frame_length;
+    }
+
+    /// Verify signed `tk_` tunnel tokens locally with these keys.
+    ///
+    /// Other credentials still go to the backend. When `check_revocation` is set,
+    /// tokens carrying a `jti` are also checked against the backend's revocation
+    /// list; if that check fails the token is accepted, since it is already signed
+    /// and unexpired.
+    pub fn set_token_verifier(&mut self, verifier: TokenVerifier, check_revocation: bool) {
+        info!(
+            keys = verifier.len(),
+            check_revocation, "Local tunnel token verification enabled"
+        );
+        self.token_verifier = Some(verifier);
+        self.check_token_revocation = check_revocation;
+    }
+
+    /// Cache backend API key validations, so reconnecting clients do not hit the
+    /// backend every time.
+    pub fn set_validation_cache(&mut self, config: CacheConfig) {
+        info!(
+            positive_ttl = ?config.positive_ttl,
+            negative_ttl = ?config.negative_ttl,
+            stale_if_error = ?config.stale_if_error,
+            "API key validation cache enabled"
+        );
+        let cache =
+            Arc::new(ValidationCache::new(config).with_revocations(Arc::clone(&self.revocations)));
+        self.commands.set_cache(Arc::clone(&cache));
+        self.validation_cache = Some(cache);
+    }
+
+    /// Check the credential of every running session again at this interval,
+    /// closing sessions whose key was revoked or whose usage is no longer allowed.
+    ///
+    /// Checks go through the same providers as new connections, so with a
+    /// validation cache a revocation takes effect within the cache's TTL. A
+    /// session is kept while the backend is unavailable.
+    pub fn set_revalidate_interval(&mut self, interval: Duration) {
+        info!(?interval, "Session revalidation enabled");
+        self.revalidate_interval = Some(interval);
+    }
+
+    /// Follow the backend's stream of commands, applying revocations to running
+    /// sessions as soon as they arrive. Revoked users and instances are refused
+    /// when they reconnect, until the credentials they hold expire.
+    pub fn set_command_stream(&mut self, stream: CommandStream) {
+        self.command_stream = Some(stream);
+    }
+
+    /// Apply a command to the running sessions it matches, as if the backend
+    /// had sent it.
+    pub fn apply_command(&self, command: BackendCommand) {
+        self.commands.send(command);
+    }
+
+    /// Enforce tunnel limits and port reservations across the cluster through
+    /// this shared state, instead of on this server alone.
+    pub fn set_shared_state(&mut self, state: impl SharedState + 'static) {
+        info!("Shared state enabled");
+        self.state = Arc::new(state);
+    }
+
+    /// Hold leases in the shared state for this long without renewing them, so
+    /// a crashed server's tunnels stop counting after at most this long.
+    pub fn set_lease_ttl(&mut self, ttl: Duration) {
+        assert!(!ttl.is_zero(), "lease TTL must not be zero");
+        self.lease_ttl = ttl;
+    }
+
+    /// Register with the backend's server registry on startup, report load
+    /// periodically, and deregister when [`Server::listen_until`] stops.
+    pub fn set_registry(&mut self, config: RegistryConfig) {
+        info!(
+            public_host = %config.public_host,
+            region = %config.region,
+            "Server registration enabled"
+        );
+        self.registry = Some(config);
+    }
+
+    /// Queue backend events in this outbox, e.g. one persisted with [`Outbox::open`]
+    /// so that events survive restarts.
+    pub fn set_outbox(&mut self, outbox: Outbox) {
+        self.outbox = Arc::new(outbox);
+    }
+
+    /// Serve the admin API on this address, requiring `token` as a bearer token if set.
+    pub fn set_admin(&mut self, addr: SocketAddr, token: Option<String>) {
+        if token.is_none() {
+            warn!(%addr, "Admin API enabled without a token");
+        }
+        self.admin_addr = Some(addr);
+        self.admin_token = token;
+    }
+
+    /// Serve only `/healthz` and `/readyz` on this address, for probes of servers
+    /// without the admin API.
+    pub fn set_health_addr(&mut self, addr: SocketAddr) {
+        self.health_addr = Some(addr);
     }
 
     /// Set the IP address where the control server will bind to.
+    pub fn set_bind_addr(&mut self, bind_addr: IpAddr) {
+        self.set_bind_addrs(vec![bind_addr]);
+    }
+
+    /// Bind the control server on each of these IP addresses, e.g. `0.0.0.0` and `::`.
+    pub fn set_bind_addrs(&mut self, bind_addrs: Vec<IpAddr>) {
+        assert!(!bind_addrs.is_empty(), "must provide at least one address");
+        self.bind_addrs = bind_addrs;
+    }
+
+    /// Set the IP address where tunnels will listen on.
     pub fn set_bind_tunnels(&mut self, bind_tunnels: IpAddr) {
+        self.set_bind_tunnels_all(vec![bind_tunnels]);
+    }
+
+    /// Listen for every tunnel on each of these IP addresses, all sharing its public port.
+    pub fn set_bind_tunnels_all(&mut self, bind_tunnels: Vec<IpAddr>) {
+        assert!(
+            !bind_tunnels.is_empty(),
+            "must provide at least one address"
+        );
         self.bind_tunnels = bind_tunnels;
     }
 
+    /// Authenticate clients with this provider instead of the built-in methods.
+    ///
+    /// The secrets, keys, token verifier and backend configured on the server are
+    /// then ignored for authentication. Combine them with custom providers through
+    /// an [`AuthChain`] of the providers in [`crate::auth`].
+    pub fn set_auth_provider(&mut self, provider: impl AuthProvider + 'static) {
+        self.auth_provider = Some(Arc::new(provider));
+    }
+
     /// Start the server, listening for new connections.
     pub async fn listen(self) -> Result<()> {
+        self.listen_until(std::future::pending()).await
+    }
+
+    /// Start the server, listening for new connections until `shutdown` completes.
+    ///
+    /// On shutdown, the server stops accepting connections, closes running
+    /// sessions so their clients can move to another server, and leaves the
+    /// backend's registry.
+    pub async fn listen_until(mut self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        if self.auth_provider.is_none() {
+            self.auth_provider = Some(Arc::new(self.default_auth_chain()));
+        }
+        if let Some(stream) = self.command_stream.take() {
+            tokio::spawn(stream.run(self.commands.clone()));
+        }
         let this = Arc::new(self);
-        let listener = TcpListener::bind((this.bind_addr, CONTROL_PORT)).await?;
-        info!(addr = ?this.bind_addr, "server listening");
+        if this.backend.is_enabled() {
+            tokio::spawn(Arc::clone(&this.outbox).run(Arc::clone(&this.backend)));
+        }
+        let admin = [(this.admin_addr, false), (this.health_addr, true)];
+        for (addr, health_only) in admin {
+            let Some(addr) = addr else { continue };
+            let state = AdminState {
+                token: this.admin_token.clone(),
+                cache: this.validation_cache.clone(),
+                backend: Arc::clone(&this.backend),
+                outbox: Arc::clone(&this.outbox),
+                listening: this.listening.subscribe(),
+                draining: this.draining.subscribe(),
+                backend_required: this.auth_provider().uses_backend(),
+                health_only,
+            };
+            let listener = TcpListener::bind(addr).await?;
+            tokio::spawn(async move {
+                if let Err(err) = admin::serve(listener, Arc::new(state)).await {
+                    error!(%err, "admin API stopped");
+                }
+            });
+        }
+        let listener = MultiListener::bind(&this.bind_addrs, CONTROL_PORT)?;
+        info!(addrs = ?listener.local_addrs()?, "server listening");
+        this.listening.send_replace(true);
+
+        let keeper = tokio::spawn(keep_leases(
+            Arc::clone(&this.state),
+            Arc::clone(&this.leases),
+            this.lease_ttl,
+        ));
+        let sweeper = this
+            .validation_cache
+            .clone()
+            .map(|validations| tokio::spawn(cache::sweep(validations)));
+        let registration = this.registry.as_ref().map(|config| {
+            let info = ServerInfo::new(&this.server_id, CONTROL_PORT, this.ports.range(), config);
+            let sampled = Arc::clone(&this);
+            Registration::start(
+                Arc::clone(&this.backend),
+                info,
+                config.heartbeat_interval,
+                move || sampled.load_sample(),
+            )
+        });
+        let result = this.accept_until(&listener, shutdown).await;
+        this.listening.send_replace(false);
+        this.draining.send_replace(true);
+        if let Some(registration) = registration {
+            registration.stop().await;
+        }
+        keeper.abort();
+        if let Some(sweeper) = sweeper {
+            sweeper.abort();
+        }
+        result
+    }
 
+    /// Accept control connections until `shutdown` completes or accepting fails.
+    async fn accept_until(
+        self: &Arc<Self>,
+        listener: &MultiListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        tokio::pin!(shutdown);
         loop {
-            let (stream, addr) = listener.accept().await?;
-            let this = Arc::clone(&this);
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                () = &mut shutdown => {
+                    info!("server shutting down");
+                    return Ok(());
+                }
+            };
+            let this = Arc::clone(self);
             tokio::spawn(
                 async move {
                     info!("incoming connection");
-                    if let Err(err) = this.handle_connection(stream).await {
+                    if let Err(err) = this.handle_connection(stream, PeerInfo { addr }).await {
                         warn!(%err, "connection exited with error");
                     } else {
                         info!("connection exited");
@@ -114,58 +497,193 @@ impl Server {
         }
     }
 
-    async fn create_listener(&self, port: u16) -> Result<TcpListener, &'static str> {
+    /// Current load, for the registry.
+    fn load_sample(&self) -> LoadSample {
+        LoadSample {
+            tunnels: self.user_tunnels.iter().map(|entry| *entry.value()).sum(),
+            connections: self.traffic.connections.load(Ordering::Relaxed),
+            bytes: self.traffic.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Open a tunnel for `request`, or add the client to the shared tunnel it asks for.
+    async fn open_tunnel(
+        &self,
+        request: TunnelRequest,
+        user_id: &str,
+        allowed_ports: &[RangeInclusive<u16>],
+    ) -> Result<Membership, ServerError> {
+        if !request.shared {
+            return self
+                .start_group(None, request.port, user_id, allowed_ports)
+                .await;
+        }
+        if request.port == 0 && request.group.is_none() {
+            return Err(ServerError::new(
+                ErrorCode::InvalidRequest,
+                "a shared tunnel needs a port or a group name",
+            ));
+        }
+
+        // Held until a new group is registered, so clients joining at once share it.
+        let mut groups = self.groups.lock().await;
+        let existing = groups.iter().find(|group| {
+            !group.is_closed()
+                && match &request.group {
+                    Some(name) => group.name() == Some(name.as_str()),
+                    None => group.port() == request.port,
+                }
+        });
+        if let Some(group) = existing {
+            if group.owner() != user_id {
+                return Err(ServerError::new(
+                    ErrorCode::PortNotAllowed,
+                    "tunnel group belongs to another user",
+                ));
+            }
+            if request.port > 0 && request.port != group.port() {
+                return Err(ServerError::new(
+                    ErrorCode::InvalidRequest,
+                    "tunnel group listens on another port",
+                ));
+            }
+            self.check_port(group.port(), user_id, allowed_ports)
+                .await?;
+            if let Some(membership) = group.join() {
+                info!(user_id, port = group.port(), "Joined shared tunnel");
+                return Ok(membership);
+            }
+        }
+
+        let membership = self
+            .start_group(request.group, request.port, user_id, allowed_ports)
+            .await?;
+        groups.push(Arc::clone(membership.group()));
+        Ok(membership)
+    }
+
+    /// Bind a new tunnel and make the client its first member.
+    async fn start_group(
+        &self,
+        name: Option<String>,
+        port: u16,
+        user_id: &str,
+        allowed_ports: &[RangeInclusive<u16>],
+    ) -> Result<Membership, ServerError> {
+        let (listener, lease) = self.create_listener(port, user_id, allowed_ports).await?;
+        let group = TunnelGroup::new(name, user_id, lease.port(), self.balance);
+        let membership = group.join().expect("a new group is open");
+        group.spawn(listener, lease, Arc::clone(&self.conns));
+        Ok(membership)
+    }
+
+    /// Check that the user may forward this explicitly requested port.
+    async fn check_port(
+        &self,
+        port: u16,
+        user_id: &str,
+        allowed_ports: &[RangeInclusive<u16>],
+    ) -> Result<(), ServerError> {
+        let refuse = |message| Err(ServerError::new(ErrorCode::PortNotAllowed, message));
+        if !self.ports.range().contains(&port) {
+            return refuse("client port number not in allowed range");
+        }
+        let owner = match self.reservations.owner(port) {
+            Some(owner) => Some(owner),
+            // Another server of the cluster may have reserved it.
+            None => self.shared_port_owner(port).await,
+        };
+        match owner {
+            Some(owner) if owner != user_id => refuse("port is reserved by another user"),
+            // A reservation grants the port even outside the key's allowed ranges.
+            Some(_) => Ok(()),
+            None if !allowed(allowed_ports, port) => {
+                refuse("client port number not allowed for this key")
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The user another server of the cluster reserved `port` for, if any. If the
+    /// shared state is unavailable, only this server's reservations count.
+    async fn shared_port_owner(&self, port: u16) -> Option<String> {
+        self.state.port_owner(port).await.unwrap_or_else(|err| {
+            warn!(%err, port, "Failed to look up port reservation in shared state");
+            None
+        })
+    }
+
+    /// Bind a tunnel listener, holding its port until the returned lease is dropped.
+    async fn create_listener(
+        &self,
+        port: u16,
+        user_id: &str,
+        allowed_ports: &[RangeInclusive<u16>],
+    ) -> Result<(MultiListener, PortLease), ServerError> {
         let try_bind = |port: u16| async move {
-            TcpListener::bind((self.bind_tunnels, port))
-                .await
-                .map_err(|err| match err.kind() {
-                    io::ErrorKind::AddrInUse => "port already in use",
-                    io::ErrorKind::PermissionDenied => "permission denied",
-                    _ => "failed to bind to port",
-                })
+            MultiListener::bind(&self.bind_tunnels, port).map_err(|err| match err.kind() {
+                io::ErrorKind::AddrInUse => {
+                    ServerError::new(ErrorCode::PortInUse, "port already in use")
+                }
+                io::ErrorKind::PermissionDenied => {
+                    ServerError::new(ErrorCode::PortNotAllowed, "permission denied")
+                }
+                _ => ServerError::new(ErrorCode::Internal, "failed to bind to port"),
+            })
         };
+        let no_ports =
+            || ServerError::new(ErrorCode::NoPortsAvailable, "no available ports in range");
         if port > 0 {
             // Client requests a specific port number.
-            if !self.port_range.contains(&port) {
-                return Err("client port number not in allowed range");
-            }
-            try_bind(port).await
+            self.check_port(port, user_id, allowed_ports).await?;
+            let lease = self
+                .ports
+                .claim(port)
+                .ok_or_else(|| ServerError::new(ErrorCode::PortInUse, "port already in use"))?;
+            Ok((try_bind(port).await?, lease))
         } else {
+            // The user's own reservations come first, so reconnecting clients keep
+            // their public address.
+            for port in self.reservations.ports_of(user_id) {
+                if let Some(lease) = self.ports.claim(port) {
+                    if let Ok(listener) = try_bind(port).await {
+                        return Ok((listener, lease));
+                    }
+                }
+            }
+
             // Client requests any available port in range.
             //
-            // We use a probabilistic approach: try binding to 150 random port numbers.
-            // This value is derived from probability theory to ensure high success rates:
-            //
-            // To find a free port with probability at least 1-δ, when ε proportion of the
-            // ports are currently available, it suffices to check approximately -2 ln(δ) / ε
-            // independently and uniformly chosen ports (up to a second-order term in ε).
-            //
-            // With 150 attempts, we achieve:
-            // - 99.999% success rate (δ=0.00001)
-            // - When 85% of ports are available (ε=0.15)
-            //
-            // This approach is more efficient than sequential scanning and distributes
-            // load evenly across the port range.
-            for _ in 0..150 {
-                // Generate a random port within the allowed range
-                let port = fastrand::u16(self.port_range.clone());
-                match try_bind(port).await {
-                    Ok(listener) => return Ok(listener),
-                    Err(_) => continue, // Port unavailable, try next random port
+            // The allocator offers the ports no tunnel holds, in the order of its
+            // strategy. Each one is bound in turn, since other processes may hold it,
+            // until one succeeds or `MAX_PORT_ATTEMPTS` have been tried. Ports outside
+            // the key's allowed ranges and ports reserved for anyone, on this server
+            // or another of the cluster, are skipped.
+            let candidates = self.ports.candidates(|port| {
+                allowed(allowed_ports, port) && !self.reservations.is_reserved(port)
+            });
+            for port in candidates.take(MAX_PORT_ATTEMPTS) {
+                // Another tunnel may have claimed it since it was offered.
+                let Some(lease) = self.ports.claim(port) else {
+                    continue;
+                };
+                if self.shared_port_owner(port).await.is_some() {
+                    continue;
+                }
+                if let Ok(listener) = try_bind(port).await {
+                    return Ok((listener, lease));
                 }
             }
-            Err("failed to find an available port")
+            Err(no_ports())
         }
     }
 
-    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
-        let mut stream = Delimited::new(stream);
+    async fn handle_connection(&self, stream: TcpStream, peer: PeerInfo) -> Result<()> {
+        let mut stream = Delimited::accept(stream, self.max_frame_length).await?;
 
-        // Authentication: Try backend API first, then fall back to legacy auth
-        let user_id: String;
-        let max_tunnels: u32;
-        let requested_port: u16;
-        let mut instance_id: Option<String> = None;
+        let credential: Credential;
+        let identity: Identity;
+        let request: TunnelRequest;
 
         // First, expect either Authenticate (with API key), Hello (legacy), or Accept (forwarding)
         let first_msg = stream.recv_timeout().await?;
@@ -177,7 +695,15 @@ impl Server {
                 // Bore client sends Accept(id) → Server matches ID and forwards data
                 info!(%id, "forwarding connection");
                 match self.conns.remove(&id) {
-                    Some((_, mut stream2)) => {
+                    Some((_, pending)) => {
+                        // The load guard counts the connection until forwarding ends.
+                        let PendingConnection {
+                            stream: stream2,
+                            load,
+                            visit,
+                        } = pending;
+                        let mut stream2 = Counted::new(stream2);
+
                         // stream = bore client connection (just received Accept message)
                         // stream2 = external client connection (waiting to be forwarded)
 
@@ -187,10 +713,33 @@ impl Server {
 
                         // Forward any buffered data from bore client to external client
                         // Usually empty, but handles edge cases where data arrives before Accept
-                        stream2.write_all(&parts.read_buf).await?;
-
-                        // Begin bidirectional forwarding: external client ↔ bore client ↔ local service
-                        tokio::io::copy_bidirectional(&mut parts.io, &mut stream2).await?;
+                        let forwarded = match stream2.write_all(&parts.read_buf).await {
+                            // Begin bidirectional forwarding: external client ↔ bore client ↔ local service
+                            Ok(()) => {
+                                let _forwarding = self.traffic.connection();
+                                tokio::io::copy_bidirectional(&mut parts.io, &mut stream2)
+                                    .await
+                                    .map(|_| ())
+                            }
+                            Err(err) => Err(err),
+                        };
+
+                        // Counted on the visitor's side, so failed connections are too
+                        let (bytes_in, bytes_out) = (stream2.read(), stream2.written());
+                        self.traffic
+                            .bytes
+                            .fetch_add(bytes_in + bytes_out, Ordering::Relaxed);
+                        load.add_usage(bytes_in, bytes_out);
+                        match &forwarded {
+                            Ok(()) => visit.finish(bytes_in, bytes_out, CloseReason::Closed, None),
+                            Err(err) => visit.finish(
+                                bytes_in,
+                                bytes_out,
+                                CloseReason::Error,
+                                Some(&err.to_string()),
+                            ),
+                        }
+                        forwarded?;
                     }
                     None => {
                         // Connection ID not found - likely timed out or already handled
@@ -199,140 +748,80 @@ impl Server {
                 }
                 return Ok(());
             }
-            Some(ClientMessage::Authenticate(api_key)) => {
-                // SECURITY: Reject Authenticate when backend is disabled but legacy auth is configured.
-                // In legacy mode, clients MUST use Hello → Challenge → Response flow.
-                // Allowing Authenticate here would bypass HMAC validation since disabled backend
-                // returns automatic success.
-                if !self.backend.enabled && self.auth.is_some() {
-                    warn!("Rejecting Authenticate message in legacy shared-secret mode");
-                    stream
-                        .send(ServerMessage::Error(
-                            "Authentication method not supported. Use shared secret mode."
-                                .to_string(),
-                        ))
-                        .await?;
+            Some(ClientMessage::Authenticate(token)) => {
+                // SECURITY: A token is only accepted if a provider handles tokens. In
+                // shared-secret or key mode there is none, so clients MUST use the
+                // Hello → Challenge → Response flow and cannot bypass the challenge.
+                credential = Credential::Token(token);
+                let Some(authenticated) =
+                    self.authenticate(&mut stream, &credential, &peer).await?
+                else {
                     return Ok(());
-                }
-
-                // Backend API authentication with individual user API keys
-                info!("Authenticating with backend API");
-
-                let validation = match self.backend.validate_api_key(&api_key).await {
-                    Ok(v) => v,
-                    Err(err) => {
-                        warn!(%err, "Failed to connect to backend API");
-                        stream
-                            .send(ServerMessage::Error(
-                                "Authentication service unavailable".to_string(),
-                            ))
-                            .await?;
-                        return Ok(());
-                    }
                 };
-
-                if !validation.valid {
-                    warn!("Invalid API key");
-                    stream
-                        .send(ServerMessage::Error(
-                            validation
-                                .message
-                                .unwrap_or_else(|| "Invalid API key".to_string()),
-                        ))
-                        .await?;
-                    return Ok(());
-                }
-
-                if !validation.usage_allowed {
-                    warn!("Usage not allowed for user");
-                    stream.send(ServerMessage::Error(
-                        validation.message.unwrap_or_else(||
-                            "Subscription expired or usage limit exceeded. Please visit the dashboard.".to_string()
-                        )
-                    )).await?;
-                    return Ok(());
-                }
-
-                // CRITICAL: Don't panic on missing user_id - handle gracefully to prevent DoS
-                // Backend bugs (data migration, partial rollouts, etc.) should not crash the server
-                let Some(validated_user_id) = validation.user_id else {
-                    error!(
-                        "Backend returned valid=true but missing user_id. This is a backend bug. \
-                        Rejecting connection to prevent undefined behavior."
-                    );
-                    stream
-                        .send(ServerMessage::Error(
-                            "Authentication service returned invalid data. Please contact support."
-                                .to_string(),
-                        ))
-                        .await?;
-                    return Ok(());
-                };
-
-                user_id = validated_user_id;
-                max_tunnels = validation.max_concurrent_tunnels.unwrap_or(5);
-
-                instance_id = validation.instance_id.clone();
-
-                info!(
-                    user_id = %user_id,
-                    instance_id = ?instance_id,
-                    plan = ?validation.plan_type,
-                    "User authenticated successfully"
-                );
+                identity = authenticated;
 
                 // Note: Tunnel limit will be checked atomically in handle_tunnel_session
 
                 // Now expect Hello message with port request
                 #[allow(clippy::single_match_else)]
-                match stream.recv_timeout().await? {
-                    Some(ClientMessage::Hello(port)) => {
-                        requested_port = port;
+                match stream
+                    .recv_timeout()
+                    .await?
+                    .and_then(TunnelRequest::from_message)
+                {
+                    Some(requested) => {
+                        request = requested;
                     }
-                    _ => {
+                    None => {
                         warn!("Expected Hello message after authentication");
                         stream
-                            .send(ServerMessage::Error("Protocol error".to_string()))
+                            .send_error(ServerError::new(
+                                ErrorCode::InvalidRequest,
+                                "Protocol error",
+                            ))
                             .await?;
                         return Ok(());
                     }
                 }
             }
-            Some(ClientMessage::Hello(port)) => {
-                // Client sent Hello without Authenticate - check if this is allowed
-
-                // If backend is enabled, reject unauthenticated Hello
-                if self.backend.enabled && self.auth.is_none() {
-                    warn!("Rejecting unauthenticated Hello - backend auth required");
-                    stream
-                        .send(ServerMessage::Error(
-                            "Authentication required. Please provide a valid API key.".to_string(),
-                        ))
-                        .await?;
-                    return Ok(());
-                }
-
-                // Legacy mode: using shared secret or no auth
-                if let Some(auth) = &self.auth {
-                    // Send challenge and validate
-                    if let Err(err) = auth.server_handshake(&mut stream).await {
-                        warn!(%err, "Legacy auth handshake failed");
-                        stream.send(ServerMessage::Error(err.to_string())).await?;
-                        return Ok(());
+            Some(message @ (ClientMessage::Hello(_) | ClientMessage::Join { .. })) => {
+                // Challenge mode: shared secret and/or public keys
+                credential = if self.auth_provider().wants_challenge() {
+                    match self.challenge_client(&mut stream).await {
+                        Ok(credential) => credential,
+                        Err(err) => {
+                            warn!(%err, "Challenge handshake failed");
+                            stream
+                                .send_error(ServerError::new(
+                                    ErrorCode::AuthRequired,
+                                    err.to_string(),
+                                ))
+                                .await?;
+                            return Ok(());
+                        }
                     }
-                }
-
-                user_id = "legacy-user".to_string();
-                max_tunnels = 999; // No limit in legacy mode
-                requested_port = port;
-
-                info!("Using legacy authentication mode");
+                } else {
+                    Credential::Anonymous
+                };
+                let Some(authenticated) =
+                    self.authenticate(&mut stream, &credential, &peer).await?
+                else {
+                    return Ok(());
+                };
+                identity = authenticated;
+                request = TunnelRequest::from_message(message).expect("hello or join");
+            }
+            None => {
+                // Health checks and latency probes connect and close right away.
+                debug!("connection closed before handshake");
+                return Ok(());
             }
             _ => {
                 warn!("Unexpected initial message");
                 stream
-                    .send(ServerMessage::Error(
-                        "Expected authentication or hello".to_string(),
+                    .send_error(ServerError::new(
+                        ErrorCode::InvalidRequest,
+                        "Expected authentication or hello",
                     ))
                     .await?;
                 return Ok(());
@@ -341,7 +830,7 @@ impl Server {
 
         // Create listener for the requested port
         match self
-            .handle_tunnel_session(stream, user_id, instance_id, requested_port, max_tunnels)
+            .handle_tunnel_session(stream, identity, request, &credential, &peer)
             .await
         {
             Ok(()) => Ok(()),
@@ -352,15 +841,259 @@ impl Server {
         }
     }
 
-    #[allow(clippy::too_many_lines)]
+    /// The provider authenticating clients, set up by [`Server::listen`].
+    fn auth_provider(&self) -> &dyn AuthProvider {
+        self.auth_provider
+            .as_deref()
+            .expect("auth provider is set before accepting connections")
+    }
+
+    /// Build the provider chain from the configured authentication methods.
+    fn default_auth_chain(&mut self) -> AuthChain {
+        let mut chain = AuthChain::new();
+        if let Some(verifier) = self.token_verifier.take() {
+            let mut provider =
+                JwtProvider::new(verifier).with_revocations(Arc::clone(&self.revocations));
+            if self.check_token_revocation {
+                provider = provider.with_revocation_check(Arc::clone(&self.backend));
+            }
+            chain.push(provider);
+        }
+        if let Some(auth) = self.auth.take() {
+            chain.push(SharedSecretProvider::new(
+                auth,
+                std::mem::take(&mut self.secrets),
+            ));
+        }
+        if let Some(keys) = self.authorized_keys.take() {
+            chain.push(KeyFileProvider::new(keys));
+        }
+        if self.backend.is_enabled() {
+            let mut provider = BackendProvider::new(Arc::clone(&self.backend))
+                .with_revocations(Arc::clone(&self.revocations));
+            if let Some(cache) = &self.validation_cache {
+                provider = provider.with_cache(Arc::clone(cache));
+            }
+            chain.push(provider);
+        }
+        if chain.is_empty() {
+            chain.push(NoAuth);
+        }
+        chain
+    }
+
+    /// Check a credential with the auth provider, telling the client if it is rejected.
+    async fn authenticate(
+        &self,
+        stream: &mut Delimited<TcpStream>,
+        credential: &Credential,
+        peer: &PeerInfo,
+    ) -> Result<Option<Identity>> {
+        match self.auth_provider().authenticate(credential, peer).await {
+            Ok(Some(identity)) => {
+                info!(
+                    user_id = %identity.user_id,
+                    instance_id = ?identity.instance_id,
+                    plan = ?identity.plan,
+                    "User authenticated successfully"
+                );
+                Ok(Some(identity))
+            }
+            Ok(None) => {
+                warn!("No authentication provider accepts this credential");
+                let code = match credential {
+                    Credential::Anonymous => ErrorCode::AuthRequired,
+                    _ => ErrorCode::InvalidCredentials,
+                };
+                stream
+                    .send_error(ServerError::new(code, credential.unsupported_message()))
+                    .await?;
+                Ok(None)
+            }
+            Err(err) => {
+                warn!(%err, "Authentication failed");
+                // Providers without a code of their own reject the credential.
+                let error = match err.downcast::<ServerError>() {
+                    Ok(error) => error,
+                    Err(err) => ServerError::new(ErrorCode::InvalidCredentials, format!("{err:#}")),
+                };
+                stream.send_error(error).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Queue an event for the backend. Failing to record it is logged, not fatal.
+    fn queue_event(&self, event: OutboxEvent) {
+        if !self.backend.is_enabled() {
+            return;
+        }
+        if let Err(err) = self.outbox.push(event) {
+            error!(%err, "Failed to queue backend event");
+        }
+    }
+
+    /// Send a challenge and turn the reply into a credential.
+    async fn challenge_client(&self, stream: &mut Delimited<TcpStream>) -> Result<Credential> {
+        let challenge = Uuid::new_v4();
+        stream.send(ServerMessage::Challenge(challenge)).await?;
+        match stream.recv_timeout().await? {
+            Some(ClientMessage::Authenticate(tag)) => Ok(Credential::Secret { challenge, tag }),
+            Some(ClientMessage::AuthenticateKey { key, signature }) => Ok(Credential::Signature {
+                challenge,
+                key,
+                signature,
+            }),
+            _ => bail!("server requires secret, but no secret was provided"),
+        }
+    }
+
     async fn handle_tunnel_session(
         &self,
         mut stream: Delimited<TcpStream>,
-        user_id: String,
-        instance_id: Option<String>,
-        requested_port: u16,
-        max_tunnels: u32,
+        identity: Identity,
+        request: TunnelRequest,
+        credential: &Credential,
+        peer: &PeerInfo,
     ) -> Result<()> {
+        for &port in &identity.reserved_ports {
+            if !(self.reserve_shared_port(port, &identity.user_id).await
+                && self.reservations.reserve(port, &identity.user_id))
+            {
+                warn!(user_id = %identity.user_id, port, "Port already reserved for another user");
+            }
+        }
+
+        // Open the first tunnel, or join a shared one
+        let tunnel = match self.start_tunnel(&identity, request).await {
+            Ok(tunnel) => tunnel,
+            Err(err) => {
+                stream.send_error(err).await?;
+                return Ok(());
+            }
+        };
+        let public_port = tunnel.port();
+        stream.send(ServerMessage::Hello(public_port)).await?;
+
+        // Lifecycle events go through the outbox, which delivers them to the backend
+        // in the background, so neither the client nor this session waits on it.
+        if let Some(instance_id) = identity.instance_id.clone() {
+            self.queue_event(OutboxEvent::TunnelConnected {
+                instance_id,
+                remote_port: Some(public_port),
+                public_url: None,
+                status: None,
+            });
+        }
+
+        // Main tunnel loop
+        let mut tunnels = HashMap::from([(0, tunnel)]);
+        let result = self
+            .run_tunnel_loop(&mut stream, &identity, &mut tunnels, credential, peer)
+            .await;
+
+        if let Some(instance_id) = identity.instance_id.clone() {
+            self.queue_event(OutboxEvent::TunnelDisconnected { instance_id });
+        }
+        for tunnel in tunnels.into_values() {
+            self.end_tunnel(&identity.user_id, tunnel);
+        }
+
+        // A group closed if this was its last member.
+        self.groups.lock().await.retain(|group| !group.is_closed());
+        result
+    }
+
+    /// Open a tunnel of a control session, if the user's tunnel limit allows.
+    async fn start_tunnel(
+        &self,
+        identity: &Identity,
+        request: TunnelRequest,
+    ) -> Result<SessionTunnel, ServerError> {
+        let Identity {
+            user_id,
+            max_tunnels,
+            allowed_ports,
+            ..
+        } = identity;
+        let limit_reached = || {
+            ServerError::new(
+                ErrorCode::TunnelLimit,
+                format!("Maximum concurrent tunnels ({max_tunnels}) reached. Please disconnect an existing tunnel or upgrade your plan."),
+            )
+        };
+        if !self.acquire_tunnel(user_id, *max_tunnels) {
+            return Err(limit_reached());
+        }
+        let lease = Uuid::new_v4().to_string();
+        if !self
+            .acquire_shared_tunnel(user_id, &lease, *max_tunnels)
+            .await
+        {
+            self.release_tunnel(user_id);
+            return Err(limit_reached());
+        }
+
+        let requested_port = request.port;
+        let membership = match self.open_tunnel(request, user_id, allowed_ports).await {
+            Ok(membership) => membership,
+            Err(err) => {
+                // Release the count since we're not creating a tunnel
+                self.release_tunnel(user_id);
+                self.release_shared_tunnel(user_id, &lease);
+                return Err(err);
+            }
+        };
+
+        let tunnel = SessionTunnel {
+            membership,
+            session_id: Uuid::new_v4().to_string(),
+            lease,
+        };
+        info!(
+            user_id = %user_id,
+            public_port = tunnel.port(),
+            "Tunnel session started"
+        );
+        self.queue_event(OutboxEvent::TunnelStart {
+            session_id: tunnel.session_id.clone(),
+            user_id: user_id.clone(),
+            public_port: tunnel.port(),
+            local_port: requested_port,
+            server_id: self.server_id.clone(),
+        });
+        Ok(tunnel)
+    }
+
+    /// Close a tunnel of a control session, giving back its place in the user's limit.
+    fn end_tunnel(&self, user_id: &str, tunnel: SessionTunnel) {
+        let public_port = tunnel.port();
+        let (bytes_in, bytes_out) = tunnel.membership.usage();
+        drop(tunnel.membership);
+        self.release_tunnel(user_id);
+        self.release_shared_tunnel(user_id, &tunnel.lease);
+        self.queue_event(OutboxEvent::TunnelEnd {
+            session_id: tunnel.session_id.clone(),
+            bytes_transferred: bytes_in + bytes_out,
+        });
+        self.queue_event(OutboxEvent::Usage {
+            user_id: user_id.to_string(),
+            session_id: tunnel.session_id.clone(),
+            bytes_in,
+            bytes_out,
+        });
+        info!(
+            user_id = %user_id,
+            public_port = public_port,
+            session_id = %tunnel.session_id,
+            bytes_in,
+            bytes_out,
+            "Tunnel session ended"
+        );
+    }
+
+    /// Count another tunnel of the user, unless they reached `max_tunnels`.
+    fn acquire_tunnel(&self, user_id: &str, max_tunnels: u32) -> bool {
         // Atomically check and increment concurrent tunnel limit using DashMap's entry API.
         // This prevents race conditions where multiple connections check the limit simultaneously
         // and could both bypass the limit before either increments the counter.
@@ -372,7 +1105,7 @@ impl Server {
         // 4. Release lock
         // All happen atomically, preventing race conditions.
         use dashmap::mapref::entry::Entry;
-        let limit_ok = match self.user_tunnels.entry(user_id.clone()) {
+        match self.user_tunnels.entry(user_id.to_string()) {
             Entry::Occupied(mut entry) => {
                 // User already has active tunnels
                 let current = *entry.get();
@@ -403,175 +1136,388 @@ impl Server {
                     true
                 }
             }
-        };
-
-        if !limit_ok {
-            stream.send(ServerMessage::Error(format!(
-                "Maximum concurrent tunnels ({max_tunnels}) reached. Please disconnect an existing tunnel or upgrade your plan."
-            ))).await?;
-            return Ok(());
         }
+    }
 
-        // Create listener
-        let listener = match self.create_listener(requested_port).await {
-            Ok(listener) => listener,
-            Err(err) => {
-                // Decrement the count since we're not creating a tunnel
-                if let Some(mut count) = self.user_tunnels.get_mut(&user_id) {
-                    *count = count.saturating_sub(1);
-                    if *count == 0 {
-                        drop(count);
-                        self.user_tunnels.remove(&user_id);
-                    }
-                }
-                stream.send(ServerMessage::Error(err.into())).await?;
-                return Ok(());
+    /// Stop counting a tunnel of the user.
+    fn release_tunnel(&self, user_id: &str) {
+        if let Some(mut count) = self.user_tunnels.get_mut(user_id) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                drop(count);
+                self.user_tunnels.remove(user_id);
             }
-        };
+        }
+    }
 
-        let _host = listener.local_addr()?.ip();
-        let public_port = listener.local_addr()?.port();
+    /// Count another tunnel of the user in the shared state, unless they reached
+    /// `max_tunnels` across the cluster.
+    ///
+    /// If the shared state is unavailable, the limit is enforced on this server
+    /// alone, and the lease is counted once the state is back.
+    async fn acquire_shared_tunnel(&self, user_id: &str, lease: &str, max_tunnels: u32) -> bool {
+        match self
+            .state
+            .acquire_tunnel(user_id, lease, max_tunnels, self.lease_ttl)
+            .await
+        {
+            Ok(false) => {
+                warn!(user_id = %user_id, max = max_tunnels, "Cluster-wide tunnel limit reached");
+                return false;
+            }
+            Ok(true) => {}
+            Err(err) => warn!(%err, "Shared state unavailable, enforcing tunnel limit locally"),
+        }
+        self.leases
+            .tunnels
+            .insert(lease.to_string(), user_id.to_string());
+        true
+    }
 
-        info!(
-            user_id = %user_id,
-            public_port = public_port,
-            "Tunnel session started"
-        );
+    /// Stop counting a tunnel of the user in the shared state, in the background.
+    fn release_shared_tunnel(&self, user_id: &str, lease: &str) {
+        self.leases.tunnels.remove(lease);
+        let state = Arc::clone(&self.state);
+        let (user_id, lease) = (user_id.to_string(), lease.to_string());
+        tokio::spawn(async move {
+            if let Err(err) = state.release_tunnel(&user_id, &lease).await {
+                // The lease expires on its own.
+                warn!(%err, "Failed to release tunnel lease");
+            }
+        });
+    }
 
-        // CRITICAL: Send Hello FIRST to prevent client timeout (3s), then log in background
-        // Backend logging can take up to 5s, which exceeds client's NETWORK_TIMEOUT
-        stream.send(ServerMessage::Hello(public_port)).await?;
+    /// Reserve a port for the user in the shared state, returning whether no
+    /// other user holds it. If the shared state is unavailable, the port is
+    /// reserved on this server alone.
+    async fn reserve_shared_port(&self, port: u16, user_id: &str) -> bool {
+        match self.state.reserve_port(port, user_id, self.lease_ttl).await {
+            Ok(false) => return false,
+            Ok(true) => {}
+            Err(err) => warn!(%err, port, "Shared state unavailable, reserving port locally"),
+        }
+        self.leases.ports.insert(port, user_id.to_string());
+        true
+    }
 
-        // Log tunnel start with backend (in background to not block)
-        let backend_clone = Arc::clone(&self.backend);
-        let user_id_clone = user_id.clone();
-        let server_id_clone = self.server_id.clone();
-        let session_id_handle = tokio::spawn(async move {
-            match backend_clone
-                .log_tunnel_start(
-                    &user_id_clone,
-                    public_port,
-                    requested_port,
-                    &server_id_clone,
-                )
-                .await
-            {
-                Ok(id) => id,
-                Err(err) => {
-                    warn!(%err, "Failed to log tunnel start");
-                    format!("session-{}", Uuid::new_v4())
+    /// Check the credential of a running session again.
+    ///
+    /// Fails with the reason to close the session, if the credential is no
+    /// longer accepted or now proves another user. An unavailable backend does
+    /// not close sessions.
+    async fn revalidate(
+        &self,
+        credential: &Credential,
+        peer: &PeerInfo,
+        identity: &Identity,
+    ) -> Result<(), ServerError> {
+        match self.auth_provider().authenticate(credential, peer).await {
+            Ok(Some(current)) if current.user_id == identity.user_id => Ok(()),
+            Ok(Some(_)) => Err(ServerError::new(
+                ErrorCode::InvalidCredentials,
+                "Credentials now belong to another user",
+            )),
+            Ok(None) => Err(ServerError::new(
+                ErrorCode::InvalidCredentials,
+                credential.unsupported_message(),
+            )),
+            Err(err) => match err.downcast::<ServerError>() {
+                Ok(error) if error.code == ErrorCode::BackendUnavailable => {
+                    warn!(user_id = %identity.user_id, %error, "Could not revalidate session, keeping it");
+                    Ok(())
                 }
-            }
-        });
+                Ok(error) => Err(error),
+                Err(err) => Err(ServerError::new(
+                    ErrorCode::InvalidCredentials,
+                    format!("{err:#}"),
+                )),
+            },
+        }
+    }
 
-        if let Some(instance_id) = instance_id.clone() {
-            let backend = Arc::clone(&self.backend);
-            tokio::spawn(async move {
-                if let Err(err) = backend
-                    .notify_tunnel_connected(&instance_id, Some(public_port), None)
-                    .await
-                {
-                    warn!(
-                        %err,
-                        instance_id = %instance_id,
-                        public_port = public_port,
-                        "Failed to notify backend of tunnel connection"
-                    );
+    async fn run_tunnel_loop(
+        &self,
+        stream: &mut Delimited<TcpStream>,
+        identity: &Identity,
+        tunnels: &mut HashMap<u32, SessionTunnel>,
+        credential: &Credential,
+        peer: &PeerInfo,
+    ) -> Result<()> {
+        let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+        let mut revalidation = self
+            .revalidate_interval
+            .map(|every| interval_at(Instant::now() + every, every));
+        let mut commands = self.commands.subscribe();
+        let mut draining = self.draining.subscribe();
+        loop {
+            tokio::select! {
+                () = until_draining(&mut draining) => {
+                    info!(user_id = %identity.user_id, "Server shutting down, closing session");
+                    return Ok(());
                 }
-            });
+                _ = heartbeat.tick() => {
+                    if stream.send(ServerMessage::Heartbeat).await.is_err() {
+                        // Assume that the TCP connection has been dropped.
+                        return Ok(());
+                    }
+                }
+                () = next_revalidation(&mut revalidation) => {
+                    if let Err(error) = self.revalidate(credential, peer, identity).await {
+                        warn!(
+                            user_id = %identity.user_id,
+                            code = ?error.code,
+                            %error,
+                            "Session failed revalidation, closing it"
+                        );
+                        stream.send_error(error).await?;
+                        return Ok(());
+                    }
+                }
+                command = next_command(&mut commands) => {
+                    let close_session = match &command {
+                        BackendCommand::RevokeUser { user_id, .. } => *user_id == identity.user_id,
+                        BackendCommand::RevokeInstance { instance_id, .. } => {
+                            identity.instance_id.as_ref() == Some(instance_id)
+                        }
+                        BackendCommand::CloseSession { session_id, .. } => {
+                            let found = tunnels
+                                .iter()
+                                .find(|(_, open)| open.session_id == *session_id)
+                                .map(|(&tunnel, _)| tunnel);
+                            match found {
+                                // Other tunnels of the control connection keep running.
+                                Some(tunnel) if tunnels.len() > 1 => {
+                                    let closed = tunnels.remove(&tunnel).expect("tunnel is open");
+                                    info!(tunnel, session_id = %closed.session_id, "Backend closed tunnel");
+                                    self.end_tunnel(&identity.user_id, closed);
+                                    self.groups.lock().await.retain(|group| !group.is_closed());
+                                    stream.send(ServerMessage::Closed(tunnel)).await?;
+                                    false
+                                }
+                                found => found.is_some(),
+                            }
+                        }
+                    };
+                    if close_session {
+                        warn!(user_id = %identity.user_id, ?command, "Backend closed session");
+                        stream.send_error(command.error()).await?;
+                        return Ok(());
+                    }
+                }
+                (tunnel, id) = next_connection(tunnels) => {
+                    let Some(id) = id else {
+                        let closed = tunnels.remove(&tunnel).expect("tunnel is open");
+                        self.end_tunnel(&identity.user_id, closed);
+                        if tunnels.is_empty() {
+                            bail!("tunnel listener closed");
+                        }
+                        stream.send(ServerMessage::Closed(tunnel)).await?;
+                        continue;
+                    };
+                    // Notify bore client of the new connection
+                    let message = match tunnel {
+                        0 => ServerMessage::Connection(id),
+                        _ => ServerMessage::TunnelConnection { tunnel, id },
+                    };
+                    stream.send(message).await?;
+                }
+                message = stream.recv() => match message {
+                    Ok(Some(ClientMessage::Status(status))) => {
+                        let Some(tunnel) = tunnels.get(&0) else {
+                            continue;
+                        };
+                        info!(
+                            port = tunnel.port(),
+                            healthy = status.healthy,
+                            detail = status.detail.as_deref().unwrap_or_default(),
+                            "Tunnel status changed"
+                        );
+                        if let Some(instance_id) = &identity.instance_id {
+                            self.queue_event(OutboxEvent::TunnelConnected {
+                                instance_id: instance_id.clone(),
+                                remote_port: Some(tunnel.port()),
+                                public_url: None,
+                                status: Some(status.clone()),
+                            });
+                        }
+                        tunnel.membership.set_status(status);
+                    }
+                    Ok(Some(ClientMessage::Open { tunnel, port })) => {
+                        let reply = match tunnels.entry(tunnel) {
+                            Entry::Occupied(_) => {
+                                let error = ServerError::new(
+                                    ErrorCode::InvalidRequest,
+                                    format!("tunnel {tunnel} is already open"),
+                                );
+                                ServerMessage::Refused { tunnel, error }
+                            }
+                            Entry::Vacant(entry) => {
+                                let request = TunnelRequest {
+                                    port,
+                                    shared: false,
+                                    group: None,
+                                };
+                                match self.start_tunnel(identity, request).await {
+                                    Ok(opened) => {
+                                        let port = entry.insert(opened).port();
+                                        ServerMessage::Opened { tunnel, port }
+                                    }
+                                    Err(error) => ServerMessage::Refused { tunnel, error },
+                                }
+                            }
+                        };
+                        stream.send(reply).await?;
+                    }
+                    Ok(Some(ClientMessage::Close(tunnel))) => {
+                        if let Some(closed) = tunnels.remove(&tunnel) {
+                            self.end_tunnel(&identity.user_id, closed);
+                            self.groups.lock().await.retain(|group| !group.is_closed());
+                        }
+                        stream.send(ServerMessage::Closed(tunnel)).await?;
+                    }
+                    Ok(Some(ClientMessage::List)) => {
+                        let mut list: Vec<_> = tunnels
+                            .iter()
+                            .map(|(&tunnel, open)| TunnelInfo {
+                                tunnel,
+                                port: open.port(),
+                            })
+                            .collect();
+                        list.sort_by_key(|info| info.tunnel);
+                        stream.send(ServerMessage::Tunnels(list)).await?;
+                    }
+                    Ok(Some(_)) => warn!("unexpected message on control connection"),
+                    // The client closed the control connection.
+                    Ok(None) | Err(_) => return Ok(()),
+                },
+            }
         }
+    }
+}
 
-        // Main tunnel loop
-        let result = self
-            .run_tunnel_loop(&mut stream, public_port, listener)
-            .await;
+/// Wait until the server shuts down.
+async fn until_draining(draining: &mut watch::Receiver<bool>) {
+    if draining.wait_for(|draining| *draining).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
 
-        if let Some(instance_id) = instance_id {
-            let backend = Arc::clone(&self.backend);
-            tokio::spawn(async move {
-                if let Err(err) = backend.notify_tunnel_disconnected(&instance_id).await {
-                    warn!(
-                        %err,
-                        instance_id = %instance_id,
-                        "Failed to notify backend of tunnel disconnect"
-                    );
-                }
-            });
+/// Counters of forwarded visitor connections.
+#[derive(Default)]
+struct Traffic {
+    /// Connections being forwarded.
+    connections: AtomicU32,
+
+    /// Bytes forwarded by connections that ended, both ways.
+    bytes: AtomicU64,
+}
+
+impl Traffic {
+    /// Count a connection until the returned guard is dropped.
+    fn connection(&self) -> ForwardingGuard<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ForwardingGuard(&self.connections)
+    }
+}
+
+/// A connection counted in [`Traffic`] while it is forwarded.
+struct ForwardingGuard<'a>(&'a AtomicU32);
+
+impl Drop for ForwardingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wait for the next revalidation of a session, forever if sessions are not
+/// revalidated.
+async fn next_revalidation(revalidation: &mut Option<Interval>) {
+    match revalidation {
+        Some(revalidation) => {
+            revalidation.tick().await;
         }
+        None => std::future::pending().await,
+    }
+}
 
-        // Cleanup: decrement tunnel count
-        if let Some(mut count) = self.user_tunnels.get_mut(&user_id) {
-            *count = count.saturating_sub(1);
-            if *count == 0 {
-                drop(count);
-                self.user_tunnels.remove(&user_id);
+/// Wait for the next command from the backend, skipping those the session was
+/// too slow to see.
+async fn next_command(commands: &mut broadcast::Receiver<BackendCommand>) -> BackendCommand {
+    loop {
+        match commands.recv().await {
+            Ok(command) => return command,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!(missed, "Session missed backend commands");
             }
+            Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
         }
+    }
+}
 
-        // Get session_id from background task
-        let session_id = match session_id_handle.await {
-            Ok(id) => id,
-            Err(err) => {
-                warn!(%err, "Failed to await session_id task");
-                format!("session-{}", Uuid::new_v4())
-            }
-        };
+/// Wait for a visitor of any tunnel of a control session, returning the tunnel's
+/// ID, and no connection once its listener closed.
+///
+/// Never resolves while the session has no tunnels.
+async fn next_connection(tunnels: &mut HashMap<u32, SessionTunnel>) -> (u32, Option<Uuid>) {
+    if tunnels.is_empty() {
+        return std::future::pending().await;
+    }
+    let waiting = tunnels.iter_mut().map(|(&tunnel, open)| {
+        Box::pin(async move { (tunnel, open.membership.next_connection().await) })
+    });
+    select_all(waiting).await.0
+}
 
-        // Log tunnel end
-        if let Err(err) = self.backend.log_tunnel_end(&session_id, 0).await {
-            warn!(%err, "Failed to log tunnel end");
-        }
+/// Whether a key limited to `allowed_ports` may forward `port`; no ranges allow any.
+fn allowed(allowed_ports: &[RangeInclusive<u16>], port: u16) -> bool {
+    allowed_ports.is_empty() || allowed_ports.iter().any(|r| r.contains(&port))
+}
 
-        info!(
-            user_id = %user_id,
-            public_port = public_port,
-            session_id = %session_id,
-            "Tunnel session ended"
-        );
+/// A tunnel opened by a control session.
+struct SessionTunnel {
+    /// The session's place in the tunnel's group.
+    membership: Membership,
 
-        result
+    /// ID of the tunnel in backend events.
+    session_id: String,
+
+    /// Lease counting the tunnel in the shared state.
+    lease: String,
+}
+
+impl SessionTunnel {
+    /// Port that is publicly available.
+    fn port(&self) -> u16 {
+        self.membership.group().port()
     }
+}
 
-    async fn run_tunnel_loop(
-        &self,
-        stream: &mut Delimited<TcpStream>,
-        port: u16,
-        listener: TcpListener,
-    ) -> Result<()> {
-        loop {
-            if stream.send(ServerMessage::Heartbeat).await.is_err() {
-                // Assume that the TCP connection has been dropped.
-                return Ok(());
-            }
+/// A client's request for a public port.
+struct TunnelRequest {
+    /// Requested port, or 0 for any port.
+    port: u16,
 
-            // Poll for new connections with a timeout to allow heartbeat checks
-            if let Ok(result) = timeout(HEARTBEAT_POLL_TIMEOUT, listener.accept()).await {
-                let (stream2, addr) = result?;
-                info!(?addr, ?port, "new connection");
-
-                // Generate unique ID for this connection to match client's Accept message
-                let id = Uuid::new_v4();
-                let conns = Arc::clone(&self.conns);
-
-                // Store the external client connection temporarily
-                conns.insert(id, stream2);
-
-                // Spawn a cleanup task to prevent memory leaks from unaccepted connections
-                // If the bore client doesn't send Accept(id) within 10 seconds, we remove
-                // the stored connection. This handles cases where:
-                // - The bore client crashes or disconnects
-                // - Network issues prevent the Connection message from arriving
-                // - The client rejects the connection for any reason
-                tokio::spawn(async move {
-                    sleep(Duration::from_secs(10)).await;
-                    if conns.remove(&id).is_some() {
-                        warn!(%id, "removed stale connection");
-                    }
-                });
+    /// Whether the port may be shared with other clients of the same user.
+    shared: bool,
 
-                // Notify bore client of the new connection
-                stream.send(ServerMessage::Connection(id)).await?;
-            }
+    /// Name of the shared tunnel, found by port when not given.
+    group: Option<String>,
+}
+
+impl TunnelRequest {
+    fn from_message(message: ClientMessage) -> Option<Self> {
+        match message {
+            ClientMessage::Hello(port) => Some(Self {
+                port,
+                shared: false,
+                group: None,
+            }),
+            ClientMessage::Join { port, group } => Some(Self {
+                port,
+                shared: true,
+                group,
+            }),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,145 @@
+//! State shared by the servers of a cluster.
+//!
+//! Each server counts the tunnels of every user itself, so on its own a limit
+//! of two tunnels means two tunnels per server. With a [`SharedState`], the
+//! servers of a cluster also count tunnels and hold port reservations in one
+//! place. Every tunnel and reservation is a lease the holding server renews
+//! while it runs, so what a crashed server held is released once its leases
+//! expire.
+//!
+//! [`MemoryState`] keeps the state in the process, for a single server.
+//! [`RedisState`] keeps it in Redis, or anything speaking its protocol, for
+//! several.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::warn;
+
+mod memory;
+mod redis;
+
+pub use memory::MemoryState;
+pub use redis::RedisState;
+
+/// Default time a lease is held without being renewed.
+pub const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(30);
+
+/// Tunnel counts and port reservations shared by the servers of a cluster.
+#[async_trait]
+pub trait SharedState: Send + Sync {
+    /// Count a tunnel of a user under `lease` for `ttl`, unless the user
+    /// already has `max_tunnels` tunnels under leases that have not expired.
+    ///
+    /// Returns whether the tunnel was counted.
+    async fn acquire_tunnel(
+        &self,
+        user_id: &str,
+        lease: &str,
+        max_tunnels: u32,
+        ttl: Duration,
+    ) -> Result<bool>;
+
+    /// Extend a tunnel lease by `ttl`, counting the tunnel again if it expired.
+    async fn renew_tunnel(&self, user_id: &str, lease: &str, ttl: Duration) -> Result<()>;
+
+    /// Stop counting the tunnel under `lease`.
+    async fn release_tunnel(&self, user_id: &str, lease: &str) -> Result<()>;
+
+    /// Tunnels of a user under leases that have not expired.
+    async fn tunnels_of(&self, user_id: &str) -> Result<u32>;
+
+    /// Reserve a port for a user for `ttl`, or extend their reservation.
+    ///
+    /// Returns whether the port is now reserved for `user_id`, which it is not
+    /// if another user holds it.
+    async fn reserve_port(&self, port: u16, user_id: &str, ttl: Duration) -> Result<bool>;
+
+    /// The user a port is reserved for, if any.
+    async fn port_owner(&self, port: u16) -> Result<Option<String>>;
+}
+
+#[async_trait]
+impl<S: SharedState + ?Sized> SharedState for Arc<S> {
+    async fn acquire_tunnel(
+        &self,
+        user_id: &str,
+        lease: &str,
+        max_tunnels: u32,
+        ttl: Duration,
+    ) -> Result<bool> {
+        (**self)
+            .acquire_tunnel(user_id, lease, max_tunnels, ttl)
+            .await
+    }
+
+    async fn renew_tunnel(&self, user_id: &str, lease: &str, ttl: Duration) -> Result<()> {
+        (**self).renew_tunnel(user_id, lease, ttl).await
+    }
+
+    async fn release_tunnel(&self, user_id: &str, lease: &str) -> Result<()> {
+        (**self).release_tunnel(user_id, lease).await
+    }
+
+    async fn tunnels_of(&self, user_id: &str) -> Result<u32> {
+        (**self).tunnels_of(user_id).await
+    }
+
+    async fn reserve_port(&self, port: u16, user_id: &str, ttl: Duration) -> Result<bool> {
+        (**self).reserve_port(port, user_id, ttl).await
+    }
+
+    async fn port_owner(&self, port: u16) -> Result<Option<String>> {
+        (**self).port_owner(port).await
+    }
+}
+
+/// Leases this server holds in the shared state.
+#[derive(Debug, Default)]
+pub(crate) struct Leases {
+    /// Users of tunnel leases, by lease.
+    pub tunnels: DashMap<String, String>,
+
+    /// Owners of reserved ports.
+    pub ports: DashMap<u16, String>,
+}
+
+/// Renew every lease in `leases` well before it expires, until the task is aborted.
+pub(crate) async fn keep_leases(state: Arc<dyn SharedState>, leases: Arc<Leases>, ttl: Duration) {
+    let mut ticks = interval(ttl / 3);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let tunnels: Vec<(String, String)> = leases
+            .tunnels
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        for (lease, user_id) in tunnels {
+            if let Err(err) = state.renew_tunnel(&user_id, &lease, ttl).await {
+                warn!(%err, "Failed to renew tunnel lease");
+            }
+        }
+
+        let ports: Vec<(u16, String)> = leases
+            .ports
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect();
+        for (port, user_id) in ports {
+            match state.reserve_port(port, &user_id, ttl).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(port, user_id = %user_id, "Port reservation taken over by another user");
+                    leases.ports.remove(&port);
+                }
+                Err(err) => warn!(%err, port, "Failed to renew port reservation"),
+            }
+        }
+    }
+}
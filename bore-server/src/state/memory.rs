@@ -0,0 +1,141 @@
+//! Shared state kept in the process, for a single server.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+use super::SharedState;
+
+/// State kept in memory, shared only by servers in the same process.
+#[derive(Debug, Default)]
+pub struct MemoryState {
+    /// Expiry of every tunnel lease, by user and lease.
+    tunnels: Mutex<HashMap<String, HashMap<String, Instant>>>,
+
+    /// Owner and expiry of every reserved port.
+    ports: Mutex<HashMap<u16, (String, Instant)>>,
+}
+
+impl MemoryState {
+    /// An empty state, counting no tunnels.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SharedState for MemoryState {
+    async fn acquire_tunnel(
+        &self,
+        user_id: &str,
+        lease: &str,
+        max_tunnels: u32,
+        ttl: Duration,
+    ) -> Result<bool> {
+        let now = Instant::now();
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let leases = tunnels.entry(user_id.to_string()).or_default();
+        leases.retain(|_, expiry| *expiry > now);
+        if leases.len() >= max_tunnels as usize {
+            return Ok(false);
+        }
+        leases.insert(lease.to_string(), now + ttl);
+        Ok(true)
+    }
+
+    async fn renew_tunnel(&self, user_id: &str, lease: &str, ttl: Duration) -> Result<()> {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let leases = tunnels.entry(user_id.to_string()).or_default();
+        leases.insert(lease.to_string(), Instant::now() + ttl);
+        Ok(())
+    }
+
+    async fn release_tunnel(&self, user_id: &str, lease: &str) -> Result<()> {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        if let Some(leases) = tunnels.get_mut(user_id) {
+            leases.remove(lease);
+            if leases.is_empty() {
+                tunnels.remove(user_id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn tunnels_of(&self, user_id: &str) -> Result<u32> {
+        let now = Instant::now();
+        let tunnels = self.tunnels.lock().unwrap();
+        let count = tunnels.get(user_id).map_or(0, |leases| {
+            leases.values().filter(|expiry| **expiry > now).count()
+        });
+        Ok(u32::try_from(count).unwrap_or(u32::MAX))
+    }
+
+    async fn reserve_port(&self, port: u16, user_id: &str, ttl: Duration) -> Result<bool> {
+        let now = Instant::now();
+        let mut ports = self.ports.lock().unwrap();
+        match ports.get(&port) {
+            Some((owner, expiry)) if owner != user_id && *expiry > now => Ok(false),
+            _ => {
+                ports.insert(port, (user_id.to_string(), now + ttl));
+                Ok(true)
+            }
+        }
+    }
+
+    async fn port_owner(&self, port: u16) -> Result<Option<String>> {
+        let now = Instant::now();
+        let ports = self.ports.lock().unwrap();
+        Ok(ports
+            .get(&port)
+            .filter(|(_, expiry)| *expiry > now)
+            .map(|(owner, _)| owner.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(30);
+    const SHORT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn counts_tunnels_until_leases_expire() {
+        let state = MemoryState::new();
+        assert!(state.acquire_tunnel("alice", "a1", 2, TTL).await.unwrap());
+        assert!(state.acquire_tunnel("alice", "a2", 2, SHORT).await.unwrap());
+        assert!(!state.acquire_tunnel("alice", "a3", 2, TTL).await.unwrap());
+        assert!(state.acquire_tunnel("bob", "b1", 2, SHORT).await.unwrap());
+
+        state.release_tunnel("alice", "a1").await.unwrap();
+        assert_eq!(state.tunnels_of("alice").await.unwrap(), 1);
+
+        // A renewed lease outlives one that was not.
+        state.renew_tunnel("alice", "a2", TTL).await.unwrap();
+        tokio::time::sleep(SHORT * 2).await;
+        assert_eq!(state.tunnels_of("alice").await.unwrap(), 1);
+        assert_eq!(state.tunnels_of("bob").await.unwrap(), 0);
+        assert!(!state.acquire_tunnel("alice", "a4", 1, TTL).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reservations_expire() {
+        let state = MemoryState::new();
+        assert!(state.reserve_port(9000, "alice", SHORT).await.unwrap());
+        assert!(state.reserve_port(9000, "alice", SHORT).await.unwrap());
+        assert!(!state.reserve_port(9000, "bob", TTL).await.unwrap());
+        assert_eq!(
+            state.port_owner(9000).await.unwrap().as_deref(),
+            Some("alice")
+        );
+
+        tokio::time::sleep(SHORT * 2).await;
+        assert_eq!(state.port_owner(9000).await.unwrap(), None);
+        assert!(state.reserve_port(9000, "bob", TTL).await.unwrap());
+    }
+}
@@ -1,33 +1,122 @@
 //! Auth implementation for bore client and server.
 
-use anyhow::{bail, ensure, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use hmac::{Hmac, Mac};
+use rand_core::OsRng;
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncWrite};
 use uuid::Uuid;
 
 use crate::protocol::{ClientMessage, Delimited, ServerMessage};
 
-/// Wrapper around a MAC used for authenticating clients that have a secret.
-pub struct Authenticator(Hmac<Sha256>);
+/// Prefix used in the text form of Ed25519 public keys.
+const ED25519_PREFIX: &str = "ed25519:";
+
+/// Name given to the secret passed to [`Authenticator::new`].
+pub const DEFAULT_SECRET_NAME: &str = "default";
+
+/// A named shared secret, optionally valid only until some point in time.
+///
+/// Naming secrets lets a server accept several at once, so that a secret can be
+/// rotated by adding the new one, moving clients over, and letting the old one
+/// expire.
+#[derive(Clone)]
+pub struct NamedSecret {
+    name: String,
+    mac: Hmac<Sha256>,
+    expires_at: Option<SystemTime>,
+}
+
+impl NamedSecret {
+    /// Create a named secret that never expires.
+    #[must_use]
+    pub fn new(name: impl Into<String>, secret: &str) -> Self {
+        let hashed_secret = Sha256::new().chain_update(secret).finalize();
+        Self {
+            name: name.into(),
+            mac: Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"),
+            expires_at: None,
+        }
+    }
+
+    /// Stop accepting this secret after the given time.
+    #[must_use]
+    pub fn expires_at(mut self, expires_at: SystemTime) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    /// The name identifying this secret.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the secret is still accepted at the given time.
+    #[must_use]
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+
+    fn answer(&self, challenge: &Uuid) -> String {
+        let mut hmac = self.mac.clone();
+        hmac.update(challenge.as_bytes());
+        hex::encode(hmac.finalize().into_bytes())
+    }
+
+    fn verify(&self, challenge: &Uuid, tag: &[u8]) -> bool {
+        let mut hmac = self.mac.clone();
+        hmac.update(challenge.as_bytes());
+        hmac.verify_slice(tag).is_ok()
+    }
+}
+
+/// Wrapper around MACs used for authenticating clients that have a secret.
+///
+/// A client holds a single secret. A server may accept any of several named
+/// secrets, and learns from the handshake which one the client used.
+#[derive(Clone)]
+pub struct Authenticator(Vec<NamedSecret>);
 
 impl Authenticator {
     /// Create an authenticator from a secret string.
     #[must_use]
     pub fn new(secret: &str) -> Self {
-        let hashed_secret = Sha256::new().chain_update(secret).finalize();
-        Self(Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"))
+        Self(vec![NamedSecret::new(DEFAULT_SECRET_NAME, secret)])
     }
 
-    /// Generate an answer to the given challenge.
+    /// Create an authenticator accepting any of the given secrets.
+    #[must_use]
+    pub fn from_secrets(secrets: impl IntoIterator<Item = NamedSecret>) -> Self {
+        Self(secrets.into_iter().collect())
+    }
+
+    /// Accept an additional secret.
+    pub fn add(&mut self, secret: NamedSecret) {
+        self.0.push(secret);
+    }
+
+    /// The secrets accepted by this authenticator.
+    #[must_use]
+    pub fn secrets(&self) -> &[NamedSecret] {
+        &self.0
+    }
+
+    /// Generate an answer to the given challenge, using the first secret.
     #[must_use]
     pub fn answer(&self, challenge: &Uuid) -> String {
-        let mut hmac = self.0.clone();
-        hmac.update(challenge.as_bytes());
-        hex::encode(hmac.finalize().into_bytes())
+        self.0
+            .first()
+            .map(|secret| secret.answer(challenge))
+            .unwrap_or_default()
     }
 
-    /// Validate a reply to a challenge.
+    /// Validate a reply to a challenge against any active secret.
     ///
     /// ```
     /// use bore_shared::auth::Authenticator;
@@ -41,25 +130,55 @@ impl Authenticator {
     /// ```
     #[must_use]
     pub fn validate(&self, challenge: &Uuid, tag: &str) -> bool {
-        hex::decode(tag).is_ok_and(|tag| {
-            let mut hmac = self.0.clone();
-            hmac.update(challenge.as_bytes());
-            hmac.verify_slice(&tag).is_ok()
-        })
+        self.check(challenge, tag).is_ok()
+    }
+
+    /// Validate a reply to a challenge, returning the name of the matching secret.
+    ///
+    /// Only active secrets are accepted, so a secret re-issued under a new name
+    /// keeps working after the old entry expires. Fails if no secret matches,
+    /// reporting an expired secret if only that one matches.
+    ///
+    /// ```
+    /// use std::time::{Duration, SystemTime};
+    /// use bore_shared::auth::{Authenticator, NamedSecret};
+    /// use uuid::Uuid;
+    ///
+    /// let old = NamedSecret::new("old", "s1").expires_at(SystemTime::now() - Duration::from_secs(1));
+    /// let auth = Authenticator::from_secrets([old, NamedSecret::new("new", "s2")]);
+    /// let challenge = Uuid::new_v4();
+    ///
+    /// let reply = Authenticator::new("s2").answer(&challenge);
+    /// assert_eq!(auth.check(&challenge, &reply).unwrap(), "new");
+    ///
+    /// let reply = Authenticator::new("s1").answer(&challenge);
+    /// assert!(auth.check(&challenge, &reply).is_err());
+    /// ```
+    pub fn check(&self, challenge: &Uuid, tag: &str) -> Result<&str> {
+        let tag = hex::decode(tag).context("invalid secret")?;
+        let now = SystemTime::now();
+        let matches = |secret: &&NamedSecret| secret.verify(challenge, &tag);
+        let mut active = self.0.iter().filter(|secret| secret.is_active(now));
+        if let Some(secret) = active.find(matches) {
+            return Ok(&secret.name);
+        }
+        match self.0.iter().find(matches) {
+            Some(secret) => bail!("secret {:?} has expired", secret.name),
+            None => bail!("invalid secret"),
+        }
     }
 
     /// As the server, send a challenge to the client and validate their response.
+    ///
+    /// Returns the name of the secret the client answered with.
     pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
         &self,
         stream: &mut Delimited<T>,
-    ) -> Result<()> {
+    ) -> Result<String> {
         let challenge = Uuid::new_v4();
         stream.send(ServerMessage::Challenge(challenge)).await?;
         match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(tag)) => {
-                ensure!(self.validate(&challenge, &tag), "invalid secret");
-                Ok(())
-            }
+            Some(ClientMessage::Authenticate(tag)) => Ok(self.check(&challenge, &tag)?.to_string()),
             _ => bail!("server requires secret, but no secret was provided"),
         }
     }
@@ -77,3 +196,132 @@ impl Authenticator {
         Ok(())
     }
 }
+
+/// Ed25519 key pair used by clients that authenticate with a public key.
+#[derive(Clone)]
+pub struct KeyPair(SigningKey);
+
+impl KeyPair {
+    /// Generate a new random key pair.
+    #[must_use]
+    pub fn generate() -> Self {
+        Self(SigningKey::generate(&mut OsRng))
+    }
+
+    /// Parse a key pair from its hex-encoded 32-byte private seed.
+    pub fn from_hex(seed: &str) -> Result<Self> {
+        let bytes = hex::decode(seed.trim()).context("private key is not valid hex")?;
+        let seed: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("private key must be 32 bytes"))?;
+        Ok(Self(SigningKey::from_bytes(&seed)))
+    }
+
+    /// Hex-encoded private seed, suitable for storing in a key file.
+    #[must_use]
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.to_bytes())
+    }
+
+    /// The public half of this key pair.
+    #[must_use]
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key())
+    }
+
+    /// Sign the given challenge, returning a hex-encoded signature.
+    ///
+    /// ```
+    /// use bore_shared::auth::KeyPair;
+    /// use uuid::Uuid;
+    ///
+    /// let key = KeyPair::generate();
+    /// let challenge = Uuid::new_v4();
+    ///
+    /// assert!(key.public_key().verify(&challenge, &key.sign(&challenge)));
+    /// assert!(!key.public_key().verify(&Uuid::new_v4(), &key.sign(&challenge)));
+    /// ```
+    #[must_use]
+    pub fn sign(&self, challenge: &Uuid) -> String {
+        hex::encode(self.0.sign(challenge.as_bytes()).to_bytes())
+    }
+
+    /// Build the reply to a challenge for this key.
+    #[must_use]
+    pub fn answer(&self, challenge: &Uuid) -> ClientMessage {
+        ClientMessage::AuthenticateKey {
+            key: self.public_key().to_hex(),
+            signature: self.sign(challenge),
+        }
+    }
+
+    /// As the client, answer a challenge by signing it with this key.
+    pub async fn client_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Delimited<T>,
+    ) -> Result<()> {
+        let Some(ServerMessage::Challenge(challenge)) = stream.recv_timeout().await? else {
+            bail!("expected authentication challenge, but no key was required")
+        };
+        stream.send(self.answer(&challenge)).await?;
+        Ok(())
+    }
+}
+
+/// Ed25519 public key identifying a client.
+///
+/// The text form is `ed25519:` followed by the hex-encoded key, which is what
+/// `bore keygen` prints and what the server's authorized keys file contains.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey(VerifyingKey);
+
+impl PublicKey {
+    /// Parse a public key from hex, without the `ed25519:` prefix.
+    pub fn from_hex(key: &str) -> Result<Self> {
+        let bytes = hex::decode(key).context("public key is not valid hex")?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("public key must be 32 bytes"))?;
+        Ok(Self(
+            VerifyingKey::from_bytes(&bytes).context("invalid Ed25519 public key")?,
+        ))
+    }
+
+    /// Hex-encoded key, without the `ed25519:` prefix.
+    #[must_use]
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.as_bytes())
+    }
+
+    /// Check a hex-encoded signature over the challenge.
+    #[must_use]
+    pub fn verify(&self, challenge: &Uuid, signature: &str) -> bool {
+        hex::decode(signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .is_some_and(|sig| self.0.verify(challenge.as_bytes(), &sig).is_ok())
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some(key) = s.strip_prefix(ED25519_PREFIX) else {
+            bail!("public key must start with {ED25519_PREFIX:?}");
+        };
+        Self::from_hex(key)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{ED25519_PREFIX}{}", self.to_hex())
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({self})")
+    }
+}
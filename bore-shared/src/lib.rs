@@ -7,12 +7,15 @@
 #![warn(missing_docs)]
 
 pub mod auth;
+#[cfg(feature = "logging")]
+pub mod logging;
 pub mod protocol;
 pub mod timeouts;
 
 // Re-export commonly used items
-pub use auth::Authenticator;
+pub use auth::{Authenticator, KeyPair, NamedSecret, PublicKey};
 pub use protocol::{
-    ClientMessage, Delimited, ServerMessage, CONTROL_PORT, MAX_FRAME_LENGTH, NETWORK_TIMEOUT,
+    ClientMessage, Delimited, ErrorCode, FrameCodec, ServerError, ServerMessage, TunnelInfo,
+    TunnelStatus, CONTROL_PORT, MAX_BINARY_FRAME_LENGTH, MAX_FRAME_LENGTH, NETWORK_TIMEOUT,
 };
 pub use timeouts::{BACKEND_HTTP_TIMEOUT, NETWORK_TIMEOUT as CLIENT_NETWORK_TIMEOUT};
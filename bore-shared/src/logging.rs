@@ -0,0 +1,223 @@
+//! Logging setup shared by the client and server binaries.
+//!
+//! Logs are written as text or as one JSON object per line, filtered by level
+//! directives in the `RUST_LOG` syntax, to the terminal or to a file rotated
+//! hourly or daily. Records on [`ACCESS_LOG_TARGET`] can be split off into a
+//! dedicated access log.
+//!
+//! This module needs the `logging` feature, so libraries embedding bore do not
+//! pull in a subscriber of their own.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use tracing::Level;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{self, RollingFileAppender};
+use tracing_subscriber::filter::{filter_fn, EnvFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt as layer_fmt, Layer, Registry};
+
+/// Target of access log records, one per proxied visitor connection.
+pub const ACCESS_LOG_TARGET: &str = "bore_access";
+
+/// How log records are formatted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+
+    /// One JSON object per record.
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("unknown log format {s:?}, expected text or json"),
+        }
+    }
+}
+
+/// How often log files are rotated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    /// A single file, never rotated.
+    #[default]
+    Never,
+
+    /// A new file every hour, suffixed with the date and hour.
+    Hourly,
+
+    /// A new file every day, suffixed with the date.
+    Daily,
+}
+
+impl LogRotation {
+    fn rotation(self) -> rolling::Rotation {
+        match self {
+            LogRotation::Never => rolling::Rotation::NEVER,
+            LogRotation::Hourly => rolling::Rotation::HOURLY,
+            LogRotation::Daily => rolling::Rotation::DAILY,
+        }
+    }
+}
+
+impl fmt::Display for LogRotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogRotation::Never => "never",
+            LogRotation::Hourly => "hourly",
+            LogRotation::Daily => "daily",
+        })
+    }
+}
+
+impl FromStr for LogRotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "never" => Ok(LogRotation::Never),
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            _ => bail!("unknown log rotation {s:?}, expected never, hourly or daily"),
+        }
+    }
+}
+
+/// Where and how a binary writes its logs.
+#[derive(Debug, Clone, Default)]
+pub struct LogConfig {
+    /// Format of log records.
+    pub format: LogFormat,
+
+    /// Level filter, e.g. `info` or `warn,bore_server=debug`. Falls back to
+    /// `RUST_LOG`, then to `info`.
+    pub level: Option<String>,
+
+    /// File to write logs to, instead of the terminal.
+    pub file: Option<PathBuf>,
+
+    /// How often the log file and access log are rotated.
+    pub rotation: LogRotation,
+
+    /// Rotated files to keep of each log, all of them if not set.
+    pub max_files: Option<usize>,
+
+    /// Write to stderr rather than stdout when logging to the terminal.
+    pub stderr: bool,
+
+    /// File for [`ACCESS_LOG_TARGET`] records, written as JSON lines. They
+    /// stay in the regular log if not set.
+    pub access_log: Option<PathBuf>,
+}
+
+/// Flushes buffered log files when dropped; keep it until the process exits.
+#[must_use = "dropping the guard stops writing log files"]
+pub struct LogGuard {
+    _writers: Vec<WorkerGuard>,
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+impl LogConfig {
+    /// Install these settings as the global logger.
+    pub fn init(&self) -> Result<LogGuard> {
+        let filter = match &self.level {
+            Some(level) => {
+                EnvFilter::try_new(level).with_context(|| format!("invalid log level {level:?}"))?
+            }
+            None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        };
+
+        let mut writers = Vec::new();
+        let mut layers: Vec<BoxedLayer> = Vec::new();
+        let split_access = self.access_log.is_some();
+        let main = match &self.file {
+            Some(path) => {
+                let (writer, guard) = self.file_writer(path)?;
+                writers.push(guard);
+                self.layer(writer, false)
+            }
+            None if self.stderr => self.layer(std::io::stderr, true),
+            None => self.layer(std::io::stdout, true),
+        };
+        layers.push(
+            main.with_filter(filter)
+                .with_filter(filter_fn(move |metadata| {
+                    !(split_access && metadata.target() == ACCESS_LOG_TARGET)
+                }))
+                .boxed(),
+        );
+
+        if let Some(path) = &self.access_log {
+            let (writer, guard) = self.file_writer(path)?;
+            writers.push(guard);
+            let access = layer_fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(false)
+                .with_writer(writer)
+                .with_filter(Targets::new().with_target(ACCESS_LOG_TARGET, Level::INFO));
+            layers.push(access.boxed());
+        }
+
+        tracing_subscriber::registry()
+            .with(layers)
+            .try_init()
+            .context("failed to install logger")?;
+        Ok(LogGuard { _writers: writers })
+    }
+
+    /// The main log layer, in the configured format.
+    fn layer<W>(&self, writer: W, ansi: bool) -> BoxedLayer
+    where
+        W: for<'w> layer_fmt::MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let layer = layer_fmt::layer().with_writer(writer).with_ansi(ansi);
+        match self.format {
+            LogFormat::Text => layer.boxed(),
+            LogFormat::Json => layer.json().boxed(),
+        }
+    }
+
+    /// A buffered writer to `path`, rotated as configured.
+    fn file_writer(&self, path: &Path) -> Result<(NonBlocking, WorkerGuard)> {
+        let name = path
+            .file_name()
+            .with_context(|| format!("log file {} has no file name", path.display()))?;
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut builder = RollingFileAppender::builder()
+            .rotation(self.rotation.rotation())
+            .filename_prefix(name.to_string_lossy());
+        if let Some(max_files) = self.max_files {
+            builder = builder.max_log_files(max_files);
+        }
+        let appender = builder
+            .build(directory)
+            .with_context(|| format!("failed to open log file {}", path.display()))?;
+        Ok(tracing_appender::non_blocking(appender))
+    }
+}
@@ -1,11 +1,17 @@
 //! Shared data structures, utilities, and protocol definitions.
 
-use anyhow::{Context, Result};
+use std::time::Duration;
+use std::{fmt, io};
+
+use anyhow::{bail, Context, Result};
 use futures_util::{SinkExt, StreamExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::time::timeout;
-use tokio_util::codec::{AnyDelimiterCodec, Framed, FramedParts};
+use tokio_util::bytes::{Bytes, BytesMut};
+use tokio_util::codec::{
+    AnyDelimiterCodec, Decoder, Encoder, Framed, FramedParts, LengthDelimitedCodec,
+};
 use tracing::trace;
 use uuid::Uuid;
 
@@ -13,8 +19,27 @@ use uuid::Uuid;
 pub const CONTROL_PORT: u16 = 7835;
 
 /// Maximum byte length for a JSON frame in the stream.
+///
+/// Only clients predating binary frames use JSON, so messages with signed
+/// tunnel tokens always travel in binary frames, under the negotiated limit.
 pub const MAX_FRAME_LENGTH: usize = 256;
 
+/// Maximum byte length for a binary frame that clients offer, and servers
+/// accept by default.
+pub const MAX_BINARY_FRAME_LENGTH: usize = 64 * 1024;
+
+/// Bytes starting a preamble, which asks for binary frames.
+///
+/// JSON frames never start with `B`, and the NUL byte makes servers that only
+/// know JSON frames fail right away instead of waiting for the frame to end.
+const PREAMBLE_MAGIC: &[u8; 5] = b"BORE\0";
+
+/// Version of the binary framing, sent in the preamble.
+const PREAMBLE_VERSION: u8 = 1;
+
+/// Length of a preamble: magic, version, and maximum frame length.
+const PREAMBLE_LENGTH: usize = PREAMBLE_MAGIC.len() + 1 + 4;
+
 // Re-export timeout constants from the centralized timeouts module
 pub use crate::timeouts::NETWORK_TIMEOUT;
 
@@ -24,11 +49,76 @@ pub enum ClientMessage {
     /// Response to an authentication challenge from the server.
     Authenticate(String),
 
+    /// Response to an authentication challenge, signed with an Ed25519 key.
+    ///
+    /// Both fields are hex encoded: the client's public key and its signature
+    /// over the challenge UUID.
+    AuthenticateKey {
+        /// Public key of the client, which the server looks up in its authorized keys.
+        key: String,
+        /// Signature over the challenge bytes.
+        signature: String,
+    },
+
     /// Initial client message specifying a port to forward.
     Hello(u16),
 
+    /// Like `Hello`, but shares the public port with other clients of the same
+    /// user joining the same group, instead of failing when it is taken.
+    ///
+    /// The group is found by name if given, and by port otherwise.
+    Join {
+        /// Port to forward, or 0 for any port if the group does not exist yet.
+        port: u16,
+        /// Name of the group.
+        group: Option<String>,
+    },
+
     /// Accepts an incoming TCP connection, using this stream as a proxy.
     Accept(Uuid),
+
+    /// Reports a change in the health of the local service, after `Hello`.
+    Status(TunnelStatus),
+
+    /// Opens another tunnel on this control connection, after `Hello` or `Join`.
+    ///
+    /// The client picks the ID of the tunnel, which must not be in use. ID 0
+    /// is the tunnel opened by `Hello` or `Join`.
+    Open {
+        /// ID of the new tunnel.
+        tunnel: u32,
+        /// Port to forward, or 0 for any port.
+        port: u16,
+    },
+
+    /// Closes a tunnel of this control connection.
+    Close(u32),
+
+    /// Asks for the tunnels open on this control connection.
+    List,
+}
+
+/// A tunnel open on a control connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    /// ID of the tunnel on its control connection.
+    pub tunnel: u32,
+
+    /// Port that is publicly available on the server.
+    pub port: u16,
+}
+
+/// Health of the local service behind a tunnel, as checked by the client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    /// Whether the local service passed its last health check.
+    pub healthy: bool,
+
+    /// Whether the local service speaks HTTP, so visitors can get an error page.
+    pub http: bool,
+
+    /// Why the health check failed, if it did.
+    pub detail: Option<String>,
 }
 
 /// A message from the server on the control connection.
@@ -46,34 +136,283 @@ pub enum ServerMessage {
     /// Asks the client to accept a forwarded TCP connection.
     Connection(Uuid),
 
+    /// Asks the client to accept a forwarded TCP connection of a tunnel opened
+    /// with `Open`.
+    TunnelConnection {
+        /// ID of the tunnel the visitor connected to.
+        tunnel: u32,
+        /// ID of the connection, for `Accept`.
+        id: Uuid,
+    },
+
+    /// Response to `Open`, with the public port of the new tunnel.
+    Opened {
+        /// ID of the tunnel.
+        tunnel: u32,
+        /// Port that is publicly available on the server.
+        port: u16,
+    },
+
+    /// Response to `Open` when the tunnel could not be opened.
+    Refused {
+        /// ID of the tunnel.
+        tunnel: u32,
+        /// Why the tunnel was not opened.
+        error: ServerError,
+    },
+
+    /// A tunnel was closed, because the client asked or its listener failed.
+    Closed(u32),
+
+    /// Response to `List`.
+    Tunnels(Vec<TunnelInfo>),
+
     /// Indicates a server error that terminates the connection.
+    ///
+    /// Sent to clients using JSON frames, which predate [`ServerError`].
     Error(String),
+
+    /// Indicates a server error that terminates the connection, with a code
+    /// clients can act on. Sent to clients that negotiated binary frames.
+    Failure(ServerError),
+}
+
+/// What went wrong on the server, for clients to decide how to react.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The server requires a credential, but the client sent none.
+    AuthRequired,
+
+    /// The credential is invalid, expired, revoked, or of a kind the server
+    /// does not accept.
+    InvalidCredentials,
+
+    /// The credential is valid, but the account may not open tunnels, e.g.
+    /// because its subscription expired.
+    UsageNotAllowed,
+
+    /// The user has as many tunnels open as their plan allows.
+    TunnelLimit,
+
+    /// The requested port is taken by another tunnel or process.
+    PortInUse,
+
+    /// The requested port is outside the server's range, reserved for someone
+    /// else, or not allowed for the credential.
+    PortNotAllowed,
+
+    /// Every port the client may use is taken.
+    NoPortsAvailable,
+
+    /// The server could not reach its backend to check the credential.
+    BackendUnavailable,
+
+    /// The client sent a message that makes no sense at this point.
+    InvalidRequest,
+
+    /// Anything else that failed on the server.
+    Internal,
+
+    /// The backend revoked the user's or instance's access, or closed the
+    /// session, while it was running.
+    Revoked,
+
+    /// A code this version does not know, sent by a newer server.
+    #[serde(other)]
+    Unknown,
 }
 
-/// Transport stream with JSON frames delimited by null characters.
-pub struct Delimited<U>(Framed<U, AnyDelimiterCodec>);
+impl ErrorCode {
+    /// How long a client should wait before trying again, if it is worth it.
+    #[must_use]
+    pub fn retry_after(self) -> Option<Duration> {
+        match self {
+            ErrorCode::BackendUnavailable | ErrorCode::Internal => Some(Duration::from_secs(5)),
+            ErrorCode::NoPortsAvailable => Some(Duration::from_secs(30)),
+            _ => None,
+        }
+    }
+}
+
+/// An error reported by the server, with a machine code and a message for people.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerError {
+    /// What went wrong.
+    pub code: ErrorCode,
+
+    /// Description of the error for people.
+    pub message: String,
+
+    /// Seconds to wait before trying again, or none if retrying won't help.
+    pub retry_after: Option<u64>,
+}
+
+impl ServerError {
+    /// An error with the retry hint of its code.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retry_after: code.retry_after().map(|delay| delay.as_secs()),
+        }
+    }
+
+    /// How long to wait before trying again, if retrying can help.
+    #[must_use]
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after.map(Duration::from_secs)
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Codec of a [`Delimited`] stream.
+#[derive(Debug)]
+pub enum FrameCodec {
+    /// JSON frames terminated by null characters, spoken by every client.
+    Json(AnyDelimiterCodec),
+
+    /// CBOR frames prefixed by their length, negotiated with a preamble.
+    Cbor(LengthDelimitedCodec),
+}
+
+impl FrameCodec {
+    fn json() -> Self {
+        FrameCodec::Json(AnyDelimiterCodec::new_with_max_length(
+            vec![0],
+            vec![0],
+            MAX_FRAME_LENGTH,
+        ))
+    }
+
+    fn cbor(max_frame_length: usize) -> Self {
+        FrameCodec::Cbor(
+            LengthDelimitedCodec::builder()
+                .max_frame_length(max_frame_length)
+                .new_codec(),
+        )
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        match self {
+            FrameCodec::Json(codec) => codec.decode(src).map_err(io::Error::other),
+            FrameCodec::Cbor(codec) => Ok(codec.decode(src)?.map(BytesMut::freeze)),
+        }
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        match self {
+            FrameCodec::Json(codec) => {
+                let text = std::str::from_utf8(&item).map_err(io::Error::other)?;
+                codec.encode(text, dst).map_err(io::Error::other)
+            }
+            FrameCodec::Cbor(codec) => codec.encode(item, dst),
+        }
+    }
+}
+
+/// Transport stream with JSON frames delimited by null characters, or with
+/// length-prefixed CBOR frames.
+///
+/// Streams start out with JSON frames. A client that wants binary frames
+/// opens the stream with a preamble naming the largest frame it accepts, and
+/// a server that understands it answers with a preamble of its own, carrying
+/// the largest frame both sides accept. Servers that only know JSON frames
+/// reject the preamble, so the client can reconnect without one.
+pub struct Delimited<U>(Framed<U, FrameCodec>);
 
 impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
-    /// Construct a new delimited stream.
+    /// Construct a new delimited stream with JSON frames.
     pub fn new(stream: U) -> Self {
-        let codec = AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], MAX_FRAME_LENGTH);
-        Self(Framed::new(stream, codec))
+        Self(Framed::new(stream, FrameCodec::json()))
     }
 
-    /// Read the next null-delimited JSON instruction from a stream.
+    /// Ask the server for binary frames no longer than `max_frame_length`.
+    ///
+    /// Fails if the server does not answer with a preamble, which servers
+    /// that only know JSON frames never do.
+    pub async fn negotiate(mut stream: U, max_frame_length: usize) -> Result<Self> {
+        stream
+            .write_all(&preamble(max_frame_length))
+            .await
+            .context("unable to send preamble")?;
+        let mut reply = [0; PREAMBLE_LENGTH];
+        timeout(NETWORK_TIMEOUT, stream.read_exact(&mut reply))
+            .await
+            .context("timed out waiting for preamble")?
+            .context("server did not answer the preamble")?;
+        let agreed = parse_preamble(&reply)?;
+        if agreed > max_frame_length {
+            bail!("server chose frames of {agreed} bytes, over the offered {max_frame_length}");
+        }
+        trace!(max_frame_length = agreed, "negotiated binary frames");
+        Ok(Self(Framed::new(stream, FrameCodec::cbor(agreed))))
+    }
+
+    /// Accept a stream from a client, with binary frames if it asks for them.
+    ///
+    /// Frames are no longer than `max_frame_length` or what the client offered.
+    /// Clients without a preamble get JSON frames.
+    pub async fn accept(mut stream: U, max_frame_length: usize) -> Result<Self> {
+        let mut first = [0; 1];
+        let read = timeout(NETWORK_TIMEOUT, stream.read(&mut first))
+            .await
+            .context("timed out waiting for initial message")??;
+        if read == 0 || first[0] != PREAMBLE_MAGIC[0] {
+            let mut parts = FramedParts::new::<Bytes>(stream, FrameCodec::json());
+            parts.read_buf.extend_from_slice(&first[..read]);
+            return Ok(Self(Framed::from_parts(parts)));
+        }
+
+        let mut offer = [0; PREAMBLE_LENGTH];
+        offer[0] = first[0];
+        timeout(NETWORK_TIMEOUT, stream.read_exact(&mut offer[1..]))
+            .await
+            .context("timed out waiting for preamble")??;
+        let agreed = parse_preamble(&offer)?.min(max_frame_length);
+        stream.write_all(&preamble(agreed)).await?;
+        trace!(max_frame_length = agreed, "negotiated binary frames");
+        Ok(Self(Framed::new(stream, FrameCodec::cbor(agreed))))
+    }
+
+    /// Whether the stream uses binary frames.
+    pub fn is_binary(&self) -> bool {
+        matches!(self.0.codec(), FrameCodec::Cbor(_))
+    }
+
+    /// Read the next message from a stream.
     pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
-        trace!("waiting to receive json message");
+        trace!("waiting to receive message");
         if let Some(next_message) = self.0.next().await {
             let byte_message = next_message.context("frame error, invalid byte length")?;
-            let serialized_obj =
-                serde_json::from_slice(&byte_message).context("unable to parse message")?;
+            let serialized_obj = if self.is_binary() {
+                ciborium::from_reader(&byte_message[..]).context("unable to parse message")?
+            } else {
+                serde_json::from_slice(&byte_message).context("unable to parse message")?
+            };
             Ok(serialized_obj)
         } else {
             Ok(None)
         }
     }
 
-    /// Read the next null-delimited JSON instruction, with a default timeout.
+    /// Read the next message, with a default timeout.
     ///
     /// This is useful for parsing the initial message of a stream for handshake or
     /// other protocol purposes, where we do not want to wait indefinitely.
@@ -83,15 +422,54 @@ impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
             .context("timed out waiting for initial message")?
     }
 
-    /// Send a null-terminated JSON instruction on a stream.
+    /// Send an error to the client, with its code if the client understands codes.
+    pub async fn send_error(&mut self, error: ServerError) -> Result<()> {
+        if self.is_binary() {
+            self.send(ServerMessage::Failure(error)).await
+        } else {
+            self.send(ServerMessage::Error(error.message)).await
+        }
+    }
+
+    /// Send a message on a stream.
     pub async fn send<T: Serialize>(&mut self, msg: T) -> Result<()> {
-        trace!("sending json message");
-        self.0.send(serde_json::to_string(&msg)?).await?;
+        trace!("sending message");
+        let frame = if self.is_binary() {
+            let mut frame = Vec::new();
+            ciborium::into_writer(&msg, &mut frame)?;
+            frame
+        } else {
+            serde_json::to_vec(&msg)?
+        };
+        self.0.send(Bytes::from(frame)).await?;
         Ok(())
     }
 
     /// Consume this object, returning current buffers and the inner transport.
-    pub fn into_parts(self) -> FramedParts<U, AnyDelimiterCodec> {
+    pub fn into_parts(self) -> FramedParts<U, FrameCodec> {
         self.0.into_parts()
     }
 }
+
+/// Build a preamble offering or agreeing to frames of `max_frame_length` bytes.
+fn preamble(max_frame_length: usize) -> [u8; PREAMBLE_LENGTH] {
+    let length = u32::try_from(max_frame_length).unwrap_or(u32::MAX);
+    let mut preamble = [0; PREAMBLE_LENGTH];
+    preamble[..PREAMBLE_MAGIC.len()].copy_from_slice(PREAMBLE_MAGIC);
+    preamble[PREAMBLE_MAGIC.len()] = PREAMBLE_VERSION;
+    preamble[PREAMBLE_MAGIC.len() + 1..].copy_from_slice(&length.to_be_bytes());
+    preamble
+}
+
+/// Parse a preamble, returning its maximum frame length.
+fn parse_preamble(preamble: &[u8; PREAMBLE_LENGTH]) -> Result<usize> {
+    let (magic, rest) = preamble.split_at(PREAMBLE_MAGIC.len());
+    if magic != PREAMBLE_MAGIC {
+        bail!("invalid preamble");
+    }
+    if rest[0] != PREAMBLE_VERSION {
+        bail!("unsupported framing version {}", rest[0]);
+    }
+    let length = u32::from_be_bytes(rest[1..].try_into()?);
+    Ok(length as usize)
+}
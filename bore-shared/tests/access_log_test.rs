@@ -0,0 +1,88 @@
+/// Integration test: access log of visitor connections
+///
+/// With an access log configured, every proxied visitor connection is written
+/// to it as one JSON line with the tunnel, user, visitor, duration, bytes each
+/// way and close reason, and is kept out of the regular log.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::Client;
+use bore_server::backend::NoopBackend;
+use bore_server::Server;
+use bore_shared::logging::LogConfig;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+#[tokio::test]
+async fn records_visitor_connections() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let dir = std::env::temp_dir().join(format!("bore-access-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let access_log = dir.join("access.log");
+    let _logs = LogConfig {
+        file: Some(dir.join("bore.log")),
+        access_log: Some(access_log.clone()),
+        ..LogConfig::default()
+    }
+    .init()?;
+
+    let mut server = Server::new(1024..=65535, None, NoopBackend, "test".to_string());
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A local service answering every five-byte request with "pong".
+    let service = TcpListener::bind("localhost:0").await?;
+    let local_port = service.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = service.accept().await {
+            let mut request = [0; 5];
+            if stream.read_exact(&mut request).await.is_ok() {
+                let _ = stream.write_all(b"pong").await;
+            }
+        }
+    });
+    let client = Client::new("localhost", local_port, "127.0.0.1", 0, None).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut visitor = TcpStream::connect(("127.0.0.1", port)).await?;
+    visitor.write_all(b"ping!").await?;
+    visitor.shutdown().await?;
+    let mut reply = Vec::new();
+    visitor.read_to_end(&mut reply).await?;
+    assert_eq!(reply, b"pong");
+
+    for _ in 0..50 {
+        let contents = std::fs::read_to_string(&access_log).unwrap_or_default();
+        if let Some(line) = contents.lines().next() {
+            let record: Value = serde_json::from_str(line)?;
+            assert_eq!(record["target"], "bore_access");
+            assert_eq!(record["tunnel"], port);
+            assert_eq!(record["user"], "legacy-user");
+            assert_eq!(record["visitor"], "127.0.0.1");
+            assert_eq!(record["bytes_in"], 5);
+            assert_eq!(record["bytes_out"], 4);
+            assert_eq!(record["reason"], "closed");
+            assert!(record["duration_ms"].is_u64());
+
+            let log = std::fs::read_to_string(dir.join("bore.log"))?;
+            assert!(!log.contains("visitor connection closed"), "{log}");
+            std::fs::remove_dir_all(&dir)?;
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    bail!("no access log record")
+}
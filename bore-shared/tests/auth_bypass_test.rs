@@ -10,7 +10,7 @@
 /// Fix: Server now rejects Authenticate messages when backend is disabled and
 /// legacy auth is configured, forcing proper Hello → Challenge → Response flow.
 use anyhow::Result;
-use bore_server::Server;
+use bore_server::{backend::NoopBackend, Server};
 use bore_shared::{ClientMessage, Delimited, ServerMessage, CONTROL_PORT};
 use std::net::{IpAddr, Ipv4Addr};
 use std::time::Duration;
@@ -21,7 +21,7 @@ use tokio::time;
 async fn test_auth_bypass_prevention() -> Result<()> {
     // Start server with legacy shared secret (backend disabled)
     let secret = "my-secret-123";
-    let mut server = Server::new(1024..=65535, Some(secret), None, None, "test".to_string());
+    let mut server = Server::new(1024..=65535, Some(secret), NoopBackend, "test".to_string());
     // Explicitly bind to loopback for constrained environments
     server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
     server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
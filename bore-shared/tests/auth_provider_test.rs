@@ -0,0 +1,92 @@
+/// Integration test: custom authentication providers
+///
+/// Library users can replace the server's built-in authentication with their own
+/// provider, chained with the built-in ones.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bore_client::Client;
+use bore_server::auth::{
+    AuthChain, AuthProvider, Credential, Identity, PeerInfo, SharedSecretProvider,
+};
+use bore_server::backend::NoopBackend;
+use bore_server::secrets::SharedSecrets;
+use bore_server::Server;
+use bore_shared::Authenticator;
+use lazy_static::lazy_static;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Admits `sk_team_*` keys from the local machine as a single team identity.
+struct TeamKeys;
+
+#[async_trait]
+impl AuthProvider for TeamKeys {
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        peer: &PeerInfo,
+    ) -> Result<Option<Identity>> {
+        match credential {
+            Credential::Token(key) if key.starts_with("sk_team_") => {
+                anyhow::ensure!(peer.addr.ip().is_loopback(), "team keys are local only");
+                Ok(Some(Identity::new("team", 1)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+async fn connect(credential: &str) -> Result<Client> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    Client::new("localhost", local_port, "localhost", 0, Some(credential)).await
+}
+
+#[tokio::test]
+async fn custom_provider_chained_with_builtin() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, NoopBackend, "test".to_string());
+    server.set_auth_provider(
+        AuthChain::new()
+            .with(TeamKeys)
+            .with(SharedSecretProvider::new(
+                Authenticator::new("s3cret"),
+                SharedSecrets::default(),
+            )),
+    );
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let first = connect("sk_team_alice").await?;
+    tokio::spawn(first.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The custom identity carries its own limit.
+    let err = connect("sk_team_bob").await.err().expect("team limit");
+    assert!(
+        err.to_string().contains("Maximum concurrent tunnels"),
+        "{err}"
+    );
+
+    // Tokens no provider handles are rejected.
+    let err = connect("sk_other").await.err().expect("unknown key");
+    assert!(err.to_string().contains("not supported"), "{err}");
+
+    // The built-in provider further down the chain still works.
+    connect("s3cret").await?;
+    assert!(connect("wrong").await.is_err());
+
+    Ok(())
+}
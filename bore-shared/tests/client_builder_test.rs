@@ -0,0 +1,146 @@
+/// Integration test: embedding a tunnel with the client builder
+///
+/// A tunnel started from a `ClientBuilder` runs in the background. Its handle
+/// knows the public port, counts forwarded connections, broadcasts events,
+/// moves the tunnel to another local service, and stops it.
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::{ClientBuilder, ClientEvent, ClientStats};
+use bore_server::backend::NoopBackend;
+use bore_server::Server;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+async fn spawn_server() {
+    let mut server = Server::new(1024..=65535, None, NoopBackend, "test".to_string());
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+/// A local service answering `reply` to four bytes.
+async fn spawn_service(reply: &'static [u8]) -> Result<u16> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = [0; 4];
+            let _ = stream.read_exact(&mut buf).await;
+            let _ = stream.write_all(reply).await;
+        }
+    });
+    Ok(port)
+}
+
+async fn visit(port: u16) -> Result<String> {
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"ping").await?;
+    let mut answer = String::new();
+    time::timeout(Duration::from_secs(2), stream.read_to_string(&mut answer)).await??;
+    Ok(answer)
+}
+
+/// Poll until the handle's stats satisfy `done`, failing after a few seconds.
+async fn wait_for_stats(
+    stats: impl Fn() -> ClientStats,
+    done: impl Fn(&ClientStats) -> bool,
+) -> Result<ClientStats> {
+    for _ in 0..100 {
+        let current = stats();
+        if done(&current) {
+            return Ok(current);
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    bail!("timed out waiting for stats, last {:?}", stats())
+}
+
+/// Poll until the server closes the public port after the client hung up.
+async fn wait_for_close(port: u16) -> Result<()> {
+    for _ in 0..100 {
+        if TcpStream::connect(("localhost", port)).await.is_err() {
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    bail!("public port {port} still open")
+}
+
+#[tokio::test]
+async fn handle_reports_and_controls_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server().await;
+    let first = spawn_service(b"first").await?;
+    let second = spawn_service(b"second").await?;
+
+    let seen = Arc::new(StdMutex::new(Vec::new()));
+    let recorded = Arc::clone(&seen);
+    let handle = ClientBuilder::new("localhost", first)
+        .on_event(move |event| recorded.lock().unwrap().push(event.clone()))
+        .start()
+        .await?;
+    let remote_port = handle.remote_port();
+    assert_ne!(remote_port, 0);
+    let mut events = handle.subscribe();
+
+    assert_eq!(visit(remote_port).await?, "first");
+    let stats = wait_for_stats(|| handle.stats(), |s| s.active_connections == 0).await?;
+    assert_eq!(
+        stats,
+        ClientStats {
+            connections: 1,
+            active_connections: 0,
+            bytes_in: 4,
+            bytes_out: 5,
+        }
+    );
+
+    // Subscribers get the same events as callbacks, once they subscribed.
+    loop {
+        let event = time::timeout(Duration::from_secs(2), events.recv()).await??;
+        if let ClientEvent::ConnectionClosed { bytes_in, .. } = event {
+            assert_eq!(bytes_in, 4);
+            break;
+        }
+    }
+
+    // New connections go to the new target without reconnecting.
+    handle.set_local_target("localhost", second);
+    assert_eq!(visit(remote_port).await?, "second");
+    wait_for_stats(|| handle.stats(), |s| s.connections == 2).await?;
+
+    handle.stop().await?;
+    wait_for_close(remote_port).await?;
+
+    let seen = seen.lock().unwrap();
+    assert!(matches!(
+        seen.first(),
+        Some(ClientEvent::Established { local_port, .. }) if *local_port == first
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn dropping_handle_stops_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server().await;
+    let local_port = spawn_service(b"hello").await?;
+
+    let handle = ClientBuilder::new("localhost", local_port).start().await?;
+    let remote_port = handle.remote_port();
+    assert_eq!(visit(remote_port).await?, "hello");
+    drop(handle);
+    wait_for_close(remote_port).await
+}
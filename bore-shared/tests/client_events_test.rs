@@ -0,0 +1,134 @@
+/// Integration test: events of a running client
+///
+/// The client reports its tunnel through typed events rather than printed text:
+/// the tunnel coming up, each forwarded connection with its byte counts, and
+/// the server hanging up. They serialize to the JSON lines of `--output json`.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::{Client, ClientEvent};
+use bore_server::backend::NoopBackend;
+use bore_server::Server;
+use bore_shared::{ClientMessage, Delimited, ServerMessage, CONTROL_PORT, MAX_BINARY_FRAME_LENGTH};
+use lazy_static::lazy_static;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+async fn next_event(events: &mut mpsc::UnboundedReceiver<ClientEvent>) -> Result<ClientEvent> {
+    match time::timeout(Duration::from_secs(3), events.recv()).await? {
+        Some(event) => Ok(event),
+        None => bail!("event stream ended"),
+    }
+}
+
+#[tokio::test]
+async fn tunnel_lifecycle_events() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, NoopBackend, "test".to_string());
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A local service answering "pong" to anything.
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = [0; 4];
+            let _ = stream.read_exact(&mut buf).await;
+            let _ = stream.write_all(b"pong!").await;
+        }
+    });
+
+    let mut client = Client::new("localhost", local_port, "localhost", 0, None).await?;
+    let remote_port = client.remote_port();
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    client.set_event_sink(events_tx);
+    tokio::spawn(client.listen());
+
+    assert_eq!(
+        next_event(&mut events).await?,
+        ClientEvent::Established {
+            server: "localhost".to_string(),
+            remote_port,
+            local_host: "localhost".to_string(),
+            local_port,
+        }
+    );
+
+    let mut visitor = TcpStream::connect(("localhost", remote_port)).await?;
+    visitor.write_all(b"ping").await?;
+    let mut answer = String::new();
+    visitor.read_to_string(&mut answer).await?;
+    assert_eq!(answer, "pong!");
+    drop(visitor);
+
+    let ClientEvent::ConnectionOpened { id } = next_event(&mut events).await? else {
+        bail!("expected a connection");
+    };
+    let closed = next_event(&mut events).await?;
+    assert_eq!(
+        closed,
+        ClientEvent::ConnectionClosed {
+            id,
+            bytes_in: 4,
+            bytes_out: 5,
+            error: None,
+        }
+    );
+    assert_eq!(
+        serde_json::to_value(&closed)?,
+        json!({
+            "event": "connection_closed",
+            "id": id,
+            "bytes_in": 4,
+            "bytes_out": 5,
+            "error": null,
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn disconnect_event() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A server that grants the tunnel, then hangs up.
+    let control = TcpListener::bind(("localhost", CONTROL_PORT)).await?;
+    tokio::spawn(async move {
+        let (stream, _) = control.accept().await?;
+        let mut stream = Delimited::accept(stream, MAX_BINARY_FRAME_LENGTH).await?;
+        let _: Option<ClientMessage> = stream.recv().await?;
+        stream.send(ServerMessage::Hello(4000)).await?;
+        anyhow::Ok(())
+    });
+
+    let mut client = Client::new("localhost", 1, "localhost", 0, None).await?;
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    client.set_event_sink(events_tx);
+    let listen = tokio::spawn(client.listen());
+
+    assert!(matches!(
+        next_event(&mut events).await?,
+        ClientEvent::Established {
+            remote_port: 4000,
+            ..
+        }
+    ));
+    assert_eq!(next_event(&mut events).await?, ClientEvent::Disconnected);
+    listen.await??;
+
+    Ok(())
+}
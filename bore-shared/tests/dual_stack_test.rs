@@ -0,0 +1,62 @@
+/// Integration test: dual-stack control and tunnel listeners
+///
+/// A server bound on both IPv4 and IPv6 loopback accepts clients on either,
+/// and each tunnel's public port forwards connections from both stacks.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_client::Client;
+use bore_server::{backend::NoopBackend, Server};
+use lazy_static::lazy_static;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+const V4: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const V6: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+/// Echo five bytes back on every connection.
+async fn echo(listener: TcpListener) -> Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        stream.write_all(&buf).await?;
+    }
+}
+
+#[tokio::test]
+async fn tunnel_accepts_both_stacks() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, NoopBackend, "test".to_string());
+    server.set_bind_addrs(vec![V4, V6]);
+    server.set_bind_tunnels_all(vec![V4, V6]);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The control connection goes over IPv6.
+    let local = TcpListener::bind((V4, 0)).await?;
+    let client = Client::new("127.0.0.1", local.local_addr()?.port(), "::1", 0, None).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    tokio::spawn(echo(local));
+
+    for ip in [V4, V6] {
+        let mut stream = TcpStream::connect(SocketAddr::new(ip, port)).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello", "echo over {ip}");
+    }
+
+    Ok(())
+}
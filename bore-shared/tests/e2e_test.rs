@@ -3,7 +3,7 @@ use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use bore_client::Client;
-use bore_server::Server;
+use bore_server::{backend::NoopBackend, Server};
 use bore_shared::CONTROL_PORT;
 use lazy_static::lazy_static;
 use rstest::*;
@@ -19,7 +19,9 @@ lazy_static! {
 
 /// Spawn the server, giving some time for the control port TcpListener to start.
 async fn spawn_server(secret: Option<&str>) {
-    tokio::spawn(Server::new(1024..=65535, secret, None, None, "test-server".to_string()).listen());
+    tokio::spawn(
+        Server::new(1024..=65535, secret, NoopBackend, "test-server".to_string()).listen(),
+    );
     time::sleep(Duration::from_millis(50)).await;
 }
 
@@ -125,7 +127,7 @@ async fn very_long_frame() -> Result<()> {
 fn empty_port_range() {
     let min_port = 5000;
     let max_port = 3000;
-    let _ = Server::new(min_port..=max_port, None, None, None, "test".to_string());
+    let _ = Server::new(min_port..=max_port, None, NoopBackend, "test".to_string());
 }
 
 #[tokio::test]
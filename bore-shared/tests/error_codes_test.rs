@@ -0,0 +1,153 @@
+/// Integration test: structured server errors
+///
+/// Clients that negotiated binary frames get errors with a machine code and
+/// a retry hint, which the command line client turns into distinct exit
+/// codes. Clients using JSON frames still get the plain message.
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bore_client::{exit, Client};
+use bore_server::backend::{RecordingBackend, UsageBackend, ValidateKeyResponse};
+use bore_server::outbox::OutboxEvent;
+use bore_server::Server;
+use bore_shared::{ClientMessage, Delimited, ErrorCode, ServerError, ServerMessage, CONTROL_PORT};
+use lazy_static::lazy_static;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+async fn spawn_server(backend: impl UsageBackend + 'static, ports: (u16, u16)) {
+    let mut server = Server::new(ports.0..=ports.1, None, backend, "test".to_string());
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+fn recording_backend() -> Arc<RecordingBackend> {
+    let backend = Arc::new(RecordingBackend::new());
+    backend.add_key("sk_one", ValidateKeyResponse::allowed("user_1", 1));
+    backend
+}
+
+/// Connect a client, expecting the server to refuse it.
+async fn refused(port: u16, key: &str) -> Result<anyhow::Error> {
+    match Client::new("localhost", 1, "localhost", port, Some(key)).await {
+        Ok(_) => bail!("expected the server to refuse the tunnel"),
+        Err(err) => Ok(err),
+    }
+}
+
+fn code(err: &anyhow::Error) -> Option<ErrorCode> {
+    err.downcast_ref::<ServerError>().map(|error| error.code)
+}
+
+#[tokio::test]
+async fn refusals_carry_codes() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(recording_backend(), (47400, 47401)).await;
+
+    let err = refused(0, "sk_wrong").await?;
+    assert_eq!(code(&err), Some(ErrorCode::InvalidCredentials));
+    assert!(err.to_string().contains("Invalid API key"), "{err}");
+    assert_eq!(exit::for_error(&err), 11);
+
+    let err = refused(80, "sk_one").await?;
+    assert_eq!(code(&err), Some(ErrorCode::PortNotAllowed));
+
+    let first = Client::new("localhost", 1, "localhost", 47400, Some("sk_one")).await?;
+    let err = refused(0, "sk_one").await?;
+    assert_eq!(code(&err), Some(ErrorCode::TunnelLimit));
+    assert_eq!(exit::for_error(&err), 13);
+    assert_eq!(err.downcast_ref::<ServerError>().unwrap().retry_after, None);
+    drop(first);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn busy_ports_carry_codes() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let backend = Arc::new(RecordingBackend::new());
+    backend.add_key("sk_many", ValidateKeyResponse::allowed("user_1", 10));
+    spawn_server(backend, (47410, 47410)).await;
+
+    let _first = Client::new("localhost", 1, "localhost", 47410, Some("sk_many")).await?;
+    let err = refused(47410, "sk_many").await?;
+    assert_eq!(code(&err), Some(ErrorCode::PortInUse));
+
+    let err = refused(0, "sk_many").await?;
+    let error = err.downcast_ref::<ServerError>().unwrap();
+    assert_eq!(error.code, ErrorCode::NoPortsAvailable);
+    assert_eq!(error.retry_after(), Some(Duration::from_secs(30)));
+
+    Ok(())
+}
+
+/// A backend that cannot be reached.
+struct UnreachableBackend;
+
+#[async_trait]
+impl UsageBackend for UnreachableBackend {
+    async fn validate_api_key(&self, _api_key: &str) -> Result<ValidateKeyResponse> {
+        bail!("connection refused")
+    }
+
+    async fn is_token_revoked(&self, _jti: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn deliver_event(&self, _event_id: &str, _event: &OutboxEvent) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn backend_outage_is_retryable() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(UnreachableBackend, (47420, 47421)).await;
+
+    let err = refused(0, "sk_any").await?;
+    let error = err.downcast_ref::<ServerError>().unwrap();
+    assert_eq!(error.code, ErrorCode::BackendUnavailable);
+    assert!(error.retry_after().is_some());
+    assert_eq!(exit::for_error(&err), 17);
+
+    Ok(())
+}
+
+#[test]
+fn unknown_codes_decode() -> Result<()> {
+    let error: ServerError = serde_json::from_str(
+        r#"{"code":"quota_exceeded","message":"Monthly quota exceeded","retry_after":null}"#,
+    )?;
+    assert_eq!(error.code, ErrorCode::Unknown);
+    assert_eq!(error.message, "Monthly quota exceeded");
+    assert_eq!(exit::for_code(error.code), exit::FAILURE);
+    Ok(())
+}
+
+#[tokio::test]
+async fn json_clients_get_plain_messages() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(recording_backend(), (47430, 47431)).await;
+
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    stream
+        .send(ClientMessage::Authenticate("sk_wrong".to_string()))
+        .await?;
+    match stream.recv_timeout().await? {
+        Some(ServerMessage::Error(message)) => assert!(message.contains("Invalid API key")),
+        other => bail!("expected a plain error, got {other:?}"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+/// Integration test: client failover across several servers
+///
+/// A client given several servers connects to one of them, and when that
+/// server shuts down and closes its tunnel, moves to another, reporting the
+/// new server and public port in its events.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_client::{ClientBuilder, ClientEvent};
+use bore_server::backend::NoopBackend;
+use bore_server::Server;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Mutex};
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+const NODE_A: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+const NODE_B: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+/// Start a server on `addr`, returning the trigger to shut it down.
+async fn spawn_server(addr: Ipv4Addr) -> oneshot::Sender<()> {
+    let mut server = Server::new(1024..=65535, None, NoopBackend, addr.to_string());
+    server.set_bind_addr(IpAddr::V4(addr));
+    server.set_bind_tunnels(IpAddr::V4(addr));
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    tokio::spawn(server.listen_until(async {
+        let _ = stopped.await;
+    }));
+    time::sleep(Duration::from_millis(50)).await;
+    shutdown
+}
+
+/// A local service echoing what it receives.
+async fn spawn_echo() -> Result<u16> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok(port)
+}
+
+async fn echo_through(server: &str, port: u16) -> Result<()> {
+    let mut stream = TcpStream::connect((server, port)).await?;
+    stream.write_all(b"ping").await?;
+    let mut buf = [0; 4];
+    time::timeout(Duration::from_secs(2), stream.read_exact(&mut buf)).await??;
+    assert_eq!(&buf, b"ping");
+    Ok(())
+}
+
+#[tokio::test]
+async fn moves_to_another_server() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let mut shutdowns = vec![
+        (NODE_A.to_string(), spawn_server(NODE_A).await),
+        (NODE_B.to_string(), spawn_server(NODE_B).await),
+    ];
+    let local_port = spawn_echo().await?;
+
+    let handle = ClientBuilder::new(&NODE_A.to_string(), local_port)
+        .alternate_server(&NODE_B.to_string())
+        .start()
+        .await?;
+    let mut events = handle.subscribe();
+    let first = handle.server();
+    echo_through(&first, handle.remote_port()).await?;
+
+    // Shut down the server the client chose.
+    let index = shutdowns
+        .iter()
+        .position(|(server, _)| *server == first)
+        .expect("client is on one of the servers");
+    let (_, shutdown) = shutdowns.remove(index);
+    shutdown.send(()).unwrap();
+
+    let mut reconnecting = false;
+    let (server, remote_port) = loop {
+        let event = time::timeout(Duration::from_secs(5), events.recv()).await??;
+        match event {
+            ClientEvent::Reconnecting { attempt, .. } => {
+                assert_eq!(attempt, 1);
+                reconnecting = true;
+            }
+            // The first tunnel may still be reported after subscribing.
+            ClientEvent::Established {
+                server,
+                remote_port,
+                ..
+            } if reconnecting => break (server, remote_port),
+            _ => {}
+        }
+    };
+    assert_eq!(server, shutdowns[0].0);
+    assert_eq!(handle.server(), server);
+    assert_eq!(handle.remote_port(), remote_port);
+    echo_through(&server, remote_port).await?;
+    assert!(!handle.is_finished());
+
+    handle.stop().await
+}
+
+#[tokio::test]
+async fn single_server_does_not_fail_over() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let shutdown = spawn_server(NODE_A).await;
+    let local_port = spawn_echo().await?;
+
+    let mut handle = ClientBuilder::new(&NODE_A.to_string(), local_port)
+        .start()
+        .await?;
+    shutdown.send(()).unwrap();
+    time::timeout(Duration::from_secs(2), handle.wait()).await??;
+    Ok(())
+}
@@ -0,0 +1,124 @@
+/// Integration test: negotiated binary framing
+///
+/// Clients open control connections with a preamble asking for length-prefixed
+/// CBOR frames, which lifts the size limit of JSON frames. Servers still speak
+/// JSON frames to clients without a preamble, and clients fall back to JSON
+/// frames when the server does not understand the preamble.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_client::Client;
+use bore_server::backend::NoopBackend;
+use bore_server::Server;
+use bore_shared::{
+    ClientMessage, Delimited, ServerMessage, CONTROL_PORT, MAX_BINARY_FRAME_LENGTH,
+    MAX_FRAME_LENGTH,
+};
+use lazy_static::lazy_static;
+use tokio::io::duplex;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+#[tokio::test]
+async fn negotiated_frames_exceed_json_limit() -> Result<()> {
+    let (client, server) = duplex(1 << 20);
+    let (client, server) = tokio::join!(
+        Delimited::negotiate(client, 2 * MAX_FRAME_LENGTH),
+        Delimited::accept(server, MAX_BINARY_FRAME_LENGTH),
+    );
+    let (mut client, mut server) = (client?, server?);
+    assert!(client.is_binary() && server.is_binary());
+
+    // Too large for a JSON frame, but within the size both sides agreed on.
+    let token = "t".repeat(MAX_FRAME_LENGTH + 100);
+    client
+        .send(ClientMessage::Authenticate(token.clone()))
+        .await?;
+    let Some(ClientMessage::Authenticate(received)) = server.recv().await? else {
+        panic!("expected an authenticate message");
+    };
+    assert_eq!(received, token);
+
+    // The client offered less than the server accepts, so that is the limit.
+    let token = "t".repeat(2 * MAX_FRAME_LENGTH + 100);
+    assert!(client
+        .send(ClientMessage::Authenticate(token))
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn json_frames_without_preamble() -> Result<()> {
+    let (client, server) = duplex(1 << 20);
+    let mut client = Delimited::new(client);
+    client.send(ClientMessage::Hello(0)).await?;
+
+    let mut server = Delimited::accept(server, MAX_BINARY_FRAME_LENGTH).await?;
+    assert!(!server.is_binary());
+    assert!(matches!(
+        server.recv().await?,
+        Some(ClientMessage::Hello(0))
+    ));
+    server.send(ServerMessage::Hello(4000)).await?;
+    assert!(matches!(
+        client.recv().await?,
+        Some(ServerMessage::Hello(4000))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_serves_json_clients() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, NoopBackend, "test".to_string());
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A client predating binary frames.
+    let mut old = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    old.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = old.recv_timeout().await? else {
+        panic!("expected a hello message");
+    };
+    assert_ne!(port, 0);
+
+    // A current client negotiates binary frames with the same server.
+    let client = Client::new("localhost", 1, "localhost", 0, None).await?;
+    assert_ne!(client.remote_port(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_falls_back_to_json_frames() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A server predating binary frames, which rejects the preamble.
+    let control = TcpListener::bind(("localhost", CONTROL_PORT)).await?;
+    let server = tokio::spawn(async move {
+        let (stream, _) = control.accept().await?;
+        let preamble = Delimited::new(stream).recv::<ClientMessage>().await;
+        assert!(preamble.is_err());
+
+        let (stream, _) = control.accept().await?;
+        let mut stream = Delimited::new(stream);
+        let hello = stream.recv().await?;
+        assert!(matches!(hello, Some(ClientMessage::Hello(0))));
+        stream.send(ServerMessage::Hello(4000)).await
+    });
+
+    let client = Client::new("localhost", 1, "localhost", 0, None).await?;
+    assert_eq!(client.remote_port(), 4000);
+    server.await??;
+    Ok(())
+}
@@ -0,0 +1,148 @@
+/// Integration test: client-side health checks
+///
+/// A client checking its local service reports the service's health to the
+/// server. While it is down, HTTP visitors get a 502 page and other visitors
+/// are disconnected, and the backend learns about each status change.
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::health::HealthCheck;
+use bore_client::Client;
+use bore_server::backend::{RecordingBackend, ValidateKeyResponse};
+use bore_server::outbox::OutboxEvent;
+use bore_server::Server;
+use bore_shared::TunnelStatus;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+async fn spawn_server() -> Arc<RecordingBackend> {
+    let backend = Arc::new(RecordingBackend::new());
+    let mut validation = ValidateKeyResponse::allowed("user_1", 2);
+    validation.instance_id = Some("inst_1".to_string());
+    backend.add_key("sk_health", validation);
+
+    let mut server = Server::new(1024..=65535, None, Arc::clone(&backend), "test".to_string());
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    backend
+}
+
+/// An HTTP service answering 200, or 503 while `up` is false.
+async fn spawn_service(up: Arc<AtomicBool>) -> Result<u16> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let status = if up.load(Ordering::SeqCst) {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let response = format!("HTTP/1.1 {status}\r\nContent-Length: 5\r\n\r\nhello");
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    Ok(port)
+}
+
+/// Send a request to the public port and read the whole answer.
+async fn visit(port: u16) -> Result<String> {
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+    let mut answer = Vec::new();
+    time::timeout(Duration::from_secs(2), stream.read_to_end(&mut answer)).await??;
+    Ok(String::from_utf8_lossy(&answer).into_owned())
+}
+
+/// Poll until the backend was told about a status, failing after a few seconds.
+async fn wait_for_status(backend: &RecordingBackend, healthy: bool) -> Result<TunnelStatus> {
+    for _ in 0..100 {
+        let reported = backend.events().into_iter().find_map(|event| match event {
+            OutboxEvent::TunnelConnected {
+                status: Some(status),
+                ..
+            } if status.healthy == healthy => Some(status),
+            _ => None,
+        });
+        if let Some(status) = reported {
+            return Ok(status);
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    bail!("timed out waiting for status healthy={healthy}")
+}
+
+#[tokio::test]
+async fn http_tunnel_serves_error_page_while_down() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let backend = spawn_server().await;
+
+    let up = Arc::new(AtomicBool::new(true));
+    let local_port = spawn_service(Arc::clone(&up)).await?;
+    let mut client =
+        Client::new("localhost", local_port, "localhost", 0, Some("sk_health")).await?;
+    let remote_port = client.remote_port();
+    client.set_health_check("http:/healthz".parse()?, CHECK_INTERVAL);
+    tokio::spawn(client.listen());
+
+    wait_for_status(&backend, true).await?;
+    assert!(visit(remote_port).await?.ends_with("hello"));
+
+    up.store(false, Ordering::SeqCst);
+    let status = wait_for_status(&backend, false).await?;
+    assert!(status.http);
+    assert_eq!(status.detail.as_deref(), Some("/healthz returned HTTP 503"));
+
+    let answer = visit(remote_port).await?;
+    assert!(answer.starts_with("HTTP/1.1 502 Bad Gateway"), "{answer}");
+    assert!(answer.contains("Service unavailable"), "{answer}");
+
+    // Once the service recovers, visitors reach it again.
+    up.store(true, Ordering::SeqCst);
+    time::sleep(CHECK_INTERVAL * 3).await;
+    assert!(visit(remote_port).await?.ends_with("hello"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn tcp_tunnel_disconnects_visitors_while_down() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let backend = spawn_server().await;
+
+    // Nothing listens on the local port.
+    let local_port = TcpListener::bind("localhost:0").await?.local_addr()?.port();
+    let mut client =
+        Client::new("localhost", local_port, "localhost", 0, Some("sk_health")).await?;
+    let remote_port = client.remote_port();
+    client.set_health_check(HealthCheck::Tcp, CHECK_INTERVAL);
+    tokio::spawn(client.listen());
+
+    let status = wait_for_status(&backend, false).await?;
+    assert!(!status.http);
+
+    // The visitor is disconnected without an answer.
+    match visit(remote_port).await {
+        Ok(answer) => assert_eq!(answer, ""),
+        Err(err) => assert!(err.to_string().contains("reset"), "{err}"),
+    }
+
+    Ok(())
+}
@@ -1,7 +1,8 @@
 /// Mock backend server for testing without external dependencies
 use anyhow::Result;
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -15,9 +16,24 @@ pub struct MockBackend {
     instances: Arc<Mutex<HashMap<String, Instance>>>,
     api_keys: Arc<Mutex<HashMap<String, String>>>, // api_key -> user_id
     tunnel_tokens: Arc<Mutex<HashMap<String, TunnelToken>>>,
+    suspended_users: Arc<Mutex<HashSet<String>>>, // users whose usage is not allowed
+    validation_requests: Arc<AtomicUsize>,
+    outage: Arc<AtomicBool>,
+    events: Arc<Mutex<Vec<DeliveredEvent>>>,
+    duplicate_events: Arc<AtomicUsize>,
+    lose_responses: Arc<AtomicBool>,
     port: u16,
 }
 
+/// A lifecycle or usage event accepted by the backend
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct DeliveredEvent {
+    pub id: String,
+    pub path: String,
+    pub body: serde_json::Value,
+}
+
 #[derive(Clone, Debug)]
 #[allow(dead_code)]
 struct User {
@@ -56,6 +72,12 @@ impl MockBackend {
             instances: Arc::new(Mutex::new(HashMap::new())),
             api_keys: Arc::new(Mutex::new(HashMap::new())),
             tunnel_tokens: Arc::new(Mutex::new(HashMap::new())),
+            suspended_users: Arc::new(Mutex::new(HashSet::new())),
+            validation_requests: Arc::new(AtomicUsize::new(0)),
+            outage: Arc::new(AtomicBool::new(false)),
+            events: Arc::new(Mutex::new(Vec::new())),
+            duplicate_events: Arc::new(AtomicUsize::new(0)),
+            lose_responses: Arc::new(AtomicBool::new(false)),
             port,
         }
     }
@@ -106,12 +128,25 @@ impl MockBackend {
             ""
         };
 
+        if path.starts_with("/api/internal/validate-key") {
+            self.validation_requests.fetch_add(1, Ordering::SeqCst);
+        }
+        if self.outage.load(Ordering::SeqCst) {
+            return self.error_response(503, "Service Unavailable");
+        }
+
         // Route requests
         match (method, path) {
             ("GET", "/health") => self.health_check(),
             ("POST", path) if path.starts_with("/api/internal/validate-key") => {
                 self.validate_api_key_internal(body)
             }
+            ("POST", path)
+                if path.starts_with("/api/internal/tunnel/")
+                    || path.starts_with("/api/internal/instances/") =>
+            {
+                self.record_event(request, path, body)
+            }
             ("POST", path) if path.starts_with("/api/v1/auth/validate") => {
                 self.validate_api_key(body)
             }
@@ -122,6 +157,36 @@ impl MockBackend {
         }
     }
 
+    /// Apply an event once per idempotency key, like the real backend does
+    fn record_event(&self, request: &str, path: &str, body: &str) -> String {
+        let id = request
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("idempotency-key"))
+            .map(|(_, value)| value.trim().to_string());
+        let Some(id) = id else {
+            return self.error_response(400, "Missing Idempotency-Key");
+        };
+
+        let mut events = self.events.lock().unwrap();
+        if events.iter().any(|event| event.id == id) {
+            self.duplicate_events.fetch_add(1, Ordering::SeqCst);
+        } else {
+            events.push(DeliveredEvent {
+                id,
+                path: path.to_string(),
+                body: serde_json::from_str(body).unwrap_or_default(),
+            });
+        }
+        drop(events);
+
+        if self.lose_responses.load(Ordering::SeqCst) {
+            // The event was applied, but the server never learns about it.
+            return self.error_response(503, "Service Unavailable");
+        }
+        self.json_response(200, &json!({ "ok": true }))
+    }
+
     fn health_check(&self) -> String {
         let body = json!({
             "status": "healthy",
@@ -160,6 +225,7 @@ impl MockBackend {
                     if let Some(user_id) = api_keys.get(api_key) {
                         let users = self.users.lock().unwrap();
                         if let Some(user) = users.get(user_id) {
+                            let suspended = self.suspended_users.lock().unwrap().contains(user_id);
                             let body = json!({
                                 "valid": true,
                                 "user_id": user.id,
@@ -167,8 +233,8 @@ impl MockBackend {
                                 "plan_type": "pro",
                                 "max_concurrent_tunnels": 10,
                                 "max_bandwidth_gb": 1000,
-                                "usage_allowed": true,
-                                "message": null,
+                                "usage_allowed": !suspended,
+                                "message": suspended.then_some("Subscription expired"),
                                 "instance_id": null
                             });
                             return self.json_response(200, &body);
@@ -250,6 +316,7 @@ impl MockBackend {
             400 => "Bad Request",
             401 => "Unauthorized",
             404 => "Not Found",
+            503 => "Service Unavailable",
             _ => "Unknown",
         };
 
@@ -291,6 +358,50 @@ impl MockBackend {
         api_key
     }
 
+    /// Revoke an API key, so that it no longer validates
+    pub fn revoke_api_key(&self, api_key: &str) {
+        self.api_keys.lock().unwrap().remove(api_key);
+    }
+
+    /// Allow or disallow usage for the user owning an API key, as when a
+    /// subscription expires or is renewed
+    pub fn set_usage_allowed(&self, api_key: &str, allowed: bool) {
+        let Some(user_id) = self.api_keys.lock().unwrap().get(api_key).cloned() else {
+            return;
+        };
+        let mut suspended = self.suspended_users.lock().unwrap();
+        if allowed {
+            suspended.remove(&user_id);
+        } else {
+            suspended.insert(user_id);
+        }
+    }
+
+    /// Number of API key validations the server has requested so far
+    pub fn validation_requests(&self) -> usize {
+        self.validation_requests.load(Ordering::SeqCst)
+    }
+
+    /// Simulate a backend outage: every request is answered with 503
+    pub fn set_outage(&self, outage: bool) {
+        self.outage.store(outage, Ordering::SeqCst);
+    }
+
+    /// Apply events but answer 503, as if responses were lost on the way back
+    pub fn set_lose_responses(&self, lose: bool) {
+        self.lose_responses.store(lose, Ordering::SeqCst);
+    }
+
+    /// Events applied so far, in order, each at most once
+    pub fn events(&self) -> Vec<DeliveredEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Number of redelivered events that were recognized and dropped
+    pub fn duplicate_events(&self) -> usize {
+        self.duplicate_events.load(Ordering::SeqCst)
+    }
+
     /// Create a tunnel token (for setup)
     pub fn create_tunnel_token(&self, instance_id: &str, user_id: &str, ttl_secs: u64) -> String {
         let token = format!("tk_test_{}", uuid::Uuid::new_v4().simple());
@@ -1,5 +1,6 @@
 /// Test helper utilities for integration tests
 use anyhow::Result;
+use bore_server::backend::{BackendClient, NoopBackend};
 use bore_server::Server;
 use std::net::TcpListener;
 use std::time::Duration;
@@ -14,14 +15,22 @@ pub fn find_available_port() -> Result<u16> {
 }
 
 /// Spawn a test bore-server with given configuration
+#[allow(dead_code)]
 pub async fn spawn_test_server(secret: Option<&str>, backend_url: Option<&str>) -> Result<()> {
-    let server = Server::new(
-        10000..=60000,
-        secret,
-        backend_url.map(|s| s.to_string()),
-        None,
-        "test-server".to_string(),
-    );
+    let server = match backend_url {
+        Some(url) => Server::new(
+            10000..=60000,
+            secret,
+            BackendClient::new(url, None),
+            "test-server".to_string(),
+        ),
+        None => Server::new(
+            10000..=60000,
+            secret,
+            NoopBackend,
+            "test-server".to_string(),
+        ),
+    };
 
     tokio::spawn(async move {
         // Ignore errors since we expect the server to be killed between tests
@@ -0,0 +1,184 @@
+/// Integration test: Ed25519 public-key authentication
+///
+/// The server loads an authorized keys file mapping keys to users and limits.
+/// Clients sign the server's challenge with their private key. Saved private
+/// keys are readable by their owner only.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Result};
+use bore_client::Client;
+use bore_server::{backend::NoopBackend, keys::AuthorizedKeys, Server};
+use bore_shared::{ClientMessage, Delimited, KeyPair, PublicKey, ServerMessage};
+use lazy_static::lazy_static;
+use tokio::io;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::time;
+use uuid::Uuid;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Spawn a server that accepts the given authorized keys and optional secret.
+async fn spawn_server(keys: &str, secret: Option<&str>) -> Result<()> {
+    let mut server = Server::new(1024..=65535, secret, NoopBackend, "test".to_string());
+    server.set_authorized_keys(AuthorizedKeys::parse(keys)?);
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    Ok(())
+}
+
+async fn local_port() -> Result<(TcpListener, u16)> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let port = listener.local_addr()?.port();
+    Ok((listener, port))
+}
+
+#[tokio::test]
+async fn key_handshake() -> Result<()> {
+    let key = KeyPair::generate();
+
+    let (client, server) = io::duplex(8); // Ensure correctness with limited capacity.
+    let mut client = Delimited::new(client);
+    let mut server = Delimited::new(server);
+
+    let server_side = async {
+        let challenge = Uuid::new_v4();
+        server.send(ServerMessage::Challenge(challenge)).await?;
+        match server.recv_timeout().await? {
+            Some(ClientMessage::AuthenticateKey {
+                key: public,
+                signature,
+            }) => {
+                let public = PublicKey::from_hex(&public)?;
+                ensure!(public == key.public_key(), "wrong public key");
+                ensure!(public.verify(&challenge, &signature), "bad signature");
+                Ok(())
+            }
+            other => bail!("unexpected reply {other:?}"),
+        }
+    };
+
+    tokio::try_join!(key.client_handshake(&mut client), server_side)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn authorized_key_connects() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let key = KeyPair::generate();
+    spawn_server(&format!("{} alice max_tunnels=1", key.public_key()), None).await?;
+
+    let (_listener, port) = local_port().await?;
+    let client = Client::with_key("localhost", port, "localhost", 0, &key).await?;
+    assert!(client.remote_port() > 0);
+    tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The key's own limit applies, independent of other keys.
+    let (_listener, port) = local_port().await?;
+    let err = Client::with_key("localhost", port, "localhost", 0, &key)
+        .await
+        .err()
+        .expect("second tunnel should exceed max_tunnels=1");
+    assert!(
+        err.to_string().contains("Maximum concurrent tunnels"),
+        "{err}"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unknown_key_rejected() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let known = KeyPair::generate();
+    spawn_server(&format!("{} alice", known.public_key()), None).await?;
+
+    let (_listener, port) = local_port().await?;
+    let stranger = KeyPair::generate();
+    assert!(
+        Client::with_key("localhost", port, "localhost", 0, &stranger)
+            .await
+            .is_err()
+    );
+
+    // Clients without any credentials are challenged and rejected too.
+    assert!(Client::new("localhost", port, "localhost", 0, None)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn key_port_restrictions() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let key = KeyPair::generate();
+    spawn_server(
+        &format!("{} alice ports=47000-47009", key.public_key()),
+        None,
+    )
+    .await?;
+
+    let (_listener, port) = local_port().await?;
+    let client = Client::with_key("localhost", port, "localhost", 0, &key).await?;
+    assert!((47000..=47009).contains(&client.remote_port()));
+    drop(client);
+
+    assert!(
+        Client::with_key("localhost", port, "localhost", 48000, &key)
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn secret_and_keys_together() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let key = KeyPair::generate();
+    spawn_server(&format!("{} alice", key.public_key()), Some("shared")).await?;
+
+    let (_listener, port) = local_port().await?;
+    Client::with_key("localhost", port, "localhost", 0, &key).await?;
+    Client::new("localhost", port, "localhost", 0, Some("shared")).await?;
+    assert!(
+        Client::new("localhost", port, "localhost", 0, Some("wrong"))
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn saved_key_is_private() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("bore-key-{}", Uuid::new_v4()));
+    let path = dir.join("id_ed25519");
+    std::fs::create_dir_all(&dir)?;
+    // Overwriting a world-readable file, as with `bore keygen --force`.
+    std::fs::write(&path, "old key\n")?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+
+    let key = KeyPair::generate();
+    bore_client::auth::save_key(&key, &path)?;
+    let mode = std::fs::metadata(&path)?.permissions().mode();
+    ensure!(mode & 0o777 == 0o600, "key file mode {mode:o}");
+    ensure!(bore_client::auth::load_key(&path)?.to_hex() == key.to_hex());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,135 @@
+/// Integration test: several tunnels over one control connection
+///
+/// A running client opens more tunnels to other local services without
+/// reconnecting or authenticating again. Each tunnel counts against the
+/// user's tunnel limit, and closing one frees its public port.
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::{ClientBuilder, ClientEvent};
+use bore_server::backend::{RecordingBackend, UsageBackend, ValidateKeyResponse};
+use bore_server::Server;
+use bore_shared::{ErrorCode, ServerError, TunnelInfo};
+use lazy_static::lazy_static;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+async fn spawn_server(backend: impl UsageBackend + 'static) {
+    let mut server = Server::new(1024..=65535, None, backend, "test".to_string());
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+/// A local service answering `reply` to four bytes.
+async fn spawn_service(reply: &'static [u8]) -> Result<u16> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = [0; 4];
+            let _ = stream.read_exact(&mut buf).await;
+            let _ = stream.write_all(reply).await;
+        }
+    });
+    Ok(port)
+}
+
+async fn visit(port: u16) -> Result<String> {
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"ping").await?;
+    let mut answer = String::new();
+    time::timeout(Duration::from_secs(2), stream.read_to_string(&mut answer)).await??;
+    Ok(answer)
+}
+
+/// Poll until the server closes a public port.
+async fn wait_for_close(port: u16) -> Result<()> {
+    for _ in 0..100 {
+        if TcpStream::connect(("localhost", port)).await.is_err() {
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    bail!("public port {port} still open")
+}
+
+#[tokio::test]
+async fn tunnels_share_a_connection() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let backend = Arc::new(RecordingBackend::new());
+    backend.add_key("sk_two", ValidateKeyResponse::allowed("user_1", 2));
+    spawn_server(Arc::clone(&backend)).await;
+    let first = spawn_service(b"first").await?;
+    let second = spawn_service(b"second").await?;
+
+    let handle = ClientBuilder::new("localhost", first)
+        .secret("sk_two")
+        .start()
+        .await?;
+    let mut events = handle.subscribe();
+
+    let opened = handle.open_tunnel("localhost", second, 0).await?;
+    assert_ne!(opened.tunnel, 0);
+    assert_ne!(opened.port, handle.remote_port());
+    assert_eq!(visit(handle.remote_port()).await?, "first");
+    assert_eq!(visit(opened.port).await?, "second");
+
+    let mut tunnels = handle.tunnels().await?;
+    tunnels.sort_by_key(|info| info.tunnel);
+    assert_eq!(
+        tunnels,
+        vec![
+            TunnelInfo {
+                tunnel: 0,
+                port: handle.remote_port(),
+            },
+            opened,
+        ]
+    );
+
+    // A third tunnel is over the user's limit.
+    let err = handle
+        .open_tunnel("localhost", second, 0)
+        .await
+        .unwrap_err();
+    let code = err.downcast_ref::<ServerError>().map(|error| error.code);
+    assert_eq!(code, Some(ErrorCode::TunnelLimit));
+
+    handle.close_tunnel(opened.tunnel).await?;
+    wait_for_close(opened.port).await?;
+    assert_eq!(visit(handle.remote_port()).await?, "first");
+    assert_eq!(handle.tunnels().await?.len(), 1);
+
+    // Closing freed a slot for another tunnel.
+    let reopened = handle.open_tunnel("localhost", second, 0).await?;
+    assert_ne!(reopened.tunnel, opened.tunnel);
+    assert_eq!(visit(reopened.port).await?, "second");
+
+    // Only the first tunnel authenticated.
+    assert_eq!(backend.validations(), 1);
+
+    loop {
+        let event = time::timeout(Duration::from_secs(2), events.recv()).await??;
+        if let ClientEvent::TunnelOpened {
+            tunnel, local_port, ..
+        } = event
+        {
+            assert_eq!((tunnel, local_port), (opened.tunnel, second));
+            break;
+        }
+    }
+
+    handle.stop().await?;
+    wait_for_close(reopened.port).await
+}
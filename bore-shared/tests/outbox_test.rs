@@ -0,0 +1,138 @@
+/// Integration test: lifecycle events delivered exactly once across an outage
+///
+/// Tunnel events go through the server's durable outbox. While the backend is
+/// down they stay queued; when responses get lost after the backend applied an
+/// event, the redelivery must be recognized by its event ID. In the end the
+/// backend must have applied every event once, in order, with the bytes the
+/// tunnel forwarded.
+mod integration {
+    pub mod fixtures;
+}
+
+use integration::fixtures;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::Client;
+use bore_server::backend::{resilience::BreakerConfig, BackendClient};
+use bore_server::{outbox::Outbox, Server};
+use fixtures::mock_backend::MockBackend;
+use fixtures::test_helpers::find_available_port;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Poll `condition` until it holds, failing after a few seconds.
+async fn wait_for(what: &str, condition: impl Fn() -> bool) -> Result<()> {
+    for _ in 0..100 {
+        if condition() {
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    bail!("timed out waiting for {what}")
+}
+
+#[tokio::test]
+async fn events_delivered_exactly_once() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let backend_port = find_available_port()?;
+    let backend = MockBackend::new(backend_port);
+    tokio::spawn(backend.clone().start());
+    let token = backend.create_tunnel_token("inst_1", "user_1", 3600);
+
+    let outbox_path: PathBuf =
+        std::env::temp_dir().join(format!("bore-outbox-test-{}.jsonl", uuid::Uuid::new_v4()));
+    let mut backend_client = BackendClient::new(format!("http://127.0.0.1:{backend_port}"), None);
+    backend_client.set_breaker_config(BreakerConfig {
+        failure_threshold: 5,
+        cooldown: Duration::from_millis(100),
+    });
+    let mut server = Server::new(1024..=65535, None, backend_client, "test".to_string());
+    server.set_outbox(
+        Outbox::open(&outbox_path)?
+            .with_retry_delays(Duration::from_millis(50), Duration::from_millis(200)),
+    );
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(100)).await;
+
+    // A local service answering every five-byte request with "pong".
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = local.accept().await {
+            let mut request = [0; 5];
+            if stream.read_exact(&mut request).await.is_ok() {
+                let _ = stream.write_all(b"pong").await;
+            }
+        }
+    });
+    let client = Client::new("localhost", local_port, "localhost", 0, Some(&token)).await?;
+    let remote_port = client.remote_port();
+    let client = tokio::spawn(client.listen());
+    wait_for("start events", || backend.events().len() == 2).await?;
+
+    let mut visitor = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+    visitor.write_all(b"ping!").await?;
+    visitor.shutdown().await?;
+    let mut reply = Vec::new();
+    visitor.read_to_end(&mut reply).await?;
+    assert_eq!(reply, b"pong");
+    time::sleep(Duration::from_millis(100)).await;
+
+    // The backend goes down, then the tunnel closes.
+    backend.set_outage(true);
+    client.abort();
+    time::sleep(Duration::from_millis(800)).await;
+    assert_eq!(backend.events().len(), 2);
+
+    // The backend comes back but its responses get lost, so the outbox redelivers.
+    backend.set_outage(false);
+    backend.set_lose_responses(true);
+    wait_for("a redelivery", || backend.duplicate_events() > 0).await?;
+    backend.set_lose_responses(false);
+    wait_for("end events", || backend.events().len() == 5).await?;
+
+    let events = backend.events();
+    let paths: Vec<&str> = events.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        [
+            "/api/internal/tunnel/start",
+            "/api/internal/instances/inst_1/tunnel-connected",
+            "/api/internal/instances/inst_1/tunnel-disconnected",
+            "/api/internal/tunnel/end",
+            "/api/internal/tunnel/usage",
+        ]
+    );
+    assert_eq!(events[3].body["bytes_transferred"], 9);
+    assert_eq!(events[4].body["bytes_in"], 5);
+    assert_eq!(events[4].body["bytes_out"], 4);
+
+    // Every queued event has been acknowledged in the log.
+    wait_for("acknowledgements", || {
+        let log = std::fs::read_to_string(&outbox_path).unwrap_or_default();
+        let acks = log
+            .lines()
+            .filter(|l| l.contains(r#""type":"ack""#))
+            .count();
+        acks == 5
+    })
+    .await?;
+    std::fs::remove_file(&outbox_path)?;
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+/// Integration test: port allocation in a small range
+///
+/// With only three ports, clients asking for any port must all get distinct
+/// ports, and the fourth must be refused right away with a clear error.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_client::Client;
+use bore_server::backend::NoopBackend;
+use bore_server::ports::AllocationStrategy;
+use bore_server::Server;
+use futures_util::future::join_all;
+use lazy_static::lazy_static;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Start of a range of three ports, high enough to be rarely used.
+const BASE_PORT: u16 = 47310;
+
+async fn connect() -> Result<Client> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    Client::new("localhost", local_port, "localhost", 0, None).await
+}
+
+async fn spawn_server(strategy: AllocationStrategy) {
+    let mut server = Server::new(
+        BASE_PORT..=BASE_PORT + 2,
+        None,
+        NoopBackend,
+        "test".to_string(),
+    );
+    server.set_port_strategy(strategy);
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn three_port_range_under_contention() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(AllocationStrategy::Random).await;
+
+    let results = join_all((0..5).map(|_| connect())).await;
+    let (clients, errors): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+
+    let mut ports: Vec<u16> = clients
+        .iter()
+        .map(|c| c.as_ref().unwrap().remote_port())
+        .collect();
+    ports.sort_unstable();
+    assert_eq!(ports, [BASE_PORT, BASE_PORT + 1, BASE_PORT + 2]);
+
+    assert_eq!(errors.len(), 2);
+    for err in errors {
+        let err = err.err().unwrap();
+        assert!(
+            err.to_string().contains("no available ports in range"),
+            "{err}"
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sequential_strategy_reuses_freed_port_last() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(AllocationStrategy::Sequential).await;
+
+    let first = connect().await?;
+    let second = connect().await?;
+    assert_eq!(first.remote_port(), BASE_PORT);
+    assert_eq!(second.remote_port(), BASE_PORT + 1);
+    drop(first);
+
+    // The next port is taken before wrapping around to the freed one.
+    let third = connect().await?;
+    assert_eq!(third.remote_port(), BASE_PORT + 2);
+
+    Ok(())
+}
@@ -0,0 +1,142 @@
+/// Integration test: health and readiness endpoints
+///
+/// The admin API reports the server ready once its control listener is bound
+/// and not ready once it drains, without asking probes for the admin token. A
+/// probe that opens and closes a control connection leaves the server serving,
+/// and is not logged as a failure.
+mod integration {
+    pub mod fixtures;
+}
+
+use integration::fixtures;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_client::Client;
+use bore_server::backend::NoopBackend;
+use bore_server::Server;
+use bore_shared::CONTROL_PORT;
+use fixtures::test_helpers::find_available_port;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Mutex};
+use tokio::time;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Records the messages of log events, with their levels.
+#[derive(Clone, Default)]
+struct Recorder(Arc<StdMutex<Vec<(Level, String)>>>);
+
+impl Recorder {
+    fn warnings(&self) -> Vec<String> {
+        let events = self.0.lock().unwrap();
+        events
+            .iter()
+            .filter(|(level, _)| *level <= Level::WARN)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    fn contains(&self, message: &str) -> bool {
+        self.0.lock().unwrap().iter().any(|(_, m)| m == message)
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Recorder {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        struct Message(String);
+        impl Visit for Message {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        let level = *event.metadata().level();
+        self.0.lock().unwrap().push((level, message.0));
+    }
+}
+
+async fn readiness(admin_url: &str) -> Result<(u16, Value)> {
+    let response = reqwest::get(format!("{admin_url}/readyz")).await?;
+    let status = response.status().as_u16();
+    Ok((status, response.json().await?))
+}
+
+#[tokio::test]
+async fn ready_until_draining() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let admin_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, find_available_port()?));
+    let admin_url = format!("http://{admin_addr}");
+    let mut server = Server::new(1024..=65535, None, NoopBackend, "test".to_string());
+    server.set_admin(admin_addr, Some("admin-token".to_string()));
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(server.listen_until(async {
+        let _ = stopped.await;
+    }));
+    time::sleep(Duration::from_millis(100)).await;
+
+    let health = reqwest::get(format!("{admin_url}/healthz")).await?;
+    assert_eq!(health.status(), 200);
+    let (status, body) = readiness(&admin_url).await?;
+    assert_eq!(status, 200, "{body}");
+    assert_eq!(body["listening"], true);
+
+    // A TCP probe of the control port is not a client.
+    drop(TcpStream::connect(("127.0.0.1", CONTROL_PORT)).await?);
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    Client::new("localhost", local_port, "localhost", 0, None).await?;
+
+    shutdown.send(()).unwrap();
+    server.await??;
+    let (status, body) = readiness(&admin_url).await?;
+    assert_eq!(status, 503, "{body}");
+    assert_eq!(body["draining"], true);
+    assert_eq!(body["listening"], false);
+    Ok(())
+}
+
+#[tokio::test]
+async fn probes_are_not_failures() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let health_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, find_available_port()?));
+    let mut server = Server::new(1024..=65535, None, NoopBackend, "test".to_string());
+    server.set_health_addr(health_addr);
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(100)).await;
+
+    // The test runtime runs every task on this thread, so a thread-local
+    // subscriber sees the server's logs from here on.
+    let recorder = Recorder::default();
+    let _logs = tracing_subscriber::registry()
+        .with(recorder.clone())
+        .set_default();
+    drop(TcpStream::connect(("127.0.0.1", CONTROL_PORT)).await?);
+    let (status, body) = readiness(&format!("http://{health_addr}")).await?;
+    assert_eq!(status, 200, "{body}");
+    time::sleep(Duration::from_millis(100)).await;
+
+    assert!(recorder.contains("connection closed before handshake"));
+    assert_eq!(recorder.warnings(), Vec::<String>::new());
+    Ok(())
+}
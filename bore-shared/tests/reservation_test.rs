@@ -0,0 +1,101 @@
+/// Integration test: per-user port reservations
+///
+/// Reserved ports, from the server's reservations file or from the backend,
+/// are only bound for their owner, who gets them back on every reconnect.
+mod integration {
+    pub mod fixtures;
+}
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::Client;
+use bore_server::backend::{RecordingBackend, ValidateKeyResponse};
+use bore_server::outbox::OutboxEvent;
+use bore_server::reservations::PortReservations;
+use bore_server::Server;
+use integration::fixtures::test_helpers::find_available_port;
+use lazy_static::lazy_static;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+async fn connect(api_key: &str, port: u16) -> Result<Client> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    Client::new("localhost", local_port, "localhost", port, Some(api_key)).await
+}
+
+/// Wait until the server has reported `started` tunnels, of which `ended` have closed.
+async fn wait_for_tunnels(backend: &RecordingBackend, started: usize, ended: usize) -> Result<()> {
+    for _ in 0..100 {
+        let events = backend.events();
+        let starts = events
+            .iter()
+            .filter(|e| matches!(e, OutboxEvent::TunnelStart { .. }))
+            .count();
+        let ends = events
+            .iter()
+            .filter(|e| matches!(e, OutboxEvent::TunnelEnd { .. }))
+            .count();
+        if starts == started && ends == ended {
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    bail!("timed out waiting for tunnels to close")
+}
+
+#[tokio::test]
+async fn reserved_ports_stick_to_their_owner() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let alice_port = find_available_port()?;
+    let bob_port = find_available_port()?;
+
+    let backend = Arc::new(RecordingBackend::new());
+    let mut alice = ValidateKeyResponse::allowed("alice", 2);
+    alice.reserved_ports = vec![alice_port];
+    backend.add_key("sk_alice", alice);
+    backend.add_key("sk_bob", ValidateKeyResponse::allowed("bob", 2));
+
+    let mut server = Server::new(1024..=65535, None, Arc::clone(&backend), "test".to_string());
+    server.set_port_reservations(PortReservations::parse(&format!("{bob_port} bob"))?);
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Reservations from the backend and from the file are handed out first.
+    let client = connect("sk_alice", 0).await?;
+    assert_eq!(client.remote_port(), alice_port);
+    drop(client);
+    let bob = connect("sk_bob", 0).await?;
+    assert_eq!(bob.remote_port(), bob_port);
+
+    // Reconnecting gives the same address again.
+    wait_for_tunnels(&backend, 2, 1).await?;
+    let client = connect("sk_alice", 0).await?;
+    assert_eq!(client.remote_port(), alice_port);
+
+    // Nobody else may bind a reserved port.
+    let err = connect("sk_bob", alice_port)
+        .await
+        .err()
+        .expect("alice's port");
+    assert!(err.to_string().contains("reserved"), "{err}");
+    let err = connect("sk_alice", bob_port)
+        .await
+        .err()
+        .expect("bob's port");
+    assert!(err.to_string().contains("reserved"), "{err}");
+
+    Ok(())
+}
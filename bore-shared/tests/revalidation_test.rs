@@ -0,0 +1,132 @@
+/// Integration test: periodic revalidation of running sessions
+///
+/// The server checks the credential of every session again on an interval.
+/// Sessions whose key was revoked or whose subscription lapsed are closed with
+/// a typed error, while a backend outage leaves them running.
+mod integration {
+    pub mod fixtures;
+}
+
+use integration::fixtures;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::{exit, Client};
+use bore_server::backend::{resilience::BreakerConfig, BackendClient};
+use bore_server::Server;
+use bore_shared::{ErrorCode, ServerError};
+use fixtures::mock_backend::MockBackend;
+use fixtures::test_helpers::find_available_port;
+use lazy_static::lazy_static;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Start a mock backend and a server revalidating sessions every 200ms.
+async fn spawn() -> Result<MockBackend> {
+    let backend_port = find_available_port()?;
+    let backend = MockBackend::new(backend_port);
+    tokio::spawn(backend.clone().start());
+
+    // Recover quickly from the simulated outage.
+    let mut backend_client = BackendClient::new(format!("http://127.0.0.1:{backend_port}"), None);
+    backend_client.set_breaker_config(BreakerConfig {
+        failure_threshold: 5,
+        cooldown: Duration::from_millis(100),
+    });
+    let mut server = Server::new(1024..=65535, None, backend_client, "test".to_string());
+    server.set_revalidate_interval(Duration::from_millis(200));
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(100)).await;
+    Ok(backend)
+}
+
+/// Connect a client with `api_key`, returning its public port and its session.
+async fn connect(api_key: &str) -> Result<(u16, tokio::task::JoinHandle<Result<()>>)> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = Client::new("localhost", local_port, "localhost", 0, Some(api_key)).await?;
+    let remote_port = client.remote_port();
+    Ok((remote_port, tokio::spawn(client.listen())))
+}
+
+/// Wait for the server to close a session, returning the client's error.
+async fn closed(session: tokio::task::JoinHandle<Result<()>>) -> Result<anyhow::Error> {
+    match time::timeout(Duration::from_secs(5), session).await?? {
+        Ok(()) => bail!("expected the session to end with an error"),
+        Err(err) => Ok(err),
+    }
+}
+
+fn code(err: &anyhow::Error) -> Option<ErrorCode> {
+    err.downcast_ref::<ServerError>().map(|error| error.code)
+}
+
+#[tokio::test]
+async fn revoked_key_closes_session() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let backend = spawn().await?;
+    let api_key = backend.register_user("revoked@example.com", "password");
+    let (remote_port, session) = connect(&api_key).await?;
+
+    backend.revoke_api_key(&api_key);
+    let err = closed(session).await?;
+    assert_eq!(code(&err), Some(ErrorCode::InvalidCredentials));
+    assert_eq!(exit::for_error(&err), 11);
+    assert!(TcpStream::connect(("localhost", remote_port))
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn lapsed_usage_closes_session() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let backend = spawn().await?;
+    let api_key = backend.register_user("lapsed@example.com", "password");
+    let (_, session) = connect(&api_key).await?;
+
+    // Still allowed after a few revalidations.
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(!session.is_finished());
+    assert!(backend.validation_requests() >= 2);
+
+    backend.set_usage_allowed(&api_key, false);
+    let err = closed(session).await?;
+    assert_eq!(code(&err), Some(ErrorCode::UsageNotAllowed));
+    assert!(err.to_string().contains("Subscription expired"), "{err}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn outage_keeps_session() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let backend = spawn().await?;
+    let api_key = backend.register_user("outage@example.com", "password");
+    let (remote_port, session) = connect(&api_key).await?;
+
+    backend.set_outage(true);
+    let before = backend.validation_requests();
+    time::sleep(Duration::from_millis(600)).await;
+    assert!(backend.validation_requests() > before);
+    assert!(!session.is_finished());
+    assert!(TcpStream::connect(("localhost", remote_port)).await.is_ok());
+
+    backend.set_outage(false);
+    backend.revoke_api_key(&api_key);
+    let err = closed(session).await?;
+    assert_eq!(code(&err), Some(ErrorCode::InvalidCredentials));
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+/// Integration test: multiple named shared secrets
+///
+/// The server accepts any active secret from its secrets file, and counts
+/// tunnels against the name of the secret each client used. A secret
+/// re-issued under a new name keeps working once its old entry expires.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_client::Client;
+use bore_server::{backend::NoopBackend, secrets::SharedSecrets, Server};
+use lazy_static::lazy_static;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+const SECRETS: &str = "
+# retired last year, kept around to report a clear error
+old-ci   s1   expires=2000-01-01T00:00:00Z
+ci       s2   expires=2999-01-01T00:00:00Z max_tunnels=1
+# the laptop's secret, re-issued under a new name
+old-laptop s3 expires=2000-01-01T00:00:00Z
+laptop   s3
+";
+
+async fn spawn_server(secret: Option<&str>) -> Result<()> {
+    let mut server = Server::new(1024..=65535, secret, NoopBackend, "test".to_string());
+    server.set_secrets(SharedSecrets::parse(SECRETS)?);
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    Ok(())
+}
+
+async fn connect(secret: &str) -> Result<Client> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    Client::new("localhost", local_port, "localhost", 0, Some(secret)).await
+}
+
+#[tokio::test]
+async fn any_active_secret_is_accepted() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(Some("legacy")).await?;
+
+    connect("s2").await?;
+    connect("s3").await?;
+    connect("legacy").await?;
+
+    let err = connect("s1").await.err().expect("expired secret");
+    assert!(err.to_string().contains("expired"), "{err}");
+    assert!(connect("wrong").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn limits_apply_per_secret() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await?;
+
+    let first = connect("s2").await?;
+    tokio::spawn(first.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let err = connect("s2").await.err().expect("second tunnel for ci");
+    assert!(
+        err.to_string().contains("Maximum concurrent tunnels"),
+        "{err}"
+    );
+
+    // Another secret is another identity, with its own count.
+    connect("s3").await?;
+
+    Ok(())
+}
@@ -0,0 +1,112 @@
+/// Integration test: registration with the backend's server registry
+///
+/// A server with a registry configuration registers its public host, region
+/// and port range on startup, reports its tunnels, connections and traffic in
+/// heartbeats, and deregisters when it shuts down.
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::Client;
+use bore_server::backend::{RecordingBackend, ValidateKeyResponse};
+use bore_server::registry::{RegistryConfig, ServerLoad};
+use bore_server::Server;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Mutex};
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// A local service echoing what it receives.
+async fn spawn_echo() -> Result<u16> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok(port)
+}
+
+/// Poll until a reported load matches `check`.
+async fn wait_for_load(
+    backend: &RecordingBackend,
+    check: impl Fn(&ServerLoad) -> bool,
+) -> Result<ServerLoad> {
+    for _ in 0..100 {
+        if let Some(load) = backend.loads().into_iter().rev().find(|load| check(load)) {
+            return Ok(load);
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    bail!("no matching load in {:?}", backend.loads())
+}
+
+#[tokio::test]
+async fn registers_reports_load_and_deregisters() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let backend = Arc::new(RecordingBackend::new());
+    backend.add_key("sk_user", ValidateKeyResponse::allowed("user_1", 5));
+
+    let mut config = RegistryConfig::new("bore.example.com", "eu-north");
+    config.heartbeat_interval = Duration::from_millis(100);
+    let mut server = Server::new(9000..=9999, None, Arc::clone(&backend), "s1".to_string());
+    server.set_registry(config);
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let listening = tokio::spawn(server.listen_until(async {
+        let _ = stopped.await;
+    }));
+    time::sleep(Duration::from_millis(50)).await;
+
+    let servers = backend.servers();
+    assert_eq!(servers.len(), 1);
+    let info = &servers[0];
+    assert_eq!(info.id, "s1");
+    assert_eq!(info.host, "bore.example.com");
+    assert_eq!(info.location, "eu-north");
+    assert_eq!((info.min_port, info.max_port), (9000, 9999));
+    assert_eq!(info.max_concurrent_tunnels, 1000);
+
+    let local_port = spawn_echo().await?;
+    let client = Client::new("localhost", local_port, "localhost", 0, Some("sk_user")).await?;
+    let remote_port = client.remote_port();
+    let session = tokio::spawn(client.listen());
+
+    let mut visitor = TcpStream::connect(("localhost", remote_port)).await?;
+    visitor.write_all(b"hello").await?;
+    let mut buf = [0; 5];
+    time::timeout(Duration::from_secs(2), visitor.read_exact(&mut buf)).await??;
+    assert_eq!(&buf, b"hello");
+    wait_for_load(&backend, |load| {
+        load.current_load == 1 && load.active_connections == 1
+    })
+    .await?;
+
+    // Traffic is counted once the connection ends.
+    drop(visitor);
+    let load = wait_for_load(&backend, |load| load.bytes_transferred > 0).await?;
+    assert_eq!(load.bytes_transferred, 10);
+    assert_eq!(load.active_connections, 0);
+
+    shutdown.send(()).unwrap();
+    time::timeout(Duration::from_secs(2), listening).await???;
+    assert!(backend.servers().is_empty());
+    assert!(TcpStream::connect(("localhost", bore_shared::CONTROL_PORT))
+        .await
+        .is_err());
+
+    session.abort();
+    Ok(())
+}
@@ -0,0 +1,317 @@
+/// Integration test: tunnel limits and port reservations across a cluster
+///
+/// Servers sharing state through the Redis protocol count a user's tunnels
+/// together, honor each other's port reservations, and stop counting the
+/// tunnels of a server that no longer renews its leases. A small in-process
+/// stand-in speaks the few Redis commands the servers use.
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use bore_client::Client;
+use bore_server::backend::{RecordingBackend, ValidateKeyResponse};
+use bore_server::state::{MemoryState, RedisState, SharedState};
+use bore_server::Server;
+use bore_shared::{ErrorCode, ServerError};
+use lazy_static::lazy_static;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// A value in the stand-in, with its expiry.
+enum Value {
+    String(String),
+    SortedSet(BTreeMap<String, f64>),
+}
+
+#[derive(Default)]
+struct Store {
+    keys: HashMap<String, (Value, Option<Instant>)>,
+}
+
+impl Store {
+    fn live(&mut self, key: &str) -> Option<&mut Value> {
+        if let Some((_, Some(expiry))) = self.keys.get(key) {
+            if *expiry <= Instant::now() {
+                self.keys.remove(key);
+            }
+        }
+        self.keys.get_mut(key).map(|(value, _)| value)
+    }
+
+    fn zset(&mut self, key: &str) -> &mut BTreeMap<String, f64> {
+        if self.live(key).is_none() {
+            self.keys
+                .insert(key.to_string(), (Value::SortedSet(BTreeMap::new()), None));
+        }
+        match self.live(key) {
+            Some(Value::SortedSet(set)) => set,
+            _ => panic!("{key} is not a sorted set"),
+        }
+    }
+
+    fn expire(&mut self, key: &str, millis: &str) -> i64 {
+        let millis: u64 = millis.parse().unwrap();
+        if self.live(key).is_none() {
+            return 0;
+        }
+        self.keys.get_mut(key).unwrap().1 = Some(Instant::now() + Duration::from_millis(millis));
+        1
+    }
+
+    fn apply(&mut self, args: &[String]) -> String {
+        let score = |bound: &str| -> (f64, bool) {
+            match bound {
+                "-inf" => (f64::NEG_INFINITY, false),
+                "+inf" => (f64::INFINITY, false),
+                _ => match bound.strip_prefix('(') {
+                    Some(bound) => (bound.parse().unwrap(), true),
+                    None => (bound.parse().unwrap(), false),
+                },
+            }
+        };
+        let in_range = |value: f64, min: (f64, bool), max: (f64, bool)| {
+            (if min.1 { value > min.0 } else { value >= min.0 })
+                && (if max.1 { value < max.0 } else { value <= max.0 })
+        };
+        let int = |n: usize| format!(":{n}\r\n");
+        match args[0].to_uppercase().as_str() {
+            "PING" | "AUTH" | "SELECT" => "+OK\r\n".to_string(),
+            "ZADD" => {
+                let added = self
+                    .zset(&args[1])
+                    .insert(args[3].clone(), args[2].parse().unwrap());
+                int(usize::from(added.is_none()))
+            }
+            "ZREM" => int(usize::from(self.zset(&args[1]).remove(&args[2]).is_some())),
+            "ZCARD" => int(self.zset(&args[1]).len()),
+            "ZCOUNT" => {
+                let (min, max) = (score(&args[2]), score(&args[3]));
+                int(self
+                    .zset(&args[1])
+                    .values()
+                    .filter(|value| in_range(**value, min, max))
+                    .count())
+            }
+            "ZREMRANGEBYSCORE" => {
+                let (min, max) = (score(&args[2]), score(&args[3]));
+                let set = self.zset(&args[1]);
+                let before = set.len();
+                set.retain(|_, value| !in_range(*value, min, max));
+                int(before - set.len())
+            }
+            "PEXPIRE" => format!(":{}\r\n", self.expire(&args[1], &args[2])),
+            "GET" => match self.live(&args[1]) {
+                Some(Value::String(value)) => format!("${}\r\n{value}\r\n", value.len()),
+                _ => "$-1\r\n".to_string(),
+            },
+            "SET" => {
+                // SET key value NX PX millis
+                if self.live(&args[1]).is_some() {
+                    return "$-1\r\n".to_string();
+                }
+                self.keys
+                    .insert(args[1].clone(), (Value::String(args[2].clone()), None));
+                self.expire(&args[1], &args[5]);
+                "+OK\r\n".to_string()
+            }
+            other => format!("-ERR unknown command '{other}'\r\n"),
+        }
+    }
+}
+
+/// Start the stand-in, returning its URL.
+async fn spawn_redis() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("redis://:password@{}/0", listener.local_addr()?);
+    let store = Arc::new(StdMutex::new(Store::default()));
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let store = Arc::clone(&store);
+            tokio::spawn(async move {
+                let mut socket = BufReader::new(socket);
+                while let Ok(args) = read_command(&mut socket).await {
+                    let reply = store.lock().unwrap().apply(&args);
+                    if socket.get_mut().write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(url)
+}
+
+async fn read_command(socket: &mut BufReader<TcpStream>) -> Result<Vec<String>> {
+    let mut line = String::new();
+    if socket.read_line(&mut line).await? == 0 {
+        bail!("closed");
+    }
+    let count: usize = line.trim_end().trim_start_matches('*').parse()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        socket.read_line(&mut line).await?;
+        let len: usize = line.trim_end().trim_start_matches('$').parse()?;
+        let mut arg = vec![0; len + 2];
+        socket.read_exact(&mut arg).await?;
+        arg.truncate(len);
+        args.push(String::from_utf8(arg)?);
+    }
+    Ok(args)
+}
+
+/// Start a server on `addr` sharing the state at `url`.
+async fn spawn_server(addr: Ipv4Addr, url: &str, backend: Arc<RecordingBackend>) -> Result<()> {
+    let mut server = Server::new(1024..=65535, None, backend, addr.to_string());
+    server.set_shared_state(RedisState::new(url)?);
+    server.set_lease_ttl(Duration::from_millis(300));
+    server.set_bind_addr(IpAddr::V4(addr));
+    server.set_bind_tunnels(IpAddr::V4(addr));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    Ok(())
+}
+
+/// Start a server on `addr` with ports `range`, sharing `state`.
+async fn spawn_server_with(
+    addr: Ipv4Addr,
+    range: std::ops::RangeInclusive<u16>,
+    state: Arc<MemoryState>,
+    backend: Arc<RecordingBackend>,
+) {
+    let mut server = Server::new(range, None, backend, addr.to_string());
+    server.set_shared_state(state);
+    server.set_bind_addr(IpAddr::V4(addr));
+    server.set_bind_tunnels(IpAddr::V4(addr));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+/// Connect a client to the server on `addr`, returning its public port and session.
+async fn connect(
+    addr: Ipv4Addr,
+    api_key: &str,
+    port: u16,
+) -> Result<(u16, JoinHandle<Result<()>>)> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let to = addr.to_string();
+    let client = Client::new("localhost", local_port, &to, port, Some(api_key)).await?;
+    Ok((client.remote_port(), tokio::spawn(client.listen())))
+}
+
+fn code(err: &anyhow::Error) -> Option<ErrorCode> {
+    err.downcast_ref::<ServerError>().map(|error| error.code)
+}
+
+const NODE_A: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+const NODE_B: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+#[tokio::test]
+async fn limits_and_reservations_span_servers() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let url = spawn_redis().await?;
+    let reserved = 30123;
+    let backend = Arc::new(RecordingBackend::new());
+    let mut alice = ValidateKeyResponse::allowed("alice", 1);
+    alice.reserved_ports = vec![reserved];
+    backend.add_key("sk_alice", alice);
+    backend.add_key("sk_bob", ValidateKeyResponse::allowed("bob", 1));
+    spawn_server(NODE_A, &url, Arc::clone(&backend)).await?;
+    spawn_server(NODE_B, &url, Arc::clone(&backend)).await?;
+
+    let (port, first) = connect(NODE_A, "sk_alice", 0).await?;
+    assert_eq!(port, reserved);
+
+    // The other server counts the tunnel against the limit...
+    let err = connect(NODE_B, "sk_alice", 0).await.unwrap_err();
+    assert_eq!(code(&err), Some(ErrorCode::TunnelLimit));
+
+    // ...and keeps the reserved port from other users.
+    let err = connect(NODE_B, "sk_bob", reserved).await.unwrap_err();
+    assert_eq!(code(&err), Some(ErrorCode::PortNotAllowed));
+
+    // Leases are renewed while the tunnel runs.
+    time::sleep(Duration::from_millis(700)).await;
+    let err = connect(NODE_B, "sk_alice", 0).await.unwrap_err();
+    assert_eq!(code(&err), Some(ErrorCode::TunnelLimit));
+
+    // Closing the tunnel frees its place on every server.
+    first.abort();
+    for _ in 0..50 {
+        if let Ok((_, second)) = connect(NODE_B, "sk_alice", 0).await {
+            second.abort();
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    bail!("tunnel limit not released")
+}
+
+#[tokio::test]
+async fn leases_of_a_crashed_server_expire() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let url = spawn_redis().await?;
+    let crashed = RedisState::new(&url)?;
+    let running = RedisState::new(&url)?;
+    let ttl = Duration::from_millis(200);
+
+    assert!(crashed.acquire_tunnel("alice", "lease-1", 1, ttl).await?);
+    assert!(crashed.reserve_port(9000, "alice", ttl).await?);
+    assert!(!running.acquire_tunnel("alice", "lease-2", 1, ttl).await?);
+    assert!(!running.reserve_port(9000, "bob", ttl).await?);
+    assert_eq!(running.port_owner(9000).await?.as_deref(), Some("alice"));
+
+    // Nobody renews the crashed server's leases.
+    time::sleep(ttl + Duration::from_millis(100)).await;
+    assert_eq!(running.tunnels_of("alice").await?, 0);
+    assert!(running.acquire_tunnel("alice", "lease-2", 1, ttl).await?);
+    assert!(running.reserve_port(9000, "bob", ttl).await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn random_ports_skip_reservations_of_other_servers() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let state = Arc::new(MemoryState::new());
+    let reserved = 30223;
+    let backend = Arc::new(RecordingBackend::new());
+    let mut alice = ValidateKeyResponse::allowed("alice", 1);
+    alice.reserved_ports = vec![reserved];
+    backend.add_key("sk_alice", alice);
+    backend.add_key("sk_bob", ValidateKeyResponse::allowed("bob", 10));
+    spawn_server_with(
+        NODE_A,
+        1024..=65535,
+        Arc::clone(&state),
+        Arc::clone(&backend),
+    )
+    .await;
+    // The second server only knows of the reservation through the shared state.
+    spawn_server_with(
+        NODE_B,
+        reserved..=reserved + 1,
+        Arc::clone(&state),
+        Arc::clone(&backend),
+    )
+    .await;
+
+    let (port, _alice) = connect(NODE_A, "sk_alice", 0).await?;
+    assert_eq!(port, reserved);
+
+    let (port, _bob) = connect(NODE_B, "sk_bob", 0).await?;
+    assert_eq!(port, reserved + 1);
+    let err = connect(NODE_B, "sk_bob", 0).await.unwrap_err();
+    assert_eq!(code(&err), Some(ErrorCode::NoPortsAvailable));
+    Ok(())
+}
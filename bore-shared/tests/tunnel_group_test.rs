@@ -0,0 +1,143 @@
+/// Integration test: several clients sharing one public port
+///
+/// Clients joining the same group get the same public port, and incoming
+/// connections are spread across them. A client that goes away stops receiving
+/// connections, while the others keep serving the port.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_client::Client;
+use bore_server::backend::NoopBackend;
+use bore_server::Server;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Start of a range of three ports, high enough to be rarely used.
+const BASE_PORT: u16 = 47320;
+
+async fn spawn_server() {
+    let mut server = Server::new(
+        BASE_PORT..=BASE_PORT + 2,
+        None,
+        NoopBackend,
+        "test".to_string(),
+    );
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+/// A local service answering every connection with its name.
+async fn spawn_service(name: &'static str) -> Result<u16> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let _ = stream.write_all(name.as_bytes()).await;
+        }
+    });
+    Ok(port)
+}
+
+/// Join a group with a client forwarding to the service `name`.
+async fn join(name: &'static str, port: u16, group: Option<&str>) -> Result<(u16, JoinHandle<()>)> {
+    let local_port = spawn_service(name).await?;
+    let client = Client::join(
+        "localhost",
+        local_port,
+        "localhost",
+        port,
+        group,
+        None,
+        None,
+    )
+    .await?;
+    let remote_port = client.remote_port();
+    let task = tokio::spawn(async move {
+        let _ = client.listen().await;
+    });
+    Ok((remote_port, task))
+}
+
+/// Which service answered a connection to the public port.
+async fn request(port: u16) -> Result<String> {
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    let mut answer = String::new();
+    time::timeout(Duration::from_secs(2), stream.read_to_string(&mut answer)).await??;
+    Ok(answer)
+}
+
+#[tokio::test]
+async fn shared_port_balances_and_drops_dead_members() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server().await;
+
+    let (first_port, first) = join("a", BASE_PORT, None).await?;
+    let (second_port, _second) = join("b", BASE_PORT, None).await?;
+    assert_eq!((first_port, second_port), (BASE_PORT, BASE_PORT));
+
+    // A plain tunnel still cannot take the port.
+    let err = Client::new("localhost", 1, "localhost", BASE_PORT, None)
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("port already in use"), "{err}");
+
+    // Round-robin takes turns.
+    let mut answers = Vec::new();
+    for _ in 0..4 {
+        answers.push(request(BASE_PORT).await?);
+    }
+    assert_eq!(answers, ["a", "b", "a", "b"]);
+
+    // Once its control connection is gone, the first client is dropped.
+    first.abort();
+    time::sleep(Duration::from_millis(1500)).await;
+    for _ in 0..3 {
+        assert_eq!(request(BASE_PORT).await?, "b");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn groups_are_found_by_name() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server().await;
+
+    let (api_port, _api) = join("a", 0, Some("api")).await?;
+    let (replica_port, _replica) = join("b", 0, Some("api")).await?;
+    let (web_port, _web) = join("c", 0, Some("web")).await?;
+    assert_eq!(api_port, replica_port);
+    assert_ne!(api_port, web_port);
+
+    // The name must agree with an explicitly requested port.
+    let other = if api_port == BASE_PORT {
+        BASE_PORT + 1
+    } else {
+        BASE_PORT
+    };
+    let err = join("d", other, Some("api")).await.err().unwrap();
+    assert!(err.to_string().contains("another port"), "{err}");
+
+    let mut answers = Vec::new();
+    for _ in 0..2 {
+        answers.push(request(api_port).await?);
+    }
+    answers.sort();
+    assert_eq!(answers, ["a", "b"]);
+    assert_eq!(request(web_port).await?, "c");
+
+    Ok(())
+}
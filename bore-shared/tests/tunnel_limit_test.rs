@@ -9,7 +9,7 @@
 /// section, with rollback on setup failure.
 use anyhow::Result;
 use bore_client::Client;
-use bore_server::Server;
+use bore_server::{backend::NoopBackend, Server};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
@@ -19,7 +19,7 @@ use tokio::time;
 #[tokio::test]
 async fn test_concurrent_tunnel_limit_enforcement() -> Result<()> {
     // Start server with backend disabled (uses default limits in legacy mode)
-    tokio::spawn(Server::new(1024..=65535, None, None, None, "test".to_string()).listen());
+    tokio::spawn(Server::new(1024..=65535, None, NoopBackend, "test".to_string()).listen());
     time::sleep(Duration::from_millis(100)).await;
 
     // Try to create multiple tunnels concurrently to test for race conditions
@@ -97,7 +97,7 @@ async fn test_tunnel_limit_rollback_on_failure() -> Result<()> {
     // the counter is properly rolled back (decremented).
 
     // Start server
-    tokio::spawn(Server::new(1024..=65535, None, None, None, "test2".to_string()).listen());
+    tokio::spawn(Server::new(1024..=65535, None, NoopBackend, "test2".to_string()).listen());
     time::sleep(Duration::from_millis(100)).await;
 
     // Create a tunnel that will succeed
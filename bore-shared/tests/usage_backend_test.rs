@@ -0,0 +1,103 @@
+/// Integration test: in-memory backend
+///
+/// A server embedded with a `RecordingBackend` validates keys and reports
+/// tunnel events without any HTTP backend, so tests can assert on the events.
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bore_client::Client;
+use bore_server::backend::{RecordingBackend, ValidateKeyResponse};
+use bore_server::outbox::OutboxEvent;
+use bore_server::Server;
+use lazy_static::lazy_static;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Poll until the backend has received `count` events, failing after a few seconds.
+async fn wait_for_events(backend: &RecordingBackend, count: usize) -> Result<Vec<OutboxEvent>> {
+    for _ in 0..100 {
+        let events = backend.events();
+        if events.len() >= count {
+            return Ok(events);
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    bail!("timed out waiting for {count} events")
+}
+
+async fn connect(api_key: &str) -> Result<Client> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    Client::new("localhost", local_port, "localhost", 0, Some(api_key)).await
+}
+
+#[tokio::test]
+async fn events_recorded_in_memory() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let backend = Arc::new(RecordingBackend::new());
+    let mut validation = ValidateKeyResponse::allowed("user_1", 2);
+    validation.instance_id = Some("inst_1".to_string());
+    backend.add_key("sk_recorded", validation);
+
+    let mut server = Server::new(1024..=65535, None, Arc::clone(&backend), "test".to_string());
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let err = connect("sk_unknown").await.err().expect("unknown key");
+    assert!(err.to_string().contains("Invalid API key"), "{err}");
+
+    let client = connect("sk_recorded").await?;
+    let public_port = client.remote_port();
+    drop(client);
+
+    let events = wait_for_events(&backend, 5).await?;
+    let OutboxEvent::TunnelStart {
+        session_id,
+        user_id,
+        public_port: started_port,
+        ..
+    } = &events[0]
+    else {
+        panic!("expected a tunnel start, got {events:?}");
+    };
+    assert_eq!(user_id, "user_1");
+    assert_eq!(*started_port, public_port);
+    assert_eq!(
+        events[1..],
+        [
+            OutboxEvent::TunnelConnected {
+                instance_id: "inst_1".to_string(),
+                remote_port: Some(public_port),
+                public_url: None,
+                status: None,
+            },
+            OutboxEvent::TunnelDisconnected {
+                instance_id: "inst_1".to_string(),
+            },
+            OutboxEvent::TunnelEnd {
+                session_id: session_id.clone(),
+                bytes_transferred: 0,
+            },
+            OutboxEvent::Usage {
+                user_id: "user_1".to_string(),
+                session_id: session_id.clone(),
+                bytes_in: 0,
+                bytes_out: 0,
+            },
+        ]
+    );
+    assert_eq!(backend.validations(), 2);
+
+    Ok(())
+}
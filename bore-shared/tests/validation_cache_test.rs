@@ -0,0 +1,121 @@
+/// Integration test: cached API key validations
+///
+/// A reconnecting client should only reach the backend once per TTL, the admin
+/// API should be able to drop entries, and a stale positive entry should keep
+/// admitting the client for a bounded time while the backend is down.
+mod integration {
+    pub mod fixtures;
+}
+
+use integration::fixtures;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_client::Client;
+use bore_server::{backend::BackendClient, cache::CacheConfig, Server};
+use fixtures::mock_backend::MockBackend;
+use fixtures::test_helpers::find_available_port;
+use lazy_static::lazy_static;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Start a mock backend and a server caching its validations, returning the
+/// backend and the admin API URL.
+async fn spawn(config: CacheConfig) -> Result<(MockBackend, String)> {
+    let backend_port = find_available_port()?;
+    let backend = MockBackend::new(backend_port);
+    tokio::spawn(backend.clone().start());
+
+    let admin_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, find_available_port()?));
+    let backend_url = format!("http://127.0.0.1:{backend_port}");
+    let mut server = Server::new(
+        1024..=65535,
+        None,
+        BackendClient::new(backend_url, None),
+        "test".to_string(),
+    );
+    server.set_validation_cache(config);
+    server.set_admin(admin_addr, Some("admin-token".to_string()));
+    server.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    server.set_bind_tunnels(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(100)).await;
+
+    Ok((backend, format!("http://{admin_addr}")))
+}
+
+async fn connect(api_key: &str) -> Result<()> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = Client::new("localhost", local_port, "localhost", 0, Some(api_key)).await?;
+    drop(client);
+    // Let the server notice the closed tunnel before the next attempt.
+    time::sleep(Duration::from_millis(50)).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnects_served_from_cache() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let (backend, admin_url) = spawn(CacheConfig {
+        positive_ttl: Duration::from_secs(60),
+        negative_ttl: Duration::from_secs(60),
+        stale_if_error: Duration::ZERO,
+    })
+    .await?;
+    let api_key = backend.register_user("cache@example.com", "password");
+
+    connect(&api_key).await?;
+    connect(&api_key).await?;
+    assert_eq!(backend.validation_requests(), 1);
+
+    // Rejected keys are cached too.
+    assert!(connect("sk_test_unknown").await.is_err());
+    assert!(connect("sk_test_unknown").await.is_err());
+    assert_eq!(backend.validation_requests(), 2);
+
+    // Dropping the entry through the admin API forces a fresh validation.
+    let response = reqwest::Client::new()
+        .post(format!("{admin_url}/cache/invalidate"))
+        .bearer_auth("admin-token")
+        .json(&json!({ "api_key": api_key }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    connect(&api_key).await?;
+    assert_eq!(backend.validation_requests(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn stale_entry_survives_outage() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let (backend, _admin_url) = spawn(CacheConfig {
+        positive_ttl: Duration::from_millis(200),
+        negative_ttl: Duration::ZERO,
+        stale_if_error: Duration::from_millis(800),
+    })
+    .await?;
+    let api_key = backend.register_user("stale@example.com", "password");
+    connect(&api_key).await?;
+
+    backend.set_outage(true);
+    time::sleep(Duration::from_millis(300)).await;
+    connect(&api_key).await?;
+
+    // Past the stale window the outage is visible to the client.
+    time::sleep(Duration::from_millis(800)).await;
+    assert!(connect(&api_key).await.is_err());
+
+    Ok(())
+}